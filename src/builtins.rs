@@ -1,8 +1,23 @@
-use std::{borrow::Cow, collections::hash_map::Entry};
+use std::{
+    borrow::Cow,
+    collections::{btree_map::Entry, BTreeMap},
+    fmt::Write as _,
+    io::Write as _,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 use color_eyre::eyre::eyre;
 
-use crate::{command::Command, Shell};
+use crate::{
+    command::Command,
+    config, output, plugins,
+    read_line::{cursor, PromptAnswer, PromptMode, PromptOpts},
+    tty::Tty,
+    utils::{self, quote_word},
+    widget::{self, GridStyle},
+    Shell,
+};
 
 pub type Result = color_eyre::Result<()>;
 
@@ -11,8 +26,14 @@ pub enum Action {
     Alias {
         cmd: String,
         extra_args: Vec<String>,
+        /// Whether the value ends with a space, which makes the next word an alias too
+        expand_next: bool,
     },
     Fn(fn(&mut Shell, Command) -> Result),
+    /// A program of the plugins folder, see [`plugins`].
+    Plugin {
+        path: PathBuf,
+    },
 }
 
 impl std::fmt::Debug for Action {
@@ -20,6 +41,7 @@ impl std::fmt::Debug for Action {
         let variant = match self {
             Self::Fn(_) => "Fn",
             Self::Alias { .. } => "Alias",
+            Self::Plugin { .. } => "Plugin",
         };
         write!(
             f,
@@ -27,6 +49,7 @@ impl std::fmt::Debug for Action {
             variant,
             match self {
                 Self::Alias { cmd, .. } => cmd.as_ref(),
+                Self::Plugin { path } => path.to_str().unwrap_or("..."),
                 _ => "...",
             }
         )
@@ -34,86 +57,249 @@ impl std::fmt::Debug for Action {
 }
 
 impl std::fmt::Display for Action {
+    /// Writes what an alias expands to, quoted so it can be typed back.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Alias { cmd, extra_args } => write!(f, "{} {}", cmd, extra_args.join(" ")),
+            Self::Alias {
+                cmd,
+                extra_args,
+                expand_next,
+            } => {
+                let words = alias_words(cmd, extra_args)
+                    .map(|word| quote_word(word))
+                    .collect::<Vec<_>>();
+                write!(f, "{}", words.join(" "))?;
+                if *expand_next {
+                    write!(f, " ")?;
+                }
+                Ok(())
+            }
             Self::Fn(_) => write!(f, "<builtin>"),
+            Self::Plugin { path } => write!(f, "<plugin {}>", path.display()),
         }
     }
 }
 
+/// The words an alias expands to. An alias to nothing has an empty `cmd` and no words.
+fn alias_words<'a>(cmd: &'a String, extra_args: &'a [String]) -> impl Iterator<Item = &'a String> {
+    std::iter::once(cmd)
+        .filter(|cmd| !cmd.is_empty())
+        .chain(extra_args)
+}
+
+/// How many aliases can be expanded inside one another.
+const MAX_ALIAS_DEPTH: usize = 16;
+
 impl Action {
     pub fn call(&self, shell: &mut Shell, command: Command) -> Result {
-        if shell.builtin_recursive_count >= 16 {
-            shell.builtin_recursive_count = 0;
-            return Err(eyre!("Too many layers deep!"));
-        }
         match self {
             Self::Fn(f) => f(shell, command),
-            Self::Alias { cmd, extra_args } => {
+            Self::Plugin { path } => plugins::run(shell, path, command),
+            Self::Alias {
+                cmd,
+                extra_args,
+                expand_next,
+            } => {
+                let name = command.command.clone();
+                if shell.alias_chain.contains(&name) {
+                    let chain = [shell.alias_chain.as_slice(), &[name]].concat();
+                    return Err(eyre!("alias loop: {}", chain.join(" -> ")));
+                }
+                if shell.alias_chain.len() >= MAX_ALIAS_DEPTH {
+                    let chain = [shell.alias_chain.as_slice(), &[name]].concat();
+                    return Err(eyre!("alias expansion too deep: {}", chain.join(" -> ")));
+                }
                 let mut args = extra_args.clone();
                 args.extend_from_slice(&command.args);
+                if *expand_next {
+                    expand_next_aliases(shell, &mut args, extra_args.len(), &name);
+                }
 
-                let cmd = Command {
+                let mut cmd = Command {
                     command: cmd.clone(),
                     args,
                     ..command
                 };
-                shell.builtin_recursive_count += 1;
+                if cmd.command.is_empty() {
+                    // An alias to nothing runs the words after it
+                    if cmd.args.is_empty() {
+                        return Ok(());
+                    }
+                    cmd = cmd.shift();
+                }
+                // An alias naming itself (`alias ls='ls -F'`) refers to the builtin it
+                // replaced, or else to the program.
+                if cmd.command == name {
+                    return match native_builtins().remove(&name) {
+                        Some(builtin) => shell.call_builtin(builtin.action, cmd),
+                        None => Ok(shell.execute_program(cmd)?),
+                    };
+                }
+                shell.alias_chain.push(name);
                 let r = shell.execute(cmd);
-                shell.builtin_recursive_count = 0;
+                shell.alias_chain.pop();
                 r
             }
         }
     }
 }
 
+/// Expands the alias at `args[index]`, and the word after it too if its value also ends
+/// with a space. Aliases already being expanded are left alone.
+fn expand_next_aliases(shell: &Shell, args: &mut Vec<String>, mut index: usize, name: &str) {
+    let mut expanded = vec![name];
+    while let Some(word) = args.get(index) {
+        let Some(Builtin {
+            name,
+            action:
+                Action::Alias {
+                    cmd,
+                    extra_args,
+                    expand_next,
+                },
+            ..
+        }) = shell.builtins.get(word)
+        else {
+            break;
+        };
+        if expanded.contains(&name.as_str()) || shell.alias_chain.contains(name) {
+            break;
+        }
+        expanded.push(name);
+        let words = alias_words(cmd, extra_args).cloned().collect::<Vec<_>>();
+        let count = words.len();
+        args.splice(index..=index, words);
+        if !expand_next {
+            break;
+        }
+        index += count;
+    }
+}
+
 #[derive(Debug)]
 pub struct Builtin {
     pub action: Action,
     pub name: String,
+    /// What the builtin does, in one line. Empty for aliases.
+    pub description: &'static str,
+    /// How to call the builtin, shown by `help NAME`.
+    pub usage: Option<&'static str>,
+    /// Whether an rc file defined it, so `reload` drops it before defining it again.
+    pub from_config: bool,
 }
 
 impl Builtin {
-    pub fn new_fn(name: String, action: fn(&mut Shell, Command) -> Result) -> Self {
+    pub fn new_fn(
+        name: String,
+        action: fn(&mut Shell, Command) -> Result,
+        description: &'static str,
+    ) -> Self {
         Self {
             action: Action::Fn(action),
             name,
+            description,
+            usage: None,
+            from_config: false,
         }
     }
     pub fn new_alias(name: String, cmd: String, extra_args: Vec<String>) -> Self {
         Self {
-            action: Action::Alias { cmd, extra_args },
+            action: Action::Alias {
+                cmd,
+                extra_args,
+                expand_next: false,
+            },
             name,
+            description: "",
+            usage: None,
+            from_config: false,
+        }
+    }
+    /// The command `name` running the plugin program at `path`.
+    pub fn new_plugin(name: String, path: PathBuf) -> Self {
+        Self {
+            action: Action::Plugin { path },
+            name,
+            description: "",
+            usage: None,
+            from_config: false,
+        }
+    }
+    /// Makes an alias expand the word after it too, like when its value ends with a space.
+    pub fn expanding_next(mut self, expand: bool) -> Self {
+        if let Action::Alias { expand_next, .. } = &mut self.action {
+            *expand_next = expand;
+        }
+        self
+    }
+    pub fn with_usage(self, usage: &'static str) -> Self {
+        Self {
+            usage: Some(usage),
+            ..self
+        }
+    }
+    /// The command line an alias expands to, quoted so it can be typed back.
+    fn alias_value(&self) -> Option<String> {
+        matches!(self.action, Action::Alias { .. }).then(|| self.action.to_string())
+    }
+    /// Returns an `alias` command line that recreates this builtin, if it is an alias.
+    pub fn alias_definition(&self) -> Option<String> {
+        let value = self.alias_value()?;
+        Some(format!(
+            "alias {}",
+            quote_word(&format!("{}={}", self.name, value))
+        ))
+    }
+    /// The description, or what an alias expands to, or which program a plugin runs.
+    pub fn summary(&self) -> Cow<'_, str> {
+        if let Action::Plugin { path } = &self.action {
+            return Cow::Owned(format!("plugin {}", path.display()));
+        }
+        match self.alias_value() {
+            Some(value) => Cow::Owned(format!("alias for {}", value)),
+            None => Cow::Borrowed(self.description),
         }
     }
 }
 
 impl std::fmt::Display for Builtin {
+    /// Writes aliases as the `alias` command line that defines them.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}={}", self.name, self.action)
+        match self.alias_definition() {
+            Some(definition) => write!(f, "{}", definition),
+            None => write!(f, "{}={}", self.name, self.action),
+        }
     }
 }
 
-pub fn get_username() -> String {
+/// Looks up the user running the shell in the user database (`/etc/passwd`).
+fn current_user() -> Option<nix::unistd::User> {
+    nix::unistd::User::from_uid(nix::unistd::getuid()).ok()?
+}
+
+pub fn get_username() -> Option<String> {
     std::env::var("USER")
         .or_else(|_| std::env::var("LOGNAME"))
-        .expect("could not get username")
+        .ok()
+        .or_else(|| Some(current_user()?.name))
 }
 
-pub fn get_home() -> String {
-    std::env::var("HOME").unwrap_or_else(|_| {
-        let mut home = String::default();
-        #[cfg(not(any(target_os = "macos")))]
-        home.push_str("/home/");
-        #[cfg(target_os = "macos")]
-        get_home.push_str("/Users/");
-        #[cfg(not(target_os = "haiku"))]
-        {
-            home.push_str(&get_username());
-        }
-        home
-    })
+/// Where the home directory of `username` usually is.
+fn guess_home(username: &str) -> String {
+    if cfg!(target_os = "macos") {
+        format!("/Users/{}", username)
+    } else if cfg!(target_os = "haiku") {
+        String::from("/home/")
+    } else {
+        format!("/home/{}", username)
+    }
+}
+
+pub fn get_home() -> Option<String> {
+    std::env::var("HOME")
+        .ok()
+        .or_else(|| current_user()?.dir.into_os_string().into_string().ok())
+        .or_else(|| Some(guess_home(&get_username()?)))
 }
 
 macro_rules! ensure_arg {
@@ -125,18 +311,171 @@ macro_rules! ensure_arg {
     };
 }
 
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Returns true if `text` has more lines than a terminal `term_height` rows tall can show.
+/// `None` means stdout is not a terminal, in which case paging never happens.
+fn should_page(text: &str, term_height: Option<u32>) -> bool {
+    match term_height {
+        Some(height) => text.lines().count() > height as usize,
+        None => false,
+    }
+}
+
+fn pager_height() -> Option<u32> {
+    use std::io::IsTerminal;
+    if !output::is_terminal() || !std::io::stdout().is_terminal() {
+        return None;
+    }
+    Some(cursor::terminal_size(crate::tty::Tty::default()).y)
+}
+
+/// Feeds `text` to `$PAGER`, handing it the terminal until it quits.
+/// Returns false if no pager could be started.
+fn spawn_pager(shell: &Shell, text: &str) -> color_eyre::Result<bool> {
+    let pager = shell
+        .get_var_or_env("PAGER")
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    let mut args = shell_word_split::split(&pager)?;
+    if args.is_empty() {
+        return Ok(false);
+    }
+    let program = args.remove(0);
+    let _token = shell.term_state.put_old_token()?;
+    let mut child = match std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    // The pager may quit before reading everything, so a broken pipe is fine.
+    let _ = child.stdin.take().unwrap().write_all(text.as_bytes());
+    child.wait()?;
+    Ok(true)
+}
+
+/// Prints whatever `output` writes, going through `$PAGER` when it wouldn't fit on the terminal.
+pub fn paged(
+    shell: &mut Shell,
+    output: impl FnOnce(&Shell, &mut String) -> std::fmt::Result,
+) -> Result {
+    let mut text = String::new();
+    output(shell, &mut text)?;
+    if should_page(&text, pager_height()) && spawn_pager(shell, &text)? {
+        return Ok(());
+    }
+    shell_print!("{}", text);
+    Ok(())
+}
+
 /* Functions that implement the builtins themselves: */
 
-/// Change current directory
+/// Finds `path` in the directories of `$CDPATH`, unless it's found from the working directory.
+fn search_cdpath(shell: &Shell, path: &str) -> Option<PathBuf> {
+    let explicit = [".", ".."].contains(&path)
+        || path.starts_with('/')
+        || path.starts_with("./")
+        || path.starts_with("../");
+    if explicit || shell.cwd().join(path).is_dir() {
+        return None;
+    }
+    shell
+        .get_var_or_env("CDPATH")?
+        .split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| shell.cwd().join(dir).join(path))
+        .find(|dir| dir.is_dir())
+}
+
+/// Change current directory. `cd -` goes back to the previous one.
 pub fn cd(shell: &mut Shell, command: Command) -> Result {
-    let path = command
-        .args
-        .get(0)
-        .map(Cow::Borrowed)
-        .unwrap_or_else(|| Cow::Owned(get_home()));
-    if let Err(e) = shell.change_directory(path.as_str()) {
+    let (path, print) = match command.args.first().map(String::as_str) {
+        None => match get_home() {
+            Some(home) => (PathBuf::from(home), false),
+            None => return Err(eyre!("cd: HOME not set")),
+        },
+        Some("-") => match shell.prev_cwd() {
+            Some(prev_cwd) => (prev_cwd.to_owned(), true),
+            None => return Err(eyre!("cd: OLDPWD not set")),
+        },
+        Some(path) => match search_cdpath(shell, path) {
+            Some(dir) => (dir, true),
+            None => (PathBuf::from(path), false),
+        },
+    };
+    if let Err(e) = shell.change_directory(&path) {
         return Err(eyre!("'{:?}': {}", path, e))?;
     }
+    // Like other shells, tell where we went when it isn't what was typed
+    if print {
+        shell_println!("{}", shell.cwd().display());
+    }
+    Ok(())
+}
+
+/// The directory stack on one line, like `pushd` and `popd` print it.
+fn stack_line(shell: &Shell) -> String {
+    let dirs: Vec<_> = shell.dirs().into_iter().map(utils::tilde_path).collect();
+    dirs.join(" ")
+}
+
+/// Goes to a directory, saving the working one on the directory stack
+pub fn pushd(shell: &mut Shell, command: Command) -> Result {
+    let arg = command.args.first().map(String::as_str);
+    let result = match arg {
+        None => shell.swap_directories(),
+        Some(arg) if let Some(n) = arg.strip_prefix('+') => match n.parse() {
+            Ok(n) => shell.rotate_directories(n),
+            Err(_) => Err(eyre!("{}: not a directory stack index", arg)),
+        },
+        Some(path) => shell
+            .push_directory(path)
+            .map_err(|e| eyre!("{}: {}", path, e)),
+    };
+    result.map_err(|e| eyre!("pushd: {}", e))?;
+    shell_println!("{}", stack_line(shell));
+    Ok(())
+}
+
+/// Goes back to the directory saved last with `pushd`
+pub fn popd(shell: &mut Shell, _: Command) -> Result {
+    shell.pop_directory().map_err(|e| eyre!("popd: {}", e))?;
+    shell_println!("{}", stack_line(shell));
+    Ok(())
+}
+
+/// Lists the directory stack
+pub fn dirs(shell: &mut Shell, _: Command) -> Result {
+    for (i, dir) in shell.dirs().into_iter().enumerate() {
+        shell_println!("{:>2}  {}", i, utils::tilde_path(dir));
+    }
+    Ok(())
+}
+
+/// Prints the working directory
+pub fn pwd(shell: &mut Shell, command: Command) -> Result {
+    let mut physical = false;
+    for arg in &command.args {
+        // The last one wins, like in other shells
+        match arg.as_str() {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            _ => return Err(eyre!("usage: pwd [-L | -P]")),
+        }
+    }
+    // The logical one is kept even if the directory was deleted, the physical one can't be
+    if physical {
+        let path = shell
+            .physical_cwd()
+            .canonicalize()
+            .map_err(|e| eyre!("pwd: {}: {}", shell.cwd().display(), e))?;
+        shell_println!("{}", path.display());
+    } else {
+        shell_println!("{}", shell.cwd().display());
+    }
     Ok(())
 }
 
@@ -144,22 +483,47 @@ pub fn cd(shell: &mut Shell, command: Command) -> Result {
 pub fn exit(shell: &mut Shell, command: Command) -> Result {
     let args = command.args;
     let code = args.get(0).and_then(|s| s.parse::<i32>().ok()).unwrap_or(0);
-    shell.exit(code);
+    shell.exit(code)
+}
+
+/// Removes a job from the jobs table, so exiting leaves it running
+pub fn disown(shell: &mut Shell, command: Command) -> Result {
+    let spec = command.args.first().map(String::as_str);
+    shell
+        .jobs
+        .disown(spec)
+        .map_err(|e| eyre!("disown: {}", e))?;
     Ok(())
 }
 
 /// Lists, creates or deletes aliases
 pub fn alias(shell: &mut Shell, command: Command) -> Result {
     let args = command.args;
-    // usage: alias
-    // print all aliases
-    if args.len() == 0 {
-        for builtin in shell.builtins.values() {
-            shell_println!("{}", builtin);
-        }
+    // usage: alias -s
+    // save all aliases so they are loaded on startup
+    if args == ["-s"] {
+        return save_aliases(shell);
+    }
+    // usage: alias [-p]
+    // print all aliases, by name
+    if args.is_empty() || args == ["-p"] {
+        return paged(shell, |shell, out| {
+            let definitions = shell
+                .builtins
+                .values()
+                .filter_map(Builtin::alias_definition);
+            for definition in definitions {
+                writeln!(out, "{}", definition)?;
+            }
+            Ok(())
+        });
     }
     for arg in args {
         match arg.split_once('=') {
+            Some(("", _)) => return Err(eyre!("alias: '{}': missing alias name", arg)),
+            Some((name, _)) if !is_alias_name(name) => {
+                return Err(eyre!("alias: '{}': invalid alias name", name))
+            }
             Some((name, cmd)) => {
                 if cmd.is_empty() {
                     // usage: alias name=
@@ -173,9 +537,18 @@ pub fn alias(shell: &mut Shell, command: Command) -> Result {
                 } else {
                     // usage: alias name=cmd
                     // Creates aliases
-                    let mut args = shell_word_split::split(cmd)?;
-                    let cmd = args.remove(0);
-                    shell.register_builtin(Builtin::new_alias(name.to_owned(), cmd, args));
+                    let expand_next = cmd.ends_with(char::is_whitespace);
+                    let mut args = shell_word_split::split(cmd)
+                        .map_err(|e| eyre!("alias: {}: {}", name, e))?;
+                    // Only blanks make an alias to nothing
+                    let cmd = match args.is_empty() {
+                        true => String::new(),
+                        false => args.remove(0),
+                    };
+                    let mut alias =
+                        Builtin::new_alias(name.to_owned(), cmd, args).expanding_next(expand_next);
+                    alias.from_config = shell.loading_config;
+                    shell.register_builtin(alias);
                 }
             }
             // usage: alias name
@@ -192,19 +565,100 @@ pub fn alias(shell: &mut Shell, command: Command) -> Result {
     Ok(())
 }
 
+/// Whether `name` can be typed as a command without quotes, as alias names must.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty() && quote_word(name) == name && !name.contains('/')
+}
+
+fn save_aliases(shell: &Shell) -> Result {
+    let definitions = shell
+        .builtins
+        .values()
+        .filter_map(Builtin::alias_definition)
+        .collect::<Vec<_>>();
+    let path = config::get_aliases_file().ok_or_else(|| eyre!("No config directory"))?;
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, definitions.join("\n") + "\n")?;
+    shell_println!(
+        "Saved {} aliases to '{}'",
+        definitions.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Deletes aliases
+pub fn unalias(shell: &mut Shell, command: Command) -> Result {
+    if command.args.is_empty() {
+        return Err(eyre!("Missing argument"));
+    }
+    for name in command.args {
+        match shell.builtins.entry(name) {
+            Entry::Occupied(b) if matches!(b.get().action, Action::Alias { .. }) => {
+                b.remove();
+            }
+            Entry::Occupied(b) => {
+                shell_println!("unalias: {}: is a builtin, not an alias", b.key());
+                shell.set_status(1);
+            }
+            Entry::Vacant(b) => {
+                shell_println!("unalias: {}: not an alias", b.key());
+                shell.set_status(1);
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Debug command to set the cursor position on-screen
 pub fn set_pos(_shell: &mut Shell, command: Command) -> Result {
     let args = command.args;
-    let x: u8 = ensure_arg!(args, 0).parse()?;
-    let y: u8 = ensure_arg!(args, 1).parse()?;
-    crate::write(&crate::read_line::cursor::set_position(x, y))?;
+    let parse = |arg: &str| {
+        arg.parse::<u32>()
+            .ok()
+            .filter(|&n| n != 0)
+            .ok_or_else(|| eyre!("set_pos: '{}' isn't from 1 to {}", arg, u32::MAX))
+    };
+    let x = parse(ensure_arg!(args, 0))?;
+    let y = parse(ensure_arg!(args, 1))?;
+    crate::output::print_bytes(&crate::read_line::cursor::set_position(x, y))?;
     Ok(())
 }
 
-/// Executes a program and exits
+/// Clears the terminal screen
+pub fn clear(_shell: &mut Shell, _: Command) -> Result {
+    crate::output::print_bytes(crate::read_line::cursor::clear_screen())?;
+    Ok(())
+}
+
+/// Copies its arguments to the clipboard of the terminal
+pub fn clip(shell: &mut Shell, command: Command) -> Result {
+    if shell.get_var_or_env("YASH_OSC52").as_deref() == Some("0") {
+        return Err(eyre!("clip: turned off with YASH_OSC52=0"));
+    }
+    let text = command.args.join(" ");
+    if text.len() > crate::read_line::cursor::MAX_CLIPBOARD_LEN {
+        shell_println!(
+            "clip: only the first {} bytes are copied",
+            crate::read_line::cursor::MAX_CLIPBOARD_LEN
+        );
+    }
+    crate::write(&crate::read_line::cursor::set_clipboard(text.as_bytes()))?;
+    Ok(())
+}
+
+/// Replaces the shell with a program
 pub fn exec(shell: &mut Shell, command: Command) -> Result {
-    shell.execute_program(command.shift())?;
-    shell.exit(0);
+    let command = command.shift();
+    if command.command.is_empty() {
+        return Ok(());
+    }
+    if command.program_names().len() > 1 {
+        return Err(eyre!("exec: can't replace the shell with a pipeline"));
+    }
+    // Nothing runs after the program takes over
+    shell.save_history()?;
+    shell.exec_program(command)?;
     Ok(())
 }
 
@@ -222,39 +676,733 @@ pub fn r(shell: &mut Shell, command: Command) -> Result {
 
 /// Executes a file as a shell script
 pub fn source(shell: &mut Shell, command: Command) -> Result {
-    let args = command.args;
+    let mut args = command.args;
+    let local = args.first().is_some_and(|arg| arg == "-l");
+    if local {
+        args.remove(0);
+    }
     let path = ensure_arg!(args, 0);
-    let path = std::path::Path::new(path);
-    shell.source_file(path)?;
+    let path = match shell.script_dir() {
+        Some(dir) => dir.join(path),
+        None => std::path::PathBuf::from(path),
+    };
+    let summary = match local {
+        true => shell.source_file_local(path)?,
+        false => shell.source_file(path)?,
+    };
+    if summary.failed > 0 {
+        shell.set_status(1);
+    }
     Ok(())
 }
 
+/// How many `eval`s can run inside one another.
+const MAX_EVAL_DEPTH: usize = 64;
+
+/// Runs its arguments, joined with spaces, as a command line
+pub fn eval(shell: &mut Shell, command: Command) -> Result {
+    if command.args.is_empty() {
+        return Ok(());
+    }
+    if shell.eval_depth >= MAX_EVAL_DEPTH {
+        return Err(eyre!("eval: nested deeper than {}", MAX_EVAL_DEPTH));
+    }
+    let line = command.args.join(" ");
+    shell.eval_depth += 1;
+    let result = shell.execute_line(&line);
+    shell.eval_depth -= 1;
+    result
+}
+
 /// Run a command without triggering a builtin
 pub fn command(shell: &mut Shell, command: Command) -> Result {
     shell.execute_program(command.shift())?;
     Ok(())
 }
 
+/// Runs a builtin even if an alias shadows it
+pub fn builtin(shell: &mut Shell, command: Command) -> Result {
+    let command = command.shift();
+    let action = match shell.builtins.get(&command.command) {
+        Some(Builtin {
+            action: action @ Action::Fn(_),
+            ..
+        }) => action.clone(),
+        // An alias replaces the builtin with its name
+        _ => native_builtins()
+            .remove(&command.command)
+            .map(|builtin| builtin.action)
+            .ok_or_else(|| eyre!("builtin: {}: not a builtin", command.command))?,
+    };
+    shell.call_builtin(action, command)
+}
+
+/// Pins programs to command names, or lists them
+pub fn hash(shell: &mut Shell, command: Command) -> Result {
+    let args = command.args;
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        // usage: hash
+        // print the pinned programs as commands that pin them again
+        [] => paged(shell, |shell, out| {
+            let mut hashed = shell.hashed.iter().collect::<Vec<_>>();
+            hashed.sort();
+            for (name, program) in hashed {
+                let program = program.to_string_lossy();
+                writeln!(out, "hash -p {} {}", quote_word(&program), quote_word(name))?;
+            }
+            Ok(())
+        }),
+        // usage: hash -r
+        // forget the pinned programs and search $PATH again
+        ["-r"] => {
+            shell.hashed.clear();
+            shell.rehash();
+            Ok(())
+        }
+        // usage: hash -p PROGRAM NAME...
+        ["-p", program, ref names @ ..] if !names.is_empty() => {
+            let program = PathBuf::from(program);
+            if !program.is_absolute() {
+                return Err(eyre!("hash: {}: not an absolute path", program.display()));
+            }
+            for name in names {
+                shell.hashed.insert(name.to_string(), program.clone());
+            }
+            Ok(())
+        }
+        _ => Err(eyre!("usage: hash [-r | -p PROGRAM NAME...]")),
+    }
+}
+
+/// Whether `name` can be a variable name, like `[A-Za-z_][A-Za-z0-9_]*`.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Sets environment variables
 pub fn export(shell: &mut Shell, command: Command) -> Result {
-    for arg in command.args {
-        match arg.split_once('=') {
-            Some((name, val)) => std::env::set_var(name, val),
+    let mut args = command.args;
+    // usage: export
+    // print the environment as commands that recreate it
+    if args.is_empty() {
+        return paged(shell, |_, out| {
+            let mut vars = std::env::vars_os()
+                .filter_map(|(name, value)| {
+                    Some((name.into_string().ok()?, value.into_string().ok()?))
+                })
+                .collect::<Vec<_>>();
+            vars.sort();
+            for (name, value) in vars {
+                writeln!(out, "export {}={}", name, quote_word(&value))?;
+            }
+            Ok(())
+        });
+    }
+    // usage: export -n NAME...
+    // remove from the environment, keeping them as shell variables
+    if args[0] == "-n" {
+        for name in args.drain(1..) {
+            if let Ok(value) = std::env::var(&name) {
+                if shell.get_var(&name).is_none() {
+                    shell.set_var(name.clone(), value);
+                }
+                std::env::remove_var(name);
+            }
+        }
+        return Ok(());
+    }
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !is_valid_name(name) {
+            return Err(eyre!("export: '{}' is not a valid name", name));
+        }
+        match value {
+            Some(value) => {
+                std::env::set_var(name, value);
+                shell.set_var(name.to_string(), value.to_string());
+            }
             None => {
-                let name = arg;
-                if let Some(v) = shell.get_var(&name) {
-                    std::env::set_var(name, v);
+                if let Some(value) = shell.get_var(name) {
+                    std::env::set_var(name, value);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sets variables only for the script being sourced
+pub fn local(shell: &mut Shell, command: Command) -> Result {
+    for arg in command.args {
+        let (name, value) = arg.split_once('=').unwrap_or((&arg, ""));
+        if !is_valid_name(name) {
+            return Err(eyre!("local: '{}' is not a valid name", name));
+        }
+        shell.set_local_var(name.to_string(), value.to_string())?;
+    }
+    Ok(())
+}
+
+/// How many commands `history --stats` shows.
+const HISTORY_STATS_COUNT: usize = 10;
+
+/// The most used command words in `lines`, most used first, with how often they are used.
+fn command_stats(lines: &[String]) -> Vec<(&str, usize)> {
+    let mut counts = std::collections::HashMap::<_, usize>::new();
+    for word in lines
+        .iter()
+        .filter_map(|line| line.split_whitespace().next())
+    {
+        *counts.entry(word).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    counts.truncate(HISTORY_STATS_COUNT);
+    counts
+}
+
+/// Lists the command history
+pub fn history(shell: &mut Shell, command: Command) -> Result {
+    match command.args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        // usage: history
+        [] => paged(shell, |shell, out| {
+            for (i, line) in shell.read_line.history().iter().enumerate() {
+                writeln!(out, "{:>5}  {}", i + 1, line)?;
+            }
+            Ok(())
+        }),
+        // usage: history --stats
+        // print the most used commands
+        ["--stats"] => {
+            for (word, count) in command_stats(shell.read_line.history()) {
+                shell_println!("{:>5}  {}", count, word);
+            }
+            Ok(())
+        }
+        // usage: history -r
+        // load what other sessions saved to the history file
+        ["-r"] => {
+            let history = shell.read_line.history_mut();
+            history
+                .file()
+                .ok_or_else(|| eyre!("history: the history isn't saved to a file"))?;
+            history.reload()?;
+            Ok(())
+        }
+        // usage: history -w
+        // save what was run to the history file now
+        ["-w"] => {
+            let history = shell.read_line.history_mut();
+            history
+                .file()
+                .ok_or_else(|| eyre!("history: the history isn't saved to a file"))?;
+            history.save()?;
+            Ok(())
+        }
+        // usage: history --archive
+        // move all but the newest entries of the history file to this month's archive
+        ["--archive"] => {
+            let path = shell
+                .read_line
+                .history_mut()
+                .file()
+                .map(std::path::Path::to_path_buf)
+                .ok_or_else(|| eyre!("history: the history isn't saved to a file"))?;
+            shell.save_history()?;
+            let (moved, archive) = shell.rotate_history(&path, true)?;
+            shell.read_line.history_mut().reload()?;
+            shell_println!("Archived {} entries to '{}'", moved, archive.display());
+            Ok(())
+        }
+        _ => Err(eyre!("usage: history [--stats | -r | -w | --archive]")),
+    }
+}
+
+/// Splits `text` into at most `count` whitespace separated fields, the last one
+/// keeping the remainder of the line.
+fn split_fields(text: &str, count: usize) -> Vec<String> {
+    let mut fields = vec![];
+    let mut rest = text.trim();
+    while fields.len() + 1 < count {
+        let Some((field, tail)) = rest.split_once(char::is_whitespace) else {
+            break;
+        };
+        fields.push(field.to_string());
+        rest = tail.trim_start();
+    }
+    fields.push(rest.to_string());
+    fields
+}
+
+/// Reads a line from the terminal into variables
+pub fn read(shell: &mut Shell, command: Command) -> Result {
+    let mut opts = PromptOpts::new(PromptMode::Line);
+    let mut prompt = String::new();
+    let mut names = vec![];
+    let mut args = command.args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" => opts.mode = PromptMode::Hidden,
+            "-n1" => opts.mode = PromptMode::Key,
+            "-p" => prompt = args.next().ok_or_else(|| eyre!("Missing argument"))?,
+            _ => names.push(arg),
+        }
+    }
+    if names.is_empty() {
+        names.push(String::from("REPLY"));
+    }
+    let width = crate::prompt::rendered_width(&prompt);
+    let text = match shell.read_line.prompt_once(&prompt, width, opts)? {
+        PromptAnswer::Line(line) => line,
+        PromptAnswer::Key(c) => c.to_string(),
+        PromptAnswer::Cancel | PromptAnswer::Eof => {
+            shell.set_status(1);
+            return Ok(());
+        }
+    };
+    let mut fields = split_fields(&text, names.len()).into_iter();
+    for name in names {
+        shell.set_var(name, fields.next().unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Rescans `$PATH` for programs
+pub fn rehash(shell: &mut Shell, _: Command) -> Result {
+    shell.rehash();
+    Ok(())
+}
+
+fn test_unary(op: &str, operand: &str) -> color_eyre::Result<bool> {
+    let path = std::path::Path::new(operand);
+    Ok(match op {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-e" => path.exists(),
+        "-f" => path.is_file(),
+        "-d" => path.is_dir(),
+        _ => return Err(eyre!("test: unknown operator '{}'", op)),
+    })
+}
+
+fn test_binary(left: &str, op: &str, right: &str) -> color_eyre::Result<bool> {
+    let numbers = || -> color_eyre::Result<(i64, i64)> {
+        let parse = |s: &str| {
+            s.parse::<i64>()
+                .map_err(|_| eyre!("test: '{}' is not a number", s))
+        };
+        Ok((parse(left)?, parse(right)?))
+    };
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "-eq" => numbers().map(|(l, r)| l == r)?,
+        "-ne" => numbers().map(|(l, r)| l != r)?,
+        "-lt" => numbers().map(|(l, r)| l < r)?,
+        "-le" => numbers().map(|(l, r)| l <= r)?,
+        "-gt" => numbers().map(|(l, r)| l > r)?,
+        "-ge" => numbers().map(|(l, r)| l >= r)?,
+        _ => return Err(eyre!("test: unknown operator '{}'", op)),
+    })
+}
+
+/// Evaluates a `test` expression.
+fn evaluate_test(args: &[String]) -> color_eyre::Result<bool> {
+    match args {
+        [] => Ok(false),
+        [bang, rest @ ..] if bang == "!" => Ok(!evaluate_test(rest)?),
+        [word] => Ok(!word.is_empty()),
+        [op, operand] => test_unary(op, operand),
+        [left, op, right] => test_binary(left, op, right),
+        _ => Err(eyre!("test: too many arguments")),
+    }
+}
+
+/// Checks file types and compares strings or numbers
+pub fn test(shell: &mut Shell, command: Command) -> Result {
+    let result = evaluate_test(&command.args)?;
+    shell.set_status(if result { 0 } else { 1 });
+    Ok(())
+}
+
+/// Same as `test`, but the last argument must be `]`
+pub fn bracket(shell: &mut Shell, mut command: Command) -> Result {
+    if command.args.pop().as_deref() != Some("]") {
+        return Err(eyre!("[: missing ']'"));
+    }
+    test(shell, command)
+}
+
+/// Formats `duration` like `0m1.234s`.
+fn format_time(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!(
+        "{}m{}.{:03}s",
+        secs / 60,
+        secs % 60,
+        duration.subsec_millis()
+    )
+}
+
+/// User and system CPU time used so far by the shell and the children it waited for.
+fn cpu_time() -> nix::Result<(Duration, Duration)> {
+    use nix::sys::resource::{getrusage, UsageWho};
+    let to_duration = |time: nix::sys::time::TimeVal| {
+        Duration::from_secs(time.tv_sec() as u64) + Duration::from_micros(time.tv_usec() as u64)
+    };
+    let (shell, children) = (
+        getrusage(UsageWho::RUSAGE_SELF)?,
+        getrusage(UsageWho::RUSAGE_CHILDREN)?,
+    );
+    Ok((
+        to_duration(shell.user_time()) + to_duration(children.user_time()),
+        to_duration(shell.system_time()) + to_duration(children.system_time()),
+    ))
+}
+
+/// Prints to stderr, which stays on the terminal when the output is redirected.
+fn print_stderr(text: &str) -> Result {
+    let stderr = Tty {
+        output: nix::libc::STDERR_FILENO,
+        ..Default::default()
+    };
+    let bytes = match output::is_raw() && std::io::IsTerminal::is_terminal(&std::io::stderr()) {
+        true => output::translate_newlines(text.as_bytes()),
+        false => text.as_bytes().to_vec(),
+    };
+    stderr.write(&bytes)?;
+    Ok(())
+}
+
+/// Runs a pipeline and reports how long it took
+pub fn time(shell: &mut Shell, command: Command) -> Result {
+    let command = command.shift();
+    let (user, system) = cpu_time()?;
+    let start = Instant::now();
+    let result = match command.command.is_empty() {
+        true => Ok(()),
+        false => shell.execute(command),
+    };
+    let real = start.elapsed();
+    let (user_after, system_after) = cpu_time()?;
+    print_stderr(&format!(
+        "real {}\nuser {}\nsys  {}\n",
+        format_time(real),
+        format_time(user_after.saturating_sub(user)),
+        format_time(system_after.saturating_sub(system)),
+    ))?;
+    result
+}
+
+/// Conditions `trap` can run commands on.
+const TRAP_CONDITIONS: [&str; 1] = ["EXIT"];
+
+/// Options `set -o` can enable.
+const SHELL_OPTIONS: &[&str] = &[
+    "errexit",
+    "noclobber",
+    "partial-line",
+    "paste-confirm",
+    "xtrace",
+];
+
+/// Enables or disables shell options
+pub fn set(shell: &mut Shell, command: Command) -> Result {
+    match command.args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        // usage: set -o
+        // print whether each option is enabled
+        ["-o"] => {
+            let rows = SHELL_OPTIONS
+                .iter()
+                .map(|&name| match shell.options.contains(name) {
+                    true => (name, "on"),
+                    false => (name, "off"),
+                })
+                .collect::<Vec<_>>();
+            shell_print!("{}", widget::table(&rows, GridStyle::default()));
+            Ok(())
+        }
+        // usage: set (-o | +o) NAME...
+        // enable or disable the options
+        [flag @ ("-o" | "+o"), ref names @ ..] => {
+            if let Some(name) = names.iter().find(|name| !SHELL_OPTIONS.contains(name)) {
+                return Err(eyre!("set: {}: unknown option", name));
+            }
+            for &name in names {
+                match flag {
+                    "-o" => shell.options.insert(name.to_string()),
+                    _ => shell.options.remove(name),
+                };
+            }
+            Ok(())
+        }
+        // usage: set (-e | +e | -x | +x)
+        // same as set (-o | +o) errexit or xtrace
+        [flag @ ("-e" | "+e" | "-x" | "+x")] => {
+            let name = match &flag[1..] {
+                "e" => "errexit",
+                _ => "xtrace",
+            };
+            match flag.starts_with('-') {
+                true => shell.options.insert(name.to_string()),
+                false => shell.options.remove(name),
+            };
+            Ok(())
+        }
+        _ => Err(eyre!(
+            "usage: set [-o | (-o | +o) NAME... | (-e | +e | -x | +x)]"
+        )),
+    }
+}
+
+/// Prints or sets the file creation mask
+pub fn umask(_shell: &mut Shell, command: Command) -> Result {
+    let mut args = command.args.iter().map(String::as_str).peekable();
+    let symbolic = args.next_if_eq(&"-S").is_some();
+    match args.collect::<Vec<_>>()[..] {
+        // usage: umask [-S]
+        // print the mask, in octal or as what it allows
+        [] => (),
+        // usage: umask [-S] MODE
+        // set the mask, printing it as well with -S
+        [mode] => {
+            let mask = crate::umask::parse(mode, crate::umask::current())
+                .map_err(|e| eyre!("umask: {}", e))?;
+            crate::umask::set(mask);
+            if !symbolic {
+                return Ok(());
+            }
+        }
+        _ => return Err(eyre!("usage: umask [-S] [MODE]")),
+    }
+    let mask = crate::umask::current();
+    match symbolic {
+        true => shell_println!("{}", crate::umask::format_symbolic(mask)),
+        false => shell_println!("{:04o}", mask),
+    }
+    Ok(())
+}
+
+/// Prints or sets the resource limits
+pub fn ulimit(_shell: &mut Shell, command: Command) -> Result {
+    let args = command.args.iter().map(String::as_str).collect::<Vec<_>>();
+    let request = crate::ulimit::parse_args(&args).map_err(|e| eyre!("ulimit: {}", e))?;
+    let (hard, value) = (request.hard, request.value);
+    match (request.limit, value) {
+        // usage: ulimit [-H] -a
+        // print every limit, with its units
+        (None, _) => {
+            for limit in &crate::ulimit::LIMITS {
+                let value = limit.get(hard).map_err(|e| eyre!("ulimit: {}", e))?;
+                shell_println!("{}", limit.row(value));
+            }
+        }
+        // usage: ulimit [-H] [-c | -f | -n | -s | -t | -v]
+        // print the limit
+        (Some(limit), None) => {
+            let value = limit.get(hard).map_err(|e| eyre!("ulimit: {}", e))?;
+            shell_println!("{}", limit.format(value));
+        }
+        // usage: ulimit [-H] [-c | -f | -n | -s | -t | -v] LIMIT
+        // set the limit for the shell and what it runs
+        (Some(limit), Some(text)) => limit
+            .parse(text)
+            .and_then(|value| limit.set(hard, value))
+            .map_err(|e| eyre!("ulimit: {}", e))?,
+    }
+    Ok(())
+}
+
+/// Sources the rc files again
+pub fn reload(shell: &mut Shell, command: Command) -> Result {
+    match command.args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        // usage: reload
+        // define the aliases of the rc files again, keeping the others
+        [] => shell.reload(false),
+        // usage: reload -a
+        // start over like a new shell, without any alias or shell variable
+        ["-a"] => shell.reload(true),
+        _ => Err(eyre!("usage: reload [-a]")),
+    }
+}
+
+/// Runs commands when the shell exits
+pub fn trap(shell: &mut Shell, command: Command) -> Result {
+    let mut args = command.args.into_iter();
+    // usage: trap
+    // print the traps as commands that set them again
+    let Some(commands) = args.next() else {
+        let mut traps = shell.traps.iter().collect::<Vec<_>>();
+        traps.sort();
+        for (condition, commands) in traps {
+            shell_println!("trap -- {} {}", quote_word(commands), condition);
+        }
+        return Ok(());
+    };
+    let conditions = args.collect::<Vec<_>>();
+    if conditions.is_empty() {
+        return Err(eyre!("trap: missing condition"));
+    }
+    for condition in conditions {
+        let condition = match condition.as_str() {
+            "0" => "EXIT".to_string(),
+            _ => condition,
+        };
+        if !TRAP_CONDITIONS.contains(&condition.as_str()) {
+            return Err(eyre!("trap: unsupported condition '{}'", condition));
+        }
+        // usage: trap - CONDITION
+        // remove the trap
+        if commands == "-" || commands.is_empty() {
+            shell.traps.remove(&condition);
+        } else {
+            shell.traps.insert(condition, commands.clone());
+        }
+    }
+    Ok(())
+}
+
+/// Adds, lists or removes the commands run around the lines typed at the prompt
+pub fn hook(shell: &mut Shell, command: Command) -> Result {
+    use crate::hooks::Hook;
+    let args = command.args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args[..] {
+        // usage: hook [list]
+        // print the hooks as commands that add them again
+        [] | ["list"] => {
+            for hook in Hook::ALL {
+                for commands in shell.hooks.get(hook) {
+                    shell_println!("hook add {} {}", hook.name(), quote_word(commands));
                 }
             }
         }
+        // usage: hook add NAME COMMANDS
+        ["add", name, commands] => {
+            let hook = Hook::from_name(name).map_err(|e| eyre!("hook: {}", e))?;
+            shell.hooks.add(hook, commands.to_string());
+        }
+        // usage: hook clear [NAME]
+        // remove the hooks added to NAME, or all of them
+        ["clear"] => Hook::ALL
+            .into_iter()
+            .for_each(|hook| shell.hooks.clear(hook)),
+        ["clear", name] => {
+            let hook = Hook::from_name(name).map_err(|e| eyre!("hook: {}", e))?;
+            shell.hooks.clear(hook);
+        }
+        _ => {
+            return Err(eyre!(
+                "usage: hook [list | add NAME COMMANDS | clear [NAME]]"
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// Allows, denies or lists the `.yashenv` files of the working directory
+pub fn yashenv(shell: &mut Shell, command: Command) -> Result {
+    let mut args = command.args.iter().map(String::as_str);
+    let subcommand = args.next().unwrap_or("status");
+    let path = args.next();
+    let allow = match subcommand {
+        "allow" => true,
+        "deny" => false,
+        // usage: yashenv status
+        "status" => {
+            for (file, status) in shell.env_files_status() {
+                shell_println!("{}: {}", file.display(), status);
+            }
+            return Ok(());
+        }
+        _ => return Err(eyre!("yashenv: unknown subcommand '{}'", subcommand)),
+    };
+    // usage: yashenv (allow | deny) [FILE]
+    let Some(file) = shell.env_file(path) else {
+        return Err(eyre!("yashenv: no {} here", crate::yashenv::FILE_NAME));
+    };
+    shell
+        .env_files
+        .set_allowed(&file, allow)
+        .map_err(|e| eyre!("yashenv: {}", e))?;
+    let cwd = shell.cwd().to_path_buf();
+    shell.update_env_files(Some(&cwd))
+}
+
+/// Shows or hides the debug overlay
+pub fn debug(shell: &mut Shell, command: Command) -> Result {
+    if !crate::debug::ENABLED {
+        return Err(eyre!("debug: support was compiled out of this build"));
+    }
+    match command.args.first().map(String::as_str) {
+        Some("on") => shell.debug_overlay = true,
+        Some("off") => {
+            shell.debug_overlay = false;
+            crate::debug::clear_debug_text()?;
+        }
+        Some("status") | None => shell_println!(
+            "debug overlay is {}, {} lines collected",
+            if shell.debug_overlay { "on" } else { "off" },
+            crate::debug::line_count()
+        ),
+        Some(arg) => return Err(eyre!("debug: unknown argument '{}'", arg)),
+    }
+    Ok(())
+}
+
+/// Lists the builtin commands and plugins by name, leaving out the aliases
+pub fn builtins(shell: &mut Shell, _: Command) -> Result {
+    paged(shell, |shell, out| {
+        let rows = shell
+            .builtins
+            .values()
+            .filter(|builtin| !matches!(builtin.action, Action::Alias { .. }))
+            .map(|builtin| (builtin.name.as_str(), builtin.summary()))
+            .collect::<Vec<_>>();
+        out.write_str(&widget::table(&rows, GridStyle::default()))
+    })
+}
+
+/// Lists the builtins, or explains one of them
+pub fn help(shell: &mut Shell, command: Command) -> Result {
+    let Some(name) = command.args.first() else {
+        return paged(shell, |shell, out| {
+            let rows = shell
+                .builtins
+                .values()
+                .map(|builtin| (builtin.name.as_str(), builtin.summary()))
+                .collect::<Vec<_>>();
+            out.write_str(&widget::table(&rows, GridStyle::default()))
+        });
+    };
+    let builtin = shell
+        .builtins
+        .get(name)
+        .ok_or_else(|| eyre!("help: no builtin named '{}'", name))?;
+    shell_println!("{}: {}", builtin.name, builtin.summary());
+    if let Some(usage) = builtin.usage {
+        shell_println!("\nusage: {}", usage);
     }
     Ok(())
 }
 
 macro_rules! register_builtins {
-    ($($name:ident),*) => {
-        pub fn native_builtins() -> std::collections::HashMap<String, Builtin> {
+    (
+        $($name:ident: $description:literal $(, $usage:literal)?;)*
+        $($builtin_name:literal => $fn:ident: $fn_description:literal $(, $fn_usage:literal)?;)*
+    ) => {
+        pub fn native_builtins() -> BTreeMap<String, Builtin> {
             [
-                $(Builtin::new_fn(stringify!($name).to_string(), $name)),*
+                $(Builtin::new_fn(stringify!($name).to_string(), $name, $description)
+                    $(.with_usage($usage))?,)*
+                $(Builtin::new_fn($builtin_name.to_string(), $fn, $fn_description)
+                    $(.with_usage($fn_usage))?,)*
             ].into_iter()
                 .map(|b| (b.name.clone(), b))
                 .collect()
@@ -263,4 +1411,937 @@ macro_rules! register_builtins {
     };
 }
 
-register_builtins!(cd, exit, alias, command, exec, set_pos, source, export, r);
+register_builtins! {
+    cd: "Change current directory",
+        "cd [DIR | -]\n\n\
+        Goes to $HOME without DIR, and back to the previous directory with '-'.\n\
+        Relative directories are also searched in $CDPATH.";
+    pushd: "Changes directory, saving the current one on the directory stack",
+        "pushd [DIR | +N]\n\n\
+        Without DIR, swaps the current directory with the last one saved. +N goes to the\n\
+        Nth entry listed by dirs, moving those before it to the bottom of the stack.";
+    popd: "Goes back to the directory saved last with pushd", "popd";
+    dirs: "Lists the directory stack",
+        "dirs\n\n\
+        Lists the current directory first, at 0, followed by the saved ones.";
+    pwd: "Prints the working directory",
+        "pwd [-L | -P]\n\n\
+        Prints the directory as it was reached, through symlinks, or with -P the one they\n\
+        lead to. The first is printed even after the directory was deleted.";
+    exit: "Quits the shell", "exit [CODE]";
+    disown: "Keeps a job running after the shell exits",
+        "disown [%JOB]\n\n\
+        Removes the job from the jobs table, the last one started by default. While jobs\n\
+        are running, exit only tells about them unless tried again right after, and they\n\
+        are sent SIGHUP when the shell exits. Disowned jobs are left alone.";
+    alias: "Lists, creates or deletes aliases",
+        "alias [-p | -s | NAME[=COMMAND]...]\n\n\
+        Without arguments or with -p, lists all the aliases, sorted by name.\n\
+        NAME=COMMAND creates an alias, NAME= deletes it and NAME prints it the way it is\n\
+        defined. COMMAND may hold several quoted words, and only blanks make NAME expand\n\
+        to nothing.\n\
+        -s saves the aliases so they are loaded on startup.";
+    command: "Run a command without triggering a builtin", "command PROGRAM [ARGS...]";
+    builtin: "Runs a builtin even if an alias has its name", "builtin NAME [ARGS...]";
+    hash: "Pins programs to command names",
+        "hash [-r | -p PROGRAM NAME...]\n\n\
+        -p runs PROGRAM, an absolute path, for NAME instead of searching $PATH.\n\
+        Without arguments, prints the pinned programs. -r forgets them and rescans $PATH.";
+    exec: "Replaces the shell with a program",
+        "exec PROGRAM [ARGS...]\n\n\
+        The program keeps the shell's process and environment. If it can't be run, the\n\
+        shell goes on.";
+    set_pos: "Debug command to set the cursor position on-screen", "set_pos COLUMN ROW";
+    source: "Executes a file as a shell script",
+        "source [-l] FILE\n\n\
+        Relative paths are relative to the script being run, if any. With -l, variables\n\
+        the script sets with local are dropped when it ends.";
+    eval: "Runs its arguments as a command line",
+        "eval [ARGS...]\n\n\
+        The arguments are joined with spaces and read again, so the variables, quotes,\n\
+        aliases, pipes and redirections in them work like in a typed line.";
+    export: "Sets environment variables",
+        "export [NAME[=VALUE]...]\n       export -n NAME...\n\n\
+        Without a value, the shell variable NAME is exported. Without arguments, prints\n\
+        the environment as export commands. -n removes variables from the environment,\n\
+        keeping them as shell variables.";
+    local: "Sets variables only for the script being sourced",
+        "local NAME[=VALUE]...\n\n\
+        Only usable in scripts run with source -l. The variables shadow those with the same\n\
+        name until the script ends. Without a value, they are empty.";
+    r: "Debug command to recompile the shell and run it", "r";
+    history: "Lists the command history",
+        "history [--stats | -r | -w | --archive]\n\n\
+        --stats prints the 10 most used commands instead, with how many times each was run.\n\
+        The history file is shared with other sessions before each prompt; -r loads what\n\
+        they saved and -w saves what was run right away.\n\
+        Once the file has more entries than $HISTROTATE (10000, or a size like 512k), all but\n\
+        the newest $HISTKEEP (1000) are moved to an archive named after the month, like\n\
+        yhist-2024-06.txt. --archive does it right away. When $HISTARCHIVES is set, going\n\
+        back past the oldest line goes on into the archives.\n\
+        Lines starting with a space are never kept, nor those matching one of the\n\
+        colon-separated patterns in $HISTIGNORE, like ls:cd *:*PASSWORD*.";
+    read: "Reads a line from the terminal into variables",
+        "read [-s] [-n1] [-p PROMPT] NAME...\n\n\
+        Splits the line into fields, the last NAME getting the rest of the line.\n\
+        -s hides what is typed, -n1 reads a single key and -p shows PROMPT.";
+    unalias: "Deletes aliases", "unalias NAME...";
+    test: "Checks file types and compares strings or numbers",
+        "test [!] [-z | -n | -e | -f | -d] WORD\n       \
+        test [!] WORD (= | != | -eq | -ne | -lt | -le | -gt | -ge) WORD";
+    rehash: "Rescans $PATH for programs", "rehash";
+    clear: "Clears the terminal screen", "clear";
+    clip: "Copies text to the clipboard of the terminal",
+        "clip [TEXT...]\n\n\
+        The arguments are joined with spaces and sent with the OSC 52 escape sequence, so\n\
+        they reach the local clipboard even over SSH. Ctrl-X Ctrl-C copies the line being\n\
+        edited the same way. Only the first 74994 bytes are copied, which is what many\n\
+        terminals take. YASH_OSC52=0 turns both off, for terminals that don't.";
+    debug: "Shows or hides the debug overlay",
+        "debug [on | off | status]\n\n\
+        The overlay shows the last values collected with sdbg! in the top right corner\n\
+        of the terminal. Release builds don't have it.";
+    help: "Lists the builtins, or explains one of them", "help [NAME]";
+    builtins: "Lists the builtin commands, without the aliases", "builtins";
+    yashenv: "Allows the .yashenv files that cd sources",
+        "yashenv [status]\n       yashenv (allow | deny) [FILE]\n\n\
+        A .yashenv file is sourced when cd enters its directory, and the variables it\n\
+        set are restored when leaving it. It must be allowed first, and again after it\n\
+        changes. FILE is the innermost one of the working directory by default.";
+    time: "Runs a pipeline and reports how long it took",
+        "time COMMAND [ARGS...] [| ...]\n\n\
+        Prints the real, user and system time of the whole pipeline to stderr, so the\n\
+        report isn't redirected with the output.";
+    set: "Enables or disables shell options",
+        "set -o\n       set (-o | +o) NAME...\n       set (-e | +e | -x | +x)\n\n\
+        -o enables the options and +o disables them. -e is short for -o errexit and -x\n\
+        for -o xtrace.\n\
+        Without a NAME, prints whether each option is enabled. The options are:\n\
+        errexit        scripts stop at the first failing command not in an if condition\n\
+        noclobber      > refuses to overwrite existing files, >| still does\n\
+        partial-line   a % ends output missing its last newline, before the prompt (on)\n\
+        paste-confirm  pasted lines wait for Enter one by one, Ctrl-C drops them (on)\n\
+        xtrace         commands are printed after a + once expanded, before they run";
+    reload: "Sources the rc files again",
+        "reload [-a]\n\n\
+        The aliases the rc files defined are dropped first, so those no longer there go\n\
+        away, while those defined otherwise are kept. -a drops every alias and shell\n\
+        variable instead, like in a new shell. History and the terminal are left alone.";
+    ulimit: "Prints or sets the resource limits",
+        "ulimit [-H] -a\n       ulimit [-H] [-c | -f | -n | -s | -t | -v] [LIMIT]\n\n\
+        Sets the soft limit of the shell, which the programs it runs inherit, or the hard\n\
+        one with -H. LIMIT is a number or unlimited. Without LIMIT, prints it, and -a\n\
+        prints them all. The limits are:\n\
+        -c  core file size, in 512-byte blocks\n\
+        -f  file size, in 512-byte blocks (the default)\n\
+        -n  open files\n\
+        -s  stack size, in kbytes\n\
+        -t  cpu time, in seconds\n\
+        -v  virtual memory, in kbytes";
+    umask: "Prints or sets the file creation mask",
+        "umask [-S] [MODE]\n\n\
+        MODE is octal, like 022, or what new files may get, like u=rwx,g=rx,o=rx. Symbolic\n\
+        clauses change the current mask: g-w denies writing to the group and o+r allows\n\
+        reading to others. -S prints the mask in the symbolic form.";
+    trap: "Runs commands when the shell exits",
+        "trap [COMMANDS EXIT]\n       trap - EXIT\n\n\
+        COMMANDS run when the shell exits, however it does. '-' removes them.\n\
+        Without arguments, prints the traps as trap commands.";
+    hook: "Runs commands around the lines typed at the prompt",
+        "hook [list]\n       hook add (precmd | preexec) COMMANDS\n       hook clear [NAME]\n\n\
+        precmd hooks run before each prompt is drawn, and preexec ones after a line is\n\
+        accepted, before it runs, with the line in $YASH_COMMAND. The commands in $PRECMD\n\
+        and $PREEXEC run first, then those added, in the order they were. A failing hook\n\
+        is told about, and the prompt or the line go on anyway.\n\
+        Without arguments, prints the hooks as hook commands. clear removes those added.";
+    "[" => bracket: "Same as `test`, but the last argument must be `]`", "[ EXPRESSION ]";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn page_only_when_output_overflows_terminal() {
+        let text = "a\nb\nc\n";
+        assert!(should_page(text, Some(2)));
+        assert!(!should_page(text, Some(3)));
+        assert!(!should_page(text, None));
+    }
+
+    #[test]
+    fn split_read_fields() {
+        assert_eq!(split_fields("  a b  c d ", 2), ["a", "b  c d"]);
+        assert_eq!(split_fields("a", 3), ["a"]);
+        assert_eq!(split_fields("a b", 1), ["a b"]);
+    }
+
+    fn alias_shell<S: AsRef<str>>(aliases: &[(S, S)]) -> Shell {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        for (name, cmd) in aliases {
+            let mut args = shell_word_split::split(cmd.as_ref()).unwrap();
+            let cmd = args.remove(0);
+            shell.register_builtin(Builtin::new_alias(name.as_ref().to_string(), cmd, args));
+        }
+        shell
+    }
+
+    #[test]
+    fn alias_loop_reports_chain() {
+        let mut shell = alias_shell(&[("a", "b"), ("b", "c --flag"), ("c", "a")]);
+        let err = shell.execute_line("a").unwrap_err().to_string();
+        assert_eq!(err, "alias loop: a -> b -> c -> a");
+        assert!(shell.alias_chain.is_empty());
+    }
+
+    #[test]
+    fn alias_deep_chain_runs() {
+        let aliases = (0..10)
+            .map(|i| (format!("deep{}", i), format!("deep{}", i + 1)))
+            .chain([("deep10".into(), "export YASH_TEST_DEEP_ALIAS=1".into())])
+            .collect::<Vec<(String, String)>>();
+        let mut shell = alias_shell(&aliases);
+        shell.execute_line("deep0").unwrap();
+        assert_eq!(std::env::var("YASH_TEST_DEEP_ALIAS").as_deref(), Ok("1"));
+        assert!(shell.alias_chain.is_empty());
+
+        // The chain is unwound, so running it again doesn't trip the guard.
+        shell.execute_line("deep0").unwrap();
+    }
+
+    #[test]
+    fn alias_too_deep_reports_chain() {
+        let aliases = (0..=MAX_ALIAS_DEPTH)
+            .map(|i| (format!("n{}", i), format!("n{}", i + 1)))
+            .collect::<Vec<(String, String)>>();
+        let mut shell = alias_shell(&aliases);
+        let err = shell.execute_line("n0").unwrap_err().to_string();
+        assert!(err.starts_with("alias expansion too deep: n0 -> n1"));
+    }
+
+    #[test]
+    fn alias_naming_itself_runs_the_builtin() {
+        let _cwd = crate::testing::WorkingDir::lock();
+        let mut shell = alias_shell(&[("cd", "cd /")]);
+        // A `cd` program couldn't change the shell's directory
+        shell.execute_line("cd").unwrap();
+        assert_eq!(shell.cwd(), std::path::Path::new("/"));
+        assert!(shell.alias_chain.is_empty());
+    }
+
+    #[test]
+    fn lookup_order_is_alias_builtin_hash_path() {
+        let mut shell = alias_shell(&[("test", "sh -c 'exit 3'")]);
+        // The alias shadows the builtin, which `builtin` still reaches
+        shell.execute_line("test -n x").unwrap();
+        assert_eq!(shell.last_status(), 3);
+        shell.execute_line("builtin test -n x").unwrap();
+        assert_eq!(shell.last_status(), 0);
+        let err = shell.execute_line("builtin ls").unwrap_err().to_string();
+        assert_eq!(err, "builtin: ls: not a builtin");
+
+        // Builtins come before pinned programs, which come before `$PATH`
+        shell.execute_line("hash -p /bin/false [").unwrap();
+        shell.execute_line("[ -n x ]").unwrap();
+        assert_eq!(shell.last_status(), 0);
+        shell.execute_line("hash -p /bin/true false").unwrap();
+        shell.execute_line("false").unwrap();
+        assert_eq!(shell.last_status(), 0);
+        shell.execute_line("command false").unwrap();
+        assert_eq!(shell.last_status(), 0);
+
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("hash").unwrap();
+        let listed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(listed, "hash -p /bin/false '['\nhash -p /bin/true false\n");
+        shell.execute_line("hash -r").unwrap();
+        shell.execute_line("false").unwrap();
+        assert_eq!(shell.last_status(), 1);
+        assert!(shell.execute_line("hash -p bin/true x").is_err());
+    }
+
+    #[test]
+    fn eval_reads_its_arguments_again() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let printed = |shell: &mut Shell, line| shell.execute_line_capture(line).unwrap().stdout;
+        // Variables in the string expand when it runs, not when it is made
+        shell.execute_line("X=1; CMD='echo $X'; X=2").unwrap();
+        assert_eq!(printed(&mut shell, "eval \"$CMD\""), b"2\n");
+        assert_eq!(printed(&mut shell, "eval echo '$X'"), b"2\n");
+        assert_eq!(printed(&mut shell, "eval echo $X"), b"2\n");
+        // Quotes in it group words again
+        assert_eq!(printed(&mut shell, "eval \"echo 'a   b'\""), b"a   b\n");
+        assert_eq!(
+            printed(&mut shell, "eval 'printf \"%s|\" \"x y\"' z"),
+            b"x y|z|"
+        );
+        shell.execute_line("alias greet='echo hi'").unwrap();
+        assert_eq!(printed(&mut shell, "eval 'greet | tr a-z A-Z'"), b"HI\n");
+        let dir = TempDir::new("eval");
+        let path = dir.join("saved");
+        shell
+            .execute_line(&format!("eval 'echo saved' '>' {}", path.display()))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "saved\n");
+        // Status of the last command, and success without any
+        let captured = shell
+            .execute_line_capture("eval sh -c \"'exit 3'\"")
+            .unwrap();
+        assert_eq!(captured.status, 3);
+        assert_eq!(shell.execute_line_capture("eval").unwrap().status, 0);
+    }
+
+    #[test]
+    fn eval_keeps_oneshot_variables_to_their_command() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let printed = |shell: &mut Shell, line| shell.execute_line_capture(line).unwrap().stdout;
+        assert_eq!(
+            printed(&mut shell, "eval 'V=inner sh -c \"echo \\$V\"'"),
+            b"inner\n"
+        );
+        assert_eq!(shell.get_var("V"), None);
+        assert_eq!(printed(&mut shell, "V=outer eval 'echo $V'"), b"outer\n");
+        assert_eq!(shell.get_var("V"), None);
+        assert_eq!(
+            printed(
+                &mut shell,
+                "V=outer eval 'V=inner sh -c \"echo \\$V\"'; echo \"[$V]\""
+            ),
+            b"inner\n[]\n"
+        );
+    }
+
+    #[test]
+    fn eval_nesting_is_capped() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.execute_line("E='eval \"$E\"'").unwrap();
+        let error = shell.execute_line("eval \"$E\"").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!("eval: nested deeper than {}", MAX_EVAL_DEPTH)
+        );
+        // Counted back down on the way out
+        let captured = shell.execute_line_capture("eval eval echo ok").unwrap();
+        assert_eq!(captured.stdout, b"ok\n");
+    }
+
+    #[test]
+    fn clip_can_be_turned_off() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.execute_line("YASH_OSC52=0").unwrap();
+        let error = shell.execute_line("clip secret").unwrap_err().to_string();
+        assert_eq!(error, "clip: turned off with YASH_OSC52=0");
+    }
+
+    #[test]
+    fn noclobber_keeps_existing_files() {
+        let dir = TempDir::new("clobber");
+        let path = dir.file("existing", "old\n");
+        let file = path.display().to_string();
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.execute_line(&format!("echo new > {}", file)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+
+        shell.execute_line("set -o noclobber").unwrap();
+        for line in ["echo again > {}", "sh -c 'echo again' > {}"] {
+            let error = shell.execute_line(&line.replace("{}", &file)).unwrap_err();
+            assert!(
+                format!("{:#}", error).contains("cannot overwrite existing file"),
+                "{:#}",
+                error
+            );
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+        shell
+            .execute_line(&format!("echo more >> {}", file))
+            .unwrap();
+        shell
+            .execute_line(&format!("sh -c 'echo forced' >| {}", file))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "forced\n");
+        // Not a regular file
+        shell.execute_line("echo gone > /dev/null").unwrap();
+
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("set -o").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(
+            printed,
+            "errexit        off\nnoclobber      on\npartial-line   on\npaste-confirm  on\nxtrace         off\n"
+        );
+        shell.execute_line("set +o noclobber").unwrap();
+        shell.execute_line(&format!("echo new > {}", file)).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(shell.execute_line("set -o nothing").is_err());
+    }
+
+    #[test]
+    fn xtrace_prints_expanded_commands() {
+        let mut shell = alias_shell(&[("greet", "echo hello")]);
+        shell.execute_line("set -x; NAME='a b'").unwrap();
+        let redirect = output::Redirect::to_buffer();
+        let traces = output::Redirect::traces_to_buffer();
+        shell
+            .execute_line("greet \"$NAME\" | cat | wc -l > /dev/null; X=1 true")
+            .unwrap();
+        let traces = traces.finish();
+        // Traces don't go with the output
+        assert_eq!(redirect.finish(), b"");
+        assert_eq!(
+            String::from_utf8(traces).unwrap(),
+            "+ echo hello 'a b'\n+ cat\n+ wc -l\n+ X=1 true\n"
+        );
+        shell.execute_line("set +x").unwrap();
+        let traces = output::Redirect::traces_to_buffer();
+        shell.execute_line("true").unwrap();
+        assert!(traces.finish().is_empty());
+    }
+
+    #[test]
+    fn history_stats_count_command_words() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let lines = [
+            "git log",
+            "ls",
+            "git status",
+            "  cargo test",
+            "ls -l",
+            "git",
+        ];
+        shell.read_line =
+            crate::read_line::ReadLine::new_with_history(lines.map(String::from).to_vec());
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("history --stats").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(printed, "    3  git\n    2  ls\n    1  cargo\n");
+
+        let lines: Vec<_> = (0..12).map(|i| format!("cmd{:02}", i)).collect();
+        assert_eq!(command_stats(&lines).len(), HISTORY_STATS_COUNT);
+        assert!(shell.execute_line("history -x").is_err());
+    }
+
+    #[test]
+    fn history_archive_moves_old_entries() {
+        let dir = TempDir::new("hist");
+        let path = dir.file("hist.txt", "first\nsecond\nthird\n");
+        let mut shell = Shell::init(Default::default()).unwrap();
+        assert!(shell.execute_line("history --archive").is_err());
+        let history = crate::read_line::history::History::open(path.clone()).unwrap();
+        shell.read_line = crate::read_line::ReadLine::new_with_history(history);
+        shell.set_var("HISTKEEP".into(), "1".into());
+        let archive = config::get_history_archive(&path, config::Date::today());
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("history --archive").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(
+            printed,
+            format!("Archived 2 entries to '{}'\n", archive.display())
+        );
+        assert_eq!(
+            std::fs::read_to_string(&archive).unwrap(),
+            "first\nsecond\n"
+        );
+        assert_eq!(shell.read_line.history(), ["third"]);
+    }
+
+    #[test]
+    fn trailing_space_expands_next_word() {
+        let mut shell = alias_shell(&[("fail3", "sh -c 'exit 3'"), ("ls", "sh -c 'exit 4'")]);
+        shell
+            .execute_line("alias run='env ' quiet='run ' plain=env")
+            .unwrap();
+        shell.execute_line("run fail3").unwrap();
+        assert_eq!(shell.last_status(), 3);
+        // Through an alias ending with a space too
+        shell.execute_line("quiet fail3").unwrap();
+        assert_eq!(shell.last_status(), 3);
+        shell.execute_line("plain fail3").unwrap();
+        assert_eq!(shell.last_status(), 127);
+        assert_eq!(
+            shell.builtins["run"].alias_definition().unwrap(),
+            "alias 'run=env '"
+        );
+
+        // Arguments are never aliases otherwise
+        let captured = shell.execute_line_capture("echo ls").unwrap();
+        assert_eq!(captured.stdout, b"ls\n");
+    }
+
+    #[test]
+    fn unalias_removes_only_aliases() {
+        let mut shell = alias_shell(&[("ll", "ls -l")]);
+        shell.execute_line("unalias ll cd missing").unwrap();
+        assert!(!shell.builtins.contains_key("ll"));
+        assert!(shell.builtins.contains_key("cd"));
+        assert_eq!(shell.last_status(), 1);
+    }
+
+    #[test]
+    fn alias_definition_round_trips() {
+        let mut shell = alias_shell(&[("greet", r"echo 'hello world' it\'s")]);
+        let definition = shell.builtins["greet"].alias_definition().unwrap();
+        shell.builtins.remove("greet");
+        shell.execute_line(&definition).unwrap();
+        let Action::Alias {
+            cmd, extra_args, ..
+        } = &shell.builtins["greet"].action
+        else {
+            panic!("greet is not an alias");
+        };
+        assert_eq!(cmd, "echo");
+        assert_eq!(extra_args, &["hello world", "it's"]);
+        assert!(shell.builtins["cd"].alias_definition().is_none());
+    }
+
+    #[test]
+    fn printed_aliases_can_be_sourced_back() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell
+            .execute_line(r#"alias greet="printf '%s|' 'a  b' it\'s \"q  r\"" blank=' '"#)
+            .unwrap();
+        let captured = shell.execute_line_capture("greet; blank greet").unwrap();
+        assert_eq!(captured.stdout, b"a  b|it's|q  r|a  b|it's|q  r|");
+        let printed = shell
+            .execute_line_capture("alias greet blank")
+            .unwrap()
+            .stdout;
+        let printed = String::from_utf8(printed).unwrap();
+        assert!(
+            printed.starts_with("alias 'greet=printf '\\''%s|'\\'' "),
+            "{}",
+            printed
+        );
+        assert!(printed.ends_with("\nalias 'blank= '\n"), "{}", printed);
+        shell.execute_line("unalias greet blank").unwrap();
+        for line in printed.lines() {
+            shell.execute_line(line).unwrap();
+        }
+        let captured = shell.execute_line_capture("blank greet").unwrap();
+        assert_eq!(captured.stdout, b"a  b|it's|q  r|");
+        // Nothing to run after an alias to nothing
+        shell.execute_line("blank").unwrap();
+    }
+
+    #[test]
+    fn malformed_aliases_are_refused() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let error = |shell: &mut Shell, line| shell.execute_line(line).unwrap_err().to_string();
+        assert_eq!(
+            error(&mut shell, "alias =foo"),
+            "alias: '=foo': missing alias name"
+        );
+        assert_eq!(
+            error(&mut shell, "alias 'a b=ls'"),
+            "alias: 'a b': invalid alias name"
+        );
+        assert!(error(&mut shell, r#"alias q="echo 'open""#).starts_with("alias: q: "));
+        assert!(!shell.builtins.contains_key("") && !shell.builtins.contains_key("q"));
+    }
+
+    fn words(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn help_lists_builtins_and_aliases() {
+        let mut shell = alias_shell(&[("ll", "ls -l")]);
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("help").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        let lines = printed.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), shell.builtins.len());
+        assert!(lines.contains(&"ll        alias for ls -l"));
+        assert!(lines.contains(&"set_pos   Debug command to set the cursor position on-screen"));
+        assert!(lines[0].starts_with("[  "));
+
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("help exit").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(printed, "exit: Quits the shell\n\nusage: exit [CODE]\n");
+        assert!(shell.execute_line("help missing").is_err());
+    }
+
+    #[test]
+    fn listings_are_sorted_by_name() {
+        let mut shell = alias_shell(&[("zz", "ls -a"), ("aa", "echo hi")]);
+        let printed = shell.execute_line_capture("alias").unwrap().stdout;
+        assert_eq!(printed, b"alias 'aa=echo hi'\nalias 'zz=ls -a'\n");
+
+        let printed = shell.execute_line_capture("builtins").unwrap().stdout;
+        let printed = String::from_utf8(printed).unwrap();
+        let names = printed
+            .lines()
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect::<Vec<_>>();
+        let mut sorted = native_builtins().into_keys().collect::<Vec<_>>();
+        sorted.sort();
+        assert_eq!(names, sorted);
+        assert!(printed.contains("\nalias     Lists, creates or deletes aliases\n"));
+    }
+
+    #[test]
+    fn debug_toggles_overlay() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.execute_line("debug on").unwrap();
+        assert!(shell.debug_overlay);
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("debug status").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert!(printed.starts_with("debug overlay is on, "));
+        assert!(shell.execute_line("debug maybe").is_err());
+    }
+
+    #[test]
+    fn export_lists_environment_that_can_be_sourced() {
+        let vars = [
+            ("YASH_TEST_EXPORT_PLAIN", "plain"),
+            ("YASH_TEST_EXPORT_QUOTED", "it's a  test\t|x"),
+            ("YASH_TEST_EXPORT_EMPTY", ""),
+        ];
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("export").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert!(printed.contains("\nexport YASH_TEST_EXPORT_PLAIN=plain\n"));
+        assert!(printed.contains("\nexport YASH_TEST_EXPORT_EMPTY=''\n"));
+
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        for line in printed
+            .lines()
+            .filter(|line| line.contains("YASH_TEST_EXPORT_"))
+        {
+            shell.execute_line(line).unwrap();
+        }
+        for (name, value) in vars {
+            assert_eq!(std::env::var(name).as_deref(), Ok(value));
+            assert_eq!(shell.get_var(name), Some(value));
+            std::env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn export_n_keeps_shell_variable() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell
+            .execute_line("export YASH_TEST_EXPORT_N=kept")
+            .unwrap();
+        assert_eq!(shell.expand_vars("$YASH_TEST_EXPORT_N").unwrap(), "kept");
+        shell.execute_line("export -n YASH_TEST_EXPORT_N").unwrap();
+        assert!(std::env::var_os("YASH_TEST_EXPORT_N").is_none());
+        assert_eq!(shell.get_var("YASH_TEST_EXPORT_N"), Some("kept"));
+        // Exporting it again takes the shell variable
+        shell.execute_line("export YASH_TEST_EXPORT_N").unwrap();
+        assert_eq!(std::env::var("YASH_TEST_EXPORT_N").as_deref(), Ok("kept"));
+        std::env::remove_var("YASH_TEST_EXPORT_N");
+        assert!(shell.execute_line("export =oops").is_err());
+        assert!(shell.execute_line("export 1A=x").is_err());
+    }
+
+    #[test]
+    fn exit_trap_runs_once_on_shutdown() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell
+            .execute_line("trap 'export YASH_TEST_TRAP=ran' EXIT")
+            .unwrap();
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("trap").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(printed, "trap -- 'export YASH_TEST_TRAP=ran' EXIT\n");
+        assert!(std::env::var_os("YASH_TEST_TRAP").is_none());
+
+        shell.shutdown();
+        assert_eq!(std::env::var("YASH_TEST_TRAP").as_deref(), Ok("ran"));
+        std::env::remove_var("YASH_TEST_TRAP");
+        shell.shutdown();
+        assert!(std::env::var_os("YASH_TEST_TRAP").is_none());
+    }
+
+    #[test]
+    fn trap_can_be_removed() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.execute_line("trap 'echo bye' 0").unwrap();
+        assert_eq!(shell.traps["EXIT"], "echo bye");
+        shell.execute_line("trap - EXIT").unwrap();
+        assert!(shell.traps.is_empty());
+        assert!(shell.execute_line("trap 'echo bye' WINCH").is_err());
+        assert!(shell.execute_line("trap 'echo bye'").is_err());
+    }
+
+    #[test]
+    fn hooks_are_listed_and_cleared() {
+        use crate::hooks::Hook;
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell
+            .execute_line("hook add preexec 'echo it runs'")
+            .unwrap();
+        shell.execute_line("hook add precmd date").unwrap();
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("hook").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(
+            printed,
+            "hook add precmd date\nhook add preexec 'echo it runs'\n"
+        );
+
+        shell.execute_line("hook clear precmd").unwrap();
+        assert!(shell.hooks.get(Hook::Precmd).is_empty());
+        assert_eq!(shell.hooks.get(Hook::Preexec), ["echo it runs"]);
+        assert!(shell.execute_line("hook add postexec true").is_err());
+        assert!(shell.execute_line("hook add precmd").is_err());
+    }
+
+    #[test]
+    fn time_runs_the_whole_pipeline() {
+        assert_eq!(format_time(Duration::from_millis(61_234)), "1m1.234s");
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let captured = shell
+            .execute_line_capture("time echo hi | tr a-z A-Z")
+            .unwrap();
+        assert_eq!(captured.stdout, b"HI\n");
+
+        // The redirection is for the timed command, builtins too
+        let dir = TempDir::new("time");
+        let file = dir.join("out");
+        let captured = shell
+            .execute_line_capture(&format!("time builtins > {}", file.display()))
+            .unwrap();
+        assert!(captured.stdout.is_empty());
+        assert!(std::fs::read_to_string(&file).unwrap().contains("\ntime "));
+    }
+
+    #[test]
+    fn test_expressions() {
+        let dir = std::env::temp_dir().display().to_string();
+        assert!(evaluate_test(&words(&["-d", &dir])).unwrap());
+        assert!(!evaluate_test(&words(&["-f", &dir])).unwrap());
+        assert!(evaluate_test(&words(&["-z", ""])).unwrap());
+        assert!(evaluate_test(&words(&["a", "=", "a"])).unwrap());
+        assert!(evaluate_test(&words(&["!", "a", "=", "b"])).unwrap());
+        assert!(evaluate_test(&words(&["2", "-lt", "10"])).unwrap());
+        assert!(!evaluate_test(&words(&[])).unwrap());
+        assert!(evaluate_test(&words(&["a", "-lt", "b"])).is_err());
+        assert!(evaluate_test(&words(&["a", "b", "c", "d"])).is_err());
+    }
+
+    #[test]
+    fn bracket_requires_closing() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.execute_line("[ a = a ]").unwrap();
+        assert_eq!(shell.last_status(), 0);
+        shell.execute_line("[ a = b ]").unwrap();
+        assert_eq!(shell.last_status(), 1);
+        assert!(shell.execute_line("[ a = a").is_err());
+    }
+
+    #[test]
+    fn home_and_username_without_env() {
+        let vars = ["HOME", "USER", "LOGNAME"].map(|name| (name, std::env::var_os(name)));
+        for (name, _) in &vars {
+            std::env::remove_var(name);
+        }
+        let user = current_user();
+        let home = get_home();
+        let username = get_username();
+        for (name, value) in vars {
+            if let Some(value) = value {
+                std::env::set_var(name, value);
+            }
+        }
+        match user {
+            Some(user) => {
+                assert_eq!(home.map(PathBuf::from), Some(user.dir));
+                assert_eq!(username, Some(user.name));
+            }
+            None => assert_eq!((home, username), (None, None)),
+        }
+        let expected = if cfg!(target_os = "macos") {
+            "/Users/alice"
+        } else if cfg!(target_os = "haiku") {
+            "/home/"
+        } else {
+            "/home/alice"
+        };
+        assert_eq!(guess_home("alice"), expected);
+    }
+
+    #[test]
+    fn pwd_prints_logical_or_physical_directory() {
+        let dir = TempDir::new("pwd");
+        let base = dir.canonicalize().unwrap();
+        let real = base.join("real");
+        std::fs::create_dir_all(&real).unwrap();
+        std::os::unix::fs::symlink(&real, base.join("link")).unwrap();
+        // Only what the shell keeps is printed, so the process doesn't need to go there
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.cwd = base.join("link");
+        shell.physical_cwd = real.clone();
+
+        let link = format!("{}\n", base.join("link").display());
+        for line in ["pwd", "pwd -L", "pwd -P -L"] {
+            let captured = shell.execute_line_capture(line).unwrap();
+            assert_eq!(String::from_utf8(captured.stdout).unwrap(), link);
+        }
+        let captured = shell.execute_line_capture("pwd -P").unwrap();
+        let physical = String::from_utf8(captured.stdout).unwrap();
+        assert_eq!(physical, format!("{}\n", real.display()));
+        assert!(shell.execute_line("pwd -x").is_err());
+
+        std::fs::remove_dir_all(&base).unwrap();
+        let captured = shell.execute_line_capture("pwd").unwrap();
+        assert_eq!(String::from_utf8(captured.stdout).unwrap(), link);
+        assert!(shell.execute_line("pwd -P").is_err());
+    }
+
+    #[test]
+    fn cd_keeps_symlinks_and_goes_back() {
+        let dir = TempDir::new("cd");
+        let base = dir.canonicalize().unwrap();
+        let real = base.join("real");
+        std::fs::create_dir_all(real.join("sub")).unwrap();
+        std::os::unix::fs::symlink(&real, base.join("link")).unwrap();
+        let _cwd = crate::testing::WorkingDir::lock();
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let redirect = output::Redirect::to_buffer();
+
+        shell
+            .execute_line(&format!("cd {}/link", base.display()))
+            .unwrap();
+        assert_eq!(shell.cwd(), base.join("link"));
+        assert_eq!(shell.physical_cwd(), real);
+        assert_eq!(std::env::var_os("PWD"), Some(base.join("link").into()));
+
+        // `..` leaves the symlink instead of going to the parent of its target
+        shell.execute_line("cd sub; cd ../..").unwrap();
+        assert_eq!(shell.cwd(), base);
+        assert_eq!(shell.prev_cwd(), Some(base.join("link/sub").as_path()));
+        assert_eq!(
+            std::env::var_os("OLDPWD"),
+            Some(base.join("link/sub").into())
+        );
+
+        shell.execute_line("cd -").unwrap();
+        assert_eq!(shell.cwd(), base.join("link/sub"));
+
+        // Found through CDPATH only when missing from the working directory
+        shell.set_var("CDPATH".into(), format!(":{}", base.display()));
+        shell.execute_line("cd /; cd link").unwrap();
+        assert_eq!(shell.cwd(), base.join("link"));
+        assert!(shell.execute_line("cd missing").is_err());
+
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        let link = base.join("link");
+        assert_eq!(
+            printed,
+            format!("{}/sub\n{}\n", link.display(), link.display())
+        );
+    }
+
+    #[test]
+    fn pushd_and_popd_keep_a_stack() {
+        let temp = TempDir::new("pushd");
+        let base = temp.canonicalize().unwrap();
+        for dir in ["a", "b", "c"] {
+            std::fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        let _cwd = crate::testing::WorkingDir::lock();
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.change_directory(&base).unwrap();
+        shell.set_var("PS1".into(), "%~$ ".into());
+        let (a, b, c) = (base.join("a"), base.join("b"), base.join("c"));
+        let line = |dirs: &[&PathBuf]| {
+            let dirs: Vec<_> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+            format!("{}\n", dirs.join(" "))
+        };
+
+        let pushed = shell
+            .execute_line_capture("pushd a; pushd ../b; pushd ../c")
+            .unwrap();
+        assert_eq!(shell.cwd(), c);
+        assert_eq!(
+            String::from_utf8(pushed.stdout).unwrap(),
+            [
+                line(&[&a, &base]),
+                line(&[&b, &a, &base]),
+                line(&[&c, &b, &a, &base])
+            ]
+            .concat()
+        );
+        assert_eq!(shell.get_prompt(), "3$ ");
+        let listed = shell.execute_line_capture("dirs").unwrap();
+        assert_eq!(
+            String::from_utf8(listed.stdout).unwrap(),
+            format!(
+                " 0  {}\n 1  {}\n 2  {}\n 3  {}\n",
+                c.display(),
+                b.display(),
+                a.display(),
+                base.display()
+            )
+        );
+
+        // Swapping the top two, then rotating the third one to the top
+        shell.execute_line_capture("pushd").unwrap();
+        assert_eq!(shell.dirs(), [&b, &c, &a, &base]);
+        shell.execute_line_capture("pushd +2").unwrap();
+        assert_eq!(shell.dirs(), [&a, &base, &b, &c]);
+        assert_eq!(shell.cwd(), a);
+        assert!(shell.execute_line("pushd +4").is_err());
+        // `cd -` goes back across pushd too
+        shell.execute_line_capture("cd -").unwrap();
+        assert_eq!(shell.cwd(), b);
+
+        let popped = shell.execute_line_capture("popd; popd; popd").unwrap();
+        assert_eq!(
+            String::from_utf8(popped.stdout).unwrap(),
+            [line(&[&base, &b, &c]), line(&[&b, &c]), line(&[&c])].concat()
+        );
+        assert_eq!(shell.cwd(), c);
+        assert_eq!(shell.get_prompt(), "$ ");
+        let error = shell.execute_line("popd").unwrap_err();
+        assert_eq!(error.to_string(), "popd: directory stack empty");
+    }
+
+    #[test]
+    fn pager_receives_output() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let dir = TempDir::new("pager");
+        let out = dir.join("pager.out");
+        let pager = dir.join("pager.sh");
+        std::fs::write(&pager, format!("#!/bin/sh\ncat > '{}'\n", out.display())).unwrap();
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&pager, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        shell.set_var("PAGER".into(), pager.display().to_string());
+        assert!(spawn_pager(&shell, "one\ntwo\n").unwrap());
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn missing_pager_falls_back() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.set_var("PAGER".into(), "/nonexistent/yash-pager".into());
+        assert!(!spawn_pager(&shell, "one\n").unwrap());
+    }
+    #[test]
+    fn set_pos_takes_big_positions() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let captured = shell.execute_line_capture("set_pos 10 300").unwrap();
+        assert_eq!(captured.stdout, b"\x1b[300;10H");
+        let error = shell.execute_line("set_pos 0 1").unwrap_err();
+        assert_eq!(error.to_string(), "set_pos: '0' isn't from 1 to 4294967295");
+    }
+
+    #[test]
+    fn clear_prints_escape() {
+        let mut shell = Shell::init(Default::default()).unwrap();
+        let captured = shell.execute_line_capture("clear").unwrap();
+        assert_eq!(captured.stdout, b"\x1b[2J\x1b[H");
+    }
+}
@@ -1,39 +1,167 @@
-use crate::{shell_println, YshResult};
+use crate::{output, read_line::cursor, shell_println, YshResult};
 
-use std::process::Stdio;
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+};
+
+pub mod parser;
+pub mod path_cache;
+
+pub use parser::Token;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SpecialAction {
-    Redir { to: String },
-    Pipe { next_command: Box<Command> },
+    /// `force` is set for `>|`, which overwrites the file even with `noclobber`.
+    Redir {
+        to: String,
+        append: bool,
+        force: bool,
+    },
+    Pipe {
+        next_command: Box<Command>,
+    },
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Default)]
 pub struct Command {
     pub command: String,
     pub args: Vec<String>,
+    /// File to read stdin from
+    pub stdin: Option<String>,
     pub special_action: Option<SpecialAction>,
 }
 
+/// Shell options that change how commands are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExecOptions {
+    /// `>` doesn't overwrite existing files, only `>|` does.
+    pub noclobber: bool,
+}
+
+/// Opens the target of an output redirection.
+///
+/// With `noclobber`, an existing regular file is only overwritten if `force` is set.
+pub fn open_redirect(
+    to: &str,
+    append: bool,
+    force: bool,
+    options: ExecOptions,
+) -> std::io::Result<std::fs::File> {
+    let clobbers = !append && std::fs::metadata(to).is_ok_and(|meta| meta.is_file());
+    if clobbers && options.noclobber && !force {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "cannot overwrite existing file",
+        ));
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(to)
+}
+
+/// A process of a pipeline, with how it is connected to the stages next to it.
+#[derive(Debug)]
+pub struct Stage {
+    pub process: std::process::Command,
+    /// Whether stdin is the output of the stage before, not a file or the terminal.
+    pub stdin_from_prev: bool,
+    /// Whether stdout goes to the stage after, not a file or the terminal.
+    pub stdout_to_next: bool,
+}
+
+/// Counts the scripts without `#!` run inside one another, each by another yash.
+const SCRIPT_DEPTH_VAR: &str = "YASH_SCRIPT_DEPTH";
+
 impl Command {
-    pub fn prepare_to_execute(self) -> std::io::Result<Vec<std::process::Command>> {
-        let mut cmd = std::process::Command::new(self.command);
-        cmd.args(self.args);
-        match (self).special_action {
-            Some(SpecialAction::Redir { to }) => {
-                cmd.stdout(std::fs::File::create(to)?);
+    /// Makes the stages running `program` run it with the shell at `shell` instead, like a
+    /// script without `#!` that the system can't execute by itself.
+    fn run_as_script(&mut self, program: &Path, programs: &HashMap<String, PathBuf>, shell: &Path) {
+        let runs_program = programs
+            .get(&self.command)
+            .map_or(Path::new(&self.command) == program, |found| {
+                found == program
+            });
+        if runs_program {
+            let script = program.to_string_lossy().into_owned();
+            self.args.insert(0, script);
+            self.command = shell.to_string_lossy().into_owned();
+        }
+        if let Some(SpecialAction::Pipe { next_command }) = &mut self.special_action {
+            next_command.run_as_script(program, programs, shell);
+        }
+    }
+
+    /// The commands of the pipeline, first to last, each without the pipe to the next.
+    fn into_stages(self) -> Vec<Command> {
+        let mut stages = vec![];
+        let mut next = Some(self);
+        while let Some(mut stage) = next.take() {
+            match stage.special_action.take() {
+                Some(SpecialAction::Pipe { next_command }) => next = Some(*next_command),
+                action => stage.special_action = action,
             }
-            Some(SpecialAction::Pipe { next_command }) => {
-                let mut cmd_string = next_command.prepare_to_execute()?;
-                cmd_string.last_mut().unwrap().stdin(Stdio::piped());
-                cmd.stdout(Stdio::piped());
-                cmd_string.push(cmd);
-                return Ok(cmd_string);
+            stages.push(stage);
+        }
+        stages
+    }
+
+    /// Builds the processes of the pipeline, first stage first.
+    ///
+    /// Programs found in `programs` are spawned from that path, keeping the typed name as `argv[0]`.
+    pub fn prepare_to_execute(
+        self,
+        programs: &HashMap<String, PathBuf>,
+        options: ExecOptions,
+    ) -> std::io::Result<Vec<Stage>> {
+        let stages = self.into_stages();
+        let count = stages.len();
+        let mut pipeline = Vec::with_capacity(count);
+        for (i, stage) in stages.into_iter().enumerate() {
+            // A file given with `<` is read instead of the pipe, like in other shells
+            let stdin_from_prev = i > 0 && stage.stdin.is_none();
+            pipeline.push(Stage {
+                process: stage.into_process(programs, options)?,
+                stdin_from_prev,
+                stdout_to_next: i + 1 < count,
+            });
+        }
+        Ok(pipeline)
+    }
+
+    /// Builds the process of a single stage, opening the files it redirects.
+    fn into_process(
+        self,
+        programs: &HashMap<String, PathBuf>,
+        options: ExecOptions,
+    ) -> std::io::Result<std::process::Command> {
+        let mut cmd = match programs.get(&self.command) {
+            Some(program) => {
+                let mut cmd = std::process::Command::new(program);
+                cmd.arg0(&self.command);
+                cmd
             }
-            None => (),
+            None => std::process::Command::new(&self.command),
+        };
+        cmd.args(self.args);
+        if let Some(from) = self.stdin {
+            cmd.stdin(std::fs::File::open(from)?);
         }
-        Ok(vec![cmd])
+        if let Some(SpecialAction::Redir { to, append, force }) = self.special_action {
+            let file = open_redirect(&to, append, force, options)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", to, e)))?;
+            cmd.stdout(file);
+        }
+        Ok(cmd)
     }
+    /// Builds a command from already split words, treating any word starting with
+    /// `>` or `|` as an operator. Superseded by [`parser::parse`].
     pub fn parse_args(mut args: Vec<String>) -> YshResult<Self> {
         if args.is_empty() {
             return Ok(Self::default());
@@ -52,7 +180,10 @@ impl Command {
                         args,
                         special_action: Some(SpecialAction::Redir {
                             to: extra_args.remove(0),
+                            append: false,
+                            force: false,
                         }),
+                        ..Default::default()
                     }),
                     b'|' => Ok(Command {
                         command,
@@ -60,6 +191,7 @@ impl Command {
                         special_action: Some(SpecialAction::Pipe {
                             next_command: Box::new(Command::parse_args(extra_args)?),
                         }),
+                        ..Default::default()
                     }),
                     _ => unreachable!(),
                 }
@@ -67,16 +199,42 @@ impl Command {
             _ => Ok(Command {
                 command,
                 args,
-                special_action: None,
+                ..Default::default()
             }),
         }
     }
     pub fn parse(line: &str) -> YshResult<Self> {
-        Self::parse_args(shell_word_split::split(line)?)
+        Ok(parser::parse(line)?)
+    }
+    /// Names of the programs of every stage of the pipeline, in order.
+    pub fn program_names(&self) -> Vec<&str> {
+        let mut names = vec![self.command.as_str()];
+        if let Some(SpecialAction::Pipe { next_command }) = &self.special_action {
+            names.extend(next_command.program_names());
+        }
+        names
+    }
+    /// The words of every stage, separated by spaces and `|`, like for a window title.
+    pub fn command_line(&self) -> String {
+        let words = std::iter::once(&self.command).chain(&self.args);
+        let mut line = words.map(String::as_str).collect::<Vec<_>>().join(" ");
+        if let Some(SpecialAction::Pipe { next_command }) = &self.special_action {
+            line.push_str(" | ");
+            line.push_str(&next_command.command_line());
+        }
+        line
+    }
+    /// Returns true if the last command of the pipeline redirects its output.
+    pub fn redirects_output(&self) -> bool {
+        match &self.special_action {
+            Some(SpecialAction::Redir { .. }) => true,
+            Some(SpecialAction::Pipe { next_command }) => next_command.redirects_output(),
+            None => false,
+        }
     }
     /// Shifts the arguments to the left by one, removing the command name
     pub fn shift(mut self) -> Self {
-        let command = if self.args.len() == 0 {
+        let command = if self.args.is_empty() {
             String::new()
         } else {
             self.args.remove(0)
@@ -85,57 +243,405 @@ impl Command {
     }
 }
 
+/// Converts an exit status to a shell status, `128 + signal` for killed processes.
+pub fn status_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+/// Describes how a process was killed, unless it was by an uninteresting signal.
+fn signal_message(stage: usize, pid: u32, status: ExitStatus) -> Option<String> {
+    use nix::sys::signal::Signal;
+    let signal = Signal::try_from(status.signal()?).ok()?;
+    // Those are expected when the user presses Ctrl-C or a pipe is closed early.
+    if matches!(signal, Signal::SIGINT | Signal::SIGPIPE) {
+        return None;
+    }
+    let core = if status.core_dumped() {
+        " (core dumped)"
+    } else {
+        ""
+    };
+    Some(format!(
+        "[{}] {} terminated by {}{}",
+        stage,
+        pid,
+        signal.as_str(),
+        core
+    ))
+}
+
+/// Whether `program` is an executable file without `#!` that the system can't run by
+/// itself, which is then a yash script.
+///
+/// Like in other shells, a NUL before its first line break makes it a binary instead.
+fn lacks_hashbang(program: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    let executable = std::fs::metadata(program)
+        .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0);
+    let mut head = Vec::with_capacity(80);
+    let read = std::fs::File::open(program).and_then(|file| file.take(80).read_to_end(&mut head));
+    if !executable || read.is_err() {
+        return false;
+    }
+    let first_line = head.split(|&b| b == b'\n').next().unwrap_or_default();
+    !head.starts_with(b"#!") && !head.starts_with(b"\x7fELF") && !first_line.contains(&0)
+}
+
+/// Makes the stages of `cmd` that run scripts without `#!` run them with the yash at
+/// `shell`, counting one more script level in `oneshot_vars`.
+///
+/// Looked for before spawning anything, since the stages before a failing one would
+/// already be running. Fails once the scripts went too deep, like with one running itself.
+fn run_scripts_with_shell(
+    cmd: &mut Command,
+    programs: &HashMap<String, PathBuf>,
+    shell: Option<&Path>,
+    oneshot_vars: &mut Vec<(String, String)>,
+) -> std::io::Result<()> {
+    let Some(shell) = shell else {
+        return Ok(());
+    };
+    // Names searched by the child are left to it
+    let scripts = cmd
+        .program_names()
+        .into_iter()
+        .filter_map(|name| match programs.get(name) {
+            Some(program) => Some(program.clone()),
+            None => name.contains('/').then(|| PathBuf::from(name)),
+        })
+        .filter(|program| lacks_hashbang(program))
+        .collect::<Vec<_>>();
+    if scripts.is_empty() {
+        return Ok(());
+    }
+    let depth = std::env::var(SCRIPT_DEPTH_VAR)
+        .ok()
+        .and_then(|depth| depth.parse::<usize>().ok())
+        .unwrap_or(0);
+    if depth >= crate::MAX_SOURCE_DEPTH {
+        let message = format!("scripts nested deeper than {}", crate::MAX_SOURCE_DEPTH);
+        return Err(std::io::Error::other(message));
+    }
+    for script in scripts {
+        cmd.run_as_script(&script, programs, shell);
+    }
+    oneshot_vars.push((SCRIPT_DEPTH_VAR.to_string(), (depth + 1).to_string()));
+    Ok(())
+}
+
+/// Explains why `name` could not be spawned, with the status to set: 126 if it exists
+/// but can't be run, 127 if it doesn't exist.
+fn spawn_error(name: &str, e: &std::io::Error) -> (String, i32) {
+    use std::io::ErrorKind;
+    // Names without a slash are searched in `$PATH`, anything else is a path
+    let is_path = name.contains('/');
+    let (reason, status) = match e.kind() {
+        _ if is_path && Path::new(name).is_dir() => ("is a directory".to_string(), 126),
+        ErrorKind::NotFound if is_path => ("no such file or directory".to_string(), 127),
+        ErrorKind::NotFound => ("command not found".to_string(), 127),
+        ErrorKind::PermissionDenied => ("permission denied".to_string(), 126),
+        _ => (e.to_string(), 126),
+    };
+    (format!("yash: {}: {}", name, reason), status)
+}
+
 impl crate::Shell {
+    /// The options enabled with `set -o` that matter to running commands.
+    pub fn exec_options(&self) -> ExecOptions {
+        ExecOptions {
+            noclobber: self.options.contains("noclobber"),
+        }
+    }
+
+    /// Another command that `name` may have been meant to be, to show after "command not found".
+    fn suggest_command(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// Reports that `name` couldn't be run because of `e`, and sets the status accordingly.
+    fn report_spawn_error(&mut self, name: &str, e: &std::io::Error) -> std::io::Result<()> {
+        let (message, status) = spawn_error(name, e);
+        self.set_status(status);
+        shell_println!("{}", message);
+        if status == 127 && !name.contains('/') {
+            if let Some(suggestion) = self.suggest_command(name) {
+                shell_println!("yash: did you mean {}?", suggestion);
+            }
+        }
+        Ok(())
+    }
+
     pub fn execute_program(&mut self, cmd: Command) -> std::io::Result<()> {
+        // The window is named after what runs in it until it is done. A title that can't
+        // be written is no reason not to run it.
+        let titled = self.sets_title();
+        if titled {
+            let _ = crate::write(&cursor::set_title(&cmd.command_line()));
+        }
+        let result = self.run_pipeline(cmd, None);
+        if titled {
+            let _ = crate::write(&cursor::set_title(&self.prompt_title()));
+        }
+        result
+    }
+
+    /// Finds the programs of every stage of `cmd`, or returns the name of a missing one.
+    ///
+    /// Names left out are searched by the child, like paths and everything with a oneshot
+    /// `PATH`.
+    fn find_programs(
+        &mut self,
+        cmd: &Command,
+        oneshot_vars: &[(String, String)],
+    ) -> Result<HashMap<String, PathBuf>, String> {
+        // A oneshot `PATH=...` must be searched by the child instead
+        let use_cache = !oneshot_vars.iter().any(|(name, _)| name == "PATH");
+        let mut programs = HashMap::new();
+        for name in cmd.program_names() {
+            if name.contains('/') {
+                continue;
+            }
+            // Programs pinned with `hash -p` come before `$PATH`, even a oneshot one
+            if let Some(program) = self.hashed.get(name) {
+                programs.insert(name.to_string(), program.clone());
+                continue;
+            }
+            if !use_cache {
+                continue;
+            }
+            match self.path_cache.lookup(name) {
+                Some(program) => programs.insert(name.to_string(), program),
+                None => return Err(name.to_string()),
+            };
+        }
+        Ok(programs)
+    }
+
+    /// Replaces the shell with the program `cmd` runs, with the terminal as it was before
+    /// the shell started.
+    ///
+    /// Only returns if the program can't be run, after reporting why.
+    pub fn exec_program(&mut self, mut cmd: Command) -> std::io::Result<()> {
+        let mut oneshot_vars = std::mem::take(&mut self.oneshot_vars);
+        let programs = match self.find_programs(&cmd, &oneshot_vars) {
+            Ok(programs) => programs,
+            Err(name) => {
+                return self.report_spawn_error(&name, &std::io::ErrorKind::NotFound.into())
+            }
+        };
+        let name = cmd.command.clone();
+        let shell = self.shell_path.as_deref();
+        if let Err(e) = run_scripts_with_shell(&mut cmd, &programs, shell, &mut oneshot_vars) {
+            return self.report_spawn_error(&name, &e);
+        }
+        let mut process = cmd
+            .prepare_to_execute(&programs, self.exec_options())?
+            .swap_remove(0)
+            .process;
+        process.envs(oneshot_vars.iter().map(|(name, value)| (name, value)));
+        output::flush()?;
+        let token = self.term_state.put_old_token()?;
+        let e = process.exec();
+        drop(token);
+        self.report_spawn_error(&name, &e)
+    }
+
+    /// Runs `cmd` as a pipeline of programs, feeding `input` to its first stage if given.
+    pub fn run_pipeline(
+        &mut self,
+        mut cmd: Command,
+        input: Option<Vec<u8>>,
+    ) -> std::io::Result<()> {
         // This vector holds all spawned processes.
         // We wait on all of them later.
         let mut spawned = vec![];
-        let _token = self.term_state.put_old_token()?;
+        let mut oneshot_vars = std::mem::take(&mut self.oneshot_vars);
+        let programs = match self.find_programs(&cmd, &oneshot_vars) {
+            Ok(programs) => programs,
+            Err(name) => {
+                return self.report_spawn_error(&name, &std::io::ErrorKind::NotFound.into())
+            }
+        };
+        let shell = self.shell_path.as_deref();
+        if let Err(e) = run_scripts_with_shell(&mut cmd, &programs, shell, &mut oneshot_vars) {
+            return self.report_spawn_error(&cmd.command, &e);
+        }
 
-        let mut pipeline = cmd.prepare_to_execute()?;
-        pipeline.reverse();
+        // Children write to the terminal directly, after what we printed
+        output::flush()?;
+        let token = self.term_state.put_old_token()?;
+        let redirects_output = cmd.redirects_output();
+        let mut pipeline = cmd.prepare_to_execute(&programs, self.exec_options())?;
 
-        // If there is a oneshot variable, apply it to all commands in the pipeline
-        if let Some(pair) = self.oneshot_var.take() {
-            for p in pipeline.iter_mut() {
-                p.env(&pair.0, &pair.1);
+        if input.is_some() {
+            pipeline[0].process.stdin(Stdio::piped());
+        }
+        // The last stage prints to the same place builtins currently do, never to a pipe
+        if !redirects_output {
+            if let Some(stdout) = output::child_stdout()? {
+                pipeline.last_mut().unwrap().process.stdout(stdout);
             }
         }
 
+        // Oneshot variables apply to all commands in the pipeline
+        for stage in pipeline.iter_mut() {
+            stage
+                .process
+                .envs(oneshot_vars.iter().map(|(name, value)| (name, value)));
+        }
+
+        // Program that could not be spawned, if any
+        let mut spawn_failure = None;
         let result = (|| {
-            let mut last_stdout = None;
-            for mut p in pipeline {
-                // Link last command's stdout with current stdin.
-                // This is how pipes are implemented.
-                if let Some(stdout) = last_stdout.take() {
-                    p.stdin(stdout);
+            // Output of the stage spawned last, for the next one to read
+            let mut prev_stdout = None;
+            for stage in pipeline {
+                let mut p = stage.process;
+                // Unless the stage reads a file, which the one before can't write to then
+                if let Some(stdout) = prev_stdout.take().filter(|_| stage.stdin_from_prev) {
+                    p.stdin(Stdio::from(stdout));
+                }
+                if stage.stdout_to_next {
+                    p.stdout(Stdio::piped());
                 }
 
                 // Spawn the program
-                let name = p.get_program().to_owned();
                 let mut child = match p.spawn() {
                     Ok(c) => c,
-                    Err(e) => match e.kind() {
-                        std::io::ErrorKind::NotFound => {
-                            shell_println!("{:?}: command not found", name);
-                            return Ok(());
-                        }
-                        _ => return Err(e)?,
-                    },
+                    Err(e) => {
+                        spawn_failure = Some(PathBuf::from(p.get_program()));
+                        return Err(e);
+                    }
                 };
-                last_stdout = child.stdout.take();
+                if stage.stdout_to_next {
+                    prev_stdout = child.stdout.take();
+                }
                 spawned.push(child);
             }
+            if let Some(input) = input {
+                let mut stdin = spawned[0].stdin.take().unwrap();
+                // Written from another thread so a full stdout pipe can't deadlock us.
+                // The program may exit before reading everything, so a broken pipe is fine.
+                std::thread::spawn(move || {
+                    let _ = stdin.write_all(&input);
+                });
+            }
+            // Only piped from the last stage when printing into a buffer
+            if let Some(stdout) = spawned.last_mut().and_then(|child| child.stdout.as_mut()) {
+                let mut buf = Vec::new();
+                stdout.read_to_end(&mut buf)?;
+                output::print_bytes(&buf)?;
+            }
             Ok(())
         })();
+        let mut statuses = vec![];
         for mut p in spawned {
-            // Kill everyone if any of them fails to spawn
+            // Kill everyone if any of them fails to spawn.
+            // They may have exited already, which is fine.
             if result.is_err() {
-                p.kill().unwrap();
-            } else {
-                p.wait().unwrap();
+                let _ = p.kill();
             }
+            // Waiting only fails if the child was already reaped.
+            statuses.push((p.id(), p.wait().ok()));
         }
-        result
+        drop(token);
+        if let Some(program) = spawn_failure {
+            return self.report_spawn_error(&program.to_string_lossy(), &result.unwrap_err());
+        }
+        result?;
+        // Like POSIX, the status of a pipeline is the status of its last command
+        if let Some(&(pid, Some(status))) = statuses.last() {
+            if let Some(message) = signal_message(statuses.len(), pid, status) {
+                shell_println!("{}", message);
+            }
+            self.set_status(status_code(status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::testing::TempDir;
+
+    #[test]
+    fn missing_program_mid_pipeline_cleans_up() {
+        let mut shell = crate::Shell::init(Default::default()).unwrap();
+        let start = Instant::now();
+        shell
+            .execute_line("sleep 5 | /nonexistent/yash-program")
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(shell.last_status(), 127);
+    }
+
+    #[test]
+    fn program_not_in_path_is_reported_before_spawning() {
+        let mut shell = crate::Shell::init(Default::default()).unwrap();
+        let redirect = crate::output::Redirect::to_buffer();
+        let start = Instant::now();
+        shell
+            .execute_line("sleep 5 | yash-missing-program")
+            .unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(printed, "yash: yash-missing-program: command not found\n");
+        assert_eq!(shell.last_status(), 127);
+    }
+
+    #[test]
+    fn paths_that_cant_run_are_explained() {
+        let dir = TempDir::new("spawn");
+        let script = dir.join("script.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        let mut shell = crate::Shell::init(Default::default()).unwrap();
+        let cases = [
+            (script.display().to_string(), "permission denied", 126),
+            (format!("{}/", dir.display()), "is a directory", 126),
+            (
+                format!("{}/missing", dir.display()),
+                "no such file or directory",
+                127,
+            ),
+        ];
+        for (name, reason, status) in cases {
+            let redirect = crate::output::Redirect::to_buffer();
+            shell.execute_line(&name).unwrap();
+            let printed = String::from_utf8(redirect.finish()).unwrap();
+            assert_eq!(printed, format!("yash: {}: {}\n", name, reason));
+            assert_eq!(shell.last_status(), status);
+        }
+    }
+
+    #[test]
+    fn command_line_leaves_out_redirections() {
+        let cmd = super::Command::parse("ls -l 'a b' | grep x > out").unwrap();
+        assert_eq!(cmd.command_line(), "ls -l a b | grep x");
+    }
+
+    #[test]
+    fn pipeline_status_is_last_command_status() {
+        let mut shell = crate::Shell::init(Default::default()).unwrap();
+        shell.execute_line("false | sh -c 'exit 3'").unwrap();
+        assert_eq!(shell.last_status(), 3);
+        shell.execute_line("sh -c 'exit 3' | true").unwrap();
+        assert_eq!(shell.last_status(), 0);
+    }
+
+    #[test]
+    fn report_termination_by_signal() {
+        let mut shell = crate::Shell::init(Default::default()).unwrap();
+        let redirect = crate::output::Redirect::to_buffer();
+        shell.execute_line("sh -c 'kill -SEGV $$'").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert!(printed.starts_with("[1] "), "{:?}", printed);
+        assert!(printed.contains(" terminated by SIGSEGV"), "{:?}", printed);
+        assert_eq!(shell.last_status(), 128 + 11);
     }
 }
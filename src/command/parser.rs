@@ -0,0 +1,723 @@
+//! Turns a command line into a [`Command`] tree.
+//!
+//! Lexing is quote aware, so operators inside quotes are plain words
+//! (`echo "|"` prints a pipe).
+
+use super::{Command, SpecialAction};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(String),
+    /// `|`
+    Pipe,
+    /// `>`
+    Redir,
+    /// `>>`
+    Append,
+    /// `>|`, which overwrites the file even with `noclobber`
+    Clobber,
+    /// `<`
+    Input,
+    /// `&&`
+    And,
+    /// `||`
+    Or,
+    /// `;`
+    Semicolon,
+    /// `&`
+    Background,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            Self::Word(word) => return write!(f, "word '{}'", word),
+            Self::Pipe => "|",
+            Self::Redir => ">",
+            Self::Append => ">>",
+            Self::Clobber => ">|",
+            Self::Input => "<",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::Semicolon => ";",
+            Self::Background => "&",
+        };
+        write!(f, "'{}'", op)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset into the parsed line
+    pub offset: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(offset: usize, message: impl Into<String>) -> Self {
+        Self {
+            offset,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at byte {})", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn is_operator(c: char) -> bool {
+    matches!(c, '|' | '&' | ';' | '<' | '>')
+}
+
+/// Splits `line` into tokens, each paired with the byte offset where it starts.
+pub fn tokenize(line: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let mut tokens = vec![];
+    let mut it = line.char_indices().peekable();
+    while let Some(&(start, c)) = it.peek() {
+        if c.is_whitespace() {
+            it.next();
+            continue;
+        }
+        if is_operator(c) {
+            it.next();
+            let next = it.peek().map(|&(_, c)| c);
+            let (token, doubled) = match (c, next) {
+                ('|', Some('|')) => (Token::Or, true),
+                ('&', Some('&')) => (Token::And, true),
+                ('>', Some('>')) => (Token::Append, true),
+                ('>', Some('|')) => (Token::Clobber, true),
+                ('|', _) => (Token::Pipe, false),
+                ('&', _) => (Token::Background, false),
+                ('>', _) => (Token::Redir, false),
+                ('<', _) => (Token::Input, false),
+                (';', _) => (Token::Semicolon, false),
+                _ => unreachable!(),
+            };
+            if doubled {
+                it.next();
+            }
+            tokens.push((start, token));
+            continue;
+        }
+        let mut word = String::new();
+        while let Some(&(i, c)) = it.peek() {
+            if c.is_whitespace() || is_operator(c) {
+                break;
+            }
+            it.next();
+            match c {
+                '\\' => match it.next() {
+                    Some((_, c)) => word.push(c),
+                    None => return Err(ParseError::new(i, "trailing backslash")),
+                },
+                '\'' => loop {
+                    match it.next() {
+                        Some((_, '\'')) => break,
+                        Some((_, c)) => word.push(c),
+                        None => return Err(ParseError::new(i, "unterminated single quote")),
+                    }
+                },
+                '"' => loop {
+                    match it.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match it.next() {
+                            Some((_, c @ ('"' | '\\' | '$' | '`'))) => word.push(c),
+                            Some((_, c)) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(ParseError::new(i, "unterminated double quote")),
+                        },
+                        Some((_, c)) => word.push(c),
+                        None => return Err(ParseError::new(i, "unterminated double quote")),
+                    }
+                },
+                c => word.push(c),
+            }
+        }
+        tokens.push((start, Token::Word(word)));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<(usize, Token)>>,
+    /// Offset reported for errors at the end of the line
+    end: usize,
+}
+
+impl Parser {
+    fn expect_word(&mut self, after: &Token) -> Result<String, ParseError> {
+        match self.tokens.next() {
+            Some((_, Token::Word(word))) => Ok(word),
+            Some((offset, token)) => Err(ParseError::new(
+                offset,
+                format!("expected a word after {}, found {}", after, token),
+            )),
+            None => Err(ParseError::new(
+                self.end,
+                format!("expected a word after {}", after),
+            )),
+        }
+    }
+
+    fn pipeline(&mut self) -> Result<Command, ParseError> {
+        let mut words = vec![];
+        let mut stdin = None;
+        let mut special_action = None;
+        while let Some((offset, token)) = self.tokens.peek().cloned() {
+            match token {
+                Token::Word(word) => {
+                    self.tokens.next();
+                    words.push(word);
+                }
+                Token::Redir | Token::Append | Token::Clobber => {
+                    self.tokens.next();
+                    let to = self.expect_word(&token)?;
+                    special_action = Some(SpecialAction::Redir {
+                        to,
+                        append: token == Token::Append,
+                        force: token == Token::Clobber,
+                    });
+                }
+                Token::Input => {
+                    self.tokens.next();
+                    stdin = Some(self.expect_word(&token)?);
+                }
+                Token::Pipe => {
+                    self.tokens.next();
+                    if words.is_empty() {
+                        return Err(ParseError::new(offset, "missing command before '|'"));
+                    }
+                    if special_action.is_some() {
+                        return Err(ParseError::new(
+                            offset,
+                            "cannot both redirect and pipe output",
+                        ));
+                    }
+                    let next_command = self.pipeline()?;
+                    if next_command.command.is_empty() {
+                        return Err(ParseError::new(offset, "missing command after '|'"));
+                    }
+                    special_action = Some(SpecialAction::Pipe {
+                        next_command: Box::new(next_command),
+                    });
+                    break;
+                }
+                _ => break,
+            }
+        }
+        if words.is_empty() {
+            if stdin.is_some() || special_action.is_some() {
+                return Err(ParseError::new(0, "missing command before redirection"));
+            }
+            return Ok(Command::default());
+        }
+        let command = words.remove(0);
+        Ok(Command {
+            command,
+            args: words,
+            stdin,
+            special_action,
+        })
+    }
+}
+
+/// A `for NAME in WORDS; do BODY; done` loop, with the word list and body left unexpanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForLoop<'a> {
+    pub name: String,
+    pub words: &'a str,
+    pub body: &'a str,
+}
+
+/// An `if COND; then BODY; [else BODY;] fi` statement, with every part left unexpanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IfStatement<'a> {
+    pub condition: &'a str,
+    pub then_branch: &'a str,
+    pub else_branch: Option<&'a str>,
+}
+
+/// Keywords opening a compound statement, that must be closed by the matching [`CLOSERS`].
+const OPENERS: [&str; 2] = ["for", "if"];
+const CLOSERS: [&str; 2] = ["done", "fi"];
+/// Keywords followed by a new statement.
+const STATEMENT_STARTERS: [&str; 3] = ["do", "then", "else"];
+
+/// Returns true if the token at `offset` is the unquoted keyword `keyword`.
+fn is_keyword(line: &str, (offset, token): &(usize, Token), keyword: &str) -> bool {
+    matches!(token, Token::Word(word) if word == keyword) && line[*offset..].starts_with(keyword)
+}
+
+fn is_any_keyword(line: &str, token: &(usize, Token), keywords: &[&str]) -> bool {
+    keywords.iter().any(|kw| is_keyword(line, token, kw))
+}
+
+/// For every token, returns whether it starts a statement and how deeply nested
+/// in compound statements it is. Openers and closers count as outside of what they delimit.
+fn nesting(line: &str, tokens: &[(usize, Token)]) -> Vec<(bool, usize)> {
+    let mut at_start = true;
+    let mut depth = 0usize;
+    let mut nesting = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        if at_start && is_any_keyword(line, token, &OPENERS) {
+            nesting.push((true, depth));
+            depth += 1;
+        } else if at_start && depth > 0 && is_any_keyword(line, token, &CLOSERS) {
+            depth -= 1;
+            nesting.push((true, depth));
+        } else {
+            nesting.push((at_start, depth));
+        }
+        at_start = token.1 == Token::Semicolon || is_any_keyword(line, token, &STATEMENT_STARTERS);
+    }
+    nesting
+}
+
+/// Finds the unnested `; KEYWORD` that starts a statement, returning the index of the keyword.
+fn find_clause(
+    line: &str,
+    tokens: &[(usize, Token)],
+    nesting: &[(bool, usize)],
+    from: usize,
+    keywords: &[&str],
+) -> Option<usize> {
+    (from..tokens.len()).find(|&i| {
+        nesting[i] == (true, 1)
+            && i > 0
+            && tokens[i - 1].1 == Token::Semicolon
+            && is_any_keyword(line, &tokens[i], keywords)
+    })
+}
+
+/// Checks that the statement ends with `; CLOSER`, returning the offset of the `;`.
+fn expect_closer(
+    line: &str,
+    tokens: &[(usize, Token)],
+    nesting: &[(bool, usize)],
+    closer: &str,
+) -> Result<usize, ParseError> {
+    let missing = || {
+        ParseError::new(
+            line.len(),
+            format!("expected '; {}' at the end of the statement", closer),
+        )
+    };
+    // Not even room for `; CLOSER`, like a lone keyword
+    if tokens.len() < 2 {
+        return Err(missing());
+    }
+    let last = tokens.len() - 1;
+    if let Some(i) = (1..last).find(|&i| nesting[i] == (true, 0)) {
+        let (offset, token) = &tokens[i + 1];
+        return Err(ParseError::new(
+            *offset,
+            format!("unexpected {} after '{}'", token, closer),
+        ));
+    }
+    match &tokens[last - 1..] {
+        [(offset, Token::Semicolon), token] if is_keyword(line, token, closer) => Ok(*offset),
+        _ => Err(missing()),
+    }
+}
+
+/// Parses `line` as a single-line `for` loop, if it starts with the `for` keyword.
+pub fn parse_for(line: &str) -> Result<Option<ForLoop<'_>>, ParseError> {
+    let tokens = tokenize(line)?;
+    match tokens.first() {
+        Some(token) if is_keyword(line, token, "for") => (),
+        _ => return Ok(None),
+    }
+    let end = line.len();
+    let name = match tokens.get(1) {
+        Some((_, Token::Word(name))) => name.clone(),
+        _ => return Err(ParseError::new(end, "expected a variable name after 'for'")),
+    };
+    let words_start = match tokens.get(2) {
+        Some(token) if is_keyword(line, token, "in") => token.0 + "in".len(),
+        Some((offset, _)) => return Err(ParseError::new(*offset, "expected 'in'")),
+        None => return Err(ParseError::new(end, "expected 'in'")),
+    };
+    let Some(words_end) = (3..tokens.len()).find(|&i| tokens[i].1 == Token::Semicolon) else {
+        return Err(ParseError::new(end, "expected ';' after the word list"));
+    };
+    let body_start = match tokens.get(words_end + 1) {
+        Some(token) if is_keyword(line, token, "do") => token.0 + "do".len(),
+        Some((offset, _)) => return Err(ParseError::new(*offset, "expected 'do'")),
+        None => return Err(ParseError::new(end, "expected 'do'")),
+    };
+    let nesting = nesting(line, &tokens);
+    let body_end = expect_closer(line, &tokens, &nesting, "done")?;
+    Ok(Some(ForLoop {
+        name,
+        words: &line[words_start..tokens[words_end].0],
+        body: &line[body_start..body_end],
+    }))
+}
+
+/// Splits the word list of a `for` loop once its variables are expanded. Words with any
+/// quoting are taken literally, the others are glob patterns.
+pub fn split_for_words(words: &str) -> Result<Vec<String>, ParseError> {
+    let tokens = tokenize(words)?;
+    let mut split = vec![];
+    for (i, (offset, token)) in tokens.iter().enumerate() {
+        let Token::Word(word) = token else {
+            return Err(ParseError::new(
+                *offset,
+                format!("unexpected {} in word list", token),
+            ));
+        };
+        let end = tokens.get(i + 1).map_or(words.len(), |(next, _)| *next);
+        if words[*offset..end].contains(['\'', '"', '\\']) {
+            split.push(word.clone());
+        } else {
+            split.extend(crate::glob::expand(word));
+        }
+    }
+    Ok(split)
+}
+
+/// Parses `line` as a single-line `if` statement, if it starts with the `if` keyword.
+pub fn parse_if(line: &str) -> Result<Option<IfStatement<'_>>, ParseError> {
+    let tokens = tokenize(line)?;
+    let condition_start = match tokens.first() {
+        Some(token) if is_keyword(line, token, "if") => token.0 + "if".len(),
+        _ => return Ok(None),
+    };
+    let nesting = nesting(line, &tokens);
+    let fi = expect_closer(line, &tokens, &nesting, "fi")?;
+    let Some(then) = find_clause(line, &tokens, &nesting, 1, &["then"]) else {
+        return Err(ParseError::new(line.len(), "expected '; then'"));
+    };
+    let else_ = find_clause(line, &tokens, &nesting, then + 1, &["else"]);
+    if let Some(i) = find_clause(line, &tokens, &nesting, then + 1, &["then"]) {
+        return Err(ParseError::new(tokens[i].0, "unexpected 'then'"));
+    }
+    let then_start = tokens[then].0 + "then".len();
+    let (then_branch, else_branch) = match else_ {
+        Some(i) => (
+            &line[then_start..tokens[i - 1].0],
+            Some(&line[tokens[i].0 + "else".len()..fi]),
+        ),
+        None => (&line[then_start..fi], None),
+    };
+    Ok(Some(IfStatement {
+        condition: &line[condition_start..tokens[then - 1].0],
+        then_branch,
+        else_branch,
+    }))
+}
+
+/// Splits `line` at every `;` outside of quotes, skipping empty statements.
+///
+/// Compound statements like `for ... done` are kept whole.
+pub fn split_statements(line: &str) -> Result<Vec<&str>, ParseError> {
+    let tokens = tokenize(line)?;
+    let separators = tokens
+        .iter()
+        .zip(nesting(line, &tokens))
+        .filter(|((_, token), (_, depth))| *token == Token::Semicolon && *depth == 0)
+        .map(|((offset, _), _)| *offset);
+    let mut statements = vec![];
+    let mut start = 0;
+    for end in separators.chain([line.len()]) {
+        let statement = &line[start..end];
+        if !statement.trim().is_empty() {
+            statements.push(statement);
+        }
+        start = end + 1;
+    }
+    Ok(statements)
+}
+
+/// Parses a single pipeline.
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(line)?.into_iter().peekable(),
+        end: line.len(),
+    };
+    let command = parser.pipeline()?;
+    if let Some((offset, token)) = parser.tokens.next() {
+        return Err(ParseError::new(offset, format!("unexpected {}", token)));
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(w: &str) -> Token {
+        Token::Word(w.to_string())
+    }
+
+    #[test]
+    fn tokenize_operators() {
+        let tokens = tokenize("a|b||c&&d&e;f>g>>h<i")
+            .unwrap()
+            .into_iter()
+            .map(|(_, t)| t)
+            .collect::<Vec<_>>();
+        use Token::*;
+        assert_eq!(
+            tokens,
+            [
+                word("a"),
+                Pipe,
+                word("b"),
+                Or,
+                word("c"),
+                And,
+                word("d"),
+                Background,
+                word("e"),
+                Semicolon,
+                word("f"),
+                Redir,
+                word("g"),
+                Append,
+                word("h"),
+                Input,
+                word("i"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_quotes() {
+        let tokens = tokenize(r#"echo "|" '>' a\;b "x\"y" '' "a b"c"#)
+            .unwrap()
+            .into_iter()
+            .map(|(_, t)| t)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                word("echo"),
+                word("|"),
+                word(">"),
+                word("a;b"),
+                word("x\"y"),
+                word(""),
+                word("a bc"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_offsets() {
+        let offsets = tokenize("ls  -l | wc")
+            .unwrap()
+            .into_iter()
+            .map(|(o, _)| o)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [0, 4, 7, 9]);
+    }
+
+    #[test]
+    fn parse_redirections() {
+        let cmd = parse("sort < in >> out").unwrap();
+        assert_eq!(cmd.stdin.as_deref(), Some("in"));
+        assert_eq!(
+            cmd.special_action,
+            Some(SpecialAction::Redir {
+                to: "out".into(),
+                append: true,
+                force: false
+            })
+        );
+        let cmd = parse("echo hi >|out").unwrap();
+        assert_eq!(
+            cmd.special_action,
+            Some(SpecialAction::Redir {
+                to: "out".into(),
+                append: false,
+                force: true
+            })
+        );
+    }
+
+    #[test]
+    fn parse_quoted_operator_is_a_word() {
+        let cmd = parse(r#"echo "|" ">weird""#).unwrap();
+        assert_eq!(cmd.args, ["|", ">weird"]);
+        assert_eq!(cmd.special_action, None);
+    }
+
+    #[test]
+    fn parse_errors_have_offsets() {
+        assert_eq!(
+            parse("echo 'abc").unwrap_err(),
+            ParseError::new(5, "unterminated single quote")
+        );
+        assert_eq!(
+            parse("ls >").unwrap_err(),
+            ParseError::new(4, "expected a word after '>'")
+        );
+        assert_eq!(
+            parse("| wc").unwrap_err(),
+            ParseError::new(0, "missing command before '|'")
+        );
+        assert_eq!(
+            parse("ls |").unwrap_err(),
+            ParseError::new(3, "missing command after '|'")
+        );
+        assert_eq!(
+            parse("ls ; wc").unwrap_err(),
+            ParseError::new(3, "unexpected ';'")
+        );
+    }
+
+    #[test]
+    fn split_at_semicolons() {
+        assert_eq!(
+            split_statements("cd /tmp; ls;; echo 'a;b' ; ").unwrap(),
+            ["cd /tmp", " ls", " echo 'a;b' "]
+        );
+        assert!(split_statements(";;").unwrap().is_empty());
+    }
+
+    #[test]
+    fn split_keeps_loops_whole() {
+        assert_eq!(
+            split_statements("A=; for f in a; do ls; done; B=1").unwrap(),
+            ["A=", " for f in a; do ls; done", " B=1"]
+        );
+        assert_eq!(
+            split_statements("if a; then b; fi; c").unwrap(),
+            ["if a; then b; fi", " c"]
+        );
+        assert_eq!(
+            split_statements("echo for; ls").unwrap(),
+            ["echo for", " ls"]
+        );
+        assert_eq!(
+            split_statements("for a in b; do for c in d; do ls; done; done; ls").unwrap(),
+            ["for a in b; do for c in d; do ls; done; done", " ls"]
+        );
+    }
+
+    #[test]
+    fn parse_for_loop() {
+        let for_loop = parse_for("for f in a $B *.txt; do echo $f; ls; done")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            for_loop,
+            ForLoop {
+                name: "f".into(),
+                words: " a $B *.txt",
+                body: " echo $f; ls",
+            }
+        );
+        assert_eq!(parse_for("echo for").unwrap(), None);
+        assert_eq!(parse_for("'for' x").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_for_loop_errors() {
+        assert_eq!(
+            parse_for("for f in a; echo $f; done").unwrap_err(),
+            ParseError::new(12, "expected 'do'")
+        );
+        assert_eq!(
+            parse_for("for f in a; do echo $f").unwrap_err(),
+            ParseError::new(22, "expected '; done' at the end of the statement")
+        );
+        assert_eq!(
+            parse_for("for f in a; do ls; done; ls").unwrap_err(),
+            ParseError::new(23, "unexpected ';' after 'done'")
+        );
+    }
+
+    #[test]
+    fn split_for_words_globs_only_unquoted_words() {
+        assert_eq!(
+            split_for_words(r#" a 'b c' "/*" \/* /"#).unwrap(),
+            ["a", "b c", "/*", "/*", "/"]
+        );
+        assert_eq!(
+            split_for_words("a | b").unwrap_err(),
+            ParseError::new(2, "unexpected '|' in word list")
+        );
+    }
+
+    #[test]
+    fn parse_nested_for_loop() {
+        let for_loop = parse_for("for a in b; do for c in d; do ls; done; done")
+            .unwrap()
+            .unwrap();
+        assert_eq!(for_loop.body, " for c in d; do ls; done");
+    }
+
+    #[test]
+    fn parse_if_statement() {
+        let statement = parse_if(r#"if [ "a;b" = x ]; then echo 'then;'; else ls; fi"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            statement,
+            IfStatement {
+                condition: r#" [ "a;b" = x ]"#,
+                then_branch: " echo 'then;'",
+                else_branch: Some(" ls"),
+            }
+        );
+        let statement = parse_if("if true; then ls; fi").unwrap().unwrap();
+        assert_eq!(statement.else_branch, None);
+        assert_eq!(parse_if("echo if").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_nested_if_statement() {
+        let statement = parse_if("if a; then if b; then c; else d; fi; else e; fi")
+            .unwrap()
+            .unwrap();
+        assert_eq!(statement.then_branch, " if b; then c; else d; fi");
+        assert_eq!(statement.else_branch, Some(" e"));
+    }
+
+    #[test]
+    fn parse_if_errors() {
+        assert_eq!(
+            parse_if("if a; b; fi").unwrap_err(),
+            ParseError::new(11, "expected '; then'")
+        );
+        assert_eq!(
+            parse_if("if a; then b").unwrap_err(),
+            ParseError::new(12, "expected '; fi' at the end of the statement")
+        );
+        // Too short to end at all
+        for (line, offset) in [("if", 2), ("if true; then", 13), ("if ;", 4)] {
+            assert_eq!(
+                parse_if(line).unwrap_err(),
+                ParseError::new(offset, "expected '; fi' at the end of the statement"),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn parse_agrees_with_legacy_parser() {
+        let corpus = [
+            "ls -la",
+            "echo 'quoted words' here",
+            "echo a b > out",
+            "cat x | grep y",
+            "a | b | c > d",
+            "",
+            "   ",
+        ];
+        for line in corpus {
+            let legacy = Command::parse_args(shell_word_split::split(line).unwrap()).unwrap();
+            assert_eq!(parse(line).unwrap(), legacy, "{:?}", line);
+        }
+    }
+}
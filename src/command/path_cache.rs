@@ -0,0 +1,118 @@
+//! Locations of the executables found in `$PATH`.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+pub type Commands = Rc<HashMap<String, PathBuf>>;
+
+/// Maps command names to the first executable with that name in `$PATH`.
+///
+/// The cache is rebuilt whenever `$PATH` changes, or explicitly with [`PathCache::rehash`].
+#[derive(Debug, Default, Clone)]
+pub struct PathCache {
+    /// Value of `$PATH` the cache was built from, `None` if it wasn't built yet.
+    path: Option<OsString>,
+    commands: Commands,
+}
+
+fn current_path() -> OsString {
+    std::env::var_os("PATH").unwrap_or_default()
+}
+
+fn is_executable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+impl PathCache {
+    /// Rebuilds the cache if `$PATH` changed since it was last built.
+    pub fn refresh(&mut self) {
+        let path = current_path();
+        if self.path.as_ref() != Some(&path) {
+            self.build(path);
+        }
+    }
+    /// Rebuilds the cache unconditionally, picking up newly installed programs.
+    pub fn rehash(&mut self) {
+        self.build(current_path());
+    }
+    fn build(&mut self, path: OsString) {
+        let mut commands = HashMap::new();
+        for dir in std::env::split_paths(&path) {
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.filter_map(Result::ok) {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                let program = entry.path();
+                // Earlier directories take precedence
+                if !commands.contains_key(&name) && is_executable(&program) {
+                    commands.insert(name, program);
+                }
+            }
+        }
+        self.commands = Rc::new(commands);
+        self.path = Some(path);
+    }
+    /// Finds the program `name` refers to, refreshing the cache first.
+    pub fn lookup(&mut self, name: &str) -> Option<PathBuf> {
+        self.refresh();
+        self.find(name)
+    }
+    /// Programs missing from the cache are searched again in `$PATH`, but not cached.
+    fn find(&self, name: &str) -> Option<PathBuf> {
+        match self.commands.get(name) {
+            Some(program) if is_executable(program) => Some(program.clone()),
+            _ => std::env::split_paths(self.path.as_ref()?)
+                .map(|dir| dir.join(name))
+                .find(|program| is_executable(program)),
+        }
+    }
+    /// All the cached commands, to be shared with completion.
+    pub fn commands(&self) -> Commands {
+        self.commands.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn write_program(dir: &Path, name: &str, mode: u32) -> PathBuf {
+        let program = dir.join(name);
+        std::fs::write(&program, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(mode)).unwrap();
+        program
+    }
+
+    #[test]
+    fn first_executable_in_path_wins() {
+        let base = TempDir::new("path-cache");
+        let (first, second) = (base.join("first"), base.join("second"));
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+        write_program(&first, "not-executable", 0o644);
+        let expected = write_program(&first, "prog", 0o755);
+        write_program(&second, "prog", 0o755);
+        let other = write_program(&second, "not-executable", 0o755);
+
+        let mut cache = PathCache::default();
+        cache.build(std::env::join_paths([&first, &second]).unwrap());
+        assert_eq!(cache.commands().get("prog"), Some(&expected));
+        assert_eq!(cache.commands().get("not-executable"), Some(&other));
+
+        // Programs installed after the cache was built are still found
+        let late = write_program(&second, "late", 0o755);
+        assert_eq!(cache.commands().get("late"), None);
+        assert_eq!(cache.find("late"), Some(late));
+    }
+}
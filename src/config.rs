@@ -1,22 +1,348 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::utils::read_file;
+use color_eyre::eyre::eyre;
 
-pub fn get_config_folder() -> PathBuf {
-    directories::BaseDirs::new()
-        .unwrap()
-        .config_dir()
-        .join("yash")
+use crate::{
+    read_line::history::{History, RotateLimit},
+    YshResult,
+};
+
+/// Returns the folder yash keeps its files in, if the platform has one for this user.
+pub fn get_config_folder() -> Option<PathBuf> {
+    Some(directories::BaseDirs::new()?.config_dir().join("yash"))
+}
+
+/// A day of the calendar, for names of files that change with the date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Today, in the local time zone.
+    pub fn today() -> Self {
+        unsafe {
+            // SAFETY: `localtime_r` only writes to the `tm` it is given
+            let time = nix::libc::time(std::ptr::null_mut());
+            let mut tm = std::mem::MaybeUninit::<nix::libc::tm>::zeroed();
+            nix::libc::localtime_r(&time, tm.as_mut_ptr());
+            let tm = tm.assume_init();
+            Self {
+                year: tm.tm_year + 1900,
+                month: tm.tm_mon as u32 + 1,
+                day: tm.tm_mday as u32,
+            }
+        }
+    }
+}
+
+/// Replaces `%Y`, `%m` and `%d` in `text` with the year, month and day of `date`, and `%%`
+/// with `%`, like `strftime` does. Any other `%` is kept.
+pub fn expand_date(text: &str, date: Date) -> String {
+    let mut expanded = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.clone().next() {
+            Some('Y') => expanded.push_str(&format!("{:04}", date.year)),
+            Some('m') => expanded.push_str(&format!("{:02}", date.month)),
+            Some('d') => expanded.push_str(&format!("{:02}", date.day)),
+            Some('%') => expanded.push('%'),
+            _ => {
+                expanded.push('%');
+                continue;
+            }
+        }
+        chars.next();
+    }
+    expanded
+}
+
+/// Returns where history is saved, or `None` if it shouldn't be saved at all.
+///
+/// `$YASH_HISTFILE` takes precedence over the `HISTFILE` shell variable, and an empty value
+/// disables history persistence. Without either, the file lives in the config folder.
+/// Dates in the path are expanded, so `yhist-%Y.txt` starts a new file every year.
+pub fn get_history_file(histfile: Option<&str>) -> YshResult<Option<PathBuf>> {
+    let configured = std::env::var("YASH_HISTFILE").ok();
+    match configured.as_deref().or(histfile) {
+        Some("") => Ok(None),
+        Some(path) => Ok(Some(expand_date(path, Date::today()).into())),
+        None => match get_config_folder() {
+            Some(folder) => Ok(Some(folder.join("yhist.txt"))),
+            None => Err(eyre!("no config directory to save history in")),
+        },
+    }
+}
+
+pub fn get_history(path: &Path) -> std::io::Result<History> {
+    History::open(path.to_path_buf())
+}
+
+/// Splits the name of the history file into what goes before and after the date of its
+/// archives.
+fn archive_name_parts(path: &Path) -> (String, String) {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = match path.extension() {
+        Some(extension) => format!(".{}", extension.to_string_lossy()),
+        None => String::new(),
+    };
+    (format!("{}-", stem), extension)
+}
+
+/// Returns the archive that entries rotated out of the history file `path` during the month
+/// of `date` go to, like `yhist-2024-06.txt` next to `yhist.txt`.
+pub fn get_history_archive(path: &Path, date: Date) -> PathBuf {
+    let (start, end) = archive_name_parts(path);
+    path.with_file_name(format!(
+        "{}{:04}-{:02}{}",
+        start, date.year, date.month, end
+    ))
 }
 
-pub fn get_history_file() -> PathBuf {
-    get_config_folder().join("yhist.txt")
+/// Returns the archives of the history file `path`, the newest first.
+pub fn get_history_archives(path: &Path) -> Vec<PathBuf> {
+    let (start, end) = archive_name_parts(path);
+    let dir = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let is_month = |date: &str| {
+        date.len() == 7
+            && date.bytes().enumerate().all(|(i, b)| {
+                if i == 4 {
+                    b == b'-'
+                } else {
+                    b.is_ascii_digit()
+                }
+            })
+    };
+    let mut archives: Vec<_> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.strip_prefix(&start)
+                .and_then(|rest| rest.strip_suffix(&end))
+                .is_some_and(is_month)
+        })
+        .map(|entry| path.with_file_name(entry.file_name()))
+        .collect();
+    // The dates sort like the names do
+    archives.sort_by(|a, b| b.cmp(a));
+    archives
 }
 
-pub fn get_history() -> std::io::Result<Vec<String>> {
-    read_file(get_history_file())
+/// How many entries the history file may hold before it is rotated by default.
+pub const DEFAULT_HISTORY_ROTATE: RotateLimit = RotateLimit::Entries(10000);
+/// How many of the newest entries stay in the history file by default when it is rotated.
+pub const DEFAULT_HISTORY_KEEP: usize = 1000;
+
+/// When the history file is rotated and how many entries it keeps, from the `HISTROTATE`
+/// and `HISTKEEP` shell variables.
+///
+/// An empty `HISTROTATE` only rotates the file with `history --archive`.
+pub fn get_history_rotation(
+    rotate: Option<&str>,
+    keep: Option<&str>,
+) -> YshResult<(Option<RotateLimit>, usize)> {
+    let limit = match rotate {
+        None => Some(DEFAULT_HISTORY_ROTATE),
+        Some("") => None,
+        Some(text) => Some(
+            RotateLimit::parse(text).ok_or_else(|| eyre!("HISTROTATE: invalid size '{}'", text))?,
+        ),
+    };
+    let keep = match keep {
+        None => DEFAULT_HISTORY_KEEP,
+        Some(text) => text
+            .parse()
+            .map_err(|_| eyre!("HISTKEEP: invalid number '{}'", text))?,
+    };
+    Ok((limit, keep))
 }
 
-pub fn get_yashfile() -> std::io::Result<Vec<String>> {
-    read_file(get_config_folder().join("yashrc"))
+/// Sourced before the user's own rc files, by every user.
+const SYSTEM_YASHRC: &str = "/etc/yash/yashrc";
+
+/// Returns the `*.ysh` files in `dir`, sorted by name.
+fn get_fragments(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut fragments: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ysh") && path.is_file())
+        .collect();
+    fragments.sort();
+    fragments
+}
+
+/// Lists the existing rc files in the order they are sourced: the system-wide one, the
+/// user's `yashrc`, the fragments in `yashrc.d` and finally the saved aliases.
+fn rc_files_in(config_folder: Option<&Path>, system_rc: &Path) -> Vec<PathBuf> {
+    let mut files = vec![system_rc.to_path_buf()];
+    if let Some(folder) = config_folder {
+        files.push(folder.join("yashrc"));
+        files.extend(get_fragments(&folder.join("yashrc.d")));
+        files.push(folder.join("aliases.ysh"));
+    }
+    files.retain(|file| file.is_file());
+    files
+}
+
+pub fn get_rc_files() -> Vec<PathBuf> {
+    rc_files_in(get_config_folder().as_deref(), Path::new(SYSTEM_YASHRC))
+}
+
+/// Returns the file login shells source before the rc files, if there is one.
+///
+/// It is meant for what only needs to be set up once per login, like `$PATH`.
+pub fn get_profile_file() -> Option<PathBuf> {
+    Some(get_config_folder()?.join("profile.ysh")).filter(|file| file.is_file())
+}
+
+/// Returns the file login shells source when they exit, if there is one.
+pub fn get_logout_file() -> Option<PathBuf> {
+    Some(get_config_folder()?.join("logout.ysh")).filter(|file| file.is_file())
+}
+
+/// Returns the folder of the programs that add commands to the shell, see [`crate::plugins`].
+pub fn get_plugins_folder() -> Option<PathBuf> {
+    Some(get_config_folder()?.join("plugins"))
+}
+
+/// Returns where the `.yashenv` files allowed with `yashenv allow` are remembered.
+pub fn get_yashenv_allow_file() -> Option<PathBuf> {
+    Some(get_config_folder()?.join("yashenv.allow"))
+}
+
+pub fn get_aliases_file() -> Option<PathBuf> {
+    Some(get_config_folder()?.join("aliases.ysh"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn rc_files_order() {
+        let base = TempDir::new("rc");
+        let fragments = base.join("yashrc.d");
+        std::fs::create_dir_all(fragments.join("dir.ysh")).unwrap();
+        for file in [
+            "system",
+            "yashrc",
+            "yashrc.d/b.ysh",
+            "yashrc.d/a.ysh",
+            "yashrc.d/c.txt",
+        ] {
+            std::fs::write(base.join(file), "").unwrap();
+        }
+        let system = base.join("system");
+        assert_eq!(
+            rc_files_in(Some(&*base), &system),
+            [
+                system.clone(),
+                base.join("yashrc"),
+                fragments.join("a.ysh"),
+                fragments.join("b.ysh")
+            ]
+        );
+        assert_eq!(rc_files_in(None, &base.join("missing")), [] as [PathBuf; 0]);
+    }
+
+    /// Both cases live in one test since they change the same env var
+    #[test]
+    fn history_file_location() {
+        std::env::remove_var("YASH_HISTFILE");
+        assert_eq!(
+            get_history_file(Some("/tmp/hist")).unwrap(),
+            Some(PathBuf::from("/tmp/hist"))
+        );
+        assert_eq!(get_history_file(Some("")).unwrap(), None);
+
+        std::env::set_var("YASH_HISTFILE", "/tmp/env-hist");
+        assert_eq!(
+            get_history_file(Some("/tmp/hist")).unwrap(),
+            Some(PathBuf::from("/tmp/env-hist"))
+        );
+        std::env::set_var("YASH_HISTFILE", "");
+        assert_eq!(get_history_file(Some("/tmp/hist")).unwrap(), None);
+        std::env::remove_var("YASH_HISTFILE");
+    }
+
+    #[test]
+    fn dates_in_history_names() {
+        let date = Date {
+            year: 2024,
+            month: 6,
+            day: 9,
+        };
+        assert_eq!(
+            expand_date("yhist-%Y-%m-%d.txt", date),
+            "yhist-2024-06-09.txt"
+        );
+        assert_eq!(expand_date("100%%-%x-%", date), "100%-%x-%");
+        assert_eq!(
+            get_history_archive(Path::new("/tmp/yhist.txt"), date),
+            Path::new("/tmp/yhist-2024-06.txt")
+        );
+        assert_eq!(
+            get_history_archive(Path::new("hist"), date),
+            Path::new("hist-2024-06")
+        );
+    }
+
+    #[test]
+    fn history_archives_newest_first() {
+        let base = TempDir::new("archives");
+        for file in [
+            "yhist.txt",
+            "yhist-2023-12.txt",
+            "yhist-2024-06.txt",
+            "yhist-2024-6.txt",
+            "yhist-notes.txt",
+            "other-2024-01.txt",
+        ] {
+            std::fs::write(base.join(file), "").unwrap();
+        }
+        assert_eq!(
+            get_history_archives(&base.join("yhist.txt")),
+            [
+                base.join("yhist-2024-06.txt"),
+                base.join("yhist-2023-12.txt")
+            ]
+        );
+    }
+
+    #[test]
+    fn history_rotation_settings() {
+        assert_eq!(
+            get_history_rotation(None, None).unwrap(),
+            (Some(DEFAULT_HISTORY_ROTATE), DEFAULT_HISTORY_KEEP)
+        );
+        assert_eq!(
+            get_history_rotation(Some("1m"), Some("50")).unwrap(),
+            (Some(RotateLimit::Bytes(1024 * 1024)), 50)
+        );
+        assert_eq!(get_history_rotation(Some(""), None).unwrap().0, None);
+        assert_eq!(
+            get_history_rotation(Some("lots"), None)
+                .unwrap_err()
+                .to_string(),
+            "HISTROTATE: invalid size 'lots'"
+        );
+        assert!(get_history_rotation(None, Some("-1")).is_err());
+    }
 }
@@ -1,3 +1,9 @@
+//! Debug overlay, toggled with the `debug` builtin.
+//!
+//! Values passed to [`sdbg!`](crate::sdbg) are kept as lines in a bounded buffer, and
+//! the last few are drawn in the top right corner of the terminal after each command
+//! while the overlay is on. Release builds compile all of it out.
+
 #[cfg(debug_assertions)]
 mod debug;
 
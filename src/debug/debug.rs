@@ -1,7 +1,16 @@
 use std::collections::VecDeque;
-use bstr::B;
-use crate::widget::Size;
-use crate::{bytes_buf, write, read_line::cursor, utils::BytesBuf};
+
+use crate::{read_line::cursor, tty::Tty, utils::BytesBuf, widget::Size};
+
+pub const ENABLED: bool = true;
+
+/// How many lines are kept, older ones are dropped.
+const MAX_LINES: usize = 200;
+/// How many rows of the terminal the overlay takes.
+const OVERLAY_ROWS: usize = 5;
+
+const SAVE_CURSOR: &[u8] = b"\x1b7";
+const RESTORE_CURSOR: &[u8] = b"\x1b8";
 
 #[derive(Default, Debug)]
 struct DebugLines {
@@ -15,60 +24,67 @@ impl DebugLines {
         }
     }
     pub fn push(&mut self, line: String) {
-        if self.lines.len() > 5 {
+        if self.lines.len() == MAX_LINES {
             self.lines.pop_front();
         }
         self.lines.push_back(line);
     }
 
-    pub fn render(&self, term_size: Size) -> std::io::Result<()> {
-        if self.lines.len() == 0 {
-            return Ok(());
-        }
-        let current_pos = cursor::get_cursor_pos().unwrap();
-        let line_len = term_size.x as usize / 2;
-        let startx = term_size.x - line_len as u32;
-        let mut lines = BytesBuf::new();
-        for l in self.lines.iter() {
-            if l.len() > line_len {
-                let chars = l.chars().collect::<Vec<_>>();
-                lines.extend(
-                    chars
-                        .chunks(line_len)
-                        .map(|slice| slice.iter().collect::<String>())
-                        .map(|s| s.into_bytes()),
-                );
-            } else {
-                lines.push_slice(l.as_bytes());
+    /// Draws the last lines, wrapped to the right half of the terminal, over its top
+    /// `rows` rows. The cursor is left where it was.
+    pub fn render(&self, term_size: Size, rows: usize) -> Vec<u8> {
+        let line_len = (term_size.x as usize / 2).max(1);
+        let startx = term_size.x.saturating_sub(line_len as u32);
+        let wrapped = self
+            .lines
+            .iter()
+            .flat_map(|line| {
+                let chars = line.chars().collect::<Vec<_>>();
+                chars
+                    .chunks(line_len)
+                    .map(|slice| slice.iter().collect::<String>())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let shown = &wrapped[wrapped.len().saturating_sub(rows)..];
+        let mut buf = BytesBuf::of([SAVE_CURSOR]);
+        for row in 0..rows {
+            buf.push(cursor::set_positionv(Size::new(startx + 1, row as u32 + 1)));
+            if let Some(line) = shown.get(row) {
+                buf.push(line.as_bytes());
             }
+            buf.push(cursor::kill_line());
         }
-        let sep = [
-            B("\n\r"),
-            cursor::move_right(startx).as_slice(),
-            cursor::kill_line(),
-        ]
-        .concat();
-        let lines = lines.join(sep);
-        let buf = bytes_buf! {
-            cursor::set_position(startx as u8+1, 1),
-            cursor::kill_line(),
-            lines,
-            cursor::set_position(current_pos.x as u8, current_pos.y as u8)
-        };
-        write(&buf.join(B("")))?;
-        Ok(())
+        buf.push(RESTORE_CURSOR);
+        buf.join(b"")
     }
 }
 
+/// Lines collected by [`sdbg!`](crate::sdbg), whether the overlay is shown or not.
 static DEBUG_LINES: std::sync::Mutex<DebugLines> = std::sync::Mutex::new(DebugLines::new());
 
 pub fn push_debug_text<S: Into<String>>(line: S) {
     DEBUG_LINES.lock().unwrap().push(line.into());
 }
 
+pub fn line_count() -> usize {
+    DEBUG_LINES.lock().unwrap().lines.len()
+}
+
 pub fn render_debug_text() -> std::io::Result<()> {
-    let term_size = cursor::terminal_size()?;
-    DEBUG_LINES.lock().unwrap().render(term_size)
+    let tty = Tty::default();
+    let term_size = cursor::terminal_size(tty);
+    let buf = DEBUG_LINES.lock().unwrap().render(term_size, OVERLAY_ROWS);
+    tty.write(&buf)?;
+    Ok(())
+}
+
+/// Erases the overlay from the terminal.
+pub fn clear_debug_text() -> std::io::Result<()> {
+    let tty = Tty::default();
+    let term_size = cursor::terminal_size(tty);
+    tty.write(&DebugLines::new().render(term_size, OVERLAY_ROWS))?;
+    Ok(())
 }
 
 #[macro_export]
@@ -79,10 +95,42 @@ macro_rules! sdbg {
             "[{}:{}] {} = {:?}",
             file!(),
             line!(),
-            stringify!(),
+            stringify!($expr),
             expr
         ));
         expr
     }};
 }
 
+#[cfg(test)]
+mod tests {
+    use bstr::ByteSlice;
+
+    use super::*;
+
+    #[test]
+    fn only_recent_lines_are_kept() {
+        let mut lines = DebugLines::new();
+        for i in 0..MAX_LINES + 10 {
+            lines.push(i.to_string());
+        }
+        assert_eq!(lines.lines.len(), MAX_LINES);
+        assert_eq!(lines.lines[0], "10");
+    }
+
+    #[test]
+    fn render_keeps_the_cursor() {
+        let mut lines = DebugLines::new();
+        lines.push("old".into());
+        lines.push("0123456789".into());
+        lines.push("x".into());
+        let drawn = lines.render(Size::new(8, 24), 3);
+        assert_eq!(
+            drawn.to_str().unwrap(),
+            "\x1b7\x1b[1;5H4567\x1b[K\x1b[2;5H89\x1b[K\x1b[3;5Hx\x1b[K\x1b8"
+        );
+        // Rows without lines are blanked
+        let drawn = DebugLines::new().render(Size::new(8, 24), 1);
+        assert_eq!(drawn, b"\x1b7\x1b[1;5H\x1b[K\x1b8");
+    }
+}
@@ -1,9 +1,18 @@
+pub const ENABLED: bool = false;
+
 #[macro_export]
 macro_rules! sdbg {
-    ($expr:expr) => {$expr};
+    ($expr:expr) => {
+        $expr
+    };
+}
+pub fn push_debug_text<S: Into<String>>(_line: S) {}
+pub fn line_count() -> usize {
+    0
 }
-pub fn push_debug_text<S: Into<String>>(line: S) {}
 pub fn render_debug_text() -> std::io::Result<()> {
     Ok(())
 }
-
+pub fn clear_debug_text() -> std::io::Result<()> {
+    Ok(())
+}
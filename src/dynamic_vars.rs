@@ -0,0 +1,56 @@
+//! Variables the shell computes each time they are expanded, like `$RANDOM` and `$SECONDS`.
+
+use std::{
+    cell::Cell,
+    time::{Instant, SystemTime},
+};
+
+/// What the dynamic variables are computed from.
+#[derive(Debug)]
+pub struct DynamicVars {
+    /// When the shell started, which `$SECONDS` counts from.
+    pub start: Instant,
+    /// Line of the file being sourced that is running, for `$LINENO`.
+    pub line: Option<usize>,
+    /// State of the generator behind `$RANDOM`.
+    random: Cell<u64>,
+}
+
+impl Default for DynamicVars {
+    fn default() -> Self {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        // Never 0, which the generator would be stuck at
+        let seed = (time.as_nanos() as u64 ^ u64::from(std::process::id())) | 1;
+        Self {
+            start: Instant::now(),
+            line: None,
+            random: Cell::new(seed),
+        }
+    }
+}
+
+impl DynamicVars {
+    /// The value of the dynamic variable `name`, or `None` if there is none with that name.
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "$" => Some(std::process::id().to_string()),
+            "PPID" => Some(nix::unistd::getppid().to_string()),
+            "RANDOM" => Some(self.next_random().to_string()),
+            "SECONDS" => Some(self.start.elapsed().as_secs().to_string()),
+            "LINENO" => self.line.map(|line| line.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Steps the xorshift64* generator, a new number on every expansion.
+    fn next_random(&self) -> u16 {
+        let mut x = self.random.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.random.set(x);
+        (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 48) as u16
+    }
+}
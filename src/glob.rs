@@ -0,0 +1,147 @@
+//! Filename expansion of `*`, `?` and `[...]` patterns.
+
+use std::path::Path;
+
+/// Returns true if `word` contains any glob metacharacter.
+pub fn has_magic(word: &str) -> bool {
+    word.contains(['*', '?', '['])
+}
+
+/// Matches a single path component against `pattern`.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    matches_chars(&pattern, &name)
+}
+
+fn matches_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches_chars(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches_chars(&pattern[1..], &name[1..]),
+        Some('[') if let Some((matched, rest)) = match_class(&pattern[1..], name.first()) => {
+            matched && matches_chars(rest, &name[1..])
+        }
+        Some(&c) => name.first() == Some(&c) && matches_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Matches `c` against the class following a `[`, returning whether it matched
+/// and the pattern after the closing `]`. Returns `None` if the class is unterminated.
+fn match_class<'a>(class: &'a [char], c: Option<&char>) -> Option<(bool, &'a [char])> {
+    let (negated, class) = match class.first() {
+        Some('!' | '^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    // A `]` right at the start is part of the class
+    let end = class.iter().skip(1).position(|&c| c == ']')? + 1;
+    let (set, rest) = (&class[..end], &class[end + 1..]);
+    let Some(&c) = c else {
+        return Some((false, rest));
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            found |= (set[i]..=set[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= set[i] == c;
+            i += 1;
+        }
+    }
+    Some((found != negated, rest))
+}
+
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        format!("{}{}", prefix, name)
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+/// Expands `pattern` into the sorted list of matching paths.
+///
+/// Like other shells, a pattern matching nothing expands to itself.
+pub fn expand(pattern: &str) -> Vec<String> {
+    if !has_magic(pattern) {
+        return vec![pattern.to_string()];
+    }
+    let (mut paths, components) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec![String::from("/")], rest),
+        None => (vec![String::new()], pattern),
+    };
+    for component in components.split('/') {
+        if !has_magic(component) {
+            for path in paths.iter_mut() {
+                *path = join(path, component);
+            }
+            continue;
+        }
+        paths = paths
+            .into_iter()
+            .flat_map(|prefix| {
+                let dir = if prefix.is_empty() { "." } else { &prefix };
+                let mut names = std::fs::read_dir(dir)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    // Hidden files only match patterns starting with a dot
+                    .filter(|name| !name.starts_with('.') || component.starts_with('.'))
+                    .filter(|name| matches(component, name))
+                    .collect::<Vec<_>>();
+                names.sort();
+                names.into_iter().map(move |name| join(&prefix, &name))
+            })
+            .collect();
+    }
+    paths.retain(|path| Path::new(path).symlink_metadata().is_ok());
+    if paths.is_empty() {
+        return vec![pattern.to_string()];
+    }
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn match_patterns() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(!matches("*.txt", "notes.md"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[abc", "[abc"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn expand_in_directory() {
+        let dir = TempDir::new("glob");
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        for name in ["b.txt", "a.txt", "c.md", ".hidden.txt", "sub/d.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let dir = dir.display();
+        assert_eq!(
+            expand(&format!("{}/*.txt", dir)),
+            [format!("{}/a.txt", dir), format!("{}/b.txt", dir)]
+        );
+        assert_eq!(
+            expand(&format!("{}/s*/*", dir)),
+            [format!("{}/sub/d.txt", dir)]
+        );
+        assert_eq!(
+            expand(&format!("{}/*.none", dir)),
+            [format!("{}/*.none", dir)]
+        );
+    }
+}
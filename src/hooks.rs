@@ -0,0 +1,102 @@
+//! Commands run around each line typed at the prompt, like the hooks of zsh.
+//!
+//! The commands in `$PRECMD` and `$PREEXEC` run first, then those added with `hook add`,
+//! in the order they were added.
+
+use color_eyre::eyre::eyre;
+
+use crate::{shell_println, Shell, YshResult};
+
+/// Where a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// Before each prompt is drawn
+    Precmd,
+    /// After a line is accepted, before it runs, with the line in `$YASH_COMMAND`
+    Preexec,
+}
+
+/// The variable the line about to run is in, for [`Hook::Preexec`].
+pub const COMMAND_VAR: &str = "YASH_COMMAND";
+
+impl Hook {
+    pub const ALL: [Self; 2] = [Self::Precmd, Self::Preexec];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Precmd => "precmd",
+            Self::Preexec => "preexec",
+        }
+    }
+
+    pub fn from_name(name: &str) -> YshResult<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|hook| hook.name() == name)
+            .ok_or_else(|| eyre!("unknown hook '{}', expected precmd or preexec", name))
+    }
+
+    /// The shell variable whose commands run before the added ones.
+    pub fn variable(self) -> &'static str {
+        match self {
+            Self::Precmd => "PRECMD",
+            Self::Preexec => "PREEXEC",
+        }
+    }
+}
+
+/// The commands added with `hook add`.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    precmd: Vec<String>,
+    preexec: Vec<String>,
+}
+
+impl Hooks {
+    pub fn get(&self, hook: Hook) -> &[String] {
+        match hook {
+            Hook::Precmd => &self.precmd,
+            Hook::Preexec => &self.preexec,
+        }
+    }
+
+    fn get_mut(&mut self, hook: Hook) -> &mut Vec<String> {
+        match hook {
+            Hook::Precmd => &mut self.precmd,
+            Hook::Preexec => &mut self.preexec,
+        }
+    }
+
+    pub fn add(&mut self, hook: Hook, commands: String) {
+        self.get_mut(hook).push(commands);
+    }
+
+    pub fn clear(&mut self, hook: Hook) {
+        self.get_mut(hook).clear();
+    }
+}
+
+/// Runs the commands of `hook`, for the typed `line` if it is about to run.
+///
+/// Failures are only told about, so a broken hook can't keep the prompt from showing or
+/// the line from running, and the status of the last command is left for the prompt.
+pub fn run(shell: &mut Shell, hook: Hook, line: Option<&str>) -> YshResult<()> {
+    let variable = shell.get_var(hook.variable()).map(str::to_string);
+    let commands = variable.into_iter().chain(shell.hooks.get(hook).to_vec());
+    let commands = commands.filter(|commands| !commands.trim().is_empty());
+    let commands = commands.collect::<Vec<_>>();
+    if commands.is_empty() {
+        return Ok(());
+    }
+    if let Some(line) = line {
+        shell.set_var(COMMAND_VAR.to_string(), line.to_string());
+    }
+    let status = shell.last_status();
+    for commands in commands {
+        if let Err(e) = shell.execute_line(&commands) {
+            shell_println!("yash: {} hook: {}", hook.name(), e);
+        }
+    }
+    shell.set_status(status);
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! Programs left running in the background, which the shell hangs up on when it exits.
+
+use color_eyre::eyre::eyre;
+use nix::{
+    sys::{
+        signal::{kill, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::Pid,
+};
+
+use crate::YshResult;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    /// The number after `%` that names the job.
+    pub id: usize,
+    pub pid: Pid,
+    /// The command line that started it.
+    pub command: String,
+}
+
+/// The jobs table, in the order the jobs were started.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    /// Adds a job for the process `pid`, returning its id.
+    pub fn add(&mut self, pid: Pid, command: String) -> usize {
+        let id = self.jobs.last().map_or(1, |job| job.id + 1);
+        self.jobs.push(Job { id, pid, command });
+        id
+    }
+
+    /// Forgets the jobs that ended, reaping them.
+    pub fn refresh(&mut self) {
+        self.jobs.retain(|job| {
+            matches!(
+                waitpid(job.pid, Some(WaitPidFlag::WNOHANG)),
+                Ok(WaitStatus::StillAlive | WaitStatus::Stopped(..) | WaitStatus::Continued(_))
+            )
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Removes the job `spec` names, `%N` or the last one started without it.
+    pub fn disown(&mut self, spec: Option<&str>) -> YshResult<Job> {
+        let index = match spec {
+            None => self
+                .jobs
+                .len()
+                .checked_sub(1)
+                .ok_or_else(|| eyre!("no current job"))?,
+            Some(spec) => spec
+                .strip_prefix('%')
+                .and_then(|id| id.parse().ok())
+                .and_then(|id: usize| self.jobs.iter().position(|job| job.id == id))
+                .ok_or_else(|| eyre!("{}: no such job", spec))?,
+        };
+        Ok(self.jobs.remove(index))
+    }
+
+    /// Sends `SIGHUP` to every job, like the terminal closing would.
+    pub fn hang_up(&mut self) {
+        for job in self.jobs.drain(..) {
+            // It may have ended in the meantime
+            let _ = kill(job.pid, Signal::SIGHUP);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn ended_jobs_are_forgotten() {
+        let mut jobs = Jobs::default();
+        // Already reaped, like by something waiting for it
+        let mut done = std::process::Command::new("true").spawn().unwrap();
+        done.wait().unwrap();
+        let mut running = std::process::Command::new("sleep")
+            .arg("5")
+            .spawn()
+            .unwrap();
+        jobs.add(Pid::from_raw(done.id() as i32), "true".into());
+        jobs.add(Pid::from_raw(running.id() as i32), "sleep 5".into());
+        jobs.refresh();
+        assert_eq!(jobs.len(), 1);
+
+        jobs.hang_up();
+        assert_eq!(jobs.len(), 0);
+        let status = running.wait().unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGHUP as i32));
+    }
+
+    #[test]
+    fn jobs_are_disowned_by_id() {
+        let mut jobs = Jobs::default();
+        assert_eq!(jobs.disown(None).unwrap_err().to_string(), "no current job");
+        let first = jobs.add(Pid::from_raw(1001), "first".into());
+        jobs.add(Pid::from_raw(1002), "second".into());
+        assert_eq!(jobs.disown(None).unwrap().command, "second");
+        let third = jobs.add(Pid::from_raw(1003), "third".into());
+        assert_eq!(third, 2);
+        assert_eq!(jobs.disown(Some("%1")).unwrap().id, first);
+        assert_eq!(
+            jobs.disown(Some("%5")).unwrap_err().to_string(),
+            "%5: no such job"
+        );
+        assert_eq!(jobs.len(), 1);
+    }
+}
@@ -0,0 +1,1843 @@
+//! The yash shell as a library: [`Shell`] runs command lines, and what they print can be
+//! captured with [`output::Redirect`] or [`Shell::execute_line_capture`], without a
+//! terminal.
+#![feature(trait_alias)]
+#![feature(variant_count)]
+#![feature(if_let_guard)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::BufRead,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, WrapErr};
+
+pub type Vec2 = glam::u32::UVec2;
+
+mod widget;
+pub mod command;
+mod config;
+mod dynamic_vars;
+pub mod glob;
+mod hooks;
+mod jobs;
+pub mod output;
+pub mod plugins;
+mod prompt;
+mod read_line;
+mod scopes;
+mod signals;
+mod strings;
+pub mod term_state;
+mod tty;
+mod ulimit;
+mod umask;
+mod utils;
+mod yashenv;
+
+mod debug;
+#[cfg(test)]
+mod testing;
+
+pub use command::Command;
+use command::SpecialAction;
+
+pub type YshResult<T> = color_eyre::Result<T>;
+
+/// How many files can be in the process of being sourced at once.
+pub const MAX_SOURCE_DEPTH: usize = 32;
+
+/// Prints to the current [`output`] sink, returning from the enclosing function if that
+/// fails, like when stdout is a closed pipe.
+#[macro_export]
+macro_rules! shell_print {
+    ($fmt:expr $(, $expr:expr)* $(,)?) => {{
+        $crate::output::print_fmt(format_args!($fmt, $($expr),*))?;
+    }};
+}
+
+#[macro_export]
+macro_rules! shell_println {
+    () => {
+        $crate::shell_print!("\n")
+    };
+    ($fmt:expr $(, $expr:expr)* $(,)?) => {
+        $crate::shell_print!(concat!($fmt, "\n") $(, $expr)*)
+    };
+}
+
+/// Writes out what [`shell_print!`] buffered for the terminal.
+fn flush_output() -> nix::Result<()> {
+    output::flush().map_err(|e| {
+        e.raw_os_error()
+            .map_or(nix::Error::EIO, nix::Error::from_i32)
+    })
+}
+
+/// Writes raw bytes, like escape sequences, to the terminal after any buffered output.
+pub fn write(bytes: &[u8]) -> nix::Result<()> {
+    tty::Tty::default().write(bytes)
+}
+
+fn write_unbuffered(bytes: &[u8]) -> nix::Result<()> {
+    tty::Tty::default().write_unbuffered(bytes)
+}
+
+pub use tty::{unread, Io};
+
+fn read(buf: &mut [u8]) -> Result<usize, nix::Error> {
+    tty::Tty::default().read(buf)
+}
+
+pub mod builtins;
+
+#[derive(Debug, Default)]
+pub struct Shell {
+    exit_code: Option<i32>,
+    /// Working directory as the user typed it, with symlinks unresolved.
+    cwd: PathBuf,
+    /// Canonical working directory.
+    physical_cwd: PathBuf,
+    /// Whether the working directory was found deleted before the last prompt.
+    cwd_deleted: bool,
+    /// Logical working directory before the last `cd`.
+    prev_cwd: Option<PathBuf>,
+    /// Background jobs that exiting the shell hangs up on.
+    jobs: jobs::Jobs,
+    /// Whether the last command tried to exit and was told about the running jobs.
+    exit_warned: bool,
+    /// Logical directories saved with `pushd`, the most recent last.
+    dir_stack: Vec<PathBuf>,
+    git_status: prompt::git::GitStatus,
+    prompt_cache: prompt::PromptCache,
+    term_state: term_state::TermState,
+    read_line: read_line::ReadLine,
+    vars: scopes::Scopes,
+    dynamic_vars: dynamic_vars::DynamicVars,
+    /// Native builtins and aliases, by name.
+    builtins: std::collections::BTreeMap<String, builtins::Builtin>,
+    /// Names of the aliases currently being expanded, outermost first.
+    alias_chain: Vec<String>,
+    /// How many `eval`s are running inside one another.
+    eval_depth: usize,
+    signals: signals::Signals,
+    /// `NAME=VALUE` words before the command about to run, set only for it.
+    oneshot_vars: Vec<(String, String)>,
+    last_status: i32,
+    /// Canonical paths of the files currently being sourced, outermost first.
+    sourcing: Vec<PathBuf>,
+    /// Whether the startup files are being sourced, which tags the aliases they define.
+    loading_config: bool,
+    path_cache: command::path_cache::PathCache,
+    /// Programs pinned to command names with `hash -p`, run instead of searching `$PATH`.
+    hashed: HashMap<String, PathBuf>,
+    /// How long the last command line typed at the prompt took to run.
+    last_duration: Option<Duration>,
+    /// Whether the debug overlay is drawn after each command.
+    debug_overlay: bool,
+    /// Commands set with `trap`, by condition. Only `EXIT` is supported for now.
+    traps: HashMap<String, String>,
+    /// Commands added with `hook add`, run around the lines typed at the prompt.
+    hooks: hooks::Hooks,
+    /// Options enabled with `set -o`, like `noclobber`.
+    options: HashSet<String>,
+    /// Whether [`Shell::shutdown`] already ran.
+    shut_down: bool,
+    /// Whether this is a login shell, which sources `logout.ysh` when it exits.
+    login: bool,
+    /// Whether commands are read from the terminal, where `errexit` only stops scripts.
+    interactive: bool,
+    /// How many `if` conditions are running, whose failures `errexit` ignores.
+    conditions: usize,
+    /// The `.yashenv` files sourced for the working directory.
+    env_files: yashenv::EnvFiles,
+    /// The yash running executable files without `#!`, which are then scripts.
+    shell_path: Option<PathBuf>,
+}
+
+/// Outcome of sourcing a script with [`Shell::source_file`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SourceSummary {
+    pub lines: usize,
+    pub failed: usize,
+}
+
+/// `$SHLVL` for a shell started with `inherited` in the environment, one level deeper.
+fn next_shell_level(inherited: Option<&str>) -> u32 {
+    match inherited.and_then(|level| level.trim().parse::<u32>().ok()) {
+        Some(level) => level.saturating_add(1),
+        None => 1,
+    }
+}
+
+/// Tells the programs run from the shell which shell it is and how deep it is nested, in
+/// `$SHELL` and `$SHLVL`.
+fn export_shell_vars() {
+    if let Ok(exe) = std::env::current_exe() {
+        std::env::set_var("SHELL", exe);
+    }
+    let level = next_shell_level(std::env::var("SHLVL").ok().as_deref());
+    std::env::set_var("SHLVL", level.to_string());
+}
+
+/// Returns the logical and canonical working directories the shell starts in.
+///
+/// `$PWD` is kept as the logical one if it really refers to the current directory.
+fn initial_cwd() -> std::io::Result<(PathBuf, PathBuf)> {
+    let physical_cwd = std::env::current_dir()?.canonicalize()?;
+    let cwd = std::env::var_os("PWD")
+        .map(PathBuf::from)
+        .filter(|pwd| pwd.is_absolute() && pwd.canonicalize().ok().as_ref() == Some(&physical_cwd))
+        .map(|pwd| utils::normalize_path(&pwd))
+        .unwrap_or_else(|| physical_cwd.clone());
+    Ok((cwd, physical_cwd))
+}
+
+/// Output of [`Shell::execute_line_capture`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CapturedOutput {
+    pub stdout: Vec<u8>,
+    pub status: i32,
+}
+
+impl Shell {
+    pub fn init(term_state: term_state::TermState) -> YshResult<Self> {
+        let mut this = Self {
+            term_state,
+            builtins: builtins::native_builtins(),
+            signals: signals::Signals::init(),
+            env_files: yashenv::EnvFiles::new(config::get_yashenv_allow_file()),
+            options: HashSet::from(["paste-confirm".to_string(), "partial-line".to_string()]),
+            ..Default::default()
+        };
+        match initial_cwd() {
+            Ok((cwd, physical_cwd)) => {
+                this.cwd = cwd;
+                this.physical_cwd = physical_cwd;
+                this.export_cwd();
+            }
+            Err(e) => this.leave_unusable_cwd(e)?,
+        }
+        this.term_state.put_new()?;
+        Ok(this)
+    }
+    /// Starts in `$HOME`, or `/` without one, when the current directory can't be used.
+    fn leave_unusable_cwd(&mut self, e: std::io::Error) -> YshResult<()> {
+        let fallbacks = builtins::get_home().map(PathBuf::from).into_iter();
+        for dir in fallbacks.chain([PathBuf::from("/")]) {
+            let Ok(physical_cwd) = dir.canonicalize() else {
+                continue;
+            };
+            if std::env::set_current_dir(&physical_cwd).is_ok() {
+                shell_println!(
+                    "yash: can't use the current directory ({}), starting in {}",
+                    e,
+                    dir.display()
+                );
+                self.cwd = utils::normalize_path(&dir);
+                self.physical_cwd = physical_cwd;
+                self.export_cwd();
+                return Ok(());
+            }
+        }
+        shell_println!("Failed to get the current directory: {}", e);
+        Ok(())
+    }
+    /// Rescans `$PATH` for programs.
+    pub fn rehash(&mut self) {
+        self.path_cache.rehash();
+    }
+    /// Reads keys from and writes to `io` instead of the standard input and output.
+    /// Programs still inherit these, unless run with [`Shell::execute_line_capture`].
+    pub fn set_io(&mut self, io: impl Io + 'static) {
+        tty::set_io(Some(std::rc::Rc::new(io)));
+    }
+    /// Runs executable files without `#!` as scripts with the yash at `path`.
+    /// Without one, running them fails like in programs embedding the shell.
+    pub fn set_shell_path(&mut self, path: PathBuf) {
+        self.shell_path = Some(path);
+    }
+    pub fn register_builtin(&mut self, builtin: builtins::Builtin) {
+        self.builtins.insert(builtin.name.to_string(), builtin);
+    }
+    /// Makes the plugins in `folder` commands, unless a builtin has the name already.
+    pub fn register_plugins(&mut self, folder: &Path) -> YshResult<()> {
+        for (name, path) in plugins::find(folder) {
+            if self.builtins.contains_key(&name) {
+                shell_println!(
+                    "yash: {}: {} is a builtin already, the plugin is left out",
+                    path.display(),
+                    name
+                );
+                continue;
+            }
+            self.register_builtin(builtins::Builtin::new_plugin(name, path));
+        }
+        Ok(())
+    }
+
+    /// Changes the working directory, resolving `..` lexically from the logical one.
+    ///
+    /// A bare `..` goes up to the closest directory that still exists, in case the working
+    /// directory and its parents were deleted. The `.yashenv` files of the directories
+    /// left are undone and those entered sourced.
+    pub fn change_directory(&mut self, path: impl AsRef<Path>) -> YshResult<()> {
+        let path = path.as_ref();
+        let mut cwd = utils::normalize_path(&self.cwd.join(path));
+        if path == Path::new("..") {
+            while !cwd.is_dir() && cwd.pop() {}
+        }
+        let physical_cwd = cwd.canonicalize()?;
+        std::env::set_current_dir(&physical_cwd)?;
+        let previous = std::mem::replace(&mut self.cwd, cwd);
+        self.prev_cwd = Some(previous.clone());
+        self.physical_cwd = physical_cwd;
+        self.export_cwd();
+        self.update_env_files(Some(&previous))
+    }
+    /// Notes for the prompt whether another program deleted the working directory.
+    ///
+    /// Checked once per prompt rather than every time it is drawn.
+    fn check_cwd(&mut self) {
+        self.cwd_deleted = std::fs::metadata(&self.physical_cwd).is_err();
+    }
+    fn export_cwd(&self) {
+        std::env::set_var("CWD", &self.cwd);
+        std::env::set_var("PWD", &self.cwd);
+        if let Some(prev_cwd) = &self.prev_cwd {
+            std::env::set_var("OLDPWD", prev_cwd);
+        }
+    }
+    pub fn cwd(&self) -> &Path {
+        &self.cwd
+    }
+    pub fn physical_cwd(&self) -> &Path {
+        &self.physical_cwd
+    }
+    pub fn prev_cwd(&self) -> Option<&Path> {
+        self.prev_cwd.as_deref()
+    }
+    /// The working directory followed by the directory stack, the most recent first.
+    pub fn dirs(&self) -> Vec<&Path> {
+        let stack = self.dir_stack.iter().rev().map(PathBuf::as_path);
+        std::iter::once(self.cwd.as_path()).chain(stack).collect()
+    }
+    /// Goes to `path`, saving the working directory on the directory stack.
+    pub fn push_directory(&mut self, path: impl AsRef<Path>) -> YshResult<()> {
+        let previous = self.cwd.clone();
+        self.change_directory(path)?;
+        self.dir_stack.push(previous);
+        Ok(())
+    }
+    /// Goes back to the most recent directory of the stack, and drops it.
+    pub fn pop_directory(&mut self) -> YshResult<()> {
+        let dir = self
+            .dir_stack
+            .last()
+            .cloned()
+            .ok_or_else(|| eyre!("directory stack empty"))?;
+        self.change_directory(dir)?;
+        self.dir_stack.pop();
+        Ok(())
+    }
+    /// Swaps the working directory with the most recent one of the stack.
+    pub fn swap_directories(&mut self) -> YshResult<()> {
+        let dir = self
+            .dir_stack
+            .pop()
+            .ok_or_else(|| eyre!("no other directory"))?;
+        if let Err(e) = self.push_directory(&dir) {
+            self.dir_stack.push(dir);
+            return Err(e);
+        }
+        Ok(())
+    }
+    /// Goes to the `n`th entry of [`Self::dirs`], rotating those before it to the bottom.
+    pub fn rotate_directories(&mut self, n: usize) -> YshResult<()> {
+        let mut dirs: Vec<_> = self.dirs().into_iter().map(Path::to_owned).collect();
+        if n >= dirs.len() {
+            return Err(eyre!("{}: directory stack index out of range", n));
+        }
+        dirs.rotate_left(n);
+        self.change_directory(&dirs[0])?;
+        self.dir_stack = dirs.split_off(1);
+        self.dir_stack.reverse();
+        Ok(())
+    }
+
+    /// Runs `cmd`, looking its name up in order: aliases, builtins and plugins, then
+    /// programs pinned with `hash -p` and at last `$PATH`.
+    pub fn execute(&mut self, cmd: Command) -> YshResult<()> {
+        // An alias replaces the builtin with its name, so one lookup finds either
+        let action = self.builtins.get(&cmd.command).map(|b| b.action.clone());
+        // Aliases are traced once expanded, as what they run
+        if !matches!(action, Some(builtins::Action::Alias { .. })) {
+            self.trace(&cmd);
+        }
+        // Exiting goes through only if tried again right away
+        if cmd.command != "exit" {
+            self.exit_warned = false;
+        }
+        match action {
+            Some(action) => {
+                self.last_status = 0;
+                let result = if matches!(action, builtins::Action::Plugin { .. }) {
+                    // Plugins are programs, which get the assignments in their environment
+                    self.call_builtin(action, cmd)
+                } else if cmd.command == "export" {
+                    let vars = std::mem::take(&mut self.oneshot_vars);
+                    // Like POSIX special builtins, `export` keeps the assignments
+                    for (name, value) in vars {
+                        self.set_var(name, value);
+                    }
+                    self.call_builtin(action, cmd)
+                } else {
+                    let vars = std::mem::take(&mut self.oneshot_vars);
+                    ScopedVars::new(self, vars).call_builtin(action, cmd)
+                };
+                output::flush()?;
+                if let Err(e) = result {
+                    self.last_status = 1;
+                    return Err(e);
+                }
+            }
+            None => self.execute_program(cmd)?,
+        }
+        Ok(())
+    }
+
+    /// With `set -o xtrace`, prints every command of the pipeline `cmd` quoted on its own
+    /// line after a `+` per sourced file, on the terminal wherever their output goes.
+    fn trace(&self, cmd: &Command) {
+        if !self.options.contains("xtrace") {
+            return;
+        }
+        let prefix = "+".repeat(self.sourcing.len().max(1));
+        let mut vars = self
+            .oneshot_vars
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, utils::quote_word(value)))
+            .collect::<Vec<_>>();
+        let mut text = String::new();
+        let mut stage = Some(cmd);
+        while let Some(cmd) = stage {
+            let words = std::iter::once(&cmd.command).chain(&cmd.args);
+            // One-shot variables are only written before the first command
+            let words = std::mem::take(&mut vars)
+                .into_iter()
+                .chain(words.map(|word| utils::quote_word(word).into_owned()));
+            let words = words.collect::<Vec<_>>().join(" ");
+            text.push_str(&format!("{} {}\n", prefix, words));
+            stage = match &cmd.special_action {
+                Some(SpecialAction::Pipe { next_command }) => Some(next_command),
+                _ => None,
+            };
+        }
+        let _ = output::print_trace(&text);
+    }
+
+    /// Calls a builtin, sending its output wherever `cmd` redirects or pipes it.
+    fn call_builtin(&mut self, action: builtins::Action, mut cmd: Command) -> YshResult<()> {
+        // Aliases pass the redirection on to whatever they expand to, and `time` to
+        // what it times.
+        if matches!(action, builtins::Action::Alias { .. }) || cmd.command == "time" {
+            return action.call(self, cmd);
+        }
+        match cmd.special_action.take() {
+            None => action.call(self, cmd),
+            Some(SpecialAction::Redir { to, append, force }) => {
+                let file = command::open_redirect(&to, append, force, self.exec_options())
+                    .wrap_err_with(|| format!("Failed to open file '{}'", to))?;
+                let _redirect = output::Redirect::to_file(file);
+                action.call(self, cmd)
+            }
+            Some(SpecialAction::Pipe { next_command }) => {
+                let redirect = output::Redirect::to_buffer();
+                let result = action.call(self, cmd);
+                let output = redirect.finish();
+                self.run_pipeline(*next_command, Some(output))?;
+                result
+            }
+        }
+    }
+
+    /// Makes the shell exit with `code` once the command line is done.
+    ///
+    /// Like bash, the first try with jobs still running only tells about them.
+    pub fn exit(&mut self, code: i32) -> YshResult<()> {
+        self.jobs.refresh();
+        let running = self.jobs.len();
+        if running > 0 && !std::mem::replace(&mut self.exit_warned, true) {
+            match running {
+                1 => shell_println!("There is 1 running job."),
+                _ => shell_println!("There are {} running jobs.", running),
+            }
+            self.set_status(1);
+            return Ok(());
+        }
+        self.exit_code = Some(code);
+        Ok(())
+    }
+
+    /// Adds the process `pid`, started in the background by `command`, to the jobs table.
+    /// Returns the job's id, which `%` names it with.
+    pub fn add_job(&mut self, pid: u32, command: String) -> usize {
+        let pid = nix::unistd::Pid::from_raw(pid as i32);
+        self.jobs.add(pid, command)
+    }
+
+    /// Sets the status of the last executed command.
+    pub fn set_status(&mut self, status: i32) {
+        self.last_status = status;
+    }
+    pub fn last_status(&self) -> i32 {
+        self.last_status
+    }
+
+    pub fn last_duration(&self) -> Option<Duration> {
+        self.last_duration
+    }
+
+    /// Remembers how long a command line took, telling the user if it was longer than
+    /// `$REPORTTIME` seconds.
+    fn report_duration(&mut self, duration: Duration) -> YshResult<()> {
+        self.last_duration = Some(duration);
+        let threshold = self
+            .get_var("REPORTTIME")
+            .and_then(|secs| secs.parse().ok())
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+        if threshold.is_some_and(|threshold| duration >= threshold) {
+            shell_println!("took {}", utils::format_duration(duration));
+        }
+        Ok(())
+    }
+
+    pub fn get_prompt(&self) -> String {
+        prompt::get_prompt(self)
+    }
+
+    /// Renders the next prompt again even if nothing it is made of seems to have changed,
+    /// for builtins changing what the prompt shows some other way.
+    pub fn invalidate_prompt(&self) {
+        self.prompt_cache.invalidate();
+    }
+
+    /// Whether the window title follows the working directory and the running command,
+    /// which `YASH_SET_TITLE=0` turns off. Never for what is captured or sourced.
+    fn sets_title(&self) -> bool {
+        let tty = tty::Tty::default().is_terminal();
+        let dumb = self.get_var_or_env("TERM").as_deref() == Some("dumb");
+        self.interactive
+            && self.sourcing.is_empty()
+            && output::is_terminal()
+            && tty
+            && !dumb
+            && self.get_var_or_env("YASH_SET_TITLE").as_deref() != Some("0")
+    }
+
+    /// The window title while at the prompt.
+    fn prompt_title(&self) -> String {
+        format!("yash: {}", utils::tilde_path(&self.cwd))
+    }
+
+    pub fn set_var(&mut self, name: String, value: String) {
+        self.vars.set(name, value);
+    }
+    pub fn get_var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name)
+    }
+
+    pub fn get_var_or_env(&self, name: &str) -> Option<String> {
+        self.dynamic_vars
+            .get(name)
+            .or_else(|| self.vars.get(name).map(str::to_owned))
+            .or_else(|| std::env::var(name).ok())
+    }
+
+    /// Takes the leading `NAME=VALUE` words off `cmd`.
+    ///
+    /// Without a command after them they are shell variables, otherwise they are only
+    /// set for that command. A word whose name has other characters than letters, digits
+    /// and `_`, like `./x=1`, is the command instead.
+    fn try_command_or_var(&mut self, mut cmd: Command) -> YshResult<Option<Command>> {
+        let mut assignments = vec![];
+        while let Some((name, value)) = cmd.command.split_once('=') {
+            if !builtins::is_valid_name(name) {
+                // Like `=x` or `1A=x`, meant as a variable that could never be expanded
+                if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    return Err(eyre!("'{}' is not a valid variable name", name));
+                }
+                break;
+            }
+            assignments.push((name.to_string(), value.to_string()));
+            if cmd.args.is_empty() {
+                for (name, value) in assignments {
+                    self.set_var(name, value);
+                }
+                return Ok(None);
+            }
+            cmd.command = cmd.args.remove(0);
+        }
+        self.oneshot_vars = assignments;
+        Ok(Some(cmd))
+    }
+
+    /// Executes every `;` separated statement of `line`, even if some of them fail,
+    /// unless [`Shell::errexit_stops`] says otherwise.
+    ///
+    /// All but the last failure are printed, the last one is returned.
+    pub fn execute_line(&mut self, line: &str) -> YshResult<()> {
+        // Whatever failed before using its one-shot variables can't leak them here
+        self.oneshot_vars.clear();
+        let line = strings::strip_comment(line);
+        let mut error = None;
+        for statement in command::parser::split_statements(line)? {
+            let result = self.execute_statement(statement);
+            let stop = self.errexit_stops(&result);
+            if let Err(e) = result {
+                if let Some(previous) = error.replace(e) {
+                    shell_println!("{}", previous);
+                }
+            }
+            if stop {
+                break;
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Whether `set -o errexit` stops the script after a command that ended with `result`.
+    ///
+    /// Only scripts stop: sourced files and `-c` lines, not what is typed at the prompt.
+    /// Failures in `if` conditions don't count.
+    fn errexit_stops(&self, result: &YshResult<()>) -> bool {
+        let failed = result.is_err() || self.last_status != 0;
+        let in_script = !self.interactive || !self.sourcing.is_empty();
+        failed && in_script && self.conditions == 0 && self.options.contains("errexit")
+    }
+
+    /// Runs `line` like [`Shell::execute_line`], returning what it printed instead of
+    /// showing it.
+    ///
+    /// Both builtins and the last command of pipelines are captured, and so are messages
+    /// from the shell itself, like `command not found`. Captures can be nested.
+    pub fn execute_line_capture(&mut self, line: &str) -> YshResult<CapturedOutput> {
+        let redirect = output::Redirect::to_buffer();
+        let result = self.execute_line(line);
+        let stdout = redirect.finish();
+        result?;
+        Ok(CapturedOutput {
+            stdout,
+            status: self.last_status,
+        })
+    }
+
+    /// Runs the body of a `for` loop once per expanded word, even if some iterations fail.
+    fn execute_for(&mut self, for_loop: command::parser::ForLoop) -> YshResult<()> {
+        let words = self.expand_vars(for_loop.words)?;
+        let expanded = command::parser::split_for_words(&words)?;
+        let mut error = None;
+        for word in expanded {
+            self.set_var(for_loop.name.clone(), word);
+            let result = self.execute_line(for_loop.body);
+            let stop = self.errexit_stops(&result);
+            if let Err(e) = result {
+                if let Some(previous) = error.replace(e) {
+                    shell_println!("{}", previous);
+                }
+            }
+            if stop {
+                break;
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Runs the branch of an `if` statement selected by the status of its condition.
+    ///
+    /// A condition that fails to run at all counts as false.
+    fn execute_if(&mut self, statement: command::parser::IfStatement) -> YshResult<()> {
+        self.conditions += 1;
+        let condition = self.execute_line(statement.condition);
+        self.conditions -= 1;
+        let condition = match condition {
+            Ok(()) => self.last_status == 0,
+            Err(e) => {
+                shell_println!("{}", e);
+                false
+            }
+        };
+        self.last_status = 0;
+        match (condition, statement.else_branch) {
+            (true, _) => self.execute_line(statement.then_branch),
+            (false, Some(else_branch)) => self.execute_line(else_branch),
+            (false, None) => Ok(()),
+        }
+    }
+
+    fn execute_statement(&mut self, cmd: &str) -> YshResult<()> {
+        if let Some(for_loop) = command::parser::parse_for(cmd)? {
+            return self.execute_for(for_loop);
+        }
+        if let Some(statement) = command::parser::parse_if(cmd)? {
+            return self.execute_if(statement);
+        }
+        let cmd = self.expand_vars(cmd)?;
+        let cmd = Command::parse(&cmd)?;
+        if cmd.command.is_empty() {
+            return Ok(());
+        }
+        let Some(cmd) = self.try_command_or_var(cmd)? else {
+            return Ok(());
+        };
+        self.execute(cmd)?;
+        Ok(())
+    }
+
+    pub fn read_line(&mut self) -> YshResult<()> {
+        // Shares the last command with the other sessions, and picks up theirs
+        self.save_history()?;
+        self.check_cwd();
+        // So `%(j...)` in the prompt leaves out the jobs that ended
+        self.jobs.refresh();
+        if self.options.contains("partial-line") {
+            self.read_line.mark_partial_line()?;
+        }
+        // May change what the prompt shows, so it runs first
+        hooks::run(self, hooks::Hook::Precmd, None)?;
+        let prompt = self.get_prompt();
+        let prompt_width = prompt::rendered_width(&prompt);
+        if self.sets_title() {
+            let _ = write(&read_line::cursor::set_title(&self.prompt_title()));
+        }
+        self.path_cache.refresh();
+        self.read_line.set_commands(self.path_cache.commands());
+        let variables = self.vars.names().cloned();
+        let env_variables = std::env::vars_os().filter_map(|(name, _)| name.into_string().ok());
+        self.read_line
+            .set_variables(variables.chain(env_variables).collect());
+        let aliases = self
+            .builtins
+            .values()
+            .filter(|builtin| matches!(builtin.action, builtins::Action::Alias { .. }));
+        self.read_line
+            .set_aliases(aliases.map(|alias| alias.name.clone()).collect());
+        let file_limit = self.get_var_or_env("YASH_COMPLETION_LIMIT");
+        self.read_line.set_file_limit(
+            file_limit
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(read_line::completion::DEFAULT_FILE_LIMIT),
+        );
+        // https://no-color.org asks for no colors when NO_COLOR isn't empty
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        let file_colors = self.get_var_or_env("YASH_COMPLETION_COLORS").as_deref() != Some("0");
+        self.read_line.set_file_colors(file_colors && !no_color);
+        let matcher = self.get_var_or_env("COMPLETION_MATCHER");
+        self.read_line.set_completion_matcher(
+            matcher
+                .and_then(|name| read_line::completion::Matcher::from_name(&name))
+                .unwrap_or_default(),
+        );
+        self.read_line
+            .set_paste_confirm(self.options.contains("paste-confirm"));
+        let clipboard = self.get_var_or_env("YASH_OSC52").as_deref() != Some("0");
+        self.read_line.set_clipboard(clipboard);
+        let dumb = std::env::var_os("TERM").is_none_or(|term| term.is_empty() || term == "dumb");
+        self.read_line.set_edit_in_place(!dumb);
+        let ignore = self.get_var("HISTIGNORE").unwrap_or_default();
+        let patterns = ignore.split(':').filter(|pattern| !pattern.is_empty());
+        let patterns = patterns.map(String::from).collect();
+        self.read_line.history_mut().set_ignore(patterns);
+        let highlight = self.get_var_or_env("YASH_HIGHLIGHT").as_deref() != Some("0");
+        self.read_line.set_highlighter(highlight.then(|| {
+            let builtins = self.builtins.keys().cloned().collect();
+            read_line::highlight::Highlighter::new(self.path_cache.commands(), builtins)
+        }));
+        match self.read_line.read_line(&prompt, prompt_width)? {
+            read_line::Execute::Exit => self.exit(0)?,
+            read_line::Execute::Command(cmd) => self.execute_typed_line(&cmd)?,
+            read_line::Execute::Cancel => (),
+        };
+        Ok(())
+    }
+
+    /// Runs a line typed at the prompt, after the `preexec` hooks.
+    fn execute_typed_line(&mut self, line: &str) -> YshResult<()> {
+        hooks::run(self, hooks::Hook::Preexec, Some(line))?;
+        // Only the command is timed, not the prompt nor the hooks
+        let start = Instant::now();
+        let result = self.execute_line(line);
+        self.report_duration(start.elapsed())?;
+        // While the history is saved and the completions gathered
+        prompt::prepare_prompt(self);
+        result
+    }
+
+    pub fn main_loop(&mut self) -> YshResult<()> {
+        while self.exit_code.is_none() {
+            if let Err(e) = self.read_line() {
+                shell_println!("{}", e);
+            }
+            if self.debug_overlay {
+                debug::render_debug_text()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Directory of the script currently being sourced, if any.
+    pub fn script_dir(&self) -> Option<&Path> {
+        self.sourcing.last().and_then(|p| p.parent())
+    }
+
+    fn sourcing_chain<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) -> String {
+        paths
+            .into_iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+
+    /// Executes every line of a file, reporting failing lines as `file:line: error`.
+    ///
+    /// Only failing to read the file itself aborts the script, or with `errexit` the first
+    /// failing command, whose status is left as the script's.
+    pub fn source_file(&mut self, filename: impl AsRef<Path>) -> YshResult<SourceSummary> {
+        let filename = filename.as_ref();
+        let path = filename
+            .canonicalize()
+            .wrap_err_with(|| format!("Failed to open file '{}'", filename.display()))?;
+        if let Some(start) = self.sourcing.iter().position(|p| *p == path) {
+            let chain = Self::sourcing_chain(self.sourcing[start..].iter().chain([&path]));
+            return Err(eyre!("source loop: {}", chain));
+        }
+        if self.sourcing.len() >= MAX_SOURCE_DEPTH {
+            let chain = Self::sourcing_chain(self.sourcing.iter().chain([&path]));
+            return Err(eyre!(
+                "source nesting deeper than {} files: {}",
+                MAX_SOURCE_DEPTH,
+                chain
+            ));
+        }
+        self.sourcing.push(path);
+        let outer_line = self.dynamic_vars.line.take();
+        let summary = self.source_lines(filename);
+        self.dynamic_vars.line = outer_line;
+        self.sourcing.pop();
+        summary
+    }
+
+    /// Like [`Shell::source_file`], with the variables set with `local` dropped afterwards.
+    pub fn source_file_local(&mut self, filename: impl AsRef<Path>) -> YshResult<SourceSummary> {
+        self.vars.push();
+        let summary = self.source_file(filename);
+        self.vars.pop();
+        summary
+    }
+
+    /// Defines a variable in the innermost scope, which only a local source has.
+    pub fn set_local_var(&mut self, name: String, value: String) -> YshResult<()> {
+        if self.vars.is_global() {
+            return Err(eyre!("local: only usable in scripts run with 'source -l'"));
+        }
+        self.vars.set_local(name, value);
+        Ok(())
+    }
+
+    fn source_lines(&mut self, filename: &Path) -> YshResult<SourceSummary> {
+        let file = std::fs::File::open(filename)
+            .wrap_err_with(|| format!("Failed to open file '{}'", filename.display()))?;
+        let file = std::io::BufReader::new(file);
+        let mut summary = SourceSummary::default();
+        for (i, l) in file.lines().enumerate() {
+            let l = l.wrap_err_with(|| format!("Failed to read file '{}'", filename.display()))?;
+            summary.lines += 1;
+            self.dynamic_vars.line = Some(i + 1);
+            let result = self.execute_line(&l);
+            let stop = self.errexit_stops(&result);
+            if let Err(e) = result {
+                shell_println!("{}:{}: {}", filename.display(), i + 1, e);
+                summary.failed += 1;
+            }
+            if stop {
+                break;
+            }
+        }
+        Ok(summary)
+    }
+    /// Saves the lines run since the last save, giving up on the history file if it fails.
+    fn save_history(&mut self) -> YshResult<()> {
+        let history = self.read_line.history_mut();
+        let Some(path) = history.file().map(Path::to_path_buf) else {
+            return Ok(());
+        };
+        if let Err(e) = history.save() {
+            history.close();
+            shell_println!(
+                "Failed to save history to '{}': {}, history won't be saved",
+                path.display(),
+                e
+            );
+        }
+        Ok(())
+    }
+    /// Moves the oldest entries of the history file at `path` to this month's archive when
+    /// it is over `$HISTROTATE`, or regardless of its size when `forced`. Returns how many
+    /// entries were moved and where to.
+    fn rotate_history(&self, path: &Path, forced: bool) -> YshResult<(usize, PathBuf)> {
+        let (limit, keep) =
+            config::get_history_rotation(self.get_var("HISTROTATE"), self.get_var("HISTKEEP"))?;
+        let archive = config::get_history_archive(path, config::Date::today());
+        let limit = match (forced, limit) {
+            (true, _) => None,
+            (false, None) => return Ok((0, archive)),
+            (false, limit) => limit,
+        };
+        let moved = read_line::history::rotate(path, &archive, limit, keep)
+            .wrap_err_with(|| format!("Failed to rotate history file '{}'", path.display()))?;
+        Ok((moved, archive))
+    }
+    pub fn run(&mut self, options: &Options) -> YshResult<i32> {
+        self.login = options.login;
+        self.interactive = options.is_interactive();
+        export_shell_vars();
+        let mut startup_files = vec![];
+        if !options.norc {
+            // Before the rc files, which may use them
+            if let Some(folder) = config::get_plugins_folder() {
+                self.register_plugins(&folder)?;
+            }
+            // The profile sets up the environment once, the rc files every interactive shell
+            if options.login {
+                startup_files.extend(config::get_profile_file());
+            }
+            if options.is_interactive() {
+                startup_files.extend(config::get_rc_files());
+            }
+        }
+        self.source_startup_files(startup_files)?;
+        if let Some(line) = &options.command {
+            if let Err(e) = self.execute_line(line) {
+                shell_println!("{}", e);
+                self.last_status = 1;
+            }
+            self.shutdown();
+            return Ok(self.exit_code.unwrap_or(self.last_status));
+        }
+        if let Some(script) = &options.script {
+            let name = script.to_string_lossy().into_owned();
+            for (i, arg) in [name].iter().chain(&options.args).enumerate() {
+                self.set_var(i.to_string(), arg.clone());
+            }
+            if let Err(e) = self.source_file(script) {
+                shell_println!("yash: {}", e);
+                self.last_status = 127;
+            }
+            self.shutdown();
+            return Ok(self.exit_code.unwrap_or(self.last_status));
+        }
+        // Loaded after yashrc so it can set HISTFILE
+        match config::get_history_file(self.get_var("HISTFILE")) {
+            Ok(Some(path)) => {
+                if let Err(e) = self.rotate_history(&path, false) {
+                    shell_println!("yash: {}", e);
+                }
+                match config::get_history(&path) {
+                    Ok(mut history) => {
+                        if self
+                            .get_var("HISTARCHIVES")
+                            .is_some_and(|var| !var.is_empty())
+                        {
+                            history.set_archives(config::get_history_archives(&path));
+                        }
+                        self.read_line = read_line::ReadLine::new_with_history(history)
+                    }
+                    Err(e) => shell_println!("Failed to open history file: {}", e),
+                }
+            }
+            Ok(None) => (),
+            Err(e) => shell_println!("yash: {}, history won't be saved", e),
+        }
+
+        // Clear anything typed in stdin before we reach the prompt.
+        let mut buf = [0u8; 16];
+        loop {
+            if read(&mut buf)? == 0 {
+                break
+            }
+        }
+
+        self.main_loop()?;
+        self.shutdown();
+        Ok(self.exit_code.unwrap_or_default())
+    }
+
+    /// Sources `files` in order, telling which ones couldn't be read.
+    fn source_startup_files(&mut self, files: Vec<PathBuf>) -> YshResult<()> {
+        self.loading_config = true;
+        let result = self.source_each(files);
+        self.loading_config = false;
+        result
+    }
+
+    fn source_each(&mut self, files: Vec<PathBuf>) -> YshResult<()> {
+        // A broken file doesn't keep the others from loading
+        for script in files {
+            if let Err(e) = self.source_file(&script) {
+                shell_println!("Failed to read '{}': {}", script.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sources the rc files again, after dropping the aliases they defined before. A
+    /// `full` reload drops every alias and shell variable instead.
+    pub fn reload(&mut self, full: bool) -> YshResult<()> {
+        self.builtins.retain(|_, builtin| {
+            let alias = matches!(builtin.action, builtins::Action::Alias { .. });
+            !alias || !(full || builtin.from_config)
+        });
+        // The aliases may have replaced builtins with their names
+        for (name, builtin) in builtins::native_builtins() {
+            self.builtins.entry(name).or_insert(builtin);
+        }
+        if full {
+            self.vars = Default::default();
+        }
+        self.invalidate_prompt();
+        self.source_startup_files(config::get_rc_files())
+    }
+
+    /// Runs the `EXIT` trap, then `logout.ysh` for login shells.
+    fn run_exit_scripts(&mut self) -> YshResult<()> {
+        if let Some(commands) = self.traps.remove("EXIT") {
+            if let Err(e) = self.execute_line(&commands) {
+                shell_println!("trap: {}", e);
+            }
+        }
+        if let Some(script) = self.login.then(config::get_logout_file).flatten() {
+            if let Err(e) = self.source_file(&script) {
+                shell_println!("Failed to read '{}': {}", script.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the `EXIT` trap, saves the history and gives the terminal back, in that order.
+    ///
+    /// Only the first call does anything, so it is safe on every way out, even after a panic.
+    pub fn shutdown(&mut self) {
+        if std::mem::replace(&mut self.shut_down, true) {
+            return;
+        }
+        // Telling what failed may fail too once the output is gone, which mustn't keep
+        // the history from being saved
+        let _ = self.run_exit_scripts();
+        self.jobs.hang_up();
+        let _ = self.save_history();
+        let _ = output::flush();
+        if let Err(e) = self.term_state.put_old() {
+            eprintln!("yash: failed to restore the terminal: {}", e);
+        }
+    }
+}
+
+/// Sets shell and environment variables while a builtin runs, restoring them on drop.
+struct ScopedVars<'a> {
+    shell: &'a mut Shell,
+    /// Each variable with its previous shell and environment values
+    saved: Vec<(String, Option<String>, Option<std::ffi::OsString>)>,
+}
+
+impl<'a> ScopedVars<'a> {
+    fn new(shell: &'a mut Shell, vars: Vec<(String, String)>) -> Self {
+        let saved = vars
+            .into_iter()
+            .map(|(name, value)| {
+                let env = std::env::var_os(&name);
+                std::env::set_var(&name, &value);
+                let var = shell.vars.set(name.clone(), value);
+                (name, var, env)
+            })
+            .collect();
+        Self { shell, saved }
+    }
+}
+
+impl std::ops::Deref for ScopedVars<'_> {
+    type Target = Shell;
+    fn deref(&self) -> &Shell {
+        self.shell
+    }
+}
+
+impl std::ops::DerefMut for ScopedVars<'_> {
+    fn deref_mut(&mut self) -> &mut Shell {
+        self.shell
+    }
+}
+
+impl Drop for ScopedVars<'_> {
+    fn drop(&mut self) {
+        // In reverse, in case a name was assigned twice
+        for (name, var, env) in self.saved.drain(..).rev() {
+            match var {
+                Some(value) => self.shell.vars.set(name.clone(), value),
+                None => self.shell.vars.remove(&name),
+            };
+            match env {
+                Some(value) => std::env::set_var(&name, value),
+                None => std::env::remove_var(&name),
+            }
+        }
+    }
+}
+
+/// How yash was started.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Don't source any rc file.
+    pub norc: bool,
+    /// Source the profile first, and `logout.ysh` on exit.
+    pub login: bool,
+    /// Line to run instead of reading commands from the terminal, given with `-c`.
+    pub command: Option<String>,
+    /// Script to run instead of reading commands from the terminal.
+    pub script: Option<PathBuf>,
+    /// Arguments after the script, its positional parameters `$1`, `$2`...
+    pub args: Vec<String>,
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> YshResult<Self> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--norc" => options.norc = true,
+                "-l" | "--login" => options.login = true,
+                "-c" => match args.next() {
+                    Some(line) => options.command = Some(line),
+                    None => return Err(eyre!("-c: missing command")),
+                },
+                _ if arg.starts_with('-') => return Err(eyre!("unknown option '{}'", arg)),
+                // The rest belongs to the script
+                _ => {
+                    options.script = Some(arg.into());
+                    options.args = args.collect();
+                    break;
+                }
+            }
+        }
+        Ok(options)
+    }
+
+    /// Whether commands are read from the terminal, which is when the rc files are sourced.
+    pub fn is_interactive(&self) -> bool {
+        self.command.is_none() && self.script.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::TempDir;
+
+    fn mock_shell() -> Shell {
+        Shell::init(Default::default()).unwrap()
+    }
+
+    #[test]
+    fn shell_level_counts_up() {
+        assert_eq!(next_shell_level(None), 1);
+        assert_eq!(next_shell_level(Some("1")), 2);
+        assert_eq!(next_shell_level(Some(" 41\n")), 42);
+        // Garbage starts over
+        assert_eq!(next_shell_level(Some("-3")), 1);
+        assert_eq!(next_shell_level(Some("deep")), 1);
+    }
+
+    #[test]
+    fn capture_program_and_builtin_output() {
+        let mut shell = mock_shell();
+        let captured = shell
+            .execute_line_capture("echo hello; sh -c 'echo fail; exit 3'")
+            .unwrap();
+        assert_eq!(captured.stdout, b"hello\nfail\n");
+        assert_eq!(captured.status, 3);
+
+        shell.execute_line("alias greet='echo hi'").unwrap();
+        let captured = shell.execute_line_capture("alias -p | grep greet").unwrap();
+        assert_eq!(captured.stdout, b"alias 'greet=echo hi'\n");
+        assert_eq!(captured.status, 0);
+    }
+
+    #[test]
+    fn oneshot_vars_apply_to_builtins() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("oneshot");
+        let script = dir.file("oneshot.ysh", "SEEN=$YASH_TEST_ONESHOT\n");
+        shell
+            .execute_line(&format!("YASH_TEST_ONESHOT=1 source {}", script.display()))
+            .unwrap();
+        assert_eq!(shell.get_var("SEEN"), Some("1"));
+        assert_eq!(shell.get_var("YASH_TEST_ONESHOT"), None);
+        assert_eq!(std::env::var_os("YASH_TEST_ONESHOT"), None);
+
+        shell
+            .execute_line("YASH_TEST_EXPORTED=bar export YASH_TEST_EXPORTED")
+            .unwrap();
+        assert_eq!(std::env::var("YASH_TEST_EXPORTED").as_deref(), Ok("bar"));
+        std::env::remove_var("YASH_TEST_EXPORTED");
+    }
+
+    #[test]
+    fn multiple_oneshot_vars() {
+        let mut shell = mock_shell();
+        let captured = shell
+            .execute_line_capture("YASH_TEST_A=1 YASH_TEST_B=2 printenv YASH_TEST_A YASH_TEST_B")
+            .unwrap();
+        assert_eq!(captured.stdout, b"1\n2\n");
+        assert_eq!(shell.get_var("YASH_TEST_A"), None);
+        shell.execute_line("YASH_TEST_A=3 YASH_TEST_B=4").unwrap();
+        assert_eq!(shell.get_var("YASH_TEST_A"), Some("3"));
+        assert_eq!(shell.get_var("YASH_TEST_B"), Some("4"));
+    }
+
+    #[test]
+    fn assignment_names_are_checked() {
+        let mut shell = mock_shell();
+        for line in ["=x", "1A=x", "YASH_TEST_OK=1 2B=x true"] {
+            let error = shell.execute_line(line).unwrap_err().to_string();
+            assert!(error.ends_with("is not a valid variable name"), "{}", error);
+        }
+        assert_eq!(shell.get_var(""), None);
+        assert_eq!(shell.get_var("1A"), None);
+        assert_eq!(shell.get_var("YASH_TEST_OK"), None);
+
+        shell.execute_line("A==b").unwrap();
+        assert_eq!(shell.get_var("A"), Some("=b"));
+        shell.execute_line("A=b=c").unwrap();
+        assert_eq!(shell.get_var("A"), Some("b=c"));
+        // Not a name at all, so a command
+        let captured = shell.execute_line_capture("./yash-missing=1").unwrap();
+        assert_eq!(captured.status, 127);
+    }
+
+    #[test]
+    fn oneshot_vars_dont_leak_after_errors() {
+        let mut shell = mock_shell();
+        assert!(shell
+            .execute_line("YASH_TEST_LEAK=1 cat < /nonexistent/yash")
+            .is_err());
+        let captured = shell
+            .execute_line_capture("printenv YASH_TEST_LEAK")
+            .unwrap();
+        assert_eq!(captured.stdout, b"");
+        assert_eq!(captured.status, 1);
+    }
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(Options::parse([]).unwrap(), Options::default());
+        assert!(Options::parse(["--norc".into()]).unwrap().norc);
+        assert!(Options::parse(["--nope".into()]).is_err());
+        let options = Options::parse(["-l", "-c", "ls -l"].map(String::from)).unwrap();
+        assert!(options.login && !options.is_interactive());
+        assert_eq!(options.command.as_deref(), Some("ls -l"));
+        assert!(Options::parse(["-c".into()]).is_err());
+        // Options after the script are its arguments
+        let options = Options::parse(["--norc", "script", "-l", "a"].map(String::from)).unwrap();
+        assert_eq!(options.script, Some(PathBuf::from("script")));
+        assert_eq!(options.args, ["-l", "a"]);
+        assert!(!options.login && !options.is_interactive());
+    }
+
+    #[test]
+    fn long_commands_are_reported() {
+        let mut shell = mock_shell();
+        let redirect = output::Redirect::to_buffer();
+        shell.report_duration(Duration::from_secs(83)).unwrap();
+        shell.set_var("REPORTTIME".into(), "60".into());
+        shell.report_duration(Duration::from_secs(59)).unwrap();
+        shell.report_duration(Duration::from_secs(83)).unwrap();
+        shell.report_duration(Duration::from_secs(3723)).unwrap();
+        assert_eq!(redirect.finish(), b"took 1m23s\ntook 1h2m3s\n");
+
+        shell.report_duration(Duration::from_millis(4567)).unwrap();
+        shell.set_var("PS1".into(), "%d $ ".into());
+        assert_eq!(shell.get_prompt(), "4.5s $ ");
+    }
+
+    #[test]
+    fn prompts_are_rendered_again_only_when_changed() {
+        let _cwd = testing::WorkingDir::lock();
+        let mut shell = mock_shell();
+        let renders = |shell: &Shell| shell.prompt_cache.renders();
+        shell.execute_line("PS1='%h $ '").unwrap();
+        let prompt = shell.get_prompt();
+        assert_eq!(renders(&shell), 1);
+        // Nothing the prompt shows changes
+        assert_eq!(shell.get_prompt(), prompt);
+        shell.execute_line("X=1").unwrap();
+        shell.execute_line("sh -c 'exit 0'").unwrap();
+        assert_eq!(shell.get_prompt(), prompt);
+        assert_eq!(renders(&shell), 1);
+
+        shell.execute_line("PS1='%h > '").unwrap();
+        assert!(shell.get_prompt().ends_with(" > "));
+        assert_eq!(renders(&shell), 2);
+        shell.execute_line("cd /").unwrap();
+        assert_eq!(shell.get_prompt(), "/ > ");
+        assert_eq!(renders(&shell), 3);
+        shell.execute_line("sh -c 'exit 3'").unwrap();
+        shell.get_prompt();
+        assert_eq!(renders(&shell), 4);
+        shell.invalidate_prompt();
+        assert_eq!(shell.get_prompt(), "/ > ");
+        assert_eq!(renders(&shell), 5);
+        assert_eq!(shell.get_prompt(), "/ > ");
+        assert_eq!(renders(&shell), 5);
+    }
+
+    #[test]
+    fn nested_captures() -> YshResult<()> {
+        let mut shell = mock_shell();
+        let outer = output::Redirect::to_buffer();
+        shell_println!("outer");
+        let inner = shell.execute_line_capture("echo inner")?;
+        assert_eq!(inner.stdout, b"inner\n");
+        shell.execute_line("echo outer again")?;
+        assert_eq!(outer.finish(), b"outer\nouter again\n");
+        Ok(())
+    }
+
+    #[test]
+    fn unread_input_is_read_first() {
+        unread(b"abc");
+        let mut buf = [0u8; 2];
+        assert_eq!(read(&mut buf), Ok(2));
+        assert_eq!(&buf, b"ab");
+        assert_eq!(read(&mut buf), Ok(1));
+        assert_eq!(buf[0], b'c');
+    }
+
+    #[test]
+    fn exit_warns_about_running_jobs_once() {
+        let _cwd = testing::WorkingDir::lock();
+        let mut shell = mock_shell();
+        let mut sleep = std::process::Command::new("sleep")
+            .arg("5")
+            .spawn()
+            .unwrap();
+        shell.add_job(sleep.id(), "sleep 5".into());
+        let redirect = output::Redirect::to_buffer();
+        shell.execute_line("exit").unwrap();
+        assert_eq!(shell.exit_code, None);
+        // Anything else in between asks again
+        shell.execute_line("cd .").unwrap();
+        shell.execute_line("exit 2").unwrap();
+        assert_eq!(shell.exit_code, None);
+        shell.execute_line("exit 2").unwrap();
+        assert_eq!(shell.exit_code, Some(2));
+        assert_eq!(
+            redirect.finish(),
+            b"There is 1 running job.\nThere is 1 running job.\n"
+        );
+
+        shell.exit_code = None;
+        shell.execute_line("disown %1").unwrap();
+        shell.execute_line("exit").unwrap();
+        assert_eq!(shell.exit_code, Some(0));
+        sleep.kill().unwrap();
+        sleep.wait().unwrap();
+    }
+
+    #[test]
+    fn shutdown_saves_history() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("shutdown-history");
+        let path = dir.file("history", "");
+        let mut history = read_line::history::History::open(path.clone()).unwrap();
+        history.push("ls");
+        shell.read_line = read_line::ReadLine::new_with_history(history);
+        shell.execute_line("trap 'exit 3' EXIT").unwrap();
+        shell.shutdown();
+        assert!(std::fs::read_to_string(&path).unwrap().ends_with("\nls\n"));
+        assert_eq!(shell.exit_code, Some(3));
+    }
+
+    #[test]
+    fn hooks_run_around_typed_lines_in_order() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("hooks");
+        let path = dir.file("hooks", "");
+        let file = path.display().to_string();
+        let line = |text: &str| text.replace("{}", &file);
+        shell.set_var("PRECMD".into(), line("echo var-precmd >> {}"));
+        shell.set_var("PREEXEC".into(), line("echo var-preexec >> {}"));
+        for hook in [
+            "hook add precmd 'echo added-precmd >> {}'",
+            "hook add preexec 'echo first >> {}'",
+            "hook add preexec 'cd /nonexistent/yash'",
+            "hook add preexec 'echo second >> {}'",
+        ] {
+            shell.execute_line(&line(hook)).unwrap();
+        }
+
+        let command = line("echo command >> {}");
+        let redirect = output::Redirect::to_buffer();
+        hooks::run(&mut shell, hooks::Hook::Precmd, None).unwrap();
+        shell.execute_typed_line(&command).unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert!(printed.starts_with("yash: preexec hook: "), "{}", printed);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "var-precmd\nadded-precmd\nvar-preexec\nfirst\nsecond\ncommand\n"
+        );
+        assert_eq!(shell.get_var("YASH_COMMAND"), Some(command.as_str()));
+
+        // The status of the line is left for the prompt
+        shell.execute_line("hook clear").unwrap();
+        shell.execute_line("hook add precmd 'sh -c true'").unwrap();
+        shell.execute_typed_line("sh -c 'exit 4'").unwrap();
+        hooks::run(&mut shell, hooks::Hook::Precmd, None).unwrap();
+        assert_eq!(shell.last_status(), 4);
+    }
+
+    #[test]
+    fn get_var_or_env() {
+        let mut shell = mock_shell();
+        shell.set_var("FOO".into(), "fool".into());
+        assert_eq!(shell.get_var_or_env("FOO"), Some("fool".into()));
+    }
+
+    #[test]
+    fn expand_var_simple() {
+        let mut shell = mock_shell();
+        shell.set_var("FOO".into(), "fool".into());
+        assert_eq!(
+            shell.expand_vars("you are a $FOO").unwrap(),
+            "you are a fool"
+        );
+    }
+
+    #[test]
+    fn expand_var_command_simple() {
+        let mut shell = mock_shell();
+        shell.set_var("CWD".into(), "/home".into());
+        assert_eq!(shell.expand_vars("echo $CWD").unwrap(), "echo /home");
+    }
+
+    #[test]
+    fn expand_env_command_simple() {
+        let mut shell = mock_shell();
+        std::env::set_var("FOO", "fool");
+        assert_eq!(shell.expand_vars("echo $FOO").unwrap(), "echo fool");
+    }
+
+    #[test]
+    fn source_file_continues_after_errors() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("mixed");
+        let script = dir.file(
+            "mixed.ysh",
+            "A=1\ncd /nonexistent/yash\nB=2\nsource /nonexistent/yash.ysh\nC=3\n",
+        );
+        let summary = shell.source_file(&script).unwrap();
+        assert_eq!(summary, SourceSummary { lines: 5, failed: 2 });
+        assert_eq!(shell.get_var("A"), Some("1"));
+        assert_eq!(shell.get_var("B"), Some("2"));
+        assert_eq!(shell.get_var("C"), Some("3"));
+    }
+
+    #[test]
+    fn source_builtin_sets_failure_status() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("failing");
+        let script = dir.file("failing.ysh", "cd /nonexistent/yash\nD=4\n");
+        shell
+            .execute_line(&format!("source {}", script.display()))
+            .unwrap();
+        assert_eq!(shell.last_status(), 1);
+        assert_eq!(shell.get_var("D"), Some("4"));
+    }
+
+    #[test]
+    fn deleted_working_directory_is_shown_and_left() {
+        let dir = TempDir::new("deleted");
+        let base = dir.canonicalize().unwrap();
+        let inner = base.join("a/b");
+        std::fs::create_dir_all(&inner).unwrap();
+        let _cwd = testing::WorkingDir::lock();
+        let mut shell = mock_shell();
+        shell.set_var("PS1".into(), "%h $ ".into());
+        shell.change_directory(&inner).unwrap();
+        shell.check_cwd();
+        assert!(!shell.get_prompt().contains("(deleted)"));
+
+        // Deleted with the process elsewhere, or the shells of other tests would start there
+        std::env::set_current_dir(&base).unwrap();
+        std::fs::remove_dir_all(base.join("a")).unwrap();
+        shell.check_cwd();
+        assert!(shell.get_prompt().ends_with("/a/b (deleted) $ "));
+        // Up to the closest directory left
+        shell.change_directory("..").unwrap();
+        assert_eq!(shell.cwd(), base);
+        shell.check_cwd();
+        assert!(!shell.get_prompt().contains("(deleted)"));
+    }
+
+    #[test]
+    fn errexit_stops_scripts_at_the_first_failure() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("errexit");
+        let script = dir.file(
+            "errexit.ysh",
+            "A=1\nif sh -c 'exit 2'; then B=1; fi\nsh -c 'exit 3'; A=2\nA=3\n",
+        );
+        shell.execute_line("set -e").unwrap();
+        shell
+            .execute_line(&format!("source {}", script.display()))
+            .unwrap();
+        assert_eq!(shell.get_var("A"), Some("1"));
+        assert_eq!(shell.get_var("B"), None);
+        assert_eq!(shell.last_status(), 3);
+
+        // What is typed at the prompt keeps going
+        shell.interactive = true;
+        shell.execute_line("sh -c 'exit 3'; A=4").unwrap();
+        assert_eq!(shell.get_var("A"), Some("4"));
+        shell.execute_line("set +e").unwrap();
+        shell.interactive = false;
+        shell.source_file(&script).unwrap();
+        assert_eq!(shell.get_var("A"), Some("3"));
+    }
+
+    /// A terminal keeping what is written to it, or failing every write when `broken`.
+    struct Screen {
+        written: std::rc::Rc<std::cell::RefCell<Vec<u8>>>,
+        broken: bool,
+    }
+
+    impl Io for Screen {
+        fn read(&self, _buf: &mut [u8]) -> nix::Result<usize> {
+            Ok(0)
+        }
+        fn write(&self, bytes: &[u8]) -> nix::Result<usize> {
+            if self.broken {
+                return Err(nix::Error::EIO);
+            }
+            self.written.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        fn poll_input(&self, _timeout: Duration) -> nix::Result<bool> {
+            Ok(false)
+        }
+        fn size(&self) -> nix::Result<Vec2> {
+            Ok(Vec2::new(80, 24))
+        }
+        fn is_terminal(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn titles_stay_out_of_captured_output() {
+        let written = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let mut shell = mock_shell();
+        shell.interactive = true;
+        shell.set_var("TERM".into(), "xterm".into());
+        shell.set_io(Screen {
+            written: written.clone(),
+            broken: false,
+        });
+        let captured = shell
+            .execute_line_capture("echo hi; sh -c 'echo from sh' | cat")
+            .unwrap();
+        assert_eq!(captured.stdout, b"hi\nfrom sh\n");
+        assert!(written.borrow().is_empty());
+
+        // Only on the terminal
+        shell.execute_line("sh -c true").unwrap();
+        assert!(written
+            .borrow()
+            .starts_with(b"\x1b]0;sh -c true\x07\x1b]0;yash: "));
+
+        // A terminal that can't take the title still runs the command
+        shell.set_io(Screen {
+            written,
+            broken: true,
+        });
+        shell.execute_line("sh -c 'exit 3'").unwrap();
+        assert_eq!(shell.last_status(), 3);
+        tty::set_io(None);
+    }
+
+    #[test]
+    fn source_file_skips_comments_and_blank_lines() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("comments");
+        let script = dir.file(
+            "comments.ysh",
+            "# leading comment\n\n   \nA=1 # inline comment\nB='#'\n",
+        );
+        let summary = shell.source_file(&script).unwrap();
+        assert_eq!(summary.failed, 0);
+        assert_eq!(shell.get_var("A"), Some("1"));
+        assert_eq!(shell.get_var("B"), Some("#"));
+    }
+
+    #[test]
+    fn source_resolves_relative_to_script() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("relative");
+        std::fs::write(dir.join("outer.ysh"), "source inner.ysh\n").unwrap();
+        std::fs::write(dir.join("inner.ysh"), "INNER=1\n").unwrap();
+        shell.source_file(dir.join("outer.ysh")).unwrap();
+        assert_eq!(shell.get_var("INNER"), Some("1"));
+        assert!(shell.sourcing.is_empty());
+    }
+
+    #[test]
+    fn local_variables_end_with_their_script() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("local");
+        let script = dir.join("script.ysh");
+        std::fs::write(&script, "local TEMP=1 SHADOW=inner\nKEPT=$TEMP$SHADOW\n").unwrap();
+        shell.execute_line("SHADOW=outer").unwrap();
+        let line = format!("source -l {}", script.display());
+        shell.execute_line(&line).unwrap();
+        assert_eq!(shell.get_var("TEMP"), None);
+        assert_eq!(shell.get_var("SHADOW"), Some("outer"));
+        assert_eq!(shell.get_var("KEPT"), Some("1inner"));
+
+        // Without -l, there is no scope for them
+        let summary = shell.source_file(&script).unwrap();
+        assert_eq!(summary.failed, 1);
+        assert_eq!(shell.get_var("TEMP"), None);
+        assert!(shell.execute_line("local X=1").is_err());
+    }
+
+    #[test]
+    fn source_detects_loops() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("loop");
+        std::fs::write(dir.join("a.ysh"), "source b.ysh\nAFTER=1\n").unwrap();
+        std::fs::write(dir.join("b.ysh"), "source a.ysh\n").unwrap();
+        shell.source_file(dir.join("a.ysh")).unwrap();
+        assert_eq!(shell.get_var("AFTER"), Some("1"));
+
+        let a = dir.join("a.ysh").canonicalize().unwrap();
+        shell.sourcing.push(a.clone());
+        let err = shell.source_file(&a).unwrap_err().to_string();
+        assert_eq!(err, format!("source loop: {0} -> {0}", a.display()));
+    }
+
+    #[test]
+    fn source_caps_nesting_depth() {
+        let mut shell = mock_shell();
+        shell.sourcing = (0..MAX_SOURCE_DEPTH)
+            .map(|i| PathBuf::from(format!("/{}.ysh", i)))
+            .collect();
+        let dir = TempDir::new("deep");
+        let script = dir.file("deep.ysh", "");
+        let err = shell.source_file(&script).unwrap_err().to_string();
+        assert!(err.starts_with("source nesting deeper than 32 files"));
+    }
+
+    #[test]
+    fn builtin_output_redirects_to_file() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("alias-redir");
+        let out = dir.file("alias-redir.out", "");
+        shell.execute_line("alias ll='ls -l'").unwrap();
+        shell
+            .execute_line(&format!("alias ll > {}", out.display()))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "alias 'll=ls -l'\n");
+    }
+
+    #[test]
+    fn builtin_output_pipes_into_program() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("alias-pipe");
+        let out = dir.file("alias-pipe.out", "");
+        shell.execute_line("alias ll='ls -l'").unwrap();
+        shell
+            .execute_line(&format!("alias ll | cat > {}", out.display()))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "alias 'll=ls -l'\n");
+    }
+
+    #[test]
+    fn programs_inherit_builtin_redirection() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("command-redir");
+        let out = dir.file("command-redir.out", "");
+        shell
+            .execute_line(&format!("command echo hi > {}", out.display()))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "hi\n");
+    }
+
+    #[test]
+    fn execute_sequence() {
+        let mut shell = mock_shell();
+        shell.execute_line("A=1; B=$A;; C='x;y';").unwrap();
+        assert_eq!(shell.get_var("A"), Some("1"));
+        assert_eq!(shell.get_var("B"), Some("1"));
+        assert_eq!(shell.get_var("C"), Some("x;y"));
+    }
+
+    #[test]
+    fn execute_sequence_continues_after_failure() {
+        let mut shell = mock_shell();
+        assert!(shell.execute_line("cd /nonexistent/yash; D=4").is_err());
+        assert_eq!(shell.get_var("D"), Some("4"));
+    }
+
+    #[test]
+    fn for_loop_over_words_and_globs() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("for");
+        std::fs::write(dir.join("one.txt"), "").unwrap();
+        std::fs::write(dir.join("two.txt"), "").unwrap();
+        shell.set_var("DIR".into(), dir.display().to_string());
+        shell
+            .execute_line("ALL=; for f in a $DIR/*.txt; do ALL=$ALL,$f; done")
+            .unwrap();
+        let dir = dir.display();
+        assert_eq!(
+            shell.get_var("ALL"),
+            Some(format!(",a,{0}/one.txt,{0}/two.txt", dir).as_str())
+        );
+    }
+
+    #[test]
+    fn for_loop_keeps_quoted_globs() {
+        let mut shell = mock_shell();
+        let dir = TempDir::new("for-quoted");
+        std::fs::write(dir.join("one.txt"), "").unwrap();
+        shell.set_var("DIR".into(), dir.display().to_string());
+        shell
+            .execute_line("ALL=; for f in '*.txt' \"$DIR/*.txt\"; do ALL=$ALL,$f; done")
+            .unwrap();
+        let all = format!(",*.txt,{}/*.txt", dir.display());
+        assert_eq!(shell.get_var("ALL"), Some(all.as_str()));
+    }
+
+    #[test]
+    fn for_loop_with_empty_word_list() {
+        let mut shell = mock_shell();
+        shell.execute_line("for f in; do RAN=1; done").unwrap();
+        assert_eq!(shell.get_var("RAN"), None);
+    }
+
+    #[test]
+    fn for_loop_body_failing_partway() {
+        let mut shell = mock_shell();
+        let result = shell.execute_line("for f in a b; do cd /nonexistent/yash; LAST=$f; done");
+        assert!(result.is_err());
+        assert_eq!(shell.get_var("LAST"), Some("b"));
+    }
+
+    #[test]
+    fn if_true_branch() {
+        let mut shell = mock_shell();
+        shell
+            .execute_line("if [ a = a ]; then R=then; else R=else; fi")
+            .unwrap();
+        assert_eq!(shell.get_var("R"), Some("then"));
+    }
+
+    #[test]
+    fn if_false_branch() {
+        let mut shell = mock_shell();
+        shell
+            .execute_line("if test -z nonempty; then R=then; else R=else; fi")
+            .unwrap();
+        assert_eq!(shell.get_var("R"), Some("else"));
+    }
+
+    #[test]
+    fn if_without_else() {
+        let mut shell = mock_shell();
+        shell.execute_line("if [ a = b ]; then R=then; fi").unwrap();
+        assert_eq!(shell.get_var("R"), None);
+        assert_eq!(shell.last_status(), 0);
+    }
+
+    #[test]
+    fn if_with_quotes_in_branches() {
+        let mut shell = mock_shell();
+        shell
+            .execute_line(r#"if [ "a b" = 'a b' ]; then R="x; fi"; fi"#)
+            .unwrap();
+        assert_eq!(shell.get_var("R"), Some("x; fi"));
+    }
+
+    #[test]
+    fn if_missing_condition_command_is_false() {
+        let mut shell = mock_shell();
+        shell
+            .execute_line("if /nonexistent/yash; then R=then; else R=else; fi")
+            .unwrap();
+        assert_eq!(shell.get_var("R"), Some("else"));
+    }
+
+    #[test]
+    fn plugins_print_and_change_the_shell() {
+        use std::os::unix::fs::PermissionsExt;
+        let folder = TempDir::new("plugin");
+        let plugins = [
+            (
+                "yash-greet",
+                "echo \"hello $1\"\necho \"::set GREETED $1 $PLUGIN_VAR\"\n\
+                 echo '::alias hi=greet you'\necho '::notify'\nexit 2\n",
+            ),
+            (
+                "yash-broken",
+                "echo before\necho '::set X 1'\necho '::cd'\n",
+            ),
+            ("yash-dirs", "echo shadowed\n"),
+        ];
+        for (name, script) in plugins {
+            let path = folder.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let mut shell = mock_shell();
+        let redirect = output::Redirect::to_buffer();
+        shell.register_plugins(&folder).unwrap();
+        let warning = format!(
+            "yash: {}: dirs is a builtin already, the plugin is left out\n",
+            folder.join("yash-dirs").display()
+        );
+        assert_eq!(redirect.finish(), warning.as_bytes());
+
+        let captured = shell
+            .execute_line_capture("PLUGIN_VAR=1 greet world")
+            .unwrap();
+        let warning = "yash: greet: unknown directive '::notify', skipped\n";
+        assert_eq!(
+            captured.stdout,
+            format!("hello world\n{}", warning).as_bytes()
+        );
+        assert_eq!(captured.status, 2);
+        assert_eq!(shell.get_var("GREETED"), Some("world 1"));
+        let captured = shell.execute_line_capture("hi | head -n 1").unwrap();
+        assert_eq!(captured.stdout, b"hello you\n");
+        let captured = shell.execute_line_capture("dirs").unwrap();
+        assert_ne!(captured.stdout, b"shadowed\n");
+
+        // A malformed directive stops them all, but not the output
+        let redirect = output::Redirect::to_buffer();
+        let error = shell.execute_line("broken").unwrap_err().to_string();
+        assert_eq!(redirect.finish(), b"before\n");
+        assert_eq!(error, "broken: line 3: '::cd': missing directory");
+        assert_eq!(shell.get_var("X"), None);
+    }
+}
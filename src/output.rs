@@ -0,0 +1,282 @@
+//! Destination of everything printed with [`shell_print!`](crate::shell_print).
+//!
+//! Normally that is the terminal, but builtin output can be redirected into a
+//! file or a buffer, e.g. when a builtin is the first command of a pipeline.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    thread::LocalKey,
+    fs::File,
+    io::{self, Write},
+    process::Stdio,
+};
+
+/// How much terminal output is buffered before it is written anyway.
+const CAPACITY: usize = 8 * 1024;
+
+/// Buffers output for the raw terminal, translating bare `\n` into `\r\n`.
+pub struct ShellWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    /// Whether the last byte given was a `\r`, in case its `\n` comes in the next write
+    after_cr: bool,
+    /// Off while the terminal isn't raw, or isn't a terminal at all, e.g. for `yash -c`
+    translate: bool,
+}
+
+impl<W: Write> ShellWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(CAPACITY),
+            after_cr: false,
+            translate: true,
+        }
+    }
+}
+
+impl<W: Write> Write for ShellWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        for &byte in bytes {
+            if byte == b'\n' && !self.after_cr && self.translate {
+                self.buf.push(b'\r');
+            }
+            self.buf.push(byte);
+            self.after_cr = byte == b'\r';
+        }
+        if self.buf.len() >= CAPACITY {
+            self.flush()?;
+        }
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        self.inner.flush()
+    }
+}
+
+/// Translates bare `\n` like a [`ShellWriter`], for text written to the terminal directly.
+pub fn translate_newlines(bytes: &[u8]) -> Vec<u8> {
+    let mut writer = ShellWriter::new(Vec::with_capacity(bytes.len()));
+    writer
+        .write_all(bytes)
+        .and_then(|()| writer.flush())
+        .expect("Writing to a Vec can't fail");
+    writer.inner
+}
+
+/// The terminal, written to without going through Rust's `Stdout` buffering.
+pub struct Terminal;
+
+impl Write for Terminal {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        crate::write_unbuffered(bytes)?;
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Sink {
+    Terminal,
+    File(File),
+    Buffer(Vec<u8>),
+}
+
+thread_local! {
+    static SINK: RefCell<Sink> = const { RefCell::new(Sink::Terminal) };
+    /// Where [`print_trace`] writes, separate from [`SINK`] so traces don't go with the output.
+    static TRACES: RefCell<Sink> = const { RefCell::new(Sink::Terminal) };
+    /// Only translates newlines once the terminal is made raw, see [`set_raw`].
+    static TERMINAL: RefCell<ShellWriter<Terminal>> = RefCell::new(ShellWriter {
+        translate: false,
+        ..ShellWriter::new(Terminal)
+    });
+}
+
+/// Tells whether the terminal is in raw mode, where a bare `\n` doesn't go back to the
+/// first column, so the output has to be translated.
+pub fn set_raw(raw: bool) {
+    TERMINAL.with_borrow_mut(|terminal| terminal.translate = raw);
+}
+
+/// Returns true while the terminal is in raw mode.
+pub fn is_raw() -> bool {
+    TERMINAL.with_borrow(|terminal| terminal.translate)
+}
+
+/// Prints `bytes` to the current sink, translating newlines for the raw terminal.
+pub fn print_bytes(bytes: &[u8]) -> io::Result<()> {
+    write_to(&SINK, bytes)
+}
+
+fn write_to(sink: &'static LocalKey<RefCell<Sink>>, bytes: &[u8]) -> io::Result<()> {
+    sink.with_borrow_mut(|sink| match sink {
+        Sink::Terminal => TERMINAL.with_borrow_mut(|terminal| terminal.write_all(bytes)),
+        Sink::File(file) => file.write_all(bytes),
+        Sink::Buffer(buf) => {
+            buf.extend_from_slice(bytes);
+            Ok(())
+        }
+    })
+}
+
+/// Prints formatted text to the current sink, without allocating it first.
+pub fn print_fmt(args: fmt::Arguments) -> io::Result<()> {
+    SINK.with_borrow_mut(|sink| match sink {
+        Sink::Terminal => TERMINAL.with_borrow_mut(|terminal| terminal.write_fmt(args)),
+        Sink::File(file) => file.write_fmt(args),
+        Sink::Buffer(buf) => buf.write_fmt(args),
+    })
+}
+
+/// Prints a trace of what the shell runs to the terminal, wherever the output of the
+/// traced command goes, unless traces are redirected with [`Redirect::traces_to_buffer`].
+pub fn print_trace(text: &str) -> io::Result<()> {
+    write_to(&TRACES, text.as_bytes())
+}
+
+/// Writes out everything buffered for the terminal.
+///
+/// Does nothing if called while printing, e.g. from a panic in the middle of it.
+pub fn flush() -> io::Result<()> {
+    TERMINAL.with(|terminal| match terminal.try_borrow_mut() {
+        Ok(mut terminal) => terminal.flush(),
+        Err(_) => Ok(()),
+    })
+}
+
+/// Returns true if `error` comes from printing to a pipe nobody reads anymore, like
+/// when the shell's output goes through `head`.
+pub fn is_broken_pipe(error: &color_eyre::Report) -> bool {
+    error.chain().any(|cause| {
+        let io_broken = cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe);
+        io_broken || cause.downcast_ref::<nix::Error>() == Some(&nix::Error::EPIPE)
+    })
+}
+
+/// Returns true if output currently goes to the terminal.
+pub fn is_terminal() -> bool {
+    SINK.with_borrow(|sink| matches!(sink, Sink::Terminal))
+}
+
+/// Returns the stdout a child process should use to print to the current sink.
+///
+/// `None` means the terminal, which children inherit anyway. For buffers the
+/// stdout is piped and the caller has to [`print_bytes`] what the child writes.
+pub fn child_stdout() -> io::Result<Option<Stdio>> {
+    SINK.with_borrow(|sink| match sink {
+        Sink::Terminal => Ok(None),
+        Sink::File(file) => Ok(Some(file.try_clone()?.into())),
+        Sink::Buffer(_) => Ok(Some(Stdio::piped())),
+    })
+}
+
+/// Sends everything printed into another sink until finished or dropped.
+#[must_use]
+pub struct Redirect {
+    target: &'static LocalKey<RefCell<Sink>>,
+    previous: Option<Sink>,
+}
+
+impl Redirect {
+    fn new(target: &'static LocalKey<RefCell<Sink>>, sink: Sink) -> Self {
+        let previous = target.replace(sink);
+        Self {
+            target,
+            previous: Some(previous),
+        }
+    }
+    pub fn to_file(file: File) -> Self {
+        Self::new(&SINK, Sink::File(file))
+    }
+    pub fn to_buffer() -> Self {
+        Self::new(&SINK, Sink::Buffer(Vec::new()))
+    }
+    /// Sends the traces of [`print_trace`] into a buffer instead, leaving the output alone.
+    pub fn traces_to_buffer() -> Self {
+        Self::new(&TRACES, Sink::Buffer(Vec::new()))
+    }
+    /// Restores the previous sink and returns what was captured, if this was a buffer.
+    pub fn finish(mut self) -> Vec<u8> {
+        let previous = self.previous.take().unwrap();
+        match self.target.replace(previous) {
+            Sink::Buffer(buf) => buf,
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            self.target.set(previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every write that reaches it.
+    #[derive(Default)]
+    struct MockWriter {
+        writes: Vec<Vec<u8>>,
+    }
+
+    impl Write for &mut MockWriter {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.writes.push(bytes.to_vec());
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn only_bare_newlines_are_translated() {
+        let mut mock = MockWriter::default();
+        let mut writer = ShellWriter::new(&mut mock);
+        writer.write_all(b"a\nb\r\nc\r").unwrap();
+        writer.write_all(b"\nd\n\n").unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(mock.writes, [b"a\r\nb\r\nc\r\nd\r\n\r\n"]);
+    }
+
+    #[test]
+    fn output_is_batched() {
+        let mut mock = MockWriter::default();
+        let mut writer = ShellWriter::new(&mut mock);
+        for i in 0..500 {
+            writeln!(writer, "{:4} some history line", i).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        let total: usize = mock.writes.iter().map(Vec::len).sum();
+        assert_eq!(total, 500 * 24);
+        // Once when the buffer filled up and once when flushed, instead of once per line
+        assert_eq!(mock.writes.len(), 2);
+    }
+
+    #[test]
+    fn nested_buffers() {
+        let outer = Redirect::to_buffer();
+        print_bytes(b"outer\n").unwrap();
+        let inner = Redirect::to_buffer();
+        print_bytes(b"inner\n").unwrap();
+        assert_eq!(inner.finish(), b"inner\n");
+        print_bytes(b"outer again\n").unwrap();
+        assert_eq!(outer.finish(), b"outer\nouter again\n");
+        assert!(is_terminal());
+    }
+}
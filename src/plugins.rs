@@ -0,0 +1,256 @@
+//! Programs named `yash-NAME` in the plugins folder, which run as the command `NAME`.
+//!
+//! What a plugin prints goes through, except for the lines starting with `::`, which are
+//! directives the shell follows once the plugin is done:
+//!
+//! - `::set NAME VALUE` sets the shell variable `NAME`
+//! - `::cd DIR` changes the working directory
+//! - `::alias NAME=VALUE` defines an alias, like `alias` does
+
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use bstr::ByteSlice;
+use color_eyre::eyre::eyre;
+
+use crate::{builtins, command::Command, output, shell_println, Shell, YshResult};
+
+/// What the names of plugin programs start with, before the name of their command.
+pub const PREFIX: &str = "yash-";
+
+/// What the lines a plugin prints for the shell start with.
+const SENTINEL: &[u8] = b"::";
+
+/// The commands of the plugins in `folder`, by name, with their programs.
+///
+/// Only executable files count, so a plugin can be turned off with `chmod -x`.
+pub fn find(folder: &Path) -> Vec<(String, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        return Vec::new();
+    };
+    let mut plugins = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let name = name.strip_prefix(PREFIX).filter(|name| !name.is_empty())?;
+            let path = entry.path();
+            let metadata = path.metadata().ok()?;
+            let executable = metadata.is_file() && metadata.permissions().mode() & 0o111 != 0;
+            executable.then(|| (name.to_string(), path))
+        })
+        .collect::<Vec<_>>();
+    plugins.sort();
+    plugins
+}
+
+/// A line a plugin printed for the shell, without the sentinel.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Directive {
+    Set {
+        name: String,
+        value: String,
+    },
+    Cd(String),
+    /// The `NAME=VALUE` argument of `alias`.
+    Alias(String),
+    /// A directive of another version of yash, maybe, which is skipped with a warning.
+    Unknown(String),
+}
+
+impl Directive {
+    /// Reads a directive, like `set NAME VALUE`.
+    pub fn parse(line: &str) -> YshResult<Self> {
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "" => Err(eyre!("'::{}': missing directive", line)),
+            "set" => {
+                let Some((name, value)) = rest.split_once(' ') else {
+                    return Err(eyre!("'::{}': missing value", line));
+                };
+                if !builtins::is_valid_name(name) {
+                    return Err(eyre!("'::{}': invalid variable name '{}'", line, name));
+                }
+                Ok(Self::Set {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+            }
+            "cd" if rest.is_empty() => Err(eyre!("'::{}': missing directory", line)),
+            "cd" => Ok(Self::Cd(rest.to_string())),
+            "alias" => match rest.split_once('=') {
+                Some((name, _)) if !name.is_empty() => Ok(Self::Alias(rest.to_string())),
+                _ => Err(eyre!("'::{}': expected NAME=VALUE", line)),
+            },
+            keyword => Ok(Self::Unknown(keyword.to_string())),
+        }
+    }
+}
+
+/// Splits what a plugin printed into what goes through and its directives.
+///
+/// Nothing is followed if any directive is malformed, so a broken plugin can't leave the
+/// shell halfway changed.
+pub fn read_output(output: &[u8]) -> (Vec<u8>, YshResult<Vec<Directive>>) {
+    let mut passed = Vec::new();
+    let mut directives = Vec::new();
+    let mut error = None;
+    for (number, line) in output.lines_with_terminator().enumerate() {
+        let Some(directive) = line.strip_prefix(SENTINEL) else {
+            passed.extend_from_slice(line);
+            continue;
+        };
+        if error.is_some() {
+            continue;
+        }
+        let directive = directive.trim_end_with(|c| c == '\n' || c == '\r');
+        let parsed = match directive.to_str() {
+            Ok(directive) => Directive::parse(directive),
+            Err(_) => Err(eyre!("directive isn't valid UTF-8")),
+        };
+        match parsed {
+            Ok(directive) => directives.push(directive),
+            Err(e) => error = Some(eyre!("line {}: {}", number + 1, e)),
+        }
+    }
+    (passed, error.map_or(Ok(directives), Err))
+}
+
+/// Does what the plugin of the command `name` asked for.
+pub fn apply(shell: &mut Shell, name: &str, directives: Vec<Directive>) -> YshResult<()> {
+    for directive in directives {
+        match directive {
+            Directive::Set { name, value } => shell.set_var(name, value),
+            Directive::Cd(dir) => shell.change_directory(&dir)?,
+            Directive::Alias(definition) => {
+                let command = Command {
+                    command: "alias".to_string(),
+                    args: vec![definition],
+                    ..Default::default()
+                };
+                builtins::alias(shell, command)?;
+            }
+            Directive::Unknown(keyword) => {
+                shell_println!("yash: {}: unknown directive '::{}', skipped", name, keyword)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the plugin `program` as the command `cmd`, then follows its directives.
+pub fn run(shell: &mut Shell, program: &Path, cmd: Command) -> YshResult<()> {
+    let name = cmd.command.clone();
+    let cmd = Command {
+        command: program.to_string_lossy().into_owned(),
+        ..cmd
+    };
+    let redirect = output::Redirect::to_buffer();
+    let result = shell.run_pipeline(cmd, None);
+    let printed = redirect.finish();
+    result?;
+    let (passed, directives) = read_output(&printed);
+    output::print_bytes(&passed)?;
+    let directives = directives.map_err(|e| eyre!("{}: {}", name, e))?;
+    apply(shell, &name, directives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn directives_are_parsed() {
+        let cases = [
+            (
+                "set PROJECT my project",
+                Directive::Set {
+                    name: "PROJECT".into(),
+                    value: "my project".into(),
+                },
+            ),
+            (
+                "set EMPTY ",
+                Directive::Set {
+                    name: "EMPTY".into(),
+                    value: "".into(),
+                },
+            ),
+            ("cd /tmp/a dir", Directive::Cd("/tmp/a dir".into())),
+            ("alias ll=ls -l", Directive::Alias("ll=ls -l".into())),
+            ("alias gone=", Directive::Alias("gone=".into())),
+            ("notify done", Directive::Unknown("notify".into())),
+        ];
+        for (line, directive) in cases {
+            assert_eq!(Directive::parse(line).unwrap(), directive, "{}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_directives_are_errors() {
+        let cases = [
+            ("", "'::': missing directive"),
+            (" set X 1", "':: set X 1': missing directive"),
+            ("set", "'::set': missing value"),
+            ("set X", "'::set X': missing value"),
+            ("set A-B 1", "'::set A-B 1': invalid variable name 'A-B'"),
+            ("cd", "'::cd': missing directory"),
+            ("alias ll", "'::alias ll': expected NAME=VALUE"),
+            ("alias =ls", "'::alias =ls': expected NAME=VALUE"),
+        ];
+        for (line, error) in cases {
+            assert_eq!(Directive::parse(line).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn output_is_split_from_directives() {
+        let (passed, directives) = read_output(b"one\n::set A 1\r\n two ::cd /\n::cd /\nend");
+        assert_eq!(passed, b"one\n two ::cd /\nend");
+        assert_eq!(
+            directives.unwrap(),
+            [
+                Directive::Set {
+                    name: "A".into(),
+                    value: "1".into()
+                },
+                Directive::Cd("/".into()),
+            ]
+        );
+        // What goes through still does with a broken directive
+        let (passed, directives) = read_output(b"one\n::set A 1\n::cd\ntwo\n");
+        assert_eq!(passed, b"one\ntwo\n");
+        let error = directives.unwrap_err().to_string();
+        assert_eq!(error, "line 3: '::cd': missing directory");
+    }
+
+    #[test]
+    fn only_executable_programs_are_plugins() {
+        let folder = TempDir::new("plugins");
+        std::fs::create_dir_all(folder.join("yash-dir")).unwrap();
+        let files = [
+            ("yash-b", 0o755),
+            ("yash-a", 0o700),
+            ("yash-off", 0o644),
+            ("yash-", 0o755),
+            ("other", 0o755),
+        ];
+        for (file, mode) in files {
+            let path = folder.join(file);
+            std::fs::write(&path, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let plugins = find(&folder);
+        std::fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(
+            plugins,
+            [
+                ("a".to_string(), folder.join("yash-a")),
+                ("b".to_string(), folder.join("yash-b")),
+            ]
+        );
+        assert_eq!(find(&folder), []);
+    }
+}
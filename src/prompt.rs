@@ -1,8 +1,15 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use regex::{Captures, Regex};
 
-use crate::Shell;
+use crate::{utils, Shell};
+
+pub mod git;
 
 struct Prefix(yansi_term::Style);
 
@@ -32,29 +39,307 @@ pub fn replace_colors(text: &str) -> Cow<str> {
     })
 }
 
+/// Number of columns the last line of `prompt` takes once printed.
+///
+/// Escape sequences, like the colors of `%F{#...}`, take no room.
+pub fn rendered_width(prompt: &str) -> u32 {
+    let last_line = prompt.rsplit('\n').next().unwrap_or_default();
+    let escapes = crate::static_regex!(r"\x1b\[[0-?]*[ -/]*[@-~]");
+    utils::display_width(&escapes.replace_all(last_line, "")) as u32
+}
+
+/// How `%h` and its variants show the working directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CwdForm {
+    /// `%h`, the whole path.
+    Full,
+    /// `%Nh`, only the last N components.
+    Last(usize),
+    /// `%H`, with every component but the last cut to its first character, like fish.
+    Abbreviated,
+}
+
+/// Shows `cwd` in `form`, with the home directory as `~`.
+fn format_cwd(cwd: &Path, home: Option<&Path>, form: CwdForm) -> String {
+    let path = utils::tilde_path_in(cwd, home);
+    // The root is no component, so `/usr` is no longer than `usr`
+    let (root, rest) = match path.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("", path.as_str()),
+    };
+    let components = rest
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+    match form {
+        CwdForm::Full => path,
+        CwdForm::Last(count) if components.len() <= count => path,
+        CwdForm::Last(count) => components[components.len() - count..].join("/"),
+        CwdForm::Abbreviated => {
+            let Some((last, parents)) = components.split_last() else {
+                return path;
+            };
+            let mut abbreviated = String::from(root);
+            for parent in parents {
+                // Hidden directories keep their dot, `.config` gives `.c`
+                let len = match parent.strip_prefix('.') {
+                    Some(name) => 1 + name.chars().next().map_or(0, char::len_utf8),
+                    None => parent.chars().next().map_or(0, char::len_utf8),
+                };
+                abbreviated.push_str(&parent[..len]);
+                abbreviated.push('/');
+            }
+            abbreviated.push_str(last);
+            abbreviated
+        }
+    }
+}
+
+/// What the condition codes of `%(x.true.false)` segments test.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Conditions {
+    /// `?`, the last command failed
+    failed: bool,
+    /// `#`, the shell runs as root
+    root: bool,
+    /// `j`, jobs are running in the background
+    jobs: bool,
+}
+
+impl Conditions {
+    /// Whether the condition of `code` holds, if it is one.
+    fn test(self, code: char) -> Option<bool> {
+        match code {
+            '?' => Some(self.failed),
+            '#' => Some(self.root),
+            'j' => Some(self.jobs),
+            _ => None,
+        }
+    }
+}
+
+/// How deep segments are read inside others.
+const MAX_NESTING: usize = 1;
+
+/// Replaces the `%(x.true.false)` segments of `format` with the branch their condition
+/// picks. The first character after `x` separates the branches, so `%(?|a.b|c)` works
+/// too. Segments that aren't closed, test no known condition or hold others too deep are
+/// left as they are, but not the segments inside them.
+fn resolve_conditionals(format: &str, conditions: Conditions) -> Cow<'_, str> {
+    if !format.contains("%(") {
+        return Cow::Borrowed(format);
+    }
+    let mut resolved = String::with_capacity(format.len());
+    let mut rest = format;
+    while let Some(start) = rest.find("%(") {
+        resolved.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match conditional(rest, conditions, 0) {
+            Some((branch, len)) => {
+                resolved.push_str(&branch);
+                rest = &rest[len..];
+            }
+            None => {
+                resolved.push_str("%(");
+                rest = &rest["%(".len()..];
+            }
+        }
+    }
+    resolved.push_str(rest);
+    Cow::Owned(resolved)
+}
+
+/// Reads the segment `text` starts with, `depth` segments deep, returning the branch
+/// `conditions` pick and how long the segment is.
+fn conditional(text: &str, conditions: Conditions, depth: usize) -> Option<(String, usize)> {
+    let mut chars = text.strip_prefix("%(")?.chars();
+    let code = chars.next()?;
+    let holds = conditions.test(code)?;
+    let delimiter = chars.next().filter(|&c| c != ')')?;
+    let mut branches = [String::new(), String::new()];
+    let mut branch = 0;
+    let mut i = "%(".len() + code.len_utf8() + delimiter.len_utf8();
+    while let Some(c) = text[i..].chars().next() {
+        if text[i..].starts_with("%(") {
+            // The segment around one that can't be read, or is too deep, can't be either
+            if depth == MAX_NESTING {
+                return None;
+            }
+            let (inner, len) = conditional(&text[i..], conditions, depth + 1)?;
+            branches[branch].push_str(&inner);
+            i += len;
+            continue;
+        }
+        match c {
+            ')' if branch == 1 => {
+                let [then, otherwise] = branches;
+                return Some((if holds { then } else { otherwise }, i + 1));
+            }
+            c if c == delimiter && branch == 0 => branch = 1,
+            c => branches[branch].push(c),
+        }
+        i += c.len_utf8();
+    }
+    None
+}
+
 const DEFAULT_PROMPT: &str = "%F{#ff8080}%n@%m %h%f $ ";
 
-pub fn get_prompt(shell: &Shell) -> String {
+fn escapes_regex() -> &'static Regex {
     static REGEX: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
-    let regex = REGEX.get_or_init(|| Regex::new(r#"%([nmhf])\b"#).unwrap());
-    let home = crate::builtins::get_home();
-    let cwd = shell.cwd.to_string_lossy().replace(&home, "~");
-    let username = crate::builtins::get_username();
+    REGEX.get_or_init(|| Regex::new(r#"%([nmhfdbgH]\b|~|[1-9][0-9]*h\b)"#).unwrap())
+}
+
+/// Everything a prompt is made of, so it is only rendered again when some of it changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PromptKey {
+    format: String,
+    cwd: PathBuf,
+    cwd_deleted: bool,
+    dir_stack_depth: usize,
+    last_status: i32,
+    /// Whether the shell runs as root, for `%(#...)`
+    root: bool,
+    /// Whether jobs run in the background, for `%(j...)`
+    jobs: bool,
+    /// As `%d` shows it, so commands that take about as long leave it alone
+    duration: String,
+    home: Option<String>,
+    username: Option<String>,
+    /// The `%b` and `%g` escapes, which git changes without the shell knowing
+    git: (String, String),
+    /// Bumped by [`PromptCache::invalidate`]
+    generation: u64,
+}
+
+/// The last prompt rendered, and what it was rendered from.
+#[derive(Debug, Default)]
+pub struct PromptCache {
+    cached: RefCell<Option<(PromptKey, String)>>,
+    generation: Cell<u64>,
+    #[cfg(test)]
+    renders: Cell<usize>,
+}
+
+impl PromptCache {
+    /// Makes the next prompt be rendered again, for what changes it behind the key's back,
+    /// like the host name.
+    pub fn invalidate(&self) {
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    /// How many times a prompt was rendered instead of reused.
+    #[cfg(test)]
+    pub fn renders(&self) -> usize {
+        self.renders.get()
+    }
+}
+
+/// Starts what the next prompt needs that takes a while, so it may be done by the time
+/// the prompt is drawn. Only `git status` does for now, in the background.
+pub fn prepare_prompt(shell: &Shell) {
+    let format = shell.get_var("PS1").unwrap_or(DEFAULT_PROMPT);
+    if shows_git(format) {
+        shell.git_status.escapes(&shell.cwd);
+    }
+}
+
+/// Whether `format` has a `%b` or `%g`, which need git to be rendered.
+fn shows_git(format: &str) -> bool {
+    escapes_regex()
+        .captures_iter(format)
+        .any(|c| matches!(&c[1], "b" | "g"))
+}
+
+/// The prompt to show, `$PS1` rendered, or the one shown last if nothing it shows changed.
+pub fn get_prompt(shell: &Shell) -> String {
+    let format = shell.get_var("PS1").unwrap_or(DEFAULT_PROMPT);
+    // Only looked for when shown, since finding whether it is dirty runs git
+    let git = match shows_git(format) {
+        true => shell.git_status.escapes(&shell.cwd),
+        false => Default::default(),
+    };
+    let cache = &shell.prompt_cache;
+    let key = PromptKey {
+        format: format.to_string(),
+        cwd: shell.cwd.clone(),
+        cwd_deleted: shell.cwd_deleted,
+        dir_stack_depth: shell.dir_stack.len(),
+        last_status: shell.last_status(),
+        root: nix::unistd::geteuid().is_root(),
+        jobs: shell.jobs.len() > 0,
+        duration: shell
+            .last_duration()
+            .map(crate::utils::format_duration)
+            .unwrap_or_default(),
+        home: crate::builtins::get_home(),
+        username: crate::builtins::get_username(),
+        git,
+        generation: cache.generation.get(),
+    };
+    if let Some((cached_key, prompt)) = &*cache.cached.borrow() {
+        if *cached_key == key {
+            return prompt.clone();
+        }
+    }
+    #[cfg(test)]
+    cache.renders.set(cache.renders.get() + 1);
+    let prompt = render(&key);
+    *cache.cached.borrow_mut() = Some((key, prompt.clone()));
+    prompt
+}
+
+fn render(key: &PromptKey) -> String {
+    let home = key.home.as_deref().map(Path::new);
+    let cwd = |form| {
+        let mut cwd = format_cwd(&key.cwd, home, form);
+        if key.cwd_deleted {
+            cwd.push_str(" (deleted)");
+        }
+        cwd
+    };
+    let username = key.username.clone().unwrap_or_else(|| String::from("?"));
     let hostname = match nix::unistd::gethostname() {
         Ok(h) => h.to_string_lossy().into_owned(),
         Err(_) => String::from("?"),
     };
+    let (branch, dirty) = key.git.clone();
     let replaces_table: HashMap<&str, String> = [
         ("n", username),
         ("m", hostname),
-        ("h", cwd),
+        ("h", cwd(CwdForm::Full)),
+        ("H", cwd(CwdForm::Abbreviated)),
         ("f", String::from("\x1B[0m")),
+        ("b", branch),
+        ("g", dirty),
+        // How many directories `pushd` saved, if any
+        (
+            "~",
+            match key.dir_stack_depth {
+                0 => String::new(),
+                depth => depth.to_string(),
+            },
+        ),
+        ("d", key.duration.clone()),
     ]
     .into_iter()
     .collect();
-    let prompt_fmt = shell.get_var("PS1").unwrap_or(DEFAULT_PROMPT);
-    let args_replaced = regex.replace_all(&prompt_fmt, |captures: &Captures| {
-        &replaces_table[&captures[1]]
+    let conditions = Conditions {
+        failed: key.last_status != 0,
+        root: key.root,
+        jobs: key.jobs,
+    };
+    // Before the escapes, so what they are replaced with is never read as a segment
+    let format = resolve_conditionals(&key.format, conditions);
+    let args_replaced = escapes_regex().replace_all(&format, |captures: &Captures| {
+        let escape = &captures[1];
+        match escape
+            .strip_suffix('h')
+            .and_then(|count| count.parse().ok())
+        {
+            Some(count) => Cow::Owned(cwd(CwdForm::Last(count))),
+            None => Cow::Borrowed(replaces_table[escape].as_str()),
+        }
     });
     replace_colors(&args_replaced).into_owned()
 }
@@ -94,4 +379,164 @@ mod tests {
             replace_colors("%F{#FF0000}I am red!%f%F{#00FF00}I am green!%f%F{#0000FF}I am blue!%f");
         assert_eq!(text, "\x1b[38;2;255;0;0mI am red!%f\x1b[38;2;0;255;0mI am green!%f\x1b[38;2;0;0;255mI am blue!%f");
     }
+    #[test]
+    fn width_without_colors() {
+        assert_eq!(
+            rendered_width(&replace_colors("%F{#FF0000}red\x1b[0m $ ")),
+            6
+        );
+        assert_eq!(rendered_width("\x1b[1;38;5;2mok\x1b[K"), 2);
+        assert_eq!(rendered_width("first line\nnext $ "), 7);
+    }
+    #[test]
+    fn width_of_multibyte_paths() {
+        let prompt = replace_colors("%F{#00FF00}~/café/日本\x1b[0m $ ");
+        assert_eq!(rendered_width(&prompt), 14);
+    }
+    #[test]
+    fn cwd_forms() {
+        use CwdForm::*;
+        let home = Some(Path::new("/home/user"));
+        let cases = [
+            ("/home/user", home, Full, "~"),
+            (
+                "/home/user/projects/yash/src",
+                home,
+                Full,
+                "~/projects/yash/src",
+            ),
+            // Only a leading home directory is replaced
+            ("/backup/home/user/x", home, Full, "/backup/home/user/x"),
+            ("/home/username", home, Full, "/home/username"),
+            (
+                "/home/user/projects/yash/src",
+                None,
+                Full,
+                "/home/user/projects/yash/src",
+            ),
+            ("/home/user/projects/yash/src", home, Last(2), "yash/src"),
+            ("/home/user/projects", home, Last(2), "~/projects"),
+            ("/usr/local/lib", home, Last(2), "local/lib"),
+            ("/usr/local", home, Last(2), "/usr/local"),
+            ("/", home, Last(1), "/"),
+            (
+                "/home/user/projects/yash/src",
+                home,
+                Abbreviated,
+                "~/p/y/src",
+            ),
+            ("/home/user/.config/yash", home, Abbreviated, "~/.c/yash"),
+            ("/usr/local/lib", home, Abbreviated, "/u/l/lib"),
+            ("/srv/été/données", None, Abbreviated, "/s/é/données"),
+            ("/home/user", home, Abbreviated, "~"),
+            ("/", home, Abbreviated, "/"),
+        ];
+        for (cwd, home, form, shown) in cases {
+            assert_eq!(
+                format_cwd(Path::new(cwd), home.map(Path::new), form),
+                shown,
+                "{} as {:?}",
+                cwd,
+                form
+            );
+        }
+    }
+
+    #[test]
+    fn cwd_escapes_in_prompt() {
+        let mut shell = Shell {
+            cwd: "/usr/local/share/doc".into(),
+            ..Default::default()
+        };
+        shell.set_var("PS1".into(), "%h|%2h|%H|%12h $ ".into());
+        assert_eq!(
+            get_prompt(&shell),
+            "/usr/local/share/doc|share/doc|/u/l/s/doc|/usr/local/share/doc $ "
+        );
+    }
+
+    #[test]
+    fn conditions_pick_their_branch() {
+        let none = Conditions::default();
+        let all = Conditions {
+            failed: true,
+            root: true,
+            jobs: true,
+        };
+        for (format, when_none, when_all) in [
+            ("%(?.fail.ok) $ ", "ok $ ", "fail $ "),
+            ("%(#.#.$) ", "$ ", "# "),
+            ("[%(j.jobs.)]", "[]", "[jobs]"),
+            ("%(?..%(j.x.y))", "y", ""),
+            // Any character can separate the branches
+            ("%(?|1.5|ok)", "ok", "1.5"),
+            ("%(#,a.b,c)", "c", "a.b"),
+        ] {
+            assert_eq!(resolve_conditionals(format, none), when_none, "{}", format);
+            assert_eq!(resolve_conditionals(format, all), when_all, "{}", format);
+        }
+    }
+
+    #[test]
+    fn conditions_nest_once() {
+        let failed = Conditions {
+            failed: true,
+            ..Default::default()
+        };
+        let format = "%(?.[%(j.bg.fg) err].ok)";
+        assert_eq!(resolve_conditionals(format, failed), "[fg err]");
+        // The separator of the outer one may be in the inner one
+        assert_eq!(resolve_conditionals("%(?.%(#.a.b).c)", failed), "b");
+        // Deeper than that, the outer one is left as written
+        let format = "%(?.%(j.%(#.a.b).c).d)";
+        assert_eq!(resolve_conditionals(format, failed), "%(?.c.d)");
+    }
+
+    #[test]
+    fn unfinished_conditions_stay_as_written() {
+        let failed = Conditions {
+            failed: true,
+            ..Default::default()
+        };
+        for format in [
+            "%(",
+            "%(?",
+            "%(?.",
+            "%(?)",
+            "%(?.a",
+            "%(?.a.b",
+            "%(x.a.b)",
+            "100%(",
+            "%(?.a)",
+            "%(?.100%(.a)",
+        ] {
+            assert_eq!(resolve_conditionals(format, failed), format);
+        }
+        // Nor does what is inside them, or after them
+        assert_eq!(resolve_conditionals("%(?.%(?.a.b)", failed), "%(?.a");
+        assert_eq!(resolve_conditionals("%(x %(?.no.) %(", failed), "%(x no %(");
+    }
+
+    #[test]
+    fn conditions_in_prompt() {
+        let mut shell = Shell {
+            cwd: "/tmp".into(),
+            ..Default::default()
+        };
+        shell.set_var("PS1".into(), "%(?.[%h].%h) %(j.&.)$ ".into());
+        assert_eq!(get_prompt(&shell), "/tmp $ ");
+        shell.set_status(2);
+        assert_eq!(get_prompt(&shell), "[/tmp] $ ");
+    }
+
+    #[test]
+    fn width_of_default_prompt() {
+        let shell = Shell::default();
+        let prompt = get_prompt(&shell);
+        let plain = prompt
+            .replace("\x1b[38;2;255;128;128m", "")
+            .replace("\x1b[0m", "");
+        assert!(plain.ends_with(" $ "));
+        assert_eq!(rendered_width(&prompt), utils::display_width(&plain) as u32);
+    }
 }
@@ -0,0 +1,224 @@
+//! Branch and dirty state of the git repository, for the `%b` and `%g` prompt escapes.
+//!
+//! The branch comes straight from `.git/HEAD`. Whether the work tree is dirty takes a
+//! `git status`, which may be slow on huge repositories, so it runs in the background and
+//! the prompt shows a placeholder until it is done.
+
+use std::{
+    cell::RefCell,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How long `git status` may run before it is killed and the state is left unknown.
+const STATUS_TIMEOUT: Duration = Duration::from_millis(150);
+
+/// Shown by `%g` while `git status` is running.
+const PENDING_MARKER: &str = "…";
+
+/// Shown by `%g` when the work tree has changes.
+const DIRTY_MARKER: &str = "*";
+
+/// A repository, found from one of its directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Repo {
+    work_tree: PathBuf,
+    git_dir: PathBuf,
+}
+
+/// Finds the repository `dir` is in, from the closest `.git` directory or `gitdir:` file.
+fn find_repo(dir: &Path) -> Option<Repo> {
+    dir.ancestors().find_map(|work_tree| {
+        let dot_git = work_tree.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else {
+            // Worktrees and submodules point at their git directory instead
+            let contents = std::fs::read_to_string(&dot_git).ok()?;
+            work_tree.join(contents.trim().strip_prefix("gitdir: ")?)
+        };
+        Some(Repo {
+            work_tree: work_tree.to_owned(),
+            git_dir,
+        })
+    })
+}
+
+/// What the contents of `HEAD` point at: the branch, or the short hash when detached.
+fn parse_head(head: &str) -> Option<String> {
+    let head = head.trim();
+    if let Some(reference) = head.strip_prefix("ref: ") {
+        let branch = reference.strip_prefix("refs/heads/").unwrap_or(reference);
+        return Some(branch.to_owned());
+    }
+    let is_hash = head.len() >= 40 && head.bytes().all(|b| b.is_ascii_hexdigit());
+    is_hash.then(|| head[..7].to_owned())
+}
+
+/// Whether the dirty state of a repository may have changed since it was last computed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CacheKey {
+    git_dir: PathBuf,
+    head: Option<SystemTime>,
+    index: Option<SystemTime>,
+}
+
+impl CacheKey {
+    fn of(git_dir: &Path) -> Self {
+        let modified = |name| std::fs::metadata(git_dir.join(name)).ok()?.modified().ok();
+        Self {
+            git_dir: git_dir.to_owned(),
+            head: modified("HEAD"),
+            index: modified("index"),
+        }
+    }
+}
+
+/// Runs `git status` in `work_tree`, giving up after [`STATUS_TIMEOUT`].
+///
+/// Only the first byte of the output is read, which is enough to tell it is dirty.
+fn is_dirty(work_tree: &Path) -> Option<bool> {
+    let mut child = Command::new("git")
+        .args(["--no-optional-locks", "status", "--porcelain"])
+        .current_dir(work_tree)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let mut stdout = child.stdout.take()?;
+    let (done, finished) = mpsc::channel::<()>();
+    // Killing git ends the read below
+    let watchdog = thread::spawn(move || -> (Child, bool) {
+        let timed_out = finished.recv_timeout(STATUS_TIMEOUT) == Err(RecvTimeoutError::Timeout);
+        if timed_out {
+            let _ = child.kill();
+        }
+        (child, timed_out)
+    });
+    let read = stdout.read(&mut [0]).unwrap_or(0);
+    let _ = done.send(());
+    let (mut child, timed_out) = watchdog.join().ok()?;
+    if read != 0 {
+        // The rest of the output doesn't matter
+        let _ = child.kill();
+    }
+    let status = child.wait().ok()?;
+    match (timed_out, read != 0) {
+        (true, _) => None,
+        (false, true) => Some(true),
+        (false, false) => status.success().then_some(false),
+    }
+}
+
+#[derive(Debug)]
+enum Dirty {
+    Pending(Receiver<Option<bool>>),
+    /// `None` when `git status` failed or took too long.
+    Known(Option<bool>),
+}
+
+/// The dirty state of the last repository the prompt showed.
+#[derive(Debug, Default)]
+pub struct GitStatus {
+    cached: RefCell<Option<(CacheKey, Dirty)>>,
+}
+
+impl GitStatus {
+    /// The `%b` and `%g` escapes for the repository `dir` is in, empty outside of one.
+    pub fn escapes(&self, dir: &Path) -> (String, String) {
+        let Some(repo) = find_repo(dir) else {
+            return Default::default();
+        };
+        let branch = std::fs::read_to_string(repo.git_dir.join("HEAD"))
+            .ok()
+            .and_then(|head| parse_head(&head))
+            .unwrap_or_default();
+        let key = CacheKey::of(&repo.git_dir);
+        let mut cached = self.cached.borrow_mut();
+        if cached.as_ref().map(|(cached, _)| cached) != Some(&key) {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || sender.send(is_dirty(&repo.work_tree)));
+            *cached = Some((key, Dirty::Pending(receiver)));
+        }
+        let (_, dirty) = cached.as_mut().unwrap();
+        if let Dirty::Pending(receiver) = dirty {
+            match receiver.try_recv() {
+                Ok(result) => *dirty = Dirty::Known(result),
+                Err(TryRecvError::Disconnected) => *dirty = Dirty::Known(None),
+                Err(TryRecvError::Empty) => (),
+            }
+        }
+        let marker = match dirty {
+            Dirty::Pending(_) => PENDING_MARKER,
+            Dirty::Known(Some(true)) => DIRTY_MARKER,
+            Dirty::Known(_) => "",
+        };
+        (branch, marker.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn head_names_the_branch_or_commit() {
+        assert_eq!(
+            parse_head("ref: refs/heads/main\n").as_deref(),
+            Some("main")
+        );
+        assert_eq!(
+            parse_head("ref: refs/heads/fix/prompt").as_deref(),
+            Some("fix/prompt")
+        );
+        let detached = "4b825dc642cb6eb9a060e54bf8d69288fbee4904\n";
+        assert_eq!(parse_head(detached).as_deref(), Some("4b825dc"));
+        assert_eq!(parse_head("garbage"), None);
+    }
+
+    #[test]
+    fn repo_is_found_from_subdirectories() {
+        let base = TempDir::new("git");
+        let git_dir = base.join("repo/.git");
+        std::fs::create_dir_all(&git_dir).unwrap();
+        std::fs::create_dir_all(base.join("repo/src/deep")).unwrap();
+        std::fs::create_dir_all(base.join("worktree")).unwrap();
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        std::fs::write(base.join("worktree/.git"), "gitdir: ../repo/.git\n").unwrap();
+
+        let repo = find_repo(&base.join("repo/src/deep")).unwrap();
+        assert_eq!(repo.work_tree, base.join("repo"));
+        assert_eq!(repo.git_dir, git_dir);
+        let worktree = find_repo(&base.join("worktree")).unwrap();
+        assert_eq!(worktree.git_dir, base.join("worktree/../repo/.git"));
+
+        let status = GitStatus::default();
+        let (branch, _) = status.escapes(&base.join("repo/src"));
+        assert_eq!(branch, "main");
+        std::fs::remove_dir_all(&base).unwrap();
+        assert_eq!(status.escapes(&base), (String::new(), String::new()));
+    }
+
+    #[test]
+    fn cache_key_follows_head_and_index() {
+        let base = TempDir::new("gitkey");
+        std::fs::write(base.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        let key = CacheKey::of(&base);
+        assert_eq!(key.index, None);
+        assert_eq!(CacheKey::of(&base), key);
+
+        let index = std::fs::File::create(base.join("index")).unwrap();
+        let with_index = CacheKey::of(&base);
+        assert_ne!(with_index, key);
+        index
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1))
+            .unwrap();
+        assert_ne!(CacheKey::of(&base), with_index);
+    }
+}
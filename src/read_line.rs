@@ -1,20 +1,51 @@
+use std::{collections::VecDeque, time::Duration};
+
 use bstr::ByteSlice;
-use glam::UVec2;
 
-use crate::{read, sdbg, shell_println, utils::char_count, write, YshResult};
+use crate::{
+    commands, output, sdbg, shell_println,
+    tty::Tty,
+    utils::{cluster_starts, display_width},
+    YshResult,
+};
 
 use self::{completion::SelectionDirection, history::History};
 
 pub mod completion;
 pub mod cursor;
+pub mod highlight;
 pub mod history;
 pub mod text_field;
+pub mod word;
+
+#[cfg(test)]
+mod pty_tests;
 
 #[derive(Debug, Default)]
 pub struct ReadLine {
     history: History,
     completion: completion::Completer,
     text_field: text_field::TextField,
+    tty: Tty,
+    /// Prompt of the line being read, to draw it again
+    prompt: String,
+    /// Columns taken by the last line of the prompt
+    prompt_width: u32,
+    highlighter: Option<highlight::Highlighter>,
+    /// Styles of the characters of the line as it was last drawn highlighted
+    styles: Vec<(char, highlight::Style)>,
+    /// Column of the terminal cursor, relative to the start of the line
+    column: u32,
+    /// Rest of a history line suggested after the cursor, while it is shown
+    suggestion: Option<String>,
+    /// Pasted lines still to be run, each put in the line of a prompt of its own
+    pending: VecDeque<String>,
+    /// How many pasted lines wait, shown after the line instead of a suggestion
+    paste_hint: Option<String>,
+    /// Whether the terminal didn't say where the cursor is, so it isn't asked again
+    cursor_unknown: bool,
+    /// Whether copying to the clipboard of the terminal was turned off
+    clipboard_off: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -23,99 +54,406 @@ pub enum Execute {
     Cancel,
     Command(String),
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PromptMode {
+    /// Read a whole line with editing.
+    #[default]
+    Line,
+    /// Return as soon as a single key is pressed.
+    Key,
+    /// Like [`PromptMode::Line`], but nothing typed is echoed.
+    Hidden,
+}
+
+/// Options for [`ReadLine::prompt_once`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PromptOpts {
+    pub mode: PromptMode,
+    pub history: bool,
+    pub completion: bool,
+}
+
+impl PromptOpts {
+    pub fn new(mode: PromptMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+    /// Options used by the interactive shell prompt.
+    pub fn interactive() -> Self {
+        Self {
+            mode: PromptMode::Line,
+            history: true,
+            completion: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PromptAnswer {
+    Line(String),
+    Key(char),
+    Cancel,
+    Eof,
+}
+/// Columns the line keeps even if the prompt takes the whole terminal width.
+const MIN_LINE_WIDTH: u32 = 10;
+
+/// How long the rest of a UTF-8 character cut at the end of a read may take to arrive.
+const UTF8_TAIL_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How long to wait for input at once before reading again.
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Ends a line left unfinished before the prompt, showing that it was.
+const PARTIAL_LINE_MARK: &[u8] = b"\x1b[7m%\x1b[27m\r\n";
+
+/// Shown after the line while pasted lines wait, after how many there are.
+const PASTE_HINT: &str = " pasted — Enter to run each, Ctrl-C to drop]";
+const PASTE_HINT_ONE: &str = " [+1 line pasted — Enter to run it, Ctrl-C to drop]";
+
+/// The start of `text` that fits in `room` columns, without cutting a character.
+fn fit_in(text: &str, room: usize) -> &str {
+    let end = cluster_starts(text)
+        .into_iter()
+        .chain([text.len()])
+        .take_while(|&end| display_width(&text[..end]) <= room)
+        .last()
+        .unwrap_or_default();
+    &text[..end]
+}
+
+/// Returns how many bytes the UTF-8 character starting with `i` takes, or `None` if `i`
+/// doesn't start a multibyte character, like ASCII, continuation and invalid bytes.
 pub fn utf8_byte_len(i: u8) -> Option<u8> {
-    if i >= 192 {
-        let len = if i >> 5 & 1 == 0 {
-            2
-        } else if i >> 6 & 1 == 0 {
-            3
-        } else if i >> 7 & 1 == 0 {
-            4
-        } else {
-            panic!("Invalid utf-8 sequence!")
-        };
-        return Some(len);
+    match i {
+        0xc0..=0xdf => Some(2),
+        0xe0..=0xef => Some(3),
+        0xf0..=0xf7 => Some(4),
+        _ => None,
+    }
+}
+
+/// Decodes input read from the terminal, leaving invalid UTF-8 out.
+///
+/// Returns the text and whether something was left out.
+fn decode_input(input: &[u8]) -> (std::borrow::Cow<'_, str>, bool) {
+    match std::str::from_utf8(input) {
+        Ok(text) => (text.into(), false),
+        Err(_) => {
+            let text: String = input.utf8_chunks().map(|chunk| chunk.valid()).collect();
+            (text.into(), true)
+        }
     }
-    None
 }
 
 impl ReadLine {
-    pub fn new_with_history(lines: Vec<String>) -> Self {
+    pub fn new_with_history(history: impl Into<History>) -> Self {
         Self {
-            history: History::from_lines(lines),
+            history: history.into(),
             ..Default::default()
         }
     }
     pub fn history(&self) -> &[String] {
         self.history.lines()
     }
-    fn aligned_read(c: &mut [u8]) -> nix::Result<&[u8]> {
-        loop {
-            let mut extra = 0;
-            if read(&mut c[0..1])? != 0 {
-                if c[0] == b'\x1b' {
-                    extra = read(&mut c[1..])?;
-                } else if let Some(utf8len) = utf8_byte_len(c[0]) {
-                    extra = read(&mut c[1..utf8len as usize])?;
-                }
-                return Ok(&c[0..1 + extra]);
-            } else {
-                continue;
-            };
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+    /// Returns how many bytes are missing to complete the UTF-8 character cut at the end of `buf`.
+    fn missing_utf8_bytes(buf: &[u8]) -> usize {
+        let tail = &buf[buf.len().saturating_sub(4)..];
+        let Some(start) = tail.iter().rposition(|b| b & 0xc0 != 0x80) else {
+            return 0;
+        };
+        match utf8_byte_len(tail[start]) {
+            Some(len) => (len as usize).saturating_sub(tail.len() - start),
+            None => 0,
         }
     }
 
+    /// Reads all the input available at once, so pastes aren't handled byte by byte.
+    ///
+    /// Blocks until there is some input, and doesn't cut a UTF-8 character in half unless
+    /// the rest of it never comes. A byte that can't continue the character is left for
+    /// the next read.
+    fn aligned_read(tty: Tty, c: &mut [u8]) -> nix::Result<&[u8]> {
+        // Room for completing a cut character
+        let end = c.len() - 4;
+        let mut len = tty.read(&mut c[..end])?;
+        while len == 0 {
+            // Reads may return right away without input, so wait for some instead of spinning
+            let ready = tty.poll_input(INPUT_POLL_INTERVAL)?;
+            len = tty.read(&mut c[..end])?;
+            if ready && len == 0 {
+                // The input ended, which would be ready again forever
+                return Err(nix::Error::EIO);
+            }
+        }
+        while len < end && tty.poll_input(Duration::ZERO)? {
+            match tty.read(&mut c[len..end])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        for _ in 0..Self::missing_utf8_bytes(&c[..len]) {
+            if !tty.poll_input(UTF8_TAIL_TIMEOUT)? || tty.read(&mut c[len..len + 1])? == 0 {
+                break;
+            }
+            if c[len] & 0xc0 != 0x80 {
+                crate::tty::unread(&c[len..len + 1]);
+                break;
+            }
+            len += 1;
+        }
+        Ok(&c[..len])
+    }
+
     pub fn scroll_history(&mut self, offset: isize) -> YshResult<()> {
         if let Some(new_line) = self.history.scroll(self.text_field.text(), offset) {
             let response = self.text_field.set_text(new_line);
-            write(&response.bytes)?;
+            self.draw(&response.bytes)?;
         } else {
-            write(cursor::bell())?;
+            self.tty.write(cursor::bell())?;
         }
         Ok(())
     }
 
     /// This function is not a method because of missing disjoint borrow rules
-    // !TODO: put this inside text_field?
-    fn word_at_cursor(text_field: &text_field::TextField) -> &str {
-        let line = text_field.text();
-        let cursor_pos = text_field.cursor_pos();
-        let UVec2 { x: word_end, .. } = cursor_pos;
-        let word_end = word_end as usize;
-        if word_end != 0 && line.chars().nth(word_end - 1) != Some(' ') {
-            // Find the start of the word by searching backwards for a space
-            let word_start = line[0..word_end]
-                .rfind(' ')
-                .map(|i| i + 1)
-                .unwrap_or_default();
-            &line[word_start..word_end]
-        } else {
-            ""
+    fn word_at_cursor(text_field: &text_field::TextField) -> word::Word<'_> {
+        word::word_at(text_field.text(), text_field.cursor_index())
+    }
+
+    /// Replaces the word at the cursor with the completion, leaving the cursor after it.
+    ///
+    /// Anything but a directory is followed by a space for the next argument, or skips the
+    /// one already there. The text is inserted as is, so no key in it is interpreted.
+    fn accept_completion(
+        text_field: &mut text_field::TextField,
+        completion: &completion::CompletionInfo,
+    ) -> text_field::Response {
+        let word = sdbg!(Self::word_at_cursor(text_field).text);
+        let word_count = cluster_starts(word).len() as u32;
+        text_field.move_left(word_count);
+        text_field.erase_right(word_count);
+        let mut response = text_field.insert(completion.item());
+        if !completion.is_dir() {
+            let rest = &text_field.text()[text_field.cursor_index()..];
+            let space = if rest.starts_with(' ') {
+                text_field.move_right(1);
+                text_field.take_response()
+            } else {
+                text_field.insert(" ")
+            };
+            response.bytes.extend(space.bytes);
         }
+        response
     }
 
     pub fn complete_next(&mut self, direction: SelectionDirection) -> YshResult<()> {
         let word = Self::word_at_cursor(&self.text_field);
-        self.completion.next(word, direction)?;
+        self.completion
+            .next(self.tty, word.text, word.position, word.command, direction)?;
         Ok(())
     }
 
-    fn handle_response(&mut self, response: text_field::Response) -> YshResult<Option<Execute>> {
+    /// Enables syntax highlighting, or disables it with `None`.
+    pub fn set_highlighter(&mut self, highlighter: Option<highlight::Highlighter>) {
+        self.highlighter = highlighter;
+    }
+
+    /// Writes the changes the text field asks for, or redraws the whole line when
+    /// highlighting and the colors of the rest of the line change too.
+    ///
+    /// Lines scrolled sideways are left uncolored.
+    fn draw(&mut self, bytes: &[u8]) -> nix::Result<()> {
+        if self.text_field.is_scrolled() {
+            self.styles.clear();
+        }
+        let bytes = match &self.highlighter {
+            Some(highlighter) if !bytes.is_empty() && !self.text_field.is_scrolled() => {
+                let text = self.text_field.text();
+                let rest = &text[self.text_field.cursor_index()..];
+                let highlighted = highlighter.highlight(text);
+                let styles = highlight::styles(&highlighted);
+                let kept =
+                    highlight::edit_keeps_styles(&self.styles, &styles, rest.chars().count());
+                self.styles = styles;
+                match kept {
+                    true => bytes.to_vec(),
+                    false => commands![
+                        cursor::move_left(self.column),
+                        highlighted,
+                        cursor::kill_line(),
+                        cursor::move_left(display_width(rest) as u32),
+                    ],
+                }
+            }
+            _ => bytes.to_vec(),
+        };
+        self.column = self.text_field.cursor_pos().x;
+        self.tty.write(&bytes)
+    }
+
+    /// Shows the rest of the latest history line starting like the text, when the cursor
+    /// is at its end, and erases the previous suggestion.
+    ///
+    /// Without a suggestion, how many pasted lines wait to be run is shown there instead.
+    fn update_suggestion(&mut self, opts: &PromptOpts) -> nix::Result<()> {
+        let text = self.text_field.text();
+        let at_end = self.text_field.cursor_index() == text.len();
+        let room = self.text_field.room();
+        let suggestion = (opts.history && opts.mode == PromptMode::Line && at_end)
+            .then(|| self.history.find_prefix(text))
+            .flatten()
+            .map(|line| fit_in(&line[text.len()..], room).to_string())
+            .filter(|suffix| !suffix.is_empty());
+        let paste_hint = match self.pending.len() {
+            _ if suggestion.is_some() || !opts.history => None,
+            0 => None,
+            1 => Some(fit_in(PASTE_HINT_ONE, room).to_string()),
+            count => Some(fit_in(&format!(" [+{} lines{}", count, PASTE_HINT), room).to_string()),
+        };
+        let shown = self.suggestion.is_some() || self.paste_hint.is_some();
+        if suggestion.is_none() && paste_hint.is_none() && !shown {
+            return Ok(());
+        }
+        let rest = self.text_field.rest_width();
+        let mut bytes = commands![cursor::move_right(rest), cursor::kill_line()];
+        if let Some(hint) = suggestion.as_ref().or(paste_hint.as_ref()) {
+            bytes.extend(commands![
+                "\x1b[2m",
+                hint,
+                "\x1b[22m",
+                cursor::move_left(display_width(hint) as u32),
+            ]);
+        }
+        bytes.extend(cursor::move_left(rest));
+        self.suggestion = suggestion;
+        self.paste_hint = paste_hint;
+        self.tty.write(&bytes)
+    }
+
+    /// Starts a new line after a reverse video `%` if the cursor isn't at the start of one,
+    /// like after output that didn't end with a newline, so the prompt starts at column 1.
+    pub fn mark_partial_line(&mut self) -> YshResult<()> {
+        if self.cursor_unknown {
+            return Ok(());
+        }
+        match cursor::get_cursor_pos(self.tty) {
+            Ok(pos) if pos.x != 1 => self.tty.write(PARTIAL_LINE_MARK)?,
+            Ok(_) => (),
+            // Waiting for an answer at every prompt would slow them all down
+            Err(_) => self.cursor_unknown = true,
+        }
+        Ok(())
+    }
+
+    /// Makes the lines of a paste after the first one wait to be run, each after Enter is
+    /// pressed on it, instead of running right away.
+    pub fn set_paste_confirm(&mut self, enabled: bool) {
+        self.text_field.set_confirm_paste(enabled);
+    }
+
+    /// Sets whether Ctrl-X Ctrl-C copies the line to the clipboard of the terminal, which
+    /// some terminals don't take well.
+    pub fn set_clipboard(&mut self, enabled: bool) {
+        self.clipboard_off = !enabled;
+    }
+
+    /// Sets whether the terminal can insert and delete characters itself, see
+    /// [`text_field::TextField::set_edit_in_place`].
+    pub fn set_edit_in_place(&mut self, enabled: bool) {
+        self.text_field.set_edit_in_place(enabled);
+    }
+
+    /// Sets the commands completed in command position.
+    pub fn set_commands(&mut self, commands: crate::command::path_cache::Commands) {
+        self.completion.set_commands(commands);
+    }
+
+    /// Sets the variable names completed after `$`.
+    pub fn set_variables(&mut self, names: Vec<String>) {
+        self.completion.set_variables(names);
+    }
+
+    /// Sets the alias names completed after `unalias`.
+    pub fn set_aliases(&mut self, names: Vec<String>) {
+        self.completion.set_aliases(names);
+    }
+
+    /// Sets how many files are completed at most.
+    pub fn set_file_limit(&mut self, limit: usize) {
+        self.completion.set_file_limit(limit);
+    }
+
+    /// Sets whether completed files are colored by what they are, like `ls --color`.
+    pub fn set_file_colors(&mut self, enabled: bool) {
+        self.completion.set_file_colors(enabled);
+    }
+
+    /// Sets how completion items are matched with the word and ordered.
+    pub fn set_completion_matcher(&mut self, matcher: completion::Matcher) {
+        self.completion.set_matcher(matcher);
+    }
+
+    fn handle_response(
+        &mut self,
+        response: text_field::Response,
+        opts: &PromptOpts,
+    ) -> YshResult<Option<PromptAnswer>> {
         use text_field::{Commands, SpecialKey};
-        write(&response.bytes)?;
+        if opts.mode != PromptMode::Hidden {
+            self.draw(&response.bytes)?;
+        }
+        crate::tty::unread(response.unprocessed.as_bytes());
+        self.pending.extend(response.pasted_lines);
+        if response.commands == Commands::ClearScreen {
+            // Clearing the screen wipes any completion grid and suggestion too
+            self.completion.unselect();
+            self.suggestion = None;
+            self.paste_hint = None;
+            self.redraw(opts)?;
+            self.update_suggestion(opts)?;
+            return Ok(None);
+        }
+        // Messages about completing only last until the next key
+        if self.completion.has_message() {
+            self.completion.clear(self.tty)?;
+        }
         let exe = match self.completion.current_completion() {
             // No completion in progress
             None => match response.commands {
                 Commands::None => None,
-                Commands::Cancel => Some(Execute::Cancel),
-                Commands::EOF => Some(Execute::Exit),
-                Commands::Newline => Some(Execute::Command(self.text_field.text().to_string())),
+                Commands::Cancel => {
+                    // The pasted lines are dropped with the one being edited
+                    self.pending.clear();
+                    Some(PromptAnswer::Cancel)
+                }
+                Commands::Eof => Some(PromptAnswer::Eof),
+                Commands::Newline => Some(PromptAnswer::Line(self.text_field.text().to_string())),
                 special if let Some(key) = special.get_key() => {
                     match key {
-                        SpecialKey::Up => self.scroll_history(1)?,
-                        SpecialKey::Down => self.scroll_history(-1)?,
-                        SpecialKey::Tab => self.complete_next(SelectionDirection::Down)?,
-                        SpecialKey::ShiftTab => self.complete_next(SelectionDirection::Up)?,
+                        SpecialKey::Up if opts.history => self.scroll_history(1)?,
+                        SpecialKey::Down if opts.history => self.scroll_history(-1)?,
+                        SpecialKey::Tab if opts.completion => {
+                            self.complete_next(SelectionDirection::Next)?
+                        }
+                        SpecialKey::ShiftTab if opts.completion => {
+                            self.complete_next(SelectionDirection::Previous)?
+                        }
+                        SpecialKey::CopyLine => self.copy_line(opts)?,
+                        SpecialKey::ForwardAtEnd => {
+                            // The suggestion may not be shown yet if keys came in a burst
+                            self.update_suggestion(opts)?;
+                            if let Some(suggestion) = self.suggestion.clone() {
+                                let response = self.text_field.insert(&suggestion);
+                                return self.handle_response(response, opts);
+                            }
+                        }
+                        _ => (),
                     };
                     None
                 }
@@ -124,58 +462,392 @@ impl ReadLine {
             // Completion in progress
             Some(completion_info) => match response.commands {
                 Commands::None => None,
-                Commands::EOF | Commands::Cancel => {
-                    self.completion.clear()?;
+                Commands::Eof | Commands::Cancel => {
+                    self.completion.clear(self.tty)?;
                     None
                 }
                 Commands::Newline => {
-                    // Accept completion
-                    let word_count =
-                        char_count(sdbg!(Self::word_at_cursor(&self.text_field))) as u32;
-                    self.text_field.move_left(word_count);
-                    self.text_field.erase_right(word_count);
-                    let response = self.text_field.handle_input(completion_info.item());
-                    // Prevents special characters in complete prompts from being interpreted
-                    self.completion.clear()?;
-                    return self.handle_response(response);
+                    let response = Self::accept_completion(&mut self.text_field, &completion_info);
+                    self.completion.clear(self.tty)?;
+                    return self.handle_response(response, opts);
                 }
                 special if let Some(key) = special.get_key() => {
-                    match key {
-                        SpecialKey::Down | SpecialKey::Tab => {
-                            self.complete_next(SelectionDirection::Down)?
-                        }
-                        SpecialKey::Up | SpecialKey::ShiftTab => {
-                            self.complete_next(SelectionDirection::Up)?
-                        }
+                    // The arrows move in the grid, Tab through its items one by one
+                    let direction = match key {
+                        SpecialKey::Tab => Some(SelectionDirection::Next),
+                        SpecialKey::ShiftTab => Some(SelectionDirection::Previous),
+                        SpecialKey::Up => Some(SelectionDirection::Up),
+                        SpecialKey::Down => Some(SelectionDirection::Down),
+                        SpecialKey::Left => Some(SelectionDirection::Left),
+                        SpecialKey::Right => Some(SelectionDirection::Right),
+                        SpecialKey::CopyLine | SpecialKey::ForwardAtEnd => None,
+                    };
+                    match direction {
+                        Some(direction) => self.complete_next(direction)?,
+                        None if key == SpecialKey::CopyLine => self.copy_line(opts)?,
+                        None => (),
                     };
                     None
                 }
                 e => unreachable!("Unknown key: {:?}", e),
             },
         };
+        if exe.is_none() {
+            self.update_suggestion(opts)?;
+        }
         Ok(exe)
     }
 
-    pub fn read_line(&mut self) -> YshResult<Execute> {
-        let termsize = cursor::terminal_size()?;
-        let pos = cursor::get_cursor_pos()?;
+    /// Copies the line to the clipboard of the terminal, leaving it as it is. Lines that
+    /// aren't shown are never copied.
+    fn copy_line(&self, opts: &PromptOpts) -> YshResult<()> {
+        if self.clipboard_off || opts.mode == PromptMode::Hidden {
+            self.tty.write(cursor::bell())?;
+            return Ok(());
+        }
+        self.tty
+            .write(&cursor::set_clipboard(self.text_field.text().as_bytes()))?;
+        Ok(())
+    }
+
+    /// Clears the screen and draws the prompt and the line typed so far at the top.
+    fn redraw(&mut self, opts: &PromptOpts) -> YshResult<()> {
+        let mut bytes = cursor::clear_screen().to_vec();
+        bytes.extend(output::translate_newlines(self.prompt.as_bytes()));
+        self.tty.write(&bytes)?;
+        self.column = 0;
+        self.styles.clear();
+        // The terminal may have been resized since
+        self.update_bounds();
+        if opts.mode != PromptMode::Hidden {
+            let bytes = self.text_field.redraw();
+            self.draw(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Handles one chunk of input, returning an answer once the prompt is done.
+    fn feed(&mut self, input: &str, opts: &PromptOpts) -> YshResult<Option<PromptAnswer>> {
+        if opts.mode == PromptMode::Key {
+            return Ok(input.chars().next().map(|c| match c {
+                '\x03' => PromptAnswer::Cancel,
+                '\x04' => PromptAnswer::Eof,
+                c => PromptAnswer::Key(c),
+            }));
+        }
+        // Keys typed after one that opens or closes the grid are fed again, so this is
+        // right for each key
+        self.text_field.set_menu_open(self.completion.is_open());
+        let response = self.text_field.handle_input(input);
+        self.handle_response(response, opts)
+    }
+
+    /// Lets the line fill the rest of the terminal after the prompt, leaving the last
+    /// column free.
+    fn update_bounds(&mut self) {
+        let termsize = cursor::terminal_size(self.tty);
+        let width = termsize
+            .x
+            .saturating_sub(self.prompt_width + 1)
+            .max(MIN_LINE_WIDTH);
+        self.text_field
+            .set_bounds(crate::Vec2::new(width, termsize.y));
+    }
+
+    /// Prints `prompt`, which takes `prompt_width` columns on its last line, and lets
+    /// the user answer it with the line editor.
+    pub fn prompt_once(
+        &mut self,
+        prompt: &str,
+        prompt_width: u32,
+        opts: PromptOpts,
+    ) -> YshResult<PromptAnswer> {
+        self.prompt = prompt.to_string();
+        self.prompt_width = prompt_width;
+        self.tty
+            .write(&output::translate_newlines(prompt.as_bytes()))?;
         self.text_field.clear();
-        self.text_field.set_bounds(termsize - pos);
-        let mut c = [0u8; 4];
-        let r = loop {
-            let buf = Self::aligned_read(&mut c)?;
-            let response = self
-                .text_field
-                .handle_input(std::str::from_utf8(buf).unwrap());
-            if let Some(execute) = self.handle_response(response)? {
-                break execute;
+        self.column = 0;
+        self.styles.clear();
+        self.update_bounds();
+        if opts.history {
+            if let Some(line) = self.pending.pop_front() {
+                let response = self.text_field.insert(&line);
+                self.draw(&response.bytes)?;
+                self.update_suggestion(&opts)?;
+            }
+        }
+        let mut c = [0u8; 4096];
+        let answer = loop {
+            let buf = Self::aligned_read(self.tty, &mut c)?;
+            let (input, invalid) = decode_input(buf);
+            if invalid {
+                self.tty.write(cursor::bell())?;
+            }
+            if input.is_empty() {
+                continue;
+            }
+            if let Some(answer) = self.feed(&input, &opts)? {
+                break answer;
+            }
+        };
+        // Hints are drawn after the line, don't leave them behind
+        if self.suggestion.take().is_some() | self.paste_hint.take().is_some() {
+            let rest = self.text_field.rest_width();
+            self.tty
+                .write(&commands![cursor::move_right(rest), cursor::kill_line()])?;
+        }
+        if answer == PromptAnswer::Cancel {
+            self.tty.write(b"^C")?;
+        }
+        if opts.history {
+            if let PromptAnswer::Line(ref line) = answer {
+                self.history.push(sdbg!(line));
             }
+            self.history.unselect();
+        }
+        self.tty.write(b"\r\n\x1b[J")?;
+        Ok(answer)
+    }
+
+    pub fn read_line(&mut self, prompt: &str, prompt_width: u32) -> YshResult<Execute> {
+        let execute = match self.prompt_once(prompt, prompt_width, PromptOpts::interactive())? {
+            PromptAnswer::Line(line) => Execute::Command(line),
+            PromptAnswer::Key(c) => Execute::Command(c.to_string()),
+            PromptAnswer::Cancel => Execute::Cancel,
+            PromptAnswer::Eof => Execute::Exit,
         };
-        if let Execute::Command(ref line) = r {
-            self.history.push(sdbg!(line));
+        Ok(execute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_utf8_bytes() {
+        assert_eq!(ReadLine::missing_utf8_bytes(b"abc"), 0);
+        assert_eq!(ReadLine::missing_utf8_bytes("aé".as_bytes()), 0);
+        assert_eq!(ReadLine::missing_utf8_bytes(&"a€".as_bytes()[..2]), 2);
+        assert_eq!(ReadLine::missing_utf8_bytes(&"a€".as_bytes()[..3]), 1);
+        assert_eq!(ReadLine::missing_utf8_bytes(b"a\xff"), 0);
+        assert_eq!(utf8_byte_len(0xf8), None);
+        assert_eq!(utf8_byte_len(0x80), None);
+    }
+
+    #[test]
+    fn invalid_input_is_left_out() {
+        let cases: [(&[u8], &str, bool); 5] = [
+            (b"ls\r", "ls\r", false),
+            (b"a\x80\xbfb", "ab", true),
+            // Overlong encodings of `/`
+            (b"\xc0\xaf\xe0\x80\xafc", "c", true),
+            (b"\xf8\xff\xfe\xc3\xa9", "é", true),
+            (b"x\xf0\x9f\x98", "x", true),
+        ];
+        for (input, text, invalid) in cases {
+            assert_eq!(decode_input(input), (text.into(), invalid), "{:?}", input);
         }
-        self.history.unselect();
-        write(b"\r\n\x1b[J")?;
-        Ok(r)
+    }
+
+    #[test]
+    fn aligned_read_resynchronizes() {
+        let (input, output) = nix::unistd::pipe().unwrap();
+        let tty = Tty { input, output };
+        // Room for 3 bytes, so the first read cuts the `€`
+        let mut buf = [0u8; 7];
+        nix::unistd::write(output, b"a\xe2\x82\xac").unwrap();
+        assert_eq!(
+            ReadLine::aligned_read(tty, &mut buf).unwrap(),
+            b"a\xe2\x82\xac"
+        );
+
+        // A character that isn't finished starts again with the next one
+        nix::unistd::write(output, b"a\xf0\x9f\xc3\xa9").unwrap();
+        assert_eq!(ReadLine::aligned_read(tty, &mut buf).unwrap(), b"a\xf0\x9f");
+        assert_eq!(ReadLine::aligned_read(tty, &mut buf).unwrap(), b"\xc3\xa9");
+
+        // Nor does one cut at the end of the input
+        nix::unistd::write(output, b"a\xf0\x9f").unwrap();
+        assert_eq!(ReadLine::aligned_read(tty, &mut buf).unwrap(), b"a\xf0\x9f");
+        nix::unistd::close(input).unwrap();
+        nix::unistd::close(output).unwrap();
+    }
+
+    #[test]
+    fn aligned_read_waits_without_spinning() {
+        use nix::{
+            fcntl::{fcntl, FcntlArg, OFlag},
+            sys::{
+                resource::{getrusage, UsageWho},
+                time::TimeValLike,
+            },
+        };
+        let (input, output) = nix::unistd::pipe().unwrap();
+        // Reads return right away without input, like from the terminal in raw mode
+        fcntl(input, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).unwrap();
+        let tty = Tty { input, output };
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            nix::unistd::write(output, b"x").unwrap();
+            nix::unistd::close(output).unwrap();
+        });
+        let cpu_time = || {
+            let usage = getrusage(UsageWho::RUSAGE_THREAD).unwrap();
+            let micros =
+                usage.user_time().num_microseconds() + usage.system_time().num_microseconds();
+            Duration::from_micros(micros as u64)
+        };
+        let before = cpu_time();
+        let mut buf = [0u8; 16];
+        assert_eq!(ReadLine::aligned_read(tty, &mut buf).unwrap(), b"x");
+        assert!(cpu_time() - before < Duration::from_millis(100));
+        writer.join().unwrap();
+
+        // Once the input is closed, reading fails instead of waiting forever
+        assert_eq!(ReadLine::aligned_read(tty, &mut buf), Err(nix::Error::EIO));
+        nix::unistd::close(input).unwrap();
+    }
+
+    #[test]
+    fn command_position() {
+        let position = |line: &str| word::word_at(line, line.len()).position;
+        assert_eq!(position(""), word::Position::Command);
+        assert_eq!(position("ls | "), word::Position::Command);
+        assert_eq!(position("if true; then "), word::Position::Command);
+        assert_eq!(position("ls "), word::Position::Argument);
+        assert_eq!(position("echo then "), word::Position::Argument);
+    }
+
+    fn feed_all(
+        read_line: &mut ReadLine,
+        input: &[&str],
+        opts: PromptOpts,
+    ) -> Option<PromptAnswer> {
+        read_line.text_field.set_bounds(crate::Vec2::new(80, 24));
+        input
+            .iter()
+            .find_map(|chunk| read_line.feed(chunk, &opts).unwrap())
+    }
+
+    #[test]
+    fn prompt_line_mode() {
+        let mut read_line = ReadLine::default();
+        let answer = feed_all(
+            &mut read_line,
+            &["a", "b", "c", "\r"],
+            PromptOpts::new(PromptMode::Line),
+        );
+        assert_eq!(answer, Some(PromptAnswer::Line("abc".into())));
+    }
+
+    #[test]
+    fn prompt_key_mode() {
+        let mut read_line = ReadLine::default();
+        let answer = feed_all(&mut read_line, &["y"], PromptOpts::new(PromptMode::Key));
+        assert_eq!(answer, Some(PromptAnswer::Key('y')));
+        let answer = feed_all(&mut read_line, &["\x03"], PromptOpts::new(PromptMode::Key));
+        assert_eq!(answer, Some(PromptAnswer::Cancel));
+    }
+
+    #[test]
+    fn ctrl_d_and_ctrl_c() {
+        let opts = PromptOpts::interactive();
+        let mut read_line = ReadLine::default();
+        let answer = feed_all(&mut read_line, &["\x04"], opts);
+        assert_eq!(answer, Some(PromptAnswer::Eof));
+
+        // Only an empty line is the end of input, otherwise Ctrl-D deletes forward
+        read_line.text_field.clear();
+        let answer = feed_all(
+            &mut read_line,
+            &["ab", "\x1b[D", "\x04", "\x04", "\r"],
+            opts,
+        );
+        assert_eq!(answer, Some(PromptAnswer::Line("a".into())));
+
+        read_line.text_field.clear();
+        let answer = feed_all(&mut read_line, &["ab", "\x03"], opts);
+        assert_eq!(answer, Some(PromptAnswer::Cancel));
+    }
+
+    #[test]
+    fn highlighting_keeps_edits_in_place() {
+        use std::{collections::HashMap, path::PathBuf, rc::Rc};
+        let (input, output) = nix::unistd::pipe().unwrap();
+        let commands: HashMap<_, _> = [("ls".to_string(), PathBuf::from("/bin/ls"))].into();
+        let mut read_line = ReadLine {
+            tty: Tty { input, output },
+            ..Default::default()
+        };
+        read_line.set_highlighter(Some(highlight::Highlighter::new(
+            Rc::new(commands),
+            Default::default(),
+        )));
+        read_line.set_edit_in_place(true);
+        let opts = PromptOpts::new(PromptMode::Line);
+        feed_all(&mut read_line, &["ls ac", "\x1b[D", "b", "\x7f"], opts);
+        let mut drawn = [0u8; 256];
+        let len = nix::unistd::read(input, &mut drawn).unwrap();
+        // Only the first chunk changes colors, the arguments are edited by the terminal
+        assert_eq!(
+            drawn[..len].as_bstr(),
+            b"\x1b[32mls\x1b[39m ac\x1b[K\x1b[1D\x1b[1@b\x1b[1D\x1b[1P".as_bstr()
+        );
+        nix::unistd::close(input).unwrap();
+        nix::unistd::close(output).unwrap();
+    }
+
+    #[test]
+    fn prompt_hidden_mode() {
+        let mut read_line = ReadLine::default();
+        let answer = feed_all(
+            &mut read_line,
+            &["p", "w", "\r"],
+            PromptOpts::new(PromptMode::Hidden),
+        );
+        assert_eq!(answer, Some(PromptAnswer::Line("pw".into())));
+    }
+
+    #[test]
+    fn prompt_without_history_ignores_arrows() {
+        let mut read_line = ReadLine::new_with_history(vec!["old".into()]);
+        let answer = feed_all(
+            &mut read_line,
+            &["\x1b[A", "\r"],
+            PromptOpts::new(PromptMode::Line),
+        );
+        assert_eq!(answer, Some(PromptAnswer::Line("".into())));
+    }
+
+    #[test]
+    fn completions_are_accepted_mid_line() {
+        use completion::CompletionInfo;
+        let mut field = text_field::TextField::new(crate::Vec2::new(80, 1));
+        // The cursor between the spaces
+        let _ = field.set_text("cat  | wc -l");
+        field.move_left(8);
+        let _ = field.take_response();
+        let file = CompletionInfo::new("notes.txt", false);
+        let response = ReadLine::accept_completion(&mut field, &file);
+        // The space already there follows the file, and the tail is drawn again
+        assert_eq!(field.text(), "cat notes.txt | wc -l");
+        assert_eq!(field.cursor_index(), "cat notes.txt ".len());
+        assert!(response.bytes.starts_with(b"notes.txt | wc -l"));
+
+        // A partial word is replaced, and directories get no space
+        let _ = field.set_text("cat no | wc -l");
+        field.move_left(8);
+        let _ = field.take_response();
+        let dir = CompletionInfo::new("notes/", true);
+        let _ = ReadLine::accept_completion(&mut field, &dir);
+        assert_eq!(field.text(), "cat notes/ | wc -l");
+        assert_eq!(field.cursor_index(), "cat notes/".len());
+
+        let file = CompletionInfo::new("notes/\"my notes.txt\"", false);
+        let _ = ReadLine::accept_completion(&mut field, &file);
+        assert_eq!(field.text(), "cat notes/\"my notes.txt\" | wc -l");
+        assert_eq!(field.cursor_index(), field.text().len() - "| wc -l".len());
     }
 }
@@ -1,126 +1,475 @@
-use glam::UVec2;
-
 use crate::utils::BytesBuf;
-use crate::widget::GridStyle;
-use crate::{widget, write};
+use crate::widget::{GridItem, GridStyle};
+use crate::tty::Tty;
+use crate::widget;
 use crate::utils;
 
 use std::io::Result as IoResult;
 
-use self::files::FileProvider;
+use self::aliases::AliasProvider;
+use self::commands::CommandProvider;
+use self::files::{FileFilter, FileProvider};
+use self::variables::VariableProvider;
+
+pub use self::matcher::Matcher;
+
+pub use self::files::DEFAULT_LIMIT as DEFAULT_FILE_LIMIT;
 
 use super::cursor;
+use super::word::Position;
 
+mod aliases;
+mod commands;
 mod files;
+mod matcher;
+mod variables;
 
 use bstr::{BString, ByteSlice};
 
 trait CompletionProvider<'a> {
     type Error: std::error::Error + Send + Sync + 'static;
     type Item: AsRef<[u8]> + 'a;
-    fn provide(&mut self, current_word: &str) -> Result<(), Self::Error>;
+    /// Looks up the items `matcher` picks for `current_word`, in the order they are shown.
+    fn provide(&mut self, current_word: &str, matcher: Matcher) -> Result<(), Self::Error>;
     fn items(&self) -> &[Self::Item];
     fn accept(&self, item: &Self::Item) -> BString {
         BString::from(item.as_ref())
     }
 }
 
+/// Where the selection moves in the grid, whose items go top to bottom, then left to right.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SelectionDirection {
+    /// To the next item, on to the top of the next column and back to the first one
+    Next,
+    /// To the previous item, the other way around
+    Previous,
+    /// Within the column, wrapping around its ends
     Up,
     Down,
+    /// To the same row of the column next to it, or the last item of a shorter one.
+    /// Nothing happens at the edges
+    Left,
+    Right,
 }
 
 #[derive(Default, Debug, Copy, Clone)]
 struct Selection {
     index: u8,
     items_shown: u8,
-    word_hash: u64,
+    /// Rows of the grid the items were shown in
+    rows: u8,
+    /// Hash of what is completed, the directory and the start of the name for files
+    key: u64,
 }
 
 impl Selection {
-    fn new(current_word: &str) -> Selection {
+    fn new(key: u64) -> Selection {
         Selection {
-            word_hash: utils::hash(current_word),
+            key,
             items_shown: 1,
+            rows: 1,
             index: 0,
         }
     }
+
+    /// Moves the index in `direction` among the items shown.
+    fn step(&mut self, direction: SelectionDirection) {
+        // Even if the grid had no room for any
+        let shown = self.items_shown.max(1) as usize;
+        let rows = (self.rows as usize).clamp(1, shown);
+        // Fewer may be shown since, after the terminal was resized
+        let index = (self.index as usize).min(shown - 1);
+        let (column, row) = (index / rows, index % rows);
+        let column_start = column * rows;
+        // The last column may be shorter
+        let column_len = rows.min(shown - column_start);
+        let index = match direction {
+            SelectionDirection::Next => (index + 1) % shown,
+            SelectionDirection::Previous => (index + shown - 1) % shown,
+            SelectionDirection::Down => column_start + (row + 1) % column_len,
+            SelectionDirection::Up => column_start + (row + column_len - 1) % column_len,
+            SelectionDirection::Left if column == 0 => index,
+            SelectionDirection::Left => index - rows,
+            SelectionDirection::Right if column_start + rows >= shown => index,
+            SelectionDirection::Right => (index + rows).min(shown - 1),
+        };
+        self.index = index as u8;
+    }
+}
+
+/// What a word is completed with.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Candidates {
+    #[default]
+    Commands,
+    Variables,
+    Aliases,
+    Files(FileFilter),
+}
+
+/// What the arguments of these commands are completed with, instead of any file.
+const COMMAND_ARGUMENTS: &[(&str, Candidates)] = &[
+    ("cd", Candidates::Files(FileFilter::Directories)),
+    ("pushd", Candidates::Files(FileFilter::Directories)),
+    ("rmdir", Candidates::Files(FileFilter::Directories)),
+    ("source", Candidates::Files(FileFilter::NotDirectories)),
+    (".", Candidates::Files(FileFilter::NotDirectories)),
+    ("unalias", Candidates::Aliases),
+    ("export", Candidates::Variables),
+    ("unset", Candidates::Variables),
+];
+
+/// What `current_word` is completed with where it is, an argument of `command` if any.
+fn candidates_for(current_word: &str, position: Position, command: Option<&str>) -> Candidates {
+    match position {
+        // Paths are completed as files even in command position
+        Position::Command if current_word.contains('/') => Candidates::Files(FileFilter::All),
+        Position::Command => Candidates::Commands,
+        Position::Variable => Candidates::Variables,
+        Position::Redirection => Candidates::Files(FileFilter::All),
+        Position::Argument => COMMAND_ARGUMENTS
+            .iter()
+            .find(|(name, _)| Some(*name) == command)
+            .map_or(Candidates::Files(FileFilter::All), |&(_, candidates)| candidates),
+    }
+}
+
+/// Identifies what `current_word` completes to, so the items are only looked up again
+/// when it changes.
+fn selection_key(current_word: &str, candidates: Candidates, matcher: Matcher) -> u64 {
+    match candidates {
+        Candidates::Files(filter) => {
+            let (dir, prefix) = files::split_word(current_word);
+            // Relative directories are different ones after `cd`
+            let dir = std::env::current_dir().unwrap_or_default().join(dir);
+            utils::hash(&(dir, prefix, filter, matcher))
+        }
+        _ => utils::hash(&(candidates, current_word, matcher)),
+    }
+}
+
+/// The part of `current_word` the items are matched with, which is only the name for files.
+fn pattern_of(current_word: &str, candidates: Candidates) -> &str {
+    match candidates {
+        Candidates::Files(_) => files::split_word(current_word).1,
+        _ => current_word,
+    }
+}
+
+/// Erases the lines below the cursor, where the grid is drawn.
+fn erase_below(tty: Tty) -> IoResult<()> {
+    let buf = BytesBuf::of([
+        cursor::save_cursor(),
+        b"\n\r",
+        cursor::kill_to_term_end(),
+        cursor::restore_cursor(),
+    ]);
+    tty.write(&buf.join(b""))?;
+    Ok(())
+}
+
+/// Tells why the files to complete `current_word` with couldn't be listed.
+fn describe_error(current_word: &str, e: &std::io::Error) -> String {
+    use std::io::ErrorKind;
+    let (dir, _) = files::split_word(current_word);
+    // With a slash, it reads as the directory it is
+    let dir = dir.join("");
+    match e.kind() {
+        ErrorKind::NotFound => format!("no such directory: {}", dir.display()),
+        ErrorKind::PermissionDenied => format!("permission denied: {}", dir.display()),
+        ErrorKind::NotADirectory => format!("not a directory: {}", dir.display()),
+        _ => format!("{}: {}", dir.display(), e),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct CompletionInfo {
     item: BString,
+    /// Whether the item is a directory, which more can be completed in
+    is_dir: bool,
 }
 
 impl CompletionInfo {
+    pub fn new(item: impl Into<BString>, is_dir: bool) -> Self {
+        Self {
+            item: item.into(),
+            is_dir,
+        }
+    }
     pub fn item(&self) -> &str {
         self.item.to_str().unwrap()
     }
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct Completer {
     current_selection: Option<Selection>,
     file_provider: FileProvider,
+    command_provider: CommandProvider,
+    variable_provider: VariableProvider,
+    alias_provider: AliasProvider,
+    /// What the current selection completes
+    completing: Candidates,
+    /// Whether files are colored by what they are
+    file_colors: bool,
+    matcher: Matcher,
+    /// Whether a message is shown below the line instead of a grid, like why nothing
+    /// could be completed
+    message_shown: bool,
 }
 
 impl Completer {
-    fn present(&mut self, current_word: &str) -> IoResult<()> {
+    pub fn set_commands(&mut self, commands: crate::command::path_cache::Commands) {
+        self.command_provider.set_commands(commands);
+    }
+    pub fn set_variables(&mut self, names: Vec<String>) {
+        self.variable_provider.set_variables(names);
+    }
+    pub fn set_aliases(&mut self, names: Vec<String>) {
+        self.alias_provider.set_aliases(names);
+    }
+    /// Sets how many files are shown at most.
+    pub fn set_file_limit(&mut self, limit: usize) {
+        self.file_provider.set_limit(limit);
+    }
+    pub fn set_file_colors(&mut self, enabled: bool) {
+        self.file_colors = enabled;
+    }
+    pub fn set_matcher(&mut self, matcher: Matcher) {
+        self.matcher = matcher;
+    }
+    fn items(&self) -> &[BString] {
+        match self.completing {
+            Candidates::Commands => self.command_provider.items(),
+            Candidates::Variables => self.variable_provider.items(),
+            Candidates::Aliases => self.alias_provider.items(),
+            Candidates::Files(_) => self.file_provider.items(),
+        }
+    }
+    fn accept(&self, item: &BString) -> BString {
+        match self.completing {
+            Candidates::Commands => self.command_provider.accept(item),
+            Candidates::Variables => self.variable_provider.accept(item),
+            Candidates::Aliases => self.alias_provider.accept(item),
+            Candidates::Files(_) => self.file_provider.accept(item),
+        }
+    }
+    fn present(&mut self, tty: Tty, current_word: &str, candidates: Candidates) -> IoResult<()> {
         // Rough caching mechanism to prevent recomputing the completion everytime
+        let key = selection_key(current_word, candidates, self.matcher);
+        // A grid for another word may still be below the line
+        let drawn = self
+            .current_selection
+            .is_some_and(|sel| sel.items_shown != 0);
         self.current_selection = self
             .current_selection
             .take()
-            .filter(|sel| sel.word_hash == utils::hash(current_word));
+            .filter(|sel| sel.key == key);
         let current_selection = match self.current_selection {
             Some(ref mut sel) => sel,
             None => {
-                self.file_provider.provide(current_word)?;
-                self.current_selection.insert(Selection::new(current_word))
+                self.completing = candidates;
+                let provided = match candidates {
+                    Candidates::Commands => {
+                        let Ok(()) = self.command_provider.provide(current_word, self.matcher);
+                        Ok(())
+                    }
+                    Candidates::Variables => {
+                        let Ok(()) = self.variable_provider.provide(current_word, self.matcher);
+                        Ok(())
+                    }
+                    Candidates::Aliases => {
+                        let Ok(()) = self.alias_provider.provide(current_word, self.matcher);
+                        Ok(())
+                    }
+                    Candidates::Files(filter) => {
+                        self.file_provider.set_filter(filter);
+                        self.file_provider.provide(current_word, self.matcher)
+                    }
+                };
+                // Nothing is selected then, so the next Tab looks again
+                if let Err(e) = provided {
+                    return self.show_message(tty, &describe_error(current_word, &e));
+                }
+                if self.items().is_empty() {
+                    // Going down from the bottom line with nothing below would scroll it
+                    if drawn {
+                        erase_below(tty)?;
+                    }
+                    tty.write(cursor::bell())?;
+                    return Ok(());
+                }
+                self.current_selection.insert(Selection::new(key))
             }
         };
-        let pos = cursor::get_cursor_pos()?;
-        let size = cursor::terminal_size()?;
-        let items = self.file_provider.items();
-        let response = widget::grid(pos, size, items, current_selection.index, GridStyle::default());
+        let pos = cursor::get_cursor_pos(tty)?;
+        let size = cursor::terminal_size(tty);
+        // Not `self.items()`, which would borrow the selection too
+        let (items, kinds, note) = match self.completing {
+            Candidates::Commands => (self.command_provider.items(), &[][..], None),
+            Candidates::Variables => (self.variable_provider.items(), &[][..], None),
+            Candidates::Aliases => (self.alias_provider.items(), &[][..], None),
+            Candidates::Files(_) => (
+                self.file_provider.items(),
+                self.file_provider.kinds(),
+                self.file_provider.truncation_note(),
+            ),
+        };
+        // Only files are cut to a limit
+        let total = match self.completing {
+            Candidates::Files(_) => self.file_provider.total(),
+            _ => items.len(),
+        };
+        // Names can hold anything, even escape sequences that would move the cursor
+        let mut items: Vec<_> = items
+            .iter()
+            .map(|item| GridItem::new(widget::escape_controls(item).as_bytes()))
+            .collect();
+        if self.file_colors {
+            for (item, kind) in items.iter_mut().zip(kinds) {
+                item.style = kind.style();
+            }
+        }
+        // Matched at the start, prefixes need no highlighting
+        if self.matcher != Matcher::Prefix {
+            let pattern = pattern_of(current_word, self.completing);
+            for item in &mut items {
+                item.marks = self.matcher.positions(pattern, item.text.as_bytes());
+            }
+        }
+        // Which item is selected among how many, and what they are, below the grid
+        let counter = format!("{}/{}", current_selection.index as usize + 1, total);
+        let counter = match note {
+            Some(note) => format!("{}  {}", counter, note),
+            None => counter,
+        };
+        let source = match self.completing {
+            Candidates::Commands => "commands".to_string(),
+            Candidates::Variables => "variables".to_string(),
+            Candidates::Aliases => "aliases".to_string(),
+            Candidates::Files(_) => {
+                let (dir, _) = files::split_word(current_word);
+                dir.join("").display().to_string()
+            }
+        };
+        let status = widget::status_line(&counter, &source, size.x as usize);
+        let response = widget::grid(
+            pos,
+            size,
+            &items,
+            current_selection.index,
+            Some(&status),
+            GridStyle::default(),
+        );
         current_selection.items_shown = response.elements_shown;
-        write(&response.response)?;
+        current_selection.rows = response.rows;
+        tty.write(&response.response)?;
+        Ok(())
+    }
+    /// Shows `message` on the line below, where the grid would be, until [`Completer::clear`].
+    fn show_message(&mut self, tty: Tty, message: &str) -> IoResult<()> {
+        let pos = cursor::get_cursor_pos(tty)?;
+        let size = cursor::terminal_size(tty);
+        let response = widget::grid(pos, size, &[], 0, Some(message), GridStyle::default());
+        tty.write(&response.response)?;
+        self.message_shown = true;
         Ok(())
     }
-    pub fn next(&mut self, current_word: &str, direction: SelectionDirection) -> IoResult<()> {
+    pub fn has_message(&self) -> bool {
+        self.message_shown
+    }
+    /// Selects the next item for `current_word`, which is an argument of `command` if any.
+    pub fn next(
+        &mut self,
+        tty: Tty,
+        current_word: &str,
+        position: Position,
+        command: Option<&str>,
+        direction: SelectionDirection,
+    ) -> IoResult<()> {
         if let Some(ref mut selection) = self.current_selection {
-            let Selection { index: index_ref, items_shown, .. } = selection;
-            let items_shown = *items_shown;
-            let index = *index_ref;
-            *index_ref = match direction {
-                SelectionDirection::Down => if index < items_shown-1 {
-                    index+1
-                } else {
-                     0
-                },
-                SelectionDirection::Up => if index > 0 {
-                    index-1
-                } else {
-                    items_shown-1
-                }
-            }
+            selection.step(direction);
         }
-        self.present(current_word)
+        self.present(tty, current_word, candidates_for(current_word, position, command))
+    }
+    /// Whether the grid is shown, so the arrow keys move its selection.
+    pub fn is_open(&self) -> bool {
+        self.current_selection.is_some()
     }
     pub fn current_completion(&self) -> Option<CompletionInfo> {
         let current_selection = self.current_selection.as_ref()?;
-        let items = self.file_provider.items();
-        let item = self.file_provider.accept(items.get(current_selection.index as usize)?);
-        Some(CompletionInfo { item })
-    }
-    pub fn clear(&mut self) -> IoResult<()> {
-        self.unselect();
-        let UVec2 { x, .. } = cursor::get_cursor_pos()?;
-        let mut buf = BytesBuf::of([b"\n\r", cursor::kill_to_term_end()]);
-        buf.extend([cursor::move_up(1), cursor::move_right(x - 1)]);
-        write(&buf.join(b""))?;
+        let index = current_selection.index as usize;
+        let item = self.accept(self.items().get(index)?);
+        let is_dir = match self.completing {
+            Candidates::Files(_) => self.file_provider.leads_to_dir(index),
+            Candidates::Commands | Candidates::Variables | Candidates::Aliases => false,
+        };
+        Some(CompletionInfo::new(item, is_dir))
+    }
+    pub fn clear(&mut self, tty: Tty) -> IoResult<()> {
+        // Without anything drawn below, going down could scroll the line away
+        let shown = self.current_selection.take().is_some_and(|s| s.items_shown != 0);
+        if shown | std::mem::take(&mut self.message_shown) {
+            erase_below(tty)?;
+        }
         Ok(())
     }
     pub fn unselect(&mut self) {
         self.current_selection = None;
+        self.message_shown = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where each of `directions` leads from `index`, among `shown` items in `rows` rows.
+    fn steps(index: u8, shown: u8, rows: u8, directions: &[SelectionDirection]) -> Vec<u8> {
+        let mut selection = Selection {
+            index,
+            items_shown: shown,
+            rows,
+            key: 0,
+        };
+        directions
+            .iter()
+            .map(|&direction| {
+                selection.step(direction);
+                selection.index
+            })
+            .collect()
+    }
+
+    #[test]
+    fn selection_moves_in_two_dimensions() {
+        use SelectionDirection::*;
+        // 10 items in 4 rows: 0-3, 4-7 and 8-9
+        assert_eq!(steps(0, 10, 4, &[Down, Down, Down, Down]), [1, 2, 3, 0]);
+        assert_eq!(steps(0, 10, 4, &[Up, Right, Right, Right]), [3, 7, 9, 9]);
+        assert_eq!(steps(9, 10, 4, &[Left, Left, Left]), [5, 1, 1]);
+        // Wrapping within the short column
+        assert_eq!(steps(8, 10, 4, &[Down, Down, Up, Up]), [9, 8, 9, 8]);
+        // Tab goes on to the next column, and back to the start at the end
+        assert_eq!(steps(3, 10, 4, &[Next]), [4]);
+        assert_eq!(steps(9, 10, 4, &[Next, Previous, Previous]), [0, 9, 8]);
+    }
+
+    #[test]
+    fn selection_stays_within_what_is_shown() {
+        use SelectionDirection::*;
+        // One row, like a short list of commands
+        assert_eq!(
+            steps(1, 3, 1, &[Down, Up, Right, Right, Left]),
+            [1, 1, 2, 2, 1]
+        );
+        // Nothing shown or no rows known yet
+        assert_eq!(steps(0, 0, 0, &[Next, Down, Right, Left]), [0, 0, 0, 0]);
+        assert_eq!(steps(2, 5, 0, &[Right, Down]), [3, 3]);
+        assert_eq!(steps(9, 5, 2, &[Up]), [4]);
     }
 }
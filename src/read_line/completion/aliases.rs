@@ -0,0 +1,37 @@
+use bstr::BString;
+
+use super::{CompletionProvider, Matcher};
+
+/// Completes alias names, like after `unalias`.
+#[derive(Default, Debug, Clone)]
+pub struct AliasProvider {
+    names: Vec<String>,
+    items: Vec<BString>,
+}
+
+impl AliasProvider {
+    pub fn set_aliases(&mut self, names: Vec<String>) {
+        self.names = names;
+    }
+}
+
+impl<'a> CompletionProvider<'a> for AliasProvider {
+    type Error = std::convert::Infallible;
+    type Item = BString;
+    fn provide(&mut self, current_word: &str, matcher: Matcher) -> Result<(), Self::Error> {
+        let mut ranked: Vec<_> = self
+            .names
+            .iter()
+            .filter_map(|name| Some((matcher.rank(current_word, name.as_bytes())?, name)))
+            .collect();
+        ranked.sort();
+        self.items = ranked
+            .into_iter()
+            .map(|(_, name)| BString::from(name.as_str()))
+            .collect();
+        Ok(())
+    }
+    fn items(&self) -> &[Self::Item] {
+        &self.items
+    }
+}
@@ -0,0 +1,60 @@
+use bstr::BString;
+
+use crate::command::path_cache::Commands;
+
+use super::{CompletionProvider, Matcher};
+
+/// Completes program names from the shell's `$PATH` cache.
+#[derive(Default, Debug, Clone)]
+pub struct CommandProvider {
+    commands: Commands,
+    items: Vec<BString>,
+}
+
+impl CommandProvider {
+    pub fn set_commands(&mut self, commands: Commands) {
+        self.commands = commands;
+    }
+}
+
+impl<'a> CompletionProvider<'a> for CommandProvider {
+    type Error = std::convert::Infallible;
+    type Item = BString;
+    fn provide(&mut self, current_word: &str, matcher: Matcher) -> Result<(), Self::Error> {
+        let mut ranked: Vec<_> = self
+            .commands
+            .keys()
+            .filter_map(|name| Some((matcher.rank(current_word, name.as_bytes())?, name)))
+            .collect();
+        ranked.sort();
+        self.items = ranked
+            .into_iter()
+            .map(|(_, name)| BString::from(name.as_str()))
+            .collect();
+        Ok(())
+    }
+    fn items(&self) -> &[Self::Item] {
+        &self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn completes_sorted_prefix_matches() {
+        let commands: HashMap<_, _> = ["ls", "lsblk", "cat", "less"]
+            .into_iter()
+            .map(|name| (name.to_string(), PathBuf::from("/bin").join(name)))
+            .collect();
+        let mut provider = CommandProvider::default();
+        provider.set_commands(Rc::new(commands));
+        provider.provide("ls", Matcher::Prefix).unwrap();
+        assert_eq!(provider.items(), ["ls", "lsblk"]);
+        provider.provide("", Matcher::Prefix).unwrap();
+        assert_eq!(provider.items(), ["cat", "less", "ls", "lsblk"]);
+    }
+}
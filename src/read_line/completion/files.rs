@@ -1,8 +1,9 @@
 use std::{
     ffi::OsStr,
     fs::DirEntry,
-    os::unix::prelude::OsStrExt,
+    os::unix::{ffi::OsStringExt, fs::PermissionsExt},
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 
 use bstr::{BString, ByteSlice, ByteVec};
@@ -10,44 +11,234 @@ use color_eyre::eyre::Context;
 
 use crate::{shell_println, utils, YshResult};
 
-use super::CompletionProvider;
+use super::{CompletionProvider, Matcher};
 
-fn format_filename(entry: DirEntry) -> BString {
-    let file_type = entry.file_type().expect("Failed to query file informaton");
-    let file_name = entry.file_name();
-    let mut file_name =
-        BString::from(Vec::from_os_string(file_name).expect("Got invalid filename"));
-    if file_type.is_dir() {
+/// What a file is, which `ls --color` shows in different colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ItemKind {
+    File,
+    Directory,
+    Executable,
+    Symlink,
+    /// A link to nothing, or to what can't be looked up.
+    BrokenSymlink,
+}
+
+impl ItemKind {
+    /// SGR parameters to draw it with, like `ls --color`.
+    pub fn style(self) -> &'static str {
+        match self {
+            Self::File => "",
+            Self::Directory => "34",
+            Self::Executable => "32",
+            Self::Symlink => "36",
+            Self::BrokenSymlink => "31",
+        }
+    }
+}
+
+/// Which files are offered, by whether they are directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FileFilter {
+    #[default]
+    All,
+    /// Directories and the links to them, like for `cd`.
+    Directories,
+    /// Anything but directories, like for `source`.
+    NotDirectories,
+}
+
+impl FileFilter {
+    fn keeps(self, is_dir: bool) -> bool {
+        match self {
+            Self::All => true,
+            Self::Directories => is_dir,
+            Self::NotDirectories => !is_dir,
+        }
+    }
+}
+
+/// The name to complete `entry` with, what it is and whether it leads to a directory.
+///
+/// Links are followed to tell if they lead to a directory, which gets a slash to complete
+/// what is inside. An entry that can't be looked up anymore, like one deleted since the
+/// directory was read, is offered as a plain file.
+fn describe(entry: &DirEntry) -> (BString, ItemKind, bool) {
+    let (kind, is_dir) = match entry.file_type() {
+        Ok(file_type) if file_type.is_symlink() => match std::fs::metadata(entry.path()) {
+            Ok(metadata) => (ItemKind::Symlink, metadata.is_dir()),
+            Err(_) => (ItemKind::BrokenSymlink, false),
+        },
+        Ok(file_type) if file_type.is_dir() => (ItemKind::Directory, true),
+        // Only regular files need another stat to find out
+        _ => match entry.metadata() {
+            Ok(metadata) if metadata.permissions().mode() & 0o111 != 0 => {
+                (ItemKind::Executable, false)
+            }
+            _ => (ItemKind::File, false),
+        },
+    };
+    (format_filename(entry, is_dir), kind, is_dir)
+}
+
+fn format_filename(entry: &DirEntry, is_dir: bool) -> BString {
+    let mut file_name = BString::from(entry.file_name().into_vec());
+    if is_dir {
         // Append a slash if it is a directory
         file_name.push(b'/');
     }
-    if file_name.find_byteset(b" \t").is_some() {
-        // Surround filename with quotes if it contains spaces/tabs
+    if file_name
+        .iter()
+        .any(|b| b.is_ascii_whitespace() || b.is_ascii_control())
+    {
+        // Surround filename with quotes if it contains spaces or control characters
         file_name.insert(0, b'"');
         file_name.push(b'"');
     }
     file_name
 }
 
-#[derive(Default, Debug, Clone)]
+/// How many matches are shown by default, the first ones by name.
+pub const DEFAULT_LIMIT: usize = 4096;
+
+/// Splits the word being completed into the directory to list and the start of the name.
+pub fn split_word(current_word: &str) -> (&Path, &str) {
+    let folder = Path::new(current_word);
+    let filename = utils::path_filename(folder).unwrap_or_default();
+    let dir = utils::path_parent(folder).unwrap_or(Path::new("."));
+    (dir, filename.to_str().unwrap_or_default())
+}
+
+/// Entries of a directory, as they were when it was last modified.
+#[derive(Debug, Clone)]
+struct Listing {
+    /// Absolute, so changing directories doesn't reuse it
+    dir: PathBuf,
+    modified: SystemTime,
+    /// With what each is, and whether it leads to a directory
+    names: Vec<(BString, ItemKind, bool)>,
+}
+
+#[derive(Debug, Clone)]
 pub struct FileProvider {
     cwd: PathBuf,
     items: Vec<BString>,
+    /// What each of the items is
+    kinds: Vec<ItemKind>,
+    /// Whether each of the items leads to a directory, maybe through links
+    dirs: Vec<bool>,
+    listing: Option<Listing>,
+    limit: usize,
+    filter: FileFilter,
+    /// How many files matched, which is more than the items if they were cut at `limit`
+    matches: usize,
+    #[cfg(test)]
+    reads: usize,
+}
+
+impl Default for FileProvider {
+    fn default() -> Self {
+        Self {
+            cwd: Default::default(),
+            items: Default::default(),
+            kinds: Default::default(),
+            dirs: Default::default(),
+            listing: None,
+            limit: DEFAULT_LIMIT,
+            filter: FileFilter::All,
+            matches: 0,
+            #[cfg(test)]
+            reads: 0,
+        }
+    }
+}
+
+impl FileProvider {
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.max(1);
+    }
+
+    /// Sets which files the next lookups offer.
+    pub fn set_filter(&mut self, filter: FileFilter) {
+        self.filter = filter;
+    }
+
+    /// How many files matched, even those left out because of the limit.
+    pub fn total(&self) -> usize {
+        self.matches
+    }
+
+    /// Tells how many files were left out because of the limit, if any.
+    pub fn truncation_note(&self) -> Option<String> {
+        (self.matches > self.items.len())
+            .then(|| format!("showing {} of {} files", self.items.len(), self.matches))
+    }
+
+    /// What each of the items is, in the same order.
+    pub fn kinds(&self) -> &[ItemKind] {
+        &self.kinds
+    }
+
+    /// Whether the item at `index` is a directory or a link to one.
+    pub fn leads_to_dir(&self, index: usize) -> bool {
+        self.dirs.get(index).copied().unwrap_or(false)
+    }
+
+    /// Names in `dir` and what they are, read again only if it changed since the last time.
+    fn names(&mut self, dir: &Path) -> std::io::Result<&[(BString, ItemKind, bool)]> {
+        let dir = std::env::current_dir()?.join(dir);
+        let modified = std::fs::metadata(&dir)?.modified()?;
+        let cached = self
+            .listing
+            .as_ref()
+            .is_some_and(|listing| listing.dir == dir && listing.modified == modified);
+        if !cached {
+            #[cfg(test)]
+            {
+                self.reads += 1;
+            }
+            let names = std::fs::read_dir(&dir)?
+                .filter_map(Result::ok)
+                .map(|entry| describe(&entry))
+                .collect();
+            self.listing = Some(Listing {
+                dir,
+                modified,
+                names,
+            });
+        }
+        Ok(&self.listing.as_ref().unwrap().names)
+    }
 }
 
 impl<'a> CompletionProvider<'a> for FileProvider {
     type Error = std::io::Error;
     type Item = BString;
-    fn provide(&mut self, current_word: &str) -> Result<(), Self::Error> {
-        let folder = Path::new(current_word);
-        let filename = utils::path_filename(folder).unwrap_or_default();
-        self.cwd = utils::path_parent(folder).unwrap_or(Path::new(".")).into();
-        self.items = std::fs::read_dir(&self.cwd)?
-            .filter_map(Result::ok)
-            .map(format_filename)
-            .filter(|f| f.starts_with(filename.as_bytes()))
+    fn provide(&mut self, current_word: &str, matcher: Matcher) -> Result<(), Self::Error> {
+        let (dir, filename) = split_word(current_word);
+        self.cwd = dir.into();
+        let (limit, filter) = (self.limit, self.filter);
+        let mut items: Vec<_> = self
+            .names(dir)?
+            .iter()
+            .filter(|(_, _, is_dir)| filter.keeps(*is_dir))
+            .filter_map(|(name, kind, is_dir)| {
+                Some((matcher.rank(filename, name)?, name.clone(), *kind, *is_dir))
+            })
             .collect();
-        self.items.sort();
+        self.matches = items.len();
+        // Only the first ones are kept, so only those need to be sorted
+        if items.len() > limit {
+            items.select_nth_unstable(limit);
+            items.truncate(limit);
+        }
+        items.sort_unstable();
+        let (items, kinds): (_, Vec<_>) = items
+            .into_iter()
+            .map(|(_, name, kind, is_dir)| (name, (kind, is_dir)))
+            .unzip();
+        self.items = items;
+        (self.kinds, self.dirs) = kinds.into_iter().unzip();
         Ok(())
     }
     fn items(&self) -> &[Self::Item] {
@@ -62,3 +253,152 @@ impl<'a> CompletionProvider<'a> for FileProvider {
             .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn directory_is_read_once_until_it_changes() {
+        let dir = TempDir::new("files");
+        for i in 0..10_000 {
+            std::fs::write(dir.join(format!("file{:05}", i)), "").unwrap();
+        }
+        let mut provider = FileProvider::default();
+        provider.set_limit(100);
+        let word = format!("{}/file", dir.display());
+        provider.provide(&word, Matcher::Prefix).unwrap();
+        assert_eq!(provider.items().len(), 100);
+        assert_eq!(provider.items()[0], "file00000");
+        assert_eq!(provider.items()[99], "file00099");
+        assert_eq!(
+            provider.truncation_note().as_deref(),
+            Some("showing 100 of 10000 files")
+        );
+        assert_eq!(provider.total(), 10000);
+        // Typing more of the name filters what was already read
+        for prefix in ["file0", "file01", "file012", "file0123"] {
+            provider
+                .provide(&format!("{}/{}", dir.display(), prefix), Matcher::Prefix)
+                .unwrap();
+        }
+        assert_eq!(
+            provider.items(),
+            [
+                "file01230",
+                "file01231",
+                "file01232",
+                "file01233",
+                "file01234",
+                "file01235",
+                "file01236",
+                "file01237",
+                "file01238",
+                "file01239"
+            ]
+        );
+        assert_eq!(provider.truncation_note(), None);
+        assert_eq!(provider.reads, 1);
+
+        // But a new file is seen
+        std::fs::create_dir(dir.join("file0123x")).unwrap();
+        provider
+            .provide(&format!("{}/file0123x", dir.display()), Matcher::Prefix)
+            .unwrap();
+        assert_eq!(provider.items(), ["file0123x/"]);
+        assert_eq!(provider.reads, 2);
+    }
+
+    #[test]
+    fn kinds_follow_the_sorted_items() {
+        let dir = TempDir::new("kinds");
+        std::fs::create_dir_all(dir.join("b_dir")).unwrap();
+        std::fs::write(dir.join("d_file"), "").unwrap();
+        std::fs::write(dir.join("a_run"), "").unwrap();
+        std::fs::set_permissions(dir.join("a_run"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::os::unix::fs::symlink("b_dir", dir.join("c_link")).unwrap();
+        let mut provider = FileProvider::default();
+        provider
+            .provide(&format!("{}/", dir.display()), Matcher::Prefix)
+            .unwrap();
+        assert_eq!(provider.items(), ["a_run", "b_dir/", "c_link/", "d_file"]);
+        assert_eq!(
+            provider.kinds(),
+            [
+                ItemKind::Executable,
+                ItemKind::Directory,
+                ItemKind::Symlink,
+                ItemKind::File
+            ]
+        );
+    }
+
+    #[test]
+    fn filters_keep_directories_or_the_rest() {
+        let dir = TempDir::new("filter");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("script.sh"), "").unwrap();
+        std::os::unix::fs::symlink("src", dir.join("source")).unwrap();
+        std::os::unix::fs::symlink("script.sh", dir.join("setup")).unwrap();
+        let word = format!("{}/s", dir.display());
+        let mut provider = FileProvider::default();
+        provider.set_filter(FileFilter::Directories);
+        provider.provide(&word, Matcher::Prefix).unwrap();
+        assert_eq!(provider.items(), ["source/", "src/"]);
+        // The listing is read once for both
+        provider.set_filter(FileFilter::NotDirectories);
+        provider.provide(&word, Matcher::Prefix).unwrap();
+        assert_eq!(provider.items(), ["script.sh", "setup"]);
+        assert_eq!(provider.reads, 1);
+    }
+
+    #[test]
+    fn links_are_followed_to_what_they_lead_to() {
+        let dir = TempDir::new("links");
+        std::fs::create_dir_all(dir.join("real_dir")).unwrap();
+        std::fs::write(dir.join("real_file"), "").unwrap();
+        std::os::unix::fs::symlink("real_dir", dir.join("to_dir")).unwrap();
+        std::os::unix::fs::symlink("real_file", dir.join("to_file")).unwrap();
+        std::os::unix::fs::symlink("nowhere", dir.join("to_nothing")).unwrap();
+        let mut provider = FileProvider::default();
+        provider
+            .provide(&format!("{}/to", dir.display()), Matcher::Prefix)
+            .unwrap();
+        assert_eq!(provider.items(), ["to_dir/", "to_file", "to_nothing"]);
+        assert_eq!(
+            provider.kinds(),
+            [
+                ItemKind::Symlink,
+                ItemKind::Symlink,
+                ItemKind::BrokenSymlink
+            ]
+        );
+        // Links to directories are completed into like them
+        let dirs = (0..3).map(|i| provider.leads_to_dir(i)).collect::<Vec<_>>();
+        assert_eq!(dirs, [true, false, false]);
+    }
+
+    #[test]
+    fn entries_deleted_while_listing_are_plain_files() {
+        let dir = TempDir::new("deleted-entries");
+        std::fs::write(dir.join("gone"), "").unwrap();
+        std::os::unix::fs::symlink("gone", dir.join("link")).unwrap();
+        let mut entries = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(DirEntry::file_name);
+        // Gone between reading the directory and looking at the entries
+        std::fs::remove_dir_all(&dir).unwrap();
+        let described = entries.iter().map(describe).collect::<Vec<_>>();
+        assert_eq!(
+            described,
+            [
+                (BString::from("gone"), ItemKind::File, false),
+                (BString::from("link"), ItemKind::BrokenSymlink, false),
+            ]
+        );
+    }
+}
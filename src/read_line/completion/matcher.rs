@@ -0,0 +1,263 @@
+use std::cmp::Reverse;
+
+use bstr::ByteSlice;
+
+/// How the items completing a word are picked and ordered, set with `$COMPLETION_MATCHER`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Matcher {
+    /// Items starting with the word, by name.
+    #[default]
+    Prefix,
+    /// Items holding the word anywhere, ignoring case, the earliest matches first.
+    Substring,
+    /// Items holding the characters of the word in order, ignoring case, the best matches
+    /// first.
+    Fuzzy,
+}
+
+/// Where an item goes among the others that match, before ordering them by name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Rank {
+    score: Reverse<i32>,
+    /// Shorter items are closer to what was typed
+    len: usize,
+}
+
+/// Score of each character matched.
+const SCORE_MATCH: i32 = 16;
+/// Bonus for matching right after the last character matched.
+const BONUS_CONSECUTIVE: i32 = 8;
+/// Bonus for matching the start of a word, like the `t` in `dl-tools` or in `DeepThought`.
+const BONUS_BOUNDARY: i32 = 8;
+/// How much more the bonus counts for the first character of the pattern.
+const BONUS_FIRST_CHAR_MULTIPLIER: i32 = 2;
+/// Penalty for skipping characters between two matched ones, and for each one after the
+/// first. Characters before the first match cost nothing.
+const SCORE_GAP_START: i32 = -3;
+const SCORE_GAP_EXTENSION: i32 = -1;
+
+fn fold(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Whether `item[index]` starts a word, after a separator or a lowercase letter.
+fn is_boundary(item: &[char], index: usize) -> bool {
+    let Some(&before) = index.checked_sub(1).and_then(|before| item.get(before)) else {
+        return true;
+    };
+    !before.is_alphanumeric() || (before.is_lowercase() && item[index].is_uppercase())
+}
+
+/// The best alignment of `pattern` as a subsequence of `item`, with its score and the
+/// indices of the characters matched.
+///
+/// Like fzf's algorithm, but scoring every alignment instead of the first one found.
+fn fuzzy_match(pattern: &[char], item: &[char]) -> Option<(i32, Vec<usize>)> {
+    let (n, m) = (pattern.len(), item.len());
+    if n == 0 {
+        return Some((0, Vec::new()));
+    }
+    // The best score with `pattern[i]` at `item[j]`, and where `pattern[i - 1]` is then
+    let mut best = vec![vec![None; m]; n];
+    let mut from = vec![vec![0; m]; n];
+    for i in 0..n {
+        // The best of the earlier matches a gap can follow, less what each step costs
+        let mut gapped: Option<(i32, usize)> = None;
+        for j in i..m {
+            if i > 0 && j >= 2 {
+                if let Some(score) = best[i - 1][j - 2] {
+                    let score = score - SCORE_GAP_EXTENSION * (j - 2) as i32;
+                    if gapped.is_none_or(|(best, _)| score > best) {
+                        gapped = Some((score, j - 2));
+                    }
+                }
+            }
+            if fold(item[j]) != fold(pattern[i]) {
+                continue;
+            }
+            let bonus = match (is_boundary(item, j), i) {
+                (false, _) => 0,
+                (true, 0) => BONUS_BOUNDARY * BONUS_FIRST_CHAR_MULTIPLIER,
+                (true, _) => BONUS_BOUNDARY,
+            };
+            let previous = match i {
+                0 => Some((0, 0)),
+                _ => {
+                    let consecutive =
+                        best[i - 1][j - 1].map(|score| (score + BONUS_CONSECUTIVE, j - 1));
+                    let gap = gapped.map(|(score, k)| {
+                        let penalty = SCORE_GAP_START + SCORE_GAP_EXTENSION * (j - 2) as i32;
+                        (score + penalty, k)
+                    });
+                    match (consecutive, gap) {
+                        (Some(a), Some(b)) => Some(if b.0 > a.0 { b } else { a }),
+                        (a, b) => a.or(b),
+                    }
+                }
+            };
+            if let Some((score, k)) = previous {
+                best[i][j] = Some(score + SCORE_MATCH + bonus);
+                from[i][j] = k;
+            }
+        }
+    }
+    // The first of the best ones
+    let (mut j, score) = best[n - 1]
+        .iter()
+        .enumerate()
+        .filter_map(|(j, score)| Some((j, (*score)?)))
+        .rev()
+        .max_by_key(|&(_, score)| score)?;
+    let mut positions = vec![0; n];
+    for i in (0..n).rev() {
+        positions[i] = j;
+        j = from[i][j];
+    }
+    Some((score, positions))
+}
+
+impl Matcher {
+    /// The matcher named `name`, one of `prefix`, `substring` and `fuzzy`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "prefix" => Some(Self::Prefix),
+            "substring" => Some(Self::Substring),
+            "fuzzy" => Some(Self::Fuzzy),
+            _ => None,
+        }
+    }
+
+    /// Where `item` goes in the order, or `None` if it doesn't match `pattern`.
+    pub fn rank(self, pattern: &str, item: &[u8]) -> Option<Rank> {
+        if self == Self::Prefix || pattern.is_empty() {
+            let rank = Rank {
+                score: Reverse(0),
+                len: 0,
+            };
+            return item.starts_with(pattern.as_bytes()).then_some(rank);
+        }
+        let (score, _) = self.find(pattern, item)?;
+        Some(Rank {
+            score: Reverse(score),
+            len: item.chars().count(),
+        })
+    }
+
+    /// Indices of the characters of `item` that `pattern` matches, to highlight them.
+    ///
+    /// Prefix matches are left as they are, always at the start.
+    pub fn positions(self, pattern: &str, item: &[u8]) -> Vec<usize> {
+        match self {
+            Self::Prefix => Vec::new(),
+            _ => self
+                .find(pattern, item)
+                .map(|(_, positions)| positions)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn find(self, pattern: &str, item: &[u8]) -> Option<(i32, Vec<usize>)> {
+        let pattern = pattern.chars().map(fold).collect::<Vec<_>>();
+        let chars = item.chars().collect::<Vec<_>>();
+        match self {
+            Self::Prefix => None,
+            Self::Substring => {
+                let folded = chars.iter().copied().map(fold).collect::<Vec<_>>();
+                let start = match pattern.len() {
+                    0 => 0,
+                    len => folded.windows(len).position(|window| window == pattern)?,
+                };
+                Some((-(start as i32), (start..start + pattern.len()).collect()))
+            }
+            Self::Fuzzy => fuzzy_match(&pattern, &chars),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `pattern` picks from `items`, in order.
+    fn ranked<'a>(matcher: Matcher, pattern: &str, items: &[&'a str]) -> Vec<&'a str> {
+        let mut ranked = items
+            .iter()
+            .filter_map(|item| Some((matcher.rank(pattern, item.as_bytes())?, *item)))
+            .collect::<Vec<_>>();
+        ranked.sort();
+        ranked.into_iter().map(|(_, item)| item).collect()
+    }
+
+    #[test]
+    fn prefixes_keep_case_and_names_order() {
+        let items = ["docs", "Downloads", "dl-tools", "old-docs"];
+        assert_eq!(ranked(Matcher::Prefix, "do", &items), ["docs"]);
+        assert_eq!(
+            ranked(Matcher::Prefix, "", &items),
+            ["Downloads", "dl-tools", "docs", "old-docs"]
+        );
+        assert_eq!(Matcher::Prefix.positions("do", b"docs"), [] as [usize; 0]);
+    }
+
+    #[test]
+    fn substrings_ignore_case_earliest_first() {
+        let items = ["old-docs", "Downloads", "docs", "dl-tools"];
+        assert_eq!(
+            ranked(Matcher::Substring, "DO", &items),
+            ["docs", "Downloads", "old-docs"]
+        );
+        assert_eq!(Matcher::Substring.positions("own", b"Downloads"), [1, 2, 3]);
+        assert_eq!(Matcher::Substring.rank("x", b"docs"), None);
+    }
+
+    #[test]
+    fn fuzzy_prefers_runs_and_word_starts() {
+        let items = ["Downloads", "dl-tools", "old", "idle"];
+        // `dl` is a run at the start of `dl-tools`, but split in `Downloads`
+        assert_eq!(
+            ranked(Matcher::Fuzzy, "dl", &items),
+            ["dl-tools", "Downloads", "idle"]
+        );
+        assert_eq!(Matcher::Fuzzy.positions("dl", b"Downloads"), [0, 4]);
+        assert_eq!(Matcher::Fuzzy.positions("dl", b"dl-tools"), [0, 1]);
+        // The `t` of `tools` starts a word, unlike the one of `dlt`
+        assert_eq!(
+            ranked(Matcher::Fuzzy, "dt", &["dltx", "dl-tools"]),
+            ["dl-tools", "dltx"]
+        );
+        assert_eq!(Matcher::Fuzzy.positions("dt", b"dl-tools"), [0, 3]);
+        assert_eq!(Matcher::Fuzzy.positions("mf", b"MyFile"), [0, 2]);
+    }
+
+    #[test]
+    fn fuzzy_finds_the_best_alignment() {
+        // The first `a` found would leave a gap before the `b`
+        assert_eq!(Matcher::Fuzzy.positions("ab", b"xaxab"), [3, 4]);
+        // A whole run beats a word start with gaps
+        assert_eq!(Matcher::Fuzzy.positions("abc", b"a_b_abc"), [4, 5, 6]);
+        // Repeated characters each match their own
+        assert_eq!(Matcher::Fuzzy.positions("ll", b"hello"), [2, 3]);
+        assert_eq!(Matcher::Fuzzy.rank("lll", b"hello"), None);
+        assert_eq!(Matcher::Fuzzy.rank("ld", b"dl-tools"), None);
+        // Not split inside a character
+        assert_eq!(Matcher::Fuzzy.positions("éf", "café-fr".as_bytes()), [3, 5]);
+    }
+
+    #[test]
+    fn fuzzy_ties_go_to_shorter_items_then_names() {
+        assert_eq!(
+            ranked(Matcher::Fuzzy, "ab", &["abcd", "abd", "abc"]),
+            ["abc", "abd", "abcd"]
+        );
+        // Without a pattern, everything is there by name
+        assert_eq!(ranked(Matcher::Fuzzy, "", &["b", "aa"]), ["aa", "b"]);
+    }
+
+    #[test]
+    fn matchers_are_named() {
+        assert_eq!(Matcher::from_name("fuzzy"), Some(Matcher::Fuzzy));
+        assert_eq!(Matcher::from_name("substring"), Some(Matcher::Substring));
+        assert_eq!(Matcher::from_name("prefix"), Some(Matcher::Prefix));
+        assert_eq!(Matcher::from_name("Fuzzy"), None);
+    }
+}
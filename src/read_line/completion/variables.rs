@@ -0,0 +1,59 @@
+use bstr::BString;
+
+use super::{CompletionProvider, Matcher};
+
+/// Completes `$NAME` with the names of shell and environment variables, or just the
+/// names without the `$`, like after `unset`.
+#[derive(Default, Debug, Clone)]
+pub struct VariableProvider {
+    names: Vec<String>,
+    items: Vec<BString>,
+}
+
+impl VariableProvider {
+    pub fn set_variables(&mut self, names: Vec<String>) {
+        self.names = names;
+    }
+}
+
+impl<'a> CompletionProvider<'a> for VariableProvider {
+    type Error = std::convert::Infallible;
+    type Item = BString;
+    fn provide(&mut self, current_word: &str, matcher: Matcher) -> Result<(), Self::Error> {
+        let (dollar, prefix) = match current_word.strip_prefix('$') {
+            Some(prefix) => ("$", prefix),
+            None => ("", current_word),
+        };
+        let mut ranked: Vec<_> = self
+            .names
+            .iter()
+            .filter_map(|name| Some((matcher.rank(prefix, name.as_bytes())?, name)))
+            .collect();
+        ranked.sort();
+        self.items = ranked
+            .into_iter()
+            .map(|(_, name)| BString::from(format!("{}{}", dollar, name)))
+            .collect();
+        self.items.dedup();
+        Ok(())
+    }
+    fn items(&self) -> &[Self::Item] {
+        &self.items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_names_after_dollar() {
+        let mut provider = VariableProvider::default();
+        let names = ["HOME", "HOSTNAME", "PATH", "HOME"];
+        provider.set_variables(names.map(String::from).to_vec());
+        provider.provide("$HO", Matcher::Prefix).unwrap();
+        assert_eq!(provider.items(), ["$HOME", "$HOSTNAME"]);
+        provider.provide("PA", Matcher::Prefix).unwrap();
+        assert_eq!(provider.items(), ["PATH"]);
+    }
+}
@@ -1,9 +1,17 @@
-use std::io::Write;
+use std::{
+    io::Write,
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
 use bstr::ByteSlice;
-use nix::pty::Winsize;
 
-use crate::{binformat, read, write, Vec2};
+use crate::{
+    binformat,
+    tty::{self, Tty},
+    Vec2,
+};
 
 #[must_use]
 pub fn move_left(times: u32) -> Vec<u8> {
@@ -32,23 +40,45 @@ pub fn move_down(times: u32) -> Vec<u8> {
     buf
 }
 
+/// Makes room for `count` columns at the cursor, shifting the rest of the line right (ICH).
 #[must_use]
-pub fn move_up(times: u32) -> Vec<u8> {
-    let mut buf: Vec<u8> = Vec::with_capacity(16);
-    if times != 0 {
-        write!(buf, "\x1b[{}A", times).unwrap();
+pub fn insert_columns(count: u32) -> Vec<u8> {
+    match count {
+        0 => vec![],
+        count => binformat!("\x1b[{}@", count),
+    }
+}
+
+/// Removes `count` columns at the cursor, shifting the rest of the line left (DCH).
+#[must_use]
+pub fn delete_columns(count: u32) -> Vec<u8> {
+    match count {
+        0 => vec![],
+        count => binformat!("\x1b[{}P", count),
     }
-    buf
 }
 
+/// Moves the cursor to column `x` and row `y`, counted from 1.
 #[must_use]
-pub fn set_position(x: u8, y: u8) -> Vec<u8> {
+pub fn set_position(x: u32, y: u32) -> Vec<u8> {
     binformat!("\x1b[{};{}H", y, x)
 }
 
 #[must_use]
 pub fn set_positionv(vec: Vec2) -> Vec<u8> {
-    set_position(vec.x as _, vec.y as _)
+    binformat!("\x1b[{};{}H", vec.y, vec.x)
+}
+
+/// Remembers where the cursor is, to go back with [`restore_cursor`] (DECSC).
+#[must_use]
+pub const fn save_cursor() -> &'static [u8] {
+    b"\x1b7"
+}
+
+/// Moves the cursor back to where [`save_cursor`] left it (DECRC).
+#[must_use]
+pub const fn restore_cursor() -> &'static [u8] {
+    b"\x1b8"
 }
 
 #[must_use]
@@ -61,48 +91,274 @@ pub fn kill_to_term_end() -> &'static [u8] {
     b"\x1b[J"
 }
 
+/// Clears the whole screen and moves the cursor to the top-left corner.
+#[must_use]
+pub const fn clear_screen() -> &'static [u8] {
+    b"\x1b[2J\x1b[H"
+}
+
 #[must_use]
 pub const fn bell() -> &'static [u8] {
     b"\x07"
 }
 
+/// Longest window title set, in characters, since terminals cut long ones anyway.
+pub const MAX_TITLE_LEN: usize = 80;
+
+/// Sets the window title (OSC 0) to `title`, without the control characters that would
+/// end the sequence early, and cut at [`MAX_TITLE_LEN`] characters.
+#[must_use]
+pub fn set_title(title: &str) -> Vec<u8> {
+    let mut chars = title.chars().filter(|c| !c.is_control());
+    let mut title: String = chars.by_ref().take(MAX_TITLE_LEN).collect();
+    if chars.next().is_some() {
+        title.pop();
+        title.push('…');
+    }
+    binformat!("\x1b]0;{}\x07", title)
+}
+
+/// Most bytes copied to the clipboard, which many terminals take at most 100000 base64
+/// characters of.
+pub const MAX_CLIPBOARD_LEN: usize = 74_994;
+
+/// Standard base64, with padding.
+fn base64(bytes: &[u8]) -> Vec<u8> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = Vec::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (i, &b)| group | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize]),
+                false => encoded.push(b'='),
+            }
+        }
+    }
+    encoded
+}
+
+/// Copies `bytes` to the clipboard of the terminal (OSC 52), even over SSH, cut at
+/// [`MAX_CLIPBOARD_LEN`] bytes without splitting a character.
 #[must_use]
-pub fn get_cursor_pos() -> nix::Result<Vec2> {
-    write(b"\x1b[6n")?;
-    let mut buf = vec![0u8; 16];
-    let mut i = 0;
+pub fn set_clipboard(bytes: &[u8]) -> Vec<u8> {
+    let mut len = bytes.len().min(MAX_CLIPBOARD_LEN);
+    // Continuation bytes of UTF-8 are 0b10xxxxxx
+    while len < bytes.len() && len > 0 && bytes[len] & 0xc0 == 0x80 {
+        len -= 1;
+    }
+    [b"\x1b]52;c;", &base64(&bytes[..len])[..], b"\x07"].concat()
+}
+
+/// How long the terminal has to answer a cursor position query.
+const CURSOR_QUERY_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Parses a cursor position report (`ESC [ row ; column R`) at the start of `bytes`,
+/// returning its length and the position.
+///
+/// Positions count from 1, but some terminals report the first row or column as 0.
+fn parse_position_report(bytes: &[u8]) -> Option<(usize, Vec2)> {
+    let rest = bytes.strip_prefix(b"\x1b[")?;
+    let end = rest.find_byte(b'R')?;
+    let (row, column) = rest[..end].to_str().ok()?.split_once(';')?;
+    let (column, row): (u32, u32) = (column.parse().ok()?, row.parse().ok()?);
+    let position = Vec2::new(column.max(1), row.max(1));
+    Some((b"\x1b[".len() + end + 1, position))
+}
+
+/// Finds a cursor position report in `bytes`, which may also contain keys the user typed.
+fn find_position_report(bytes: &[u8]) -> Option<(Range<usize>, Vec2)> {
+    bytes.find_iter(b"\x1b[").find_map(|start| {
+        let (len, position) = parse_position_report(&bytes[start..])?;
+        Some((start..start + len, position))
+    })
+}
+
+/// Asks the terminal where the cursor is.
+///
+/// Anything typed while waiting for the answer is given back with [`tty::unread`].
+pub fn get_cursor_pos(tty: Tty) -> std::io::Result<Vec2> {
+    tty.write(b"\x1b[6n")?;
+    let deadline = Instant::now() + CURSOR_QUERY_TIMEOUT;
+    let mut buf = Vec::with_capacity(16);
     loop {
-        let read = read(&mut buf[i..])?;
-        if read == 0 {
-            continue;
+        if let Some((range, position)) = find_position_report(&buf) {
+            tty::unread(&buf[..range.start]);
+            tty::unread(&buf[range.end..]);
+            return Ok(position);
         }
-        i += read;
-        if let Some(new_i) = buf.find(&b"R") {
-            i = new_i;
-            break;
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let mut chunk = [0u8; 32];
+        let read = if tty.poll_input(timeout)? {
+            nix::unistd::read(tty.input, &mut chunk)?
+        } else {
+            0
+        };
+        if read == 0 {
+            tty::unread(&buf);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "the terminal did not report the cursor position",
+            ));
         }
+        buf.extend_from_slice(&chunk[..read]);
     }
-    let nums = &buf[2..i]
-        .split_str(&b";")
-        .map(|i| i.to_str().unwrap())
-        .map(|i| i.parse::<u32>().unwrap())
-        .collect::<Vec<_>>();
-    let x = nums[0];
-    let y = nums[1];
-    Ok(Vec2::new(y, x))
 }
 
-mod ioctl {
-    use super::*;
-    nix::ioctl_read_bad!(getwinsz, nix::libc::TIOCGWINSZ, Winsize);
+/// Size assumed when neither the terminal nor `$COLUMNS` and `$LINES` tell.
+pub const DEFAULT_TERMINAL_SIZE: Vec2 = Vec2::new(80, 24);
+
+/// Whether the user was already told the terminal doesn't report its size.
+static SIZE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Picks the size of the terminal from what it `reported`, if it did.
+///
+/// A missing or zero dimension is taken from `$COLUMNS` or `$LINES`, and then from
+/// [`DEFAULT_TERMINAL_SIZE`].
+pub fn resolve_terminal_size(reported: Option<Vec2>) -> Vec2 {
+    let columns = std::env::var("COLUMNS").ok();
+    let lines = std::env::var("LINES").ok();
+    pick_terminal_size(reported, columns.as_deref(), lines.as_deref())
+}
+
+/// [`resolve_terminal_size`] with the values of `$COLUMNS` and `$LINES` given.
+fn pick_terminal_size(reported: Option<Vec2>, columns: Option<&str>, lines: Option<&str>) -> Vec2 {
+    let reported = reported.unwrap_or(Vec2::ZERO);
+    let resolve = |reported: u32, var: Option<&str>, default: u32| match reported {
+        0 => var
+            .and_then(|value| value.parse().ok())
+            .filter(|&value| value != 0)
+            .unwrap_or(default),
+        reported => reported,
+    };
+    Vec2::new(
+        resolve(reported.x, columns, DEFAULT_TERMINAL_SIZE.x),
+        resolve(reported.y, lines, DEFAULT_TERMINAL_SIZE.y),
+    )
 }
 
+/// Size of the terminal, in columns and rows, which is never zero.
+///
+/// If the terminal doesn't tell, it is guessed with [`resolve_terminal_size`] and the
+/// user is warned once.
 #[must_use]
-pub fn terminal_size() -> nix::Result<Vec2> {
-    unsafe {
-        let mut winsz = std::mem::MaybeUninit::<Winsize>::uninit();
-        ioctl::getwinsz(nix::libc::STDOUT_FILENO, winsz.as_mut_ptr())?;
-        let winsz = winsz.assume_init();
-        Ok(Vec2::new(winsz.ws_col as u32, winsz.ws_row as u32))
+pub fn terminal_size(tty: Tty) -> Vec2 {
+    let reported = tty.size().ok().filter(|size| size.x != 0 && size.y != 0);
+    let size = resolve_terminal_size(reported);
+    if reported.is_none() && !SIZE_WARNED.swap(true, Ordering::Relaxed) {
+        // Only a warning, not worth failing to draw for
+        let _ = crate::output::print_fmt(format_args!(
+            "yash: the terminal doesn't report its size, assuming {}x{}\n",
+            size.x, size.y
+        ));
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terminal_size_falls_back() {
+        assert_eq!(pick_terminal_size(None, None, None), DEFAULT_TERMINAL_SIZE);
+        let reported = Some(Vec2::new(120, 0));
+        assert_eq!(pick_terminal_size(reported, None, None), Vec2::new(120, 24));
+
+        let (columns, lines) = (Some("100"), Some("0"));
+        assert_eq!(pick_terminal_size(None, columns, lines), Vec2::new(100, 24));
+        assert_eq!(pick_terminal_size(reported, columns, lines), Vec2::new(120, 24));
+        assert_eq!(pick_terminal_size(reported, columns, Some("50")), Vec2::new(120, 50));
+        assert_eq!(pick_terminal_size(None, Some("wide"), None), DEFAULT_TERMINAL_SIZE);
+    }
+
+    #[test]
+    fn positions_are_not_capped() {
+        assert_eq!(set_position(1, 1), b"\x1b[1;1H");
+        assert_eq!(set_position(12, 3), b"\x1b[3;12H");
+        assert_eq!(set_position(256, 300), b"\x1b[300;256H");
+        assert_eq!(set_positionv(Vec2::new(1000, 70000)), b"\x1b[70000;1000H");
+        assert_eq!([save_cursor(), restore_cursor()].concat(), b"\x1b7\x1b8");
+    }
+
+    #[test]
+    fn columns_are_inserted_and_deleted() {
+        assert_eq!(insert_columns(0), b"");
+        assert_eq!(insert_columns(2), b"\x1b[2@");
+        assert_eq!(delete_columns(0), b"");
+        assert_eq!(delete_columns(12), b"\x1b[12P");
+    }
+
+    #[test]
+    fn titles_are_sanitized_and_cut() {
+        assert_eq!(set_title("yash: ~/src"), b"\x1b]0;yash: ~/src\x07");
+        assert_eq!(
+            set_title("echo \x07\x1b]0;evil\x1b\\\u{9c}done\n"),
+            b"\x1b]0;echo ]0;evil\\done\x07"
+        );
+        let exact = "x".repeat(MAX_TITLE_LEN);
+        assert_eq!(
+            set_title(&exact),
+            [&b"\x1b]0;"[..], exact.as_bytes(), b"\x07"].concat()
+        );
+        let long = "é".repeat(MAX_TITLE_LEN + 1);
+        let cut = format!("\x1b]0;{}…\x07", "é".repeat(MAX_TITLE_LEN - 1));
+        assert_eq!(set_title(&long), cut.as_bytes());
+    }
+
+    #[test]
+    fn clipboard_is_set_in_base64() {
+        assert_eq!(set_clipboard(b""), b"\x1b]52;c;\x07");
+        assert_eq!(set_clipboard(b"f"), b"\x1b]52;c;Zg==\x07");
+        assert_eq!(set_clipboard(b"fo"), b"\x1b]52;c;Zm8=\x07");
+        assert_eq!(set_clipboard(b"foo"), b"\x1b]52;c;Zm9v\x07");
+        assert_eq!(
+            set_clipboard("ls -l ~/日本\n".as_bytes()),
+            b"\x1b]52;c;bHMgLWwgfi/ml6XmnKwK\x07"
+        );
+        assert_eq!(set_clipboard(b"\xff\xfe\x00"), b"\x1b]52;c;//4A\x07");
+    }
+
+    #[test]
+    fn clipboard_is_cut_between_characters() {
+        let exact = "abc".repeat(MAX_CLIPBOARD_LEN / 3);
+        let encoded = set_clipboard(exact.as_bytes());
+        assert_eq!(encoded.len(), b"\x1b]52;c;\x07".len() + 99_992);
+        assert_eq!(encoded, set_clipboard(format!("{}d", exact).as_bytes()));
+        // The `é` across the limit is left out whole
+        let long = format!("{}é", "a".repeat(MAX_CLIPBOARD_LEN - 1));
+        let cut = set_clipboard(&long.as_bytes()[..MAX_CLIPBOARD_LEN - 1]);
+        assert_eq!(set_clipboard(long.as_bytes()), cut);
+    }
+
+    #[test]
+    fn finds_report_among_typed_keys() {
+        let bytes = b"ls\x1b[A\x1b[12;345Rx";
+        let (range, position) = find_position_report(bytes).unwrap();
+        assert_eq!(&bytes[..range.start], b"ls\x1b[A");
+        assert_eq!(&bytes[range.end..], b"x");
+        assert_eq!(position, Vec2::new(345, 12));
+    }
+
+    #[test]
+    fn zero_based_reports_start_at_one() {
+        let (_, position) = find_position_report(b"\x1b[0;0R").unwrap();
+        assert_eq!(position, Vec2::new(1, 1));
+        let (_, position) = find_position_report(b"\x1b[5;0R").unwrap();
+        assert_eq!(position, Vec2::new(1, 5));
+    }
+
+    #[test]
+    fn incomplete_report_is_not_found() {
+        assert_eq!(find_position_report(b"\x1b[12;3"), None);
+        assert_eq!(find_position_report(b"\x1b[A typed R"), None);
+        let big = b"\x1b[123456789;987654321R";
+        assert_eq!(
+            find_position_report(big),
+            Some((0..big.len(), Vec2::new(987654321, 123456789)))
+        );
     }
 }
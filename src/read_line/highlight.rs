@@ -0,0 +1,222 @@
+//! Colors the line being typed: command names by whether they exist, and quoted strings.
+
+use std::{collections::HashSet, path::Path};
+
+use crate::command::path_cache::Commands;
+
+const KNOWN: &str = "\x1b[32m";
+const UNKNOWN: &str = "\x1b[31m";
+const DEFAULT_COLOR: &str = "\x1b[39m";
+const DIM: &str = "\x1b[2m";
+const NORMAL_INTENSITY: &str = "\x1b[22m";
+
+/// Keywords that are followed by a command.
+const COMMAND_KEYWORDS: [&str; 4] = ["if", "then", "else", "do"];
+const OTHER_KEYWORDS: [&str; 3] = ["fi", "for", "done"];
+
+fn is_operator(c: char) -> bool {
+    matches!(c, '|' | '&' | ';' | '<' | '>')
+}
+
+/// Returns true for `NAME=value` words, which are followed by the actual command.
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+/// Knows which commands exist, from the `$PATH` cache and the builtins and aliases.
+#[derive(Debug, Default, Clone)]
+pub struct Highlighter {
+    commands: Commands,
+    builtins: HashSet<String>,
+}
+
+impl Highlighter {
+    pub fn new(commands: Commands, builtins: HashSet<String>) -> Self {
+        Self { commands, builtins }
+    }
+
+    fn is_known(&self, name: &str) -> bool {
+        if name.contains('/') {
+            return Path::new(name).is_file();
+        }
+        self.builtins.contains(name) || self.commands.contains_key(name)
+    }
+
+    /// Returns `line` with color sequences around command names and quoted strings.
+    ///
+    /// Only SGR sequences are added, so the displayed width doesn't change. Unterminated
+    /// quotes are fine, since the line is still being typed.
+    pub fn highlight(&self, line: &str) -> String {
+        let mut out = String::with_capacity(line.len() * 2);
+        let mut command_position = true;
+        let mut chars = line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() || is_operator(c) {
+                chars.next();
+                out.push(c);
+                if is_operator(c) {
+                    // The word after a redirection is a file
+                    command_position = !matches!(c, '<' | '>');
+                }
+                continue;
+            }
+            // The word as typed, with quoted parts dimmed, and its value
+            let mut styled = String::new();
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || is_operator(c) {
+                    break;
+                }
+                chars.next();
+                styled.push(c);
+                match c {
+                    '\\' => {
+                        if let Some(c) = chars.next() {
+                            styled.push(c);
+                            word.push(c);
+                        }
+                    }
+                    '\'' | '"' => {
+                        styled.insert_str(styled.len() - 1, DIM);
+                        for quoted in chars.by_ref() {
+                            styled.push(quoted);
+                            if quoted == c {
+                                break;
+                            }
+                            word.push(quoted);
+                        }
+                        styled.push_str(NORMAL_INTENSITY);
+                    }
+                    c => word.push(c),
+                }
+            }
+            if !command_position || is_assignment(&word) {
+                out.push_str(&styled);
+                continue;
+            }
+            let is_keyword = COMMAND_KEYWORDS.contains(&word.as_str());
+            let known =
+                is_keyword || OTHER_KEYWORDS.contains(&word.as_str()) || self.is_known(&word);
+            out.push_str(if known { KNOWN } else { UNKNOWN });
+            out.push_str(&styled);
+            out.push_str(DEFAULT_COLOR);
+            command_position = is_keyword;
+        }
+        out
+    }
+}
+
+/// How a character of a highlighted line is drawn: the SGR parameter of its color, or 0
+/// for the default one, and whether it is dimmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Style {
+    color: u8,
+    dim: bool,
+}
+
+/// The characters of a line returned by [`Highlighter::highlight`], each with its style.
+pub fn styles(highlighted: &str) -> Vec<(char, Style)> {
+    let mut styles = vec![];
+    let mut style = Style::default();
+    let mut rest = highlighted;
+    while let Some(c) = rest.chars().next() {
+        if let Some(sgr) = rest.strip_prefix("\x1b[") {
+            let end = sgr.find('m').unwrap_or(sgr.len());
+            match &sgr[..end] {
+                "2" => style.dim = true,
+                "22" => style.dim = false,
+                "39" => style.color = 0,
+                color => style.color = color.parse().unwrap_or_default(),
+            }
+            rest = &sgr[(end + 1).min(sgr.len())..];
+            continue;
+        }
+        styles.push((c, style));
+        rest = &rest[c.len_utf8()..];
+    }
+    styles
+}
+
+/// Whether a line drawn with the styles of `old` gets those of `new` when only what
+/// changed is drawn, uncolored. The change is right before the last `rest` characters,
+/// which may be drawn again uncolored too.
+pub fn edit_keeps_styles(old: &[(char, Style)], new: &[(char, Style)], rest: usize) -> bool {
+    if old == new {
+        return true;
+    }
+    if rest > old.len() || rest > new.len() {
+        return false;
+    }
+    let (old_head, old_tail) = old.split_at(old.len() - rest);
+    let (new_head, new_tail) = new.split_at(new.len() - rest);
+    let common = old_head
+        .iter()
+        .zip(new_head)
+        .take_while(|(old, new)| old.0 == new.0)
+        .count();
+    let plain = |chars: &[(char, Style)]| chars.iter().all(|(_, style)| *style == Style::default());
+    old_tail == new_tail
+        && plain(new_tail)
+        && old_head[..common] == new_head[..common]
+        && plain(&new_head[common..])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf, rc::Rc};
+
+    use super::*;
+
+    fn highlighter() -> Highlighter {
+        let commands: HashMap<_, _> = [("ls".to_string(), PathBuf::from("/bin/ls"))].into();
+        Highlighter::new(Rc::new(commands), ["cd".to_string()].into())
+    }
+
+    #[test]
+    fn command_names_are_colored() {
+        let highlighter = highlighter();
+        assert_eq!(
+            highlighter.highlight("ls -l | nope x; cd"),
+            "\x1b[32mls\x1b[39m -l | \x1b[31mnope\x1b[39m x; \x1b[32mcd\x1b[39m"
+        );
+        assert_eq!(
+            highlighter.highlight("A=1 ls > cd"),
+            "A=1 \x1b[32mls\x1b[39m > cd"
+        );
+        assert_eq!(
+            highlighter.highlight("if ls; then x"),
+            "\x1b[32mif\x1b[39m \x1b[32mls\x1b[39m; \x1b[32mthen\x1b[39m \x1b[31mx\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn quotes_are_dimmed() {
+        let highlighter = highlighter();
+        assert_eq!(
+            highlighter.highlight("ls 'a b'c \"d"),
+            "\x1b[32mls\x1b[39m \x1b[2m'a b'\x1b[22mc \x1b[2m\"d\x1b[22m"
+        );
+        // The quotes are not part of the command name
+        assert_eq!(
+            highlighter.highlight("'ls'"),
+            "\x1b[32m\x1b[2m'ls'\x1b[22m\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn edits_keeping_the_styles() {
+        let highlighter = highlighter();
+        let of = |line: &str| styles(&highlighter.highlight(line));
+        // Typing or erasing arguments changes nothing else
+        assert!(edit_keeps_styles(&of("ls ac"), &of("ls abc"), 1));
+        assert!(edit_keeps_styles(&of("ls abc"), &of("ls ac"), 1));
+        assert!(edit_keeps_styles(&of("ls 'a"), &of("ls 'a"), 0));
+        // The command name changes color, and so does what is typed in quotes
+        assert!(!edit_keeps_styles(&of("l"), &of("ls"), 0));
+        assert!(!edit_keeps_styles(&of("ls 'a"), &of("ls 'ab"), 0));
+        // What follows the cursor may be drawn again uncolored
+        assert!(!edit_keeps_styles(&of("ls 'a'"), &of("ls b'a'"), 3));
+    }
+}
@@ -1,47 +1,588 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::fcntl::{flock, FlockArg};
+
+/// Seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// The time in a `#EPOCH` line of the history file.
+fn parse_time(line: &str) -> Option<u64> {
+    let digits = line.strip_prefix('#')?;
+    match !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        true => digits.parse().ok(),
+        false => None,
+    }
+}
+
+/// Reads the entries of a history file, where an entry may come after a `#EPOCH` line
+/// saying when it was run. Entries without one, like in older files, get time 0.
+fn parse_entries(text: &str) -> Vec<(u64, String)> {
+    let mut entries = vec![];
+    let mut time = 0;
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        match parse_time(line) {
+            Some(line_time) => time = line_time,
+            None => entries.push((std::mem::take(&mut time), line.to_string())),
+        }
+    }
+    entries
+}
+
+/// Writes entries the way [`parse_entries`] reads them, with a `#EPOCH` line before each
+/// one with a known time.
+fn format_entries<'a>(entries: impl IntoIterator<Item = (u64, &'a str)>) -> String {
+    let mut text = String::new();
+    for (time, line) in entries {
+        if time != 0 {
+            text.push_str(&format!("#{}\n", time));
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    text
+}
+
+/// How big the history file may get before it is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotateLimit {
+    Entries(usize),
+    Bytes(u64),
+}
+
+impl RotateLimit {
+    /// Parses a number of entries like `5000`, or of bytes with a `b`, `k` or `m` suffix
+    /// like `512k`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (digits, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+            Some(end) => text.split_at(end),
+            None => return text.parse().ok().map(Self::Entries),
+        };
+        let unit = match unit.to_ascii_lowercase().as_str() {
+            "b" => 1,
+            "k" => 1024,
+            "m" => 1024 * 1024,
+            _ => return None,
+        };
+        let count: u64 = digits.parse().ok()?;
+        Some(Self::Bytes(count.checked_mul(unit)?))
+    }
+
+    fn exceeded(self, entries: usize, bytes: usize) -> bool {
+        match self {
+            Self::Entries(limit) => entries > limit,
+            Self::Bytes(limit) => bytes as u64 > limit,
+        }
+    }
+}
+
+/// Splits the entries into the oldest ones, which are archived, and the newest `keep`.
+fn split_overflow<T>(mut entries: Vec<T>, keep: usize) -> (Vec<T>, Vec<T>) {
+    let kept = entries.split_off(entries.len().saturating_sub(keep));
+    (entries, kept)
+}
+
+/// Moves all but the newest `keep` entries of the history file at `path` to the end of
+/// `archive` when the file is over `limit`, or regardless of its size without one.
+/// Returns how many entries were moved.
+///
+/// The archive is written and synced before the history file is replaced, so a crash in
+/// between leaves entries in both files rather than in neither.
+pub fn rotate(
+    path: &Path,
+    archive: &Path,
+    limit: Option<RotateLimit>,
+    keep: usize,
+) -> io::Result<usize> {
+    // Sessions saving meanwhile wait, and then append to the new file
+    let mut file = match lock_exclusive(path, OpenOptions::new().read(true)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut text = vec![];
+    file.read_to_end(&mut text)?;
+    let entries = parse_entries(&String::from_utf8_lossy(&text));
+    let over = limit.is_none_or(|limit| limit.exceeded(entries.len(), text.len()));
+    if !over || entries.len() <= keep {
+        return Ok(0);
+    }
+    let (archived, kept) = split_overflow(entries, keep);
+    let as_str = |entries: &[(u64, String)]| -> String {
+        format_entries(entries.iter().map(|(time, line)| (*time, line.as_str())))
+    };
+    let mut archive_file = OpenOptions::new().append(true).create(true).open(archive)?;
+    archive_file.write_all(as_str(&archived).as_bytes())?;
+    archive_file.sync_all()?;
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    let mut temp_file = File::create(&temp)?;
+    temp_file.write_all(as_str(&kept).as_bytes())?;
+    temp_file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    Ok(archived.len())
+}
+
+/// Opens the file at `path` with `options` and locks it exclusively, opening it again when
+/// a rotation replaced it while waiting for the lock.
+fn lock_exclusive(path: &Path, options: &OpenOptions) -> io::Result<File> {
+    loop {
+        let file = options.open(path)?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+        if file.metadata()?.ino() == std::fs::metadata(path)?.ino() {
+            return Ok(file);
+        }
+    }
+}
+
+fn ends_with_newline(file: &mut File) -> io::Result<bool> {
+    let mut last = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct History {
     past_lines: Vec<String>,
-    draft_line: Option<String>,
+    /// When each past line was run, in seconds since the epoch, or 0 if unknown.
+    times: Vec<u64>,
+    /// Lines edited during the current prompt, by index. The draft is at index 0.
+    edits: HashMap<usize, String>,
+    /// How many lines back the selected one is, 0 being the line being typed.
     index: usize,
+    /// The file the history is shared through with other sessions.
+    file: Option<PathBuf>,
+    /// How many bytes of the file are already in `past_lines`.
+    file_len: u64,
+    /// How many past lines, from the oldest, are in the file. The others were run in this
+    /// session and are still to be saved.
+    saved: usize,
+    /// Files with older history, the newest first, loaded one by one when going back past
+    /// the oldest line.
+    archives: Vec<PathBuf>,
+    /// Patterns of the lines that aren't kept, from `$HISTIGNORE`.
+    ignore: Vec<String>,
+}
+
+impl From<Vec<String>> for History {
+    fn from(lines: Vec<String>) -> Self {
+        Self::from_lines(lines)
+    }
 }
 
 impl History {
     pub fn from_lines(lines: Vec<String>) -> Self {
         Self {
+            times: vec![0; lines.len()],
             past_lines: lines,
             ..Default::default()
         }
     }
+    /// Loads the history shared through `path`, which doesn't need to exist yet.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut history = Self {
+            file: Some(path),
+            ..Default::default()
+        };
+        history.reload()?;
+        Ok(history)
+    }
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+    /// Sets the archives of older history to go back into once the loaded lines run out.
+    pub fn set_archives(&mut self, archives: Vec<PathBuf>) {
+        self.archives = archives;
+    }
+    /// Sets the glob patterns of the lines that [`History::push`] leaves out.
+    pub fn set_ignore(&mut self, patterns: Vec<String>) {
+        self.ignore = patterns;
+    }
+    /// Whether `line` is left out of the history, like any starting with a space so
+    /// secrets can be typed without being saved.
+    pub fn ignores(&self, line: &str) -> bool {
+        line.starts_with(' ')
+            || self
+                .ignore
+                .iter()
+                .any(|pattern| crate::glob::matches(pattern, line))
+    }
+    /// Stops sharing the history, keeping what was loaded.
+    pub fn close(&mut self) {
+        self.file = None;
+    }
+    /// Loads the lines other sessions saved to the file since it was last read, returning
+    /// how many there were.
+    pub fn reload(&mut self) -> io::Result<usize> {
+        let Some(path) = &self.file else {
+            return Ok(0);
+        };
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        flock(file.as_raw_fd(), FlockArg::LockShared)?;
+        self.read_new(&mut file)
+    }
+    /// Appends the lines run since the last save to the file, after loading those other
+    /// sessions saved meanwhile.
+    ///
+    /// The file stays locked while it is written so entries of two sessions don't mix.
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut options = OpenOptions::new();
+        options.read(true).append(true).create(true);
+        let mut file = lock_exclusive(path, &options)?;
+        self.read_new(&mut file)?;
+        if self.saved == self.past_lines.len() {
+            return Ok(());
+        }
+        let mut text = self.file_text(self.saved);
+        if self.file_len > 0 && !ends_with_newline(&mut file)? {
+            text.insert(0, '\n');
+        }
+        file.write_all(text.as_bytes())?;
+        self.file_len += text.len() as u64;
+        self.saved = self.past_lines.len();
+        Ok(())
+    }
+    fn read_new(&mut self, file: &mut File) -> io::Result<usize> {
+        if file.metadata()?.len() < self.file_len {
+            // Rewritten by someone else, so it is read again from the start
+            self.unselect();
+            self.past_lines.drain(..self.saved);
+            self.times.drain(..self.saved);
+            self.saved = 0;
+            self.file_len = 0;
+        }
+        let mut new = vec![];
+        file.seek(SeekFrom::Start(self.file_len))?;
+        file.read_to_end(&mut new)?;
+        self.file_len += new.len() as u64;
+        let entries = parse_entries(&String::from_utf8_lossy(&new));
+        let count = entries.len();
+        self.merge(entries);
+        Ok(count)
+    }
+    /// Inserts saved entries before the lines still to be saved, keeping the selected line
+    /// and the edits on the lines they were made on.
+    fn merge(&mut self, entries: Vec<(u64, String)>) {
+        let count = entries.len();
+        // Lines further back than this come before the inserted ones
+        let unsaved = self.past_lines.len() - self.saved;
+        let shift = |index: usize| match index > unsaved {
+            true => index + count,
+            false => index,
+        };
+        self.index = shift(self.index);
+        self.edits = std::mem::take(&mut self.edits)
+            .into_iter()
+            .map(|(index, edit)| (shift(index), edit))
+            .collect();
+        let (times, lines): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+        self.times.splice(self.saved..self.saved, times);
+        self.past_lines.splice(self.saved..self.saved, lines);
+        self.saved += count;
+    }
+    /// Loads the newest archive not loaded yet before the oldest line, returning false
+    /// when there are none left.
+    fn load_archive(&mut self) -> bool {
+        if self.archives.is_empty() {
+            return false;
+        }
+        let archive = self.archives.remove(0);
+        // One that can't be read is skipped
+        let text = std::fs::read(archive).unwrap_or_default();
+        let (times, lines): (Vec<_>, Vec<_>) = parse_entries(&String::from_utf8_lossy(&text))
+            .into_iter()
+            .unzip();
+        self.saved += lines.len();
+        self.times.splice(0..0, times);
+        self.past_lines.splice(0..0, lines);
+        true
+    }
+    /// The lines from `start` on as they are written to the file.
+    fn file_text(&self, start: usize) -> String {
+        let times = self.times[start..].iter().copied();
+        format_entries(times.zip(self.past_lines[start..].iter().map(String::as_str)))
+    }
     pub fn push(&mut self, line: impl Into<String>) {
         let line = line.into();
-        if !line.is_empty() {
+        if !line.is_empty() && !self.ignores(&line) {
             self.past_lines.push(line);
+            self.times.push(now());
         }
     }
+    /// Goes back to the draft, forgetting the edits made to past lines.
     pub fn unselect(&mut self) {
-        self.draft_line = None;
+        self.edits.clear();
         self.index = 0;
     }
-    fn get_line<'a, 'b>(&self, index: usize) -> Option<&str> {
-        if index == 0 {
-            return self.draft_line.as_deref();
-        } else {
-            self.past_lines
+    fn get_line(&self, index: usize) -> Option<&str> {
+        if let Some(edited) = self.edits.get(&index) {
+            return Some(edited);
+        }
+        match index {
+            0 => Some(""),
+            _ => self
+                .past_lines
                 .get(self.past_lines.len().checked_sub(index)?)
+                .map(String::as_ref),
         }
-        .map(String::as_ref)
     }
-    pub fn scroll(&mut self, last_prompt: &str, offset: isize) -> Option<&str> {
-        if self.index == 0 {
-            self.draft_line = Some(last_prompt.into());
+    /// Selects the line `offset` lines further back, keeping `current_text` as the
+    /// edited version of the line being left.
+    ///
+    /// Returns `None` without moving when there is no line there. Going back past the
+    /// oldest line first loads the lines other sessions saved since, and then the archives.
+    pub fn scroll(&mut self, current_text: &str, offset: isize) -> Option<&str> {
+        let past_oldest = |history: &Self| {
+            offset > 0 && history.index + offset as usize > history.past_lines.len()
+        };
+        if past_oldest(self) {
+            // Nothing to do about a file that can't be read right now
+            let _ = self.reload();
+        }
+        while past_oldest(self) && self.load_archive() {}
+        let new_index = self
+            .index
+            .checked_add_signed(offset)
+            .filter(|&index| index <= self.past_lines.len())?;
+        if self.get_line(self.index) != Some(current_text) {
+            self.edits.insert(self.index, current_text.into());
         }
-        let new_index = (self.index as isize + offset) as usize;
-        if self.get_line(new_index).is_some() {
-            self.index = new_index;
+        self.index = new_index;
+        self.get_line(new_index)
+    }
+    /// Finds the most recent line that continues `prefix`.
+    pub fn find_prefix(&self, prefix: &str) -> Option<&str> {
+        if prefix.is_empty() {
+            return None;
         }
-        self.get_line(self.index)
+        self.past_lines
+            .iter()
+            .rev()
+            .find(|line| line.len() > prefix.len() && line.starts_with(prefix))
+            .map(String::as_str)
     }
     pub fn lines(&self) -> &[String] {
         &self.past_lines
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn history() -> History {
+        History::from_lines(vec!["first".into(), "second".into()])
+    }
+
+    #[test]
+    fn up_at_top_stops() {
+        let mut history = history();
+        assert_eq!(history.scroll("draft", 1), Some("second"));
+        assert_eq!(history.scroll("second", 1), Some("first"));
+        assert_eq!(history.scroll("first", 1), None);
+        assert_eq!(history.scroll("first", -1), Some("second"));
+    }
+
+    #[test]
+    fn down_at_bottom_stops() {
+        let mut history = history();
+        assert_eq!(history.scroll("draft", -1), None);
+        assert_eq!(history.scroll("draft", 1), Some("second"));
+        assert_eq!(history.scroll("second", -1), Some("draft"));
+        assert_eq!(history.scroll("draft", -1), None);
+        assert_eq!(History::default().scroll("", 1), None);
+    }
+
+    #[test]
+    fn most_recent_prefix_match() {
+        let history = History::from_lines(vec!["git log".into(), "git st".into(), "ls".into()]);
+        assert_eq!(history.find_prefix("gi"), Some("git st"));
+        assert_eq!(history.find_prefix("git l"), Some("git log"));
+        assert_eq!(history.find_prefix("ls"), None);
+        assert_eq!(history.find_prefix(""), None);
+    }
+
+    #[test]
+    fn edits_survive_scrolling() {
+        let mut history = history();
+        assert_eq!(history.scroll("draft", 1), Some("second"));
+        assert_eq!(history.scroll("second edited", 1), Some("first"));
+        assert_eq!(history.scroll("first", -1), Some("second edited"));
+        assert_eq!(history.scroll("second edited", -1), Some("draft"));
+        // The history itself is untouched, and edits last only for one prompt
+        assert_eq!(history.lines(), ["first", "second"]);
+        history.unselect();
+        assert_eq!(history.scroll("", 1), Some("second"));
+    }
+
+    #[test]
+    fn ignored_lines_are_left_out() {
+        let mut history = history();
+        history.set_ignore(["ls", "cd *", "*PASSWORD*"].map(String::from).to_vec());
+        for line in [
+            " curl -u me:secret",
+            "ls",
+            "cd /tmp",
+            "export PASSWORD=hunter2",
+        ] {
+            history.push(line);
+        }
+        assert_eq!(history.lines(), ["first", "second"]);
+        // Only whole lines match
+        for line in ["ls -l", "cd", "echo ls"] {
+            history.push(line);
+        }
+        assert_eq!(
+            history.lines(),
+            ["first", "second", "ls -l", "cd", "echo ls"]
+        );
+    }
+
+    fn append(path: &Path, text: &str) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn file_round_trip_keeps_times() {
+        // Old entries have no time, and a `#` line that isn't a time is a command
+        let file = "ls\n#1700000000\ngit log\ncd /tmp\n#1700000100\n#x\n#1700000200\nmake\n";
+        let mut history = History::default();
+        history.merge(parse_entries(file));
+        assert_eq!(history.lines(), ["ls", "git log", "cd /tmp", "#x", "make"]);
+        assert_eq!(history.times, [0, 1700000000, 0, 1700000100, 1700000200]);
+        assert_eq!(history.file_text(0), file);
+        let mut reloaded = History::default();
+        reloaded.merge(parse_entries(&history.file_text(0)));
+        assert_eq!(reloaded, history);
+    }
+
+    #[test]
+    fn other_sessions_lines_are_merged() {
+        let dir = TempDir::new("shared-history");
+        let path = dir.join("history");
+        // An old file, without times nor a final newline
+        std::fs::write(&path, "old").unwrap();
+        let mut history = History::open(path.clone()).unwrap();
+        history.push("mine");
+        assert_eq!(history.scroll("draft", 1), Some("mine"));
+        assert_eq!(history.scroll("mine edited", 1), Some("old"));
+
+        // Another session saves a line, which goes before the unsaved one
+        append(&path, "\n#1700000000\ntheirs\n");
+        assert_eq!(history.reload().unwrap(), 1);
+        assert_eq!(history.lines(), ["old", "theirs", "mine"]);
+        assert_eq!(history.scroll("old", -1), Some("theirs"));
+        assert_eq!(history.scroll("theirs", -1), Some("mine edited"));
+        assert_eq!(history.scroll("mine edited", -1), Some("draft"));
+
+        history.save().unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            saved.starts_with("old\n#1700000000\ntheirs\n#"),
+            "{}",
+            saved
+        );
+        assert!(saved.ends_with("\nmine\n"), "{}", saved);
+        // Saving again has nothing to add
+        history.save().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+
+        // Going back past the oldest line looks for new ones
+        let path = dir.join("new-history");
+        let mut history = History::open(path.clone()).unwrap();
+        std::fs::write(&path, "#1700000000\nlater\n").unwrap();
+        assert_eq!(history.scroll("", 1), Some("later"));
+    }
+
+    #[test]
+    fn saving_under_a_file_fails() {
+        let dir = TempDir::new("history-parent");
+        let not_a_dir = dir.join("file");
+        std::fs::write(&not_a_dir, "").unwrap();
+        let mut history = history();
+        history.file = Some(not_a_dir.join("hist"));
+        assert!(history.save().is_err());
+        assert!(History::open(not_a_dir.join("hist")).is_err());
+    }
+
+    #[test]
+    fn rotate_limits() {
+        assert_eq!(RotateLimit::parse("5000"), Some(RotateLimit::Entries(5000)));
+        assert_eq!(
+            RotateLimit::parse("512k"),
+            Some(RotateLimit::Bytes(512 * 1024))
+        );
+        assert_eq!(
+            RotateLimit::parse("2M"),
+            Some(RotateLimit::Bytes(2 * 1024 * 1024))
+        );
+        assert_eq!(RotateLimit::parse("100b"), Some(RotateLimit::Bytes(100)));
+        for invalid in ["", "k", "5g", "-1", "1.5m"] {
+            assert_eq!(RotateLimit::parse(invalid), None, "{}", invalid);
+        }
+        assert_eq!(split_overflow(vec![1, 2, 3], 2), (vec![1], vec![2, 3]));
+        assert_eq!(split_overflow(vec![1], 2), (vec![], vec![1]));
+    }
+
+    #[test]
+    fn rotation_moves_the_oldest_entries() {
+        let dir = TempDir::new("rotated-history");
+        let path = dir.join("history");
+        let archive = dir.join("archive");
+        std::fs::write(&path, "one\n#1700000000\ntwo\nthree\nfour\n").unwrap();
+        // Under the limit, nothing happens
+        let limit = Some(RotateLimit::Entries(4));
+        assert_eq!(rotate(&path, &archive, limit, 1).unwrap(), 0);
+        assert!(!archive.exists());
+
+        let limit = Some(RotateLimit::Bytes(10));
+        assert_eq!(rotate(&path, &archive, limit, 2).unwrap(), 2);
+        assert_eq!(
+            std::fs::read_to_string(&archive).unwrap(),
+            "one\n#1700000000\ntwo\n"
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "three\nfour\n");
+
+        // Without a limit it always rotates, appending to the archive
+        assert_eq!(rotate(&path, &archive, None, 1).unwrap(), 1);
+        assert!(std::fs::read_to_string(&archive)
+            .unwrap()
+            .ends_with("two\nthree\n"));
+
+        // The archives are gone back into after the lines of the file
+        let mut history = History::open(path.clone()).unwrap();
+        history.set_archives(vec![archive.clone(), dir.join("missing-archive")]);
+        assert_eq!(history.scroll("", 1), Some("four"));
+        assert_eq!(history.scroll("four", 1), Some("three"));
+        assert_eq!(history.scroll("three", 1), Some("two"));
+        assert_eq!(history.scroll("two", 1), Some("one"));
+        assert_eq!(history.scroll("one", 1), None);
+        assert_eq!(history.lines(), ["one", "two", "three", "four"]);
+        assert_eq!(rotate(&dir.join("no-history"), &archive, None, 1).unwrap(), 0);
+    }
+}
@@ -0,0 +1,408 @@
+//! Runs the line editor against a pseudo terminal, the way a user would drive it.
+
+use std::{
+    collections::HashMap,
+    os::unix::io::RawFd,
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bstr::ByteSlice;
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    pty::{openpty, Winsize},
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg},
+};
+
+use super::*;
+use crate::testing::TempDir;
+
+/// Where the emulated terminal says the cursor is, right after the `$ ` prompt.
+const CURSOR_REPORT: &[u8] = b"\x1b[1;3R";
+
+/// Gives up on the line editor after this long, so a bug can't hang the tests.
+const DEADLINE: Duration = Duration::from_secs(5);
+
+/// Plays the terminal on the master end of the pty: types `keys`, records what is
+/// drawn and answers cursor position queries with `report`, until `done` is set.
+fn emulate(master: RawFd, keys: Vec<u8>, report: &[u8], done: Arc<AtomicBool>) -> Vec<u8> {
+    let deadline = Instant::now() + DEADLINE;
+    nix::unistd::write(master, &keys).unwrap();
+    let mut drawn = Vec::new();
+    let mut answered = 0;
+    while !done.load(Ordering::SeqCst) && Instant::now() < deadline {
+        let mut fds = [PollFd::new(master, PollFlags::POLLIN)];
+        if poll(&mut fds, 10).unwrap() > 0 {
+            let mut chunk = [0u8; 1024];
+            match nix::unistd::read(master, &mut chunk) {
+                Ok(n) => drawn.extend_from_slice(&chunk[..n]),
+                Err(_) => break,
+            }
+        }
+        let queries = drawn.find_iter(b"\x1b[6n").count();
+        for _ in answered..queries {
+            nix::unistd::write(master, report).unwrap();
+        }
+        answered = queries;
+    }
+    // Makes the line editor fail instead of waiting forever
+    nix::unistd::close(master).unwrap();
+    drawn
+}
+
+/// Reads a line from a fresh 80x24 pty after typing `keys`, returning what was
+/// read and what was drawn.
+fn read_line_with(keys: &[u8], read_line: &mut ReadLine) -> (Execute, Vec<u8>) {
+    read_line_reporting(keys, CURSOR_REPORT, read_line)
+}
+
+/// Like [`read_line_with`], with the terminal saying the cursor is where `report` has it.
+fn read_line_reporting(
+    keys: &[u8],
+    report: &'static [u8],
+    read_line: &mut ReadLine,
+) -> (Execute, Vec<u8>) {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let pty = openpty(Some(&winsize), None).unwrap();
+    let mut termios = tcgetattr(pty.slave).unwrap();
+    cfmakeraw(&mut termios);
+    tcsetattr(pty.slave, SetArg::TCSANOW, &termios).unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let terminal = {
+        let (keys, done) = (keys.to_vec(), done.clone());
+        thread::spawn(move || emulate(pty.master, keys, report, done))
+    };
+    read_line.tty = Tty {
+        input: pty.slave,
+        output: pty.slave,
+    };
+    let execute = read_line.read_line("$ ", 2);
+    done.store(true, Ordering::SeqCst);
+    let drawn = terminal.join().unwrap();
+    nix::unistd::close(pty.slave).unwrap();
+    (execute.unwrap(), drawn)
+}
+
+fn read_line(keys: &[u8]) -> (Execute, Vec<u8>) {
+    read_line_with(keys, &mut ReadLine::default())
+}
+
+#[test]
+fn typed_line_is_executed() {
+    let (execute, drawn) = read_line(b"ls -l\r");
+    assert_eq!(execute, Execute::Command("ls -l".into()));
+    assert_eq!(drawn, b"$ ls -l\r\n\x1b[J");
+}
+
+#[test]
+fn ctrl_c_cancels() {
+    let (execute, drawn) = read_line(b"sleep\x03");
+    assert_eq!(execute, Execute::Cancel);
+    assert!(drawn.ends_with(b"sleep^C\r\n\x1b[J"));
+}
+
+#[test]
+fn ctrl_x_ctrl_c_copies_the_line() {
+    let (execute, drawn) = read_line(b"ls -l\x1b[D\x18\x03\r");
+    assert_eq!(execute, Execute::Command("ls -l".into()));
+    assert_eq!(drawn, b"$ ls -l\x1b[1D\x1b]52;c;bHMgLWw=\x07\r\n\x1b[J");
+    let mut read_line = ReadLine::default();
+    read_line.set_clipboard(false);
+    let (_, drawn) = read_line_with(b"ls\x18\x03\r", &mut read_line);
+    assert_eq!(drawn, b"$ ls\x07\r\n\x1b[J");
+}
+
+#[test]
+fn binary_garbage_rings_the_bell() {
+    let (execute, drawn) = read_line(b"l\x80\xc0\xafs\xff\xf0\x9f\x98\r");
+    assert_eq!(execute, Execute::Command("ls".into()));
+    assert_eq!(drawn, b"$ \x07ls\r\n\x1b[J");
+}
+
+#[test]
+fn arrows_move_the_cursor() {
+    let (execute, drawn) = read_line(b"ac\x1b[Db\x1b[C!\r");
+    assert_eq!(execute, Execute::Command("abc!".into()));
+    assert_eq!(drawn, b"$ ac\x1b[1Dbc\x1b[1D\x1b[1C!\r\n\x1b[J");
+}
+
+#[test]
+fn backspace_erases() {
+    let (execute, drawn) = read_line(b"lz\x7fs\r");
+    assert_eq!(execute, Execute::Command("ls".into()));
+    assert_eq!(drawn, b"$ lz\x1b[1D\x1b[Ks\r\n\x1b[J");
+}
+
+#[test]
+fn ctrl_l_redraws_at_the_top() {
+    let (execute, drawn) = read_line(b"ab\x1b[D\x0c\r");
+    assert_eq!(execute, Execute::Command("ab".into()));
+    assert!(drawn.ends_with(b"\x1b[2J\x1b[H$ ab\x1b[1D\r\n\x1b[J"));
+}
+
+#[test]
+fn pasted_lines_run_one_per_enter() {
+    let mut editor = ReadLine::default();
+    editor.set_paste_confirm(true);
+    let keys = b"\x1b[200~echo 1\necho 2\necho 3\necho 4\n\x1b[201~\r";
+    let (execute, _) = read_line_with(keys, &mut editor);
+    assert_eq!(execute, Execute::Command("echo 1".into()));
+    // The next lines wait in the prompts after it, until Enter is pressed on each
+    let (execute, drawn) = read_line_with(b"\r", &mut editor);
+    assert_eq!(execute, Execute::Command("echo 2".into()));
+    assert!(drawn.contains_str("$ echo 2\x1b[K\x1b[2m [+2 lines pasted — Enter to run each"));
+    let (execute, drawn) = read_line_with(b"\x03", &mut editor);
+    assert_eq!(execute, Execute::Cancel);
+    assert!(drawn.contains_str("[+1 line pasted"));
+    // Ctrl-C dropped the last one
+    let (execute, _) = read_line_with(b"ls\r", &mut editor);
+    assert_eq!(execute, Execute::Command("ls".into()));
+}
+
+#[test]
+fn tab_completes_commands() {
+    let commands: HashMap<_, _> = ["frobnicate", "ls"]
+        .into_iter()
+        .map(|name| (name.to_string(), PathBuf::from("/bin").join(name)))
+        .collect();
+    let mut editor = ReadLine::default();
+    editor.set_commands(Rc::new(commands));
+    let (execute, drawn) = read_line_with(b"fro\t\r\r", &mut editor);
+    // Commands are followed by a space for their arguments
+    assert_eq!(execute, Execute::Command("frobnicate ".into()));
+    // Only the completion grid asks for the cursor, the cleanup goes back to where it was
+    assert_eq!(drawn.find_iter(b"\x1b[6n").count(), 1);
+    assert!(drawn.contains_str("\x1b7\n\r\x1b[J\x1b8"));
+    assert!(drawn.contains_str("frobnicate"));
+    assert!(drawn.ends_with(b"\r\n\x1b[J"));
+}
+
+#[test]
+fn completion_is_cancelled_from_any_column() {
+    let commands: HashMap<_, _> = ["frobnicate", "frobulate"]
+        .into_iter()
+        .map(|name| (name.to_string(), PathBuf::from("/bin").join(name)))
+        .collect();
+    let mut editor = ReadLine::default();
+    editor.set_commands(Rc::new(commands));
+    // The first column, one reported from 0 like some terminals do, and the last one on
+    // the bottom line
+    let reports: [&[u8]; 3] = [b"\x1b[1;1R", b"\x1b[0;0R", b"\x1b[24;80R"];
+    for report in reports {
+        let (execute, drawn) = read_line_reporting(b"fro\t\t\x03\r", report, &mut editor);
+        let shown = drawn.as_bstr();
+        assert_eq!(execute, Execute::Command("fro".into()), "{:?}", shown);
+        assert!(drawn.contains_str("frobulate"), "{:?}", shown);
+        // Where the selection is, dimmed below the grid
+        assert!(
+            drawn.contains_str("\x1b[2m1/2  commands\x1b[22m"),
+            "{:?}",
+            shown
+        );
+        assert!(
+            drawn.contains_str("\x1b[2m2/2  commands\x1b[22m"),
+            "{:?}",
+            shown
+        );
+        // Positions count from 1
+        assert!(
+            !drawn.contains_str("\x1b[0;") && !drawn.contains_str(";0H"),
+            "{:?}",
+            shown
+        );
+        // Back to the line to finish it
+        assert!(drawn.contains_str("\x1b7\n\r\x1b[J\x1b8"), "{:?}", shown);
+        assert!(drawn.ends_with(b"\r\n\x1b[J"), "{:?}", shown);
+    }
+}
+
+#[test]
+fn completion_on_the_bottom_line_makes_room_first() {
+    let commands: HashMap<_, _> = ["frobnicate", "frobulate"]
+        .into_iter()
+        .map(|name| (name.to_string(), PathBuf::from("/bin").join(name)))
+        .collect();
+    let mut editor = ReadLine::default();
+    editor.set_commands(Rc::new(commands));
+    let (execute, drawn) = read_line_reporting(b"fro\t\r\r", b"\x1b[24;6R", &mut editor);
+    let shown = drawn.as_bstr();
+    assert_eq!(execute, Execute::Command("frobnicate ".into()));
+    // The items and the status take two lines, so the screen scrolls by two and the
+    // grid is drawn below where the line went
+    let grid = "fro\x1b[6n\n\n\x1b[22;6H\x1b7\x1b[23;1H\x1b[J\x1b[23;1H\x1b[7mfrobnicate";
+    assert!(drawn.contains_str(grid), "{:?}", shown);
+    let status = "\x1b[24;1H\x1b[2m1/2  commands";
+    assert!(drawn.contains_str(status), "{:?}", shown);
+    // Erased from the line, which is back where it is, and only once
+    let after = &drawn[drawn.find("\x1b[22m\x1b8").unwrap()..];
+    let erase = after.find_iter("\x1b7\n\r\x1b[J\x1b8").count();
+    assert_eq!(erase, 1, "{:?}", shown);
+    assert!(drawn.ends_with(b"\r\n\x1b[J"), "{:?}", shown);
+
+    // Nothing to erase when nothing matches, which would scroll the line away
+    let (execute, drawn) = read_line_reporting(b"zzz\t\r", b"\x1b[24;6R", &mut editor);
+    assert_eq!(execute, Execute::Command("zzz".into()));
+    assert_eq!(drawn, b"$ zzz\x07\r\n\x1b[J");
+}
+
+#[test]
+fn arguments_complete_by_command() {
+    let base = TempDir::new("arguments");
+    std::fs::create_dir_all(base.join("docs")).unwrap();
+    std::fs::create_dir_all(base.join("dist")).unwrap();
+    std::fs::write(base.join("data.txt"), "").unwrap();
+    let base = base.display().to_string();
+    let mut editor = ReadLine::default();
+    editor.set_aliases(vec!["ll".into(), "la".into(), "gs".into()]);
+    editor.set_variables(vec!["PATH".into(), "PAGER".into()]);
+
+    let keys = format!("cd {}/d\t\r\r", base);
+    let (execute, drawn) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(execute, Execute::Command(format!("cd {}/dist/", base)));
+    assert!(drawn.contains_str("docs/"));
+    assert!(!drawn.contains_str("data.txt"));
+    assert!(drawn.contains_str(format!("\x1b[2m1/2  {}/\x1b[22m", base)));
+    // Links to directories are completed into too, without a space after them
+    std::os::unix::fs::symlink("docs", format!("{}/zlink", base)).unwrap();
+    let keys = format!("cd {}/z\t\r\r", base);
+    let (execute, _) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(execute, Execute::Command(format!("cd {}/zlink/", base)));
+    // Other commands still get every file
+    let keys = format!("ls; cat {}/d\t\r\r", base);
+    let (execute, _) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(
+        execute,
+        Execute::Command(format!("ls; cat {}/data.txt ", base))
+    );
+    let keys = format!("source {}/d\t\r\r", base);
+    let (execute, drawn) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(
+        execute,
+        Execute::Command(format!("source {}/data.txt ", base))
+    );
+    assert!(!drawn.contains_str("docs/"));
+
+    let (execute, drawn) = read_line_with(b"unalias l\t\r\r", &mut editor);
+    assert_eq!(execute, Execute::Command("unalias la ".into()));
+    assert!(drawn.contains_str("ll") && !drawn.contains_str("gs"));
+    let (execute, _) = read_line_with(b"unset PA\t\t\r\r", &mut editor);
+    assert_eq!(execute, Execute::Command("unset PATH ".into()));
+}
+
+#[test]
+fn fuzzy_completion_ranks_and_marks_matches() {
+    let base = TempDir::new("fuzzy");
+    std::fs::create_dir_all(base.join("Downloads")).unwrap();
+    std::fs::create_dir_all(base.join("dl-tools")).unwrap();
+    std::fs::write(base.join("docs"), "").unwrap();
+    let base = base.display().to_string();
+    let mut editor = ReadLine::default();
+    let keys = format!("ls {}/dl\t\r\r", base);
+    // Prefixes keep their case
+    let (_, drawn) = read_line_with(keys.as_bytes(), &mut editor);
+    assert!(!drawn.contains_str("Downloads"));
+
+    editor.set_completion_matcher(completion::Matcher::Fuzzy);
+    let (execute, drawn) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(execute, Execute::Command(format!("ls {}/dl-tools/", base)));
+    assert!(drawn.contains_str("\x1b[7m\x1b[4mdl\x1b[24m-tools/\x1b[0m"));
+    assert!(drawn.contains_str("\x1b[4mD\x1b[24mown\x1b[4ml\x1b[24moads/"));
+    assert!(!drawn.contains_str("docs"));
+    let keys = format!("ls {}/dl\t\t\r\r", base);
+    let (execute, _) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(execute, Execute::Command(format!("ls {}/Downloads/", base)));
+}
+
+#[test]
+fn failed_completions_leave_the_prompt_working() {
+    use std::os::unix::fs::PermissionsExt;
+    let base = TempDir::new("completion");
+    std::fs::write(base.join("file1"), "").unwrap();
+    let base = base.display().to_string();
+    let mut editor = ReadLine::default();
+    // The message goes with the next key, and the next Tab completes again
+    let erase = "\x7f".repeat("missing/x".len());
+    let keys = format!("cat {}/missing/x\t{}f\t\r\r", base, erase);
+    let (execute, drawn) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(execute, Execute::Command(format!("cat {}/file1 ", base)));
+    let message = format!("no such directory: {}/missing/", base);
+    assert!(drawn.contains_str(&message), "{:?}", drawn.as_bstr());
+    let after = &drawn[drawn.find(&message).unwrap()..];
+    assert!(after.contains_str("\x1b7\n\r\x1b[J\x1b8"));
+
+    // Nothing to complete rings the bell
+    let keys = format!("cat {}/zzz\t\r", base);
+    let (execute, drawn) = read_line_with(keys.as_bytes(), &mut editor);
+    assert_eq!(execute, Execute::Command(format!("cat {}/zzz", base)));
+    assert!(drawn.contains(&b'\x07'));
+
+    // Root can list any directory, so there is no permission to be denied
+    if nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let locked = format!("{}/locked", base);
+    std::fs::create_dir(&locked).unwrap();
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+    let keys = format!("cat {}/x\t\r", locked);
+    let (execute, drawn) = read_line_with(keys.as_bytes(), &mut editor);
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(execute, Execute::Command(format!("cat {}/x", locked)));
+    assert!(drawn.contains_str(format!("permission denied: {}/", locked)));
+}
+
+#[test]
+fn highlighting_redraws_the_line() {
+    let commands: HashMap<_, _> = [("ls".to_string(), PathBuf::from("/bin/ls"))].into();
+    let mut editor = ReadLine::new_with_history(vec!["lz".into()]);
+    editor.set_highlighter(Some(highlight::Highlighter::new(
+        Rc::new(commands),
+        Default::default(),
+    )));
+    let (execute, drawn) = read_line_with(b"\x1b[A\x7fs\r", &mut editor);
+    assert_eq!(execute, Execute::Command("ls".into()));
+    // Unknown until the name is complete, and each redraw starts at the prompt
+    assert!(drawn.ends_with(b"\x1b[31mlz\x1b[39m\x1b[K\x1b[2D\x1b[32mls\x1b[39m\x1b[K\r\n\x1b[J"));
+}
+
+#[test]
+fn history_is_suggested() {
+    let mut editor = ReadLine::new_with_history(vec!["git log".into(), "ls".into()]);
+    let (execute, drawn) = read_line_with(b"gi\x1b[C\r", &mut editor);
+    assert_eq!(execute, Execute::Command("git log".into()));
+    // Shown dimmed after the cursor, then accepted and erased before running
+    let suggested = b"gi\x1b[K\x1b[2mt log\x1b[22m\x1b[5D";
+    let accepted = b"t log\x1b[K\r\n\x1b[J";
+    assert!(drawn.ends_with(&[&suggested[..], accepted].concat()));
+
+    // Typing past the suggestion doesn't accept it
+    let (execute, drawn) = read_line_with(b"gix\r", &mut editor);
+    assert_eq!(execute, Execute::Command("gix".into()));
+    assert!(drawn.ends_with(b"gix\r\n\x1b[J"));
+}
+
+#[test]
+fn ignored_lines_run_without_being_kept() {
+    let mut editor = ReadLine::new_with_history(vec!["ls".into()]);
+    editor.history_mut().set_ignore(vec!["*TOKEN*".into()]);
+    let (execute, _) = read_line_with(b" echo secret\r", &mut editor);
+    // The space is still there for the shell to run
+    assert_eq!(execute, Execute::Command(" echo secret".into()));
+    let (execute, _) = read_line_with(b"export TOKEN=1\r", &mut editor);
+    assert_eq!(execute, Execute::Command("export TOKEN=1".into()));
+    // Going back skips them
+    let (execute, _) = read_line_with(b"\x1b[A\r", &mut editor);
+    assert_eq!(execute, Execute::Command("ls".into()));
+    assert_eq!(editor.history(), ["ls", "ls"]);
+}
@@ -1,11 +1,9 @@
 use std::mem;
 
-use bstr::ByteVec;
-
-use crate::utils::{char_at, char_count};
+use crate::utils::{cluster_starts, display_width};
 use crate::Vec2 as Pos;
 
-use super::cursor;
+use super::{cursor, fit_in};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
@@ -14,14 +12,63 @@ pub enum SpecialKey {
     Down,
     Tab,
     ShiftTab,
+    /// Right, End or Ctrl-F with the cursor already at the end of the line.
+    ForwardAtEnd,
+    /// Ctrl-X then Ctrl-C, copying the line to the clipboard.
+    CopyLine,
+    /// Left and Right while a menu is open, see [`TextField::set_menu_open`].
+    Left,
+    Right,
 }
 
+/// Columns the field keeps however little room it is given, so typing goes on.
+const MIN_WIDTH: u32 = 4;
+
+/// Sent by the terminal around pasted text when bracketed paste is enabled.
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
 #[derive(Debug, Default)]
 pub struct TextField {
     text: String,
+    /// Byte index of the cursor in `text`, always at the start of a character
+    cursor: usize,
+    /// Column of the cursor, since characters may be wider than one column
     cursor_pos: Pos,
     bounds: Pos,
+    /// Byte index of the first character shown, once the text is wider than the field
+    scroll: usize,
+    /// Whether only part of the text is shown, which the next response draws again
+    scrolled: bool,
+    /// Column the terminal cursor was left at, counted from the first character shown
+    shown_column: u32,
     response: Response,
+    /// Whether we are in between [`PASTE_START`] and [`PASTE_END`]
+    pasting: bool,
+    /// Start of an escape sequence that was cut at the end of the last input
+    partial: String,
+    /// Whether the lines of a paste wait to be run one by one instead of running right away
+    confirm_paste: bool,
+    /// What was pasted after the first line break, while the paste goes on
+    queued: Option<String>,
+    /// Whether the terminal inserts and deletes columns itself, so editing the middle of
+    /// the line doesn't write the rest of it again
+    edit_in_place: bool,
+    /// Whether the last key was Ctrl-X, which starts the key bindings of two keys
+    ctrl_x: bool,
+    /// Whether Left and Right are keys of a menu instead of moving the cursor
+    menu_open: bool,
+}
+
+/// A pasted line as it is inserted, with tabs as spaces and no other control characters.
+fn paste_line(line: &str) -> String {
+    line.chars()
+        .filter_map(|c| match c {
+            '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
 }
 
 #[macro_export]
@@ -47,9 +94,12 @@ bitflags::bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
     pub struct Commands: u8 {
         const None = 0;
-        const EOF = 1;
+        /// Ctrl-D on an empty line
+        const Eof = 1;
+        /// Ctrl-C, abandoning the line
         const Cancel = 1<<1;
         const Newline = 1<<2;
+        const ClearScreen = 1<<3;
         const Special = 1<<7;
     }
 }
@@ -74,12 +124,12 @@ impl Commands {
             None
         }
     }
-    /// Returns true if this instance is the command [`Commands::EOF`].
+    /// Returns true if this instance is the command [`Commands::Eof`].
     pub fn is_eof(&self) -> bool {
-        !self.contains(Commands::Special) && self.contains(Commands::EOF)
+        !self.contains(Commands::Special) && self.contains(Commands::Eof)
     }
-    /// Returns true if this instance is the command [`Commands::Exit`].
-    pub fn is_exit(&self) -> bool {
+    /// Returns true if this instance is the command [`Commands::Cancel`].
+    pub fn is_cancel(&self) -> bool {
         !self.contains(Commands::Special) && self.contains(Commands::Cancel)
     }
     /// Returns true if this instance is the command [`Commands::Newline`].
@@ -95,6 +145,59 @@ impl Commands {
 pub struct Response {
     pub bytes: Vec<u8>,
     pub commands: Commands,
+    /// Input left after a command key or a pasted line, to be handled once it is done
+    pub unprocessed: String,
+    /// Lines of a paste after its first one, to be run only once confirmed
+    pub pasted_lines: Vec<String>,
+}
+
+/// An escape sequence, without its leading `ESC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape<'a> {
+    /// `[ params final` (CSI), or `O final` (SS3) as sent in application mode.
+    Control {
+        intro: char,
+        params: &'a str,
+        final_char: char,
+    },
+    /// `ESC` followed by a key, as sent for Alt and that key.
+    Alt(char),
+    /// A control sequence with a byte that doesn't belong there, dropped up to it.
+    Invalid,
+}
+
+/// Parses the escape sequence at the start of `input`, which follows an `ESC`,
+/// returning it along with its length.
+///
+/// Returns `None` if the sequence is cut at the end of `input`.
+fn parse_escape(input: &str) -> Option<(Escape<'_>, usize)> {
+    let mut chars = input.char_indices();
+    let (_, intro) = chars.next()?;
+    if intro != '[' && intro != 'O' {
+        return Some((Escape::Alt(intro), intro.len_utf8()));
+    }
+    for (i, c) in chars {
+        match c {
+            // Parameter and intermediate bytes
+            ' '..='?' if intro == '[' => (),
+            '@'..='~' => {
+                let escape = Escape::Control {
+                    intro,
+                    params: &input[1..i],
+                    final_char: c,
+                };
+                return Some((escape, i + 1));
+            }
+            _ => return Some((Escape::Invalid, i)),
+        }
+    }
+    None
+}
+
+/// Returns true if the parameters of a control sequence mean Ctrl or Alt were held.
+fn is_word_modifier(params: &str) -> bool {
+    // `1;5` is Ctrl and `1;3` is Alt, other modifiers are added to them
+    matches!(params.split_once(';'), Some((_, "3" | "5" | "7")))
 }
 
 impl TextField {
@@ -105,25 +208,93 @@ impl TextField {
         }
     }
 
+    /// Lets the text take `bounds`, at least a few columns wide. Text wider than that
+    /// scrolls sideways to keep the cursor in view.
     pub fn set_bounds(&mut self, bounds: Pos) {
-        self.text.truncate(bounds.x as usize);
-        self.bounds = bounds;
+        self.bounds = Pos::new(bounds.x.max(MIN_WIDTH), bounds.y.max(1));
+    }
+
+    /// Scrolls so the cursor is shown, and as much of the text as fits.
+    fn scroll_to_cursor(&mut self) {
+        let width = self.bounds.x as usize;
+        if display_width(&self.text) <= width {
+            self.scroll = 0;
+            return;
+        }
+        self.scroll = self.scroll.min(self.cursor);
+        // The cursor may be right after the last column, which is left free for it
+        while display_width(&self.text[self.scroll..self.cursor]) > width {
+            self.scroll = self.next_boundary(self.scroll);
+        }
+        while self.scroll > 0 {
+            let previous = self.prev_boundary(self.scroll);
+            if display_width(&self.text[previous..]) > width {
+                break;
+            }
+            self.scroll = previous;
+        }
+    }
+
+    /// The part of the text shown.
+    fn shown(&self) -> &str {
+        fit_in(&self.text[self.scroll..], self.bounds.x as usize)
+    }
+
+    /// Columns shown after the cursor.
+    pub fn rest_width(&self) -> u32 {
+        display_width(self.shown()) as u32 - self.cursor_pos().x
+    }
+
+    /// Whether the text is wider than the field, which only shows part of it.
+    pub fn is_scrolled(&self) -> bool {
+        self.scrolled
+    }
+
+    /// Moves the cursor to the byte index `index`, keeping its column in sync.
+    fn set_cursor(&mut self, index: usize) {
+        self.cursor = index;
+        self.cursor_pos.x = display_width(&self.text[..index]) as u32;
+    }
+
+    /// Byte index of the character before the one at `index`.
+    fn prev_boundary(&self, index: usize) -> usize {
+        cluster_starts(&self.text)
+            .into_iter()
+            .rev()
+            .find(|&start| start < index)
+            .unwrap_or_default()
+    }
+
+    /// Byte index of the character after the one at `index`.
+    fn next_boundary(&self, index: usize) -> usize {
+        cluster_starts(&self.text)
+            .into_iter()
+            .find(|&start| start > index)
+            .unwrap_or(self.text.len())
     }
 
     fn handle_backspace(&mut self) {
         // Do nothing on line start
-        if self.cursor_pos.x == 0 {
+        if self.cursor == 0 {
+            return;
+        }
+        let start = self.prev_boundary(self.cursor);
+        let removed = display_width(&self.text[start..self.cursor]) as u32;
+        self.text.replace_range(start..self.cursor, "");
+        self.set_cursor(start);
+        let replacement = &self.text[start..];
+        if self.edit_in_place && !replacement.is_empty() {
+            self.response.bytes.extend_from_slice(&commands![
+                cursor::move_left(removed),
+                cursor::delete_columns(removed),
+            ]);
             return;
         }
-        self.cursor_pos.x -= 1;
-        let char_idx = self.char_at(self.cx()).unwrap();
-        self.text.remove(char_idx);
-        let replacement = &self.text[char_idx..].to_owned();
         self.response.bytes.extend_from_slice(&commands![
-            cursor::move_left(1),
+            cursor::move_left(removed),
             cursor::kill_line(),
             replacement,
-            cursor::move_left(char_count(replacement) as u32),
+            cursor::move_left(display_width(replacement) as u32),
         ])
     }
 
@@ -135,42 +306,146 @@ impl TextField {
     }
 
     pub fn erase_right(&mut self, times: u32) {
-        self.move_right(times);
         for _ in 0..times {
+            if self.cursor == self.text.len() {
+                break;
+            }
+            self.move_right(1);
             self.handle_backspace();
         }
     }
 
-    fn cx(&self) -> usize {
-        self.cursor_pos.x as usize
+    fn handle_char(&mut self, c: char) {
+        self.insert_str(c.encode_utf8(&mut [0; 4]));
     }
 
-    fn char_at(&self, index: usize) -> Option<usize> {
-        char_at(&self.text, index)
+    /// How many more columns of text fit in the field without scrolling.
+    pub fn room(&self) -> usize {
+        (self.bounds.x as usize).saturating_sub(display_width(&self.text))
     }
 
-    fn text_len(&self) -> usize {
-        char_count(&self.text)
+    /// Inserts `s` at the cursor as typed text, without interpreting any key in it.
+    pub fn insert(&mut self, s: &str) -> Response {
+        self.insert_str(s);
+        self.take_response()
     }
 
-    fn handle_char(&mut self, c: char) {
-        if self.cursor_pos.x >= self.bounds.x {
+    /// Inserts `s` at the cursor, building the response once instead of per character.
+    fn insert_str(&mut self, s: &str) {
+        if s.is_empty() {
             return;
         }
-        let text_len = self.text_len();
-        if self.cursor_pos.x as usize == text_len {
-            self.text.push(c);
-            self.response.bytes.push_char(c);
-        } else {
-            self.text.insert(self.char_at(self.cx()).unwrap(), c);
-            let replacement = &self.text[self.cursor_pos.x as usize..];
+        let index = self.cursor;
+        self.text.insert_str(index, s);
+        self.set_cursor(index + s.len());
+        let rest = &self.text[self.cursor..];
+        if self.edit_in_place && !rest.is_empty() {
             self.response.bytes.extend_from_slice(&commands![
-                cursor::kill_line(),
-                replacement,
-                cursor::move_left(char_count(replacement) as u32 - 1),
-            ])
+                cursor::insert_columns(display_width(s) as u32),
+                s,
+            ]);
+            return;
         }
-        self.cursor_pos.x += 1;
+        self.response.bytes.extend_from_slice(&commands![
+            s,
+            rest,
+            cursor::move_left(display_width(rest) as u32),
+        ]);
+    }
+
+    /// Lets the terminal shift the rest of the line when editing in the middle of it,
+    /// instead of writing it again. Dumb terminals can't.
+    ///
+    /// The field never wraps but scrolls, so the rest of the line always stays on the
+    /// same row.
+    pub fn set_edit_in_place(&mut self, enabled: bool) {
+        self.edit_in_place = enabled;
+    }
+
+    /// Makes Left and Right come out as [`SpecialKey::Left`] and [`SpecialKey::Right`],
+    /// like Up and Down, while a menu below the line uses them.
+    pub fn set_menu_open(&mut self, open: bool) {
+        self.menu_open = open;
+    }
+
+    /// Makes the lines of a paste after its first one wait in [`Response::pasted_lines`]
+    /// instead of ending the line.
+    pub fn set_confirm_paste(&mut self, enabled: bool) {
+        self.confirm_paste = enabled;
+    }
+
+    /// Handles pasted text, returning the input that follows it.
+    ///
+    /// Control characters are never interpreted: tabs become spaces and the others are dropped.
+    /// A line break ends the current line, and the lines after it are pasted again once
+    /// this one is done. When pastes are confirmed, they are queued instead.
+    fn handle_paste<'a>(&mut self, input: &'a str) -> &'a str {
+        let (paste, rest) = match input.find(PASTE_END) {
+            Some(end) => {
+                self.pasting = false;
+                (&input[..end], &input[end + PASTE_END.len()..])
+            }
+            None => {
+                // Keep a cut end marker for the next input
+                let cut = (1..PASTE_END.len())
+                    .rev()
+                    .find(|&len| input.ends_with(&PASTE_END[..len]))
+                    .unwrap_or_default();
+                self.partial = input[input.len() - cut..].to_string();
+                (&input[..input.len() - cut], "")
+            }
+        };
+        if let Some(queued) = &mut self.queued {
+            queued.push_str(paste);
+            if !self.pasting {
+                self.finish_queue();
+            }
+            return rest;
+        }
+        let (line, next_lines) = match paste.find(['\r', '\n']) {
+            Some(i) => {
+                let next = &paste[i + 1..];
+                let next = match &paste[i..i + 1] {
+                    "\r" => next.strip_prefix('\n').unwrap_or(next),
+                    _ => next,
+                };
+                (&paste[..i], Some(next))
+            }
+            None => (paste, None),
+        };
+        self.insert_str(&paste_line(line));
+        match next_lines {
+            Some(next_lines) if self.confirm_paste => {
+                self.queued = Some(next_lines.to_string());
+                if !self.pasting {
+                    self.finish_queue();
+                }
+                rest
+            }
+            Some(next_lines) => {
+                self.response.commands = Commands::Newline;
+                let mut unprocessed = format!("{}{}", PASTE_START, next_lines);
+                if !self.pasting {
+                    unprocessed.push_str(PASTE_END);
+                    unprocessed.push_str(rest);
+                }
+                unprocessed.push_str(&mem::take(&mut self.partial));
+                self.pasting = false;
+                self.response.unprocessed = unprocessed;
+                ""
+            }
+            None => rest,
+        }
+    }
+
+    /// Hands the queued lines of a paste that is over to the response.
+    fn finish_queue(&mut self) {
+        let queued = self.queued.take().unwrap_or_default();
+        self.response.pasted_lines = queued
+            .split(['\r', '\n'])
+            .map(paste_line)
+            .filter(|line| !line.is_empty())
+            .collect();
     }
 
     pub fn set_text(&mut self, text: &str) -> Response {
@@ -181,68 +456,151 @@ impl TextField {
             text
         ];
 
-        self.cursor_pos.x = char_count(text) as u32;
         self.text = text.to_string();
+        self.set_cursor(self.text.len());
 
-        mem::take(&mut self.response)
+        self.take_response()
     }
 
     pub fn erase_rest(&mut self) {
         self.response.bytes = commands![cursor::kill_line(),];
-        self.text.truncate(self.cursor_pos.x as usize);
+        self.text.truncate(self.cursor);
     }
 
-    pub fn move_to(&mut self, _text: &str, index: usize) {
-        let x = self.cursor_pos.x;
-        let index = index as u32;
-        if index > x {
-            self.move_right(index - x)
-        } else if index < x {
-            self.move_left(x - index)
-        }
+    /// Moves the cursor to the byte index `index`, telling the terminal to do the same.
+    fn move_cursor_to(&mut self, index: usize) {
+        let column = self.cursor_pos.x;
+        self.set_cursor(index);
+        let bytes = match self.cursor_pos.x {
+            x if x < column => cursor::move_left(column - x),
+            x => cursor::move_right(x - column),
+        };
+        self.response.bytes.extend_from_slice(&bytes);
     }
 
     pub fn move_left(&mut self, times: u32) {
-        let times = times.min(self.cursor_pos.x);
-        if times == 0 {
-            return;
-        };
-        self.cursor_pos.x -= times;
-        self.response
-            .bytes
-            .extend_from_slice(&cursor::move_left(times));
+        let mut index = self.cursor;
+        for _ in 0..times {
+            index = self.prev_boundary(index);
+        }
+        self.move_cursor_to(index);
     }
 
     pub fn move_right(&mut self, times: u32) {
-        let newx = self.cursor_pos.x + times;
-        if newx >= self.bounds.x {
+        let mut index = self.cursor;
+        for _ in 0..times {
+            index = self.next_boundary(index);
+        }
+        self.move_cursor_to(index);
+    }
+
+    /// Byte index of the start of the word before the cursor.
+    fn word_start(&self) -> usize {
+        let before = self.text[..self.cursor].trim_end_matches(' ');
+        before.rfind(' ').map(|i| i + 1).unwrap_or_default()
+    }
+
+    /// Byte index of the end of the word after the cursor.
+    fn word_end(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let word = after.trim_start_matches(' ');
+        let skipped = after.len() - word.len();
+        self.cursor + skipped + word.find(' ').unwrap_or(word.len())
+    }
+
+    /// Moves the cursor right to `index`, unless it is at the end of the line already.
+    fn forward(&mut self, index: usize) {
+        if self.cursor == self.text.len() {
+            self.response.commands = Commands::special(SpecialKey::ForwardAtEnd);
+        } else {
+            self.move_cursor_to(index);
+        }
+    }
+
+    fn handle_escape(&mut self, escape: Escape) {
+        let Escape::Control {
+            intro,
+            params,
+            final_char,
+        } = escape
+        else {
             return;
+        };
+        match (final_char, params) {
+            ('A', _) => self.response.commands = Commands::special(SpecialKey::Up),
+            ('B', _) => self.response.commands = Commands::special(SpecialKey::Down),
+            ('C', params) if is_word_modifier(params) => self.move_cursor_to(self.word_end()),
+            ('D', params) if is_word_modifier(params) => self.move_cursor_to(self.word_start()),
+            ('C', _) if self.menu_open => {
+                self.response.commands = Commands::special(SpecialKey::Right)
+            }
+            ('D', _) if self.menu_open => {
+                self.response.commands = Commands::special(SpecialKey::Left)
+            }
+            ('C', _) => self.forward(self.next_boundary(self.cursor)),
+            ('D', _) => self.move_left(1),
+            ('H', _) | ('~', "1" | "7") => self.move_cursor_to(0),
+            ('F', _) | ('~', "4" | "8") => self.forward(self.text.len()),
+            ('Z', _) => self.response.commands = Commands::special(SpecialKey::ShiftTab),
+            ('~', "3") if self.cursor != self.text.len() => {
+                self.move_right(1);
+                self.handle_backspace()
+            }
+            ('~', "200") if intro == '[' => self.pasting = true,
+            // PageUp, PageDown, function keys...
+            _ => (),
         }
-        self.cursor_pos.x = newx;
-        self.response
-            .bytes
-            .extend_from_slice(&cursor::move_right(times));
     }
 
     pub fn handle_input(&mut self, input: &str) -> Response {
+        let input = mem::take(&mut self.partial) + input;
         let mut it = input.chars();
-        while let Some(c) = it.next() {
+        loop {
+            if self.pasting {
+                it = self.handle_paste(it.as_str()).chars();
+            }
+            let Some(c) = it.next() else {
+                break;
+            };
+            if mem::take(&mut self.ctrl_x) && c == '\x03' {
+                self.response.commands = Commands::special(SpecialKey::CopyLine);
+                self.response.unprocessed.push_str(it.as_str());
+                break;
+            }
             match c as u8 {
                 1 => {
                     // ctrl A
-                    self.move_left(self.cursor_pos.x);
+                    self.move_cursor_to(0);
                 }
                 3 => {
                     // ctrl C
+                    // The abandoned line stays on screen, so whatever is after the cursor too
+                    self.move_cursor_to(self.text.len());
                     self.response.commands = Commands::Cancel;
                 }
                 4 => {
                     // ctrl D
-                    self.response.commands = Commands::EOF;
+                    if self.text.is_empty() {
+                        self.response.commands = Commands::Eof;
+                    } else {
+                        self.erase_right(1);
+                    }
                 }
                 5 => {
                     // ctrl E
-                    self.move_right(self.text_len() as u32 - self.cursor_pos.x);
+                    self.move_cursor_to(self.text.len());
+                }
+                6 => {
+                    // ctrl F
+                    self.forward(self.next_boundary(self.cursor));
+                }
+                12 => {
+                    // ctrl L
+                    self.response.commands = Commands::ClearScreen;
+                }
+                24 => {
+                    // ctrl X, waiting for the next key
+                    self.ctrl_x = true;
                 }
                 b'\t' => {
                     self.response.commands = Commands::special(SpecialKey::Tab);
@@ -251,39 +609,61 @@ impl TextField {
                     self.response.commands = Commands::Newline;
                 }
                 b'\x1b' => {
-                    if it.next() != Some('[') {
-                        continue;
-                    }
-                    match it.next().unwrap() {
-                        'A' => self.response.commands = Commands::special(SpecialKey::Up),
-                        'B' => self.response.commands = Commands::special(SpecialKey::Down),
-                        'C' => self.move_right(1),
-                        'D' => self.move_left(1),
-                        'Z' => self.response.commands = Commands::special(SpecialKey::ShiftTab),
-                        '3' => {
-                            if it.next() == Some('~') {
-                                self.move_right(1);
-                                self.handle_backspace()
-                            }
-                        }
-                        _ => (),
-                    }
+                    let rest = it.as_str();
+                    let Some((escape, len)) = parse_escape(rest) else {
+                        // Wait for the rest of the sequence
+                        self.partial = format!("\x1b{}", rest);
+                        break;
+                    };
+                    it = rest[len..].chars();
+                    self.handle_escape(escape);
                 }
                 1..=26 => (),
                 127 => self.handle_backspace(),
                 _ => self.handle_char(c),
             }
+            if !self.response.commands.is_empty() {
+                // Keys typed after a command are handled once it is done
+                self.response.unprocessed.push_str(it.as_str());
+                break;
+            }
         }
         self.take_response()
     }
 
     pub fn clear(&mut self) {
         self.text.clear();
+        self.cursor = 0;
         self.cursor_pos = Default::default();
+        self.scroll = 0;
+        self.scrolled = false;
+        self.shown_column = 0;
         self.response = Default::default();
+        self.pasting = false;
+        self.partial.clear();
+        self.queued = None;
+        self.ctrl_x = false;
     }
 
+    /// Draws the text again on an empty line, with the cursor where it was, or the part
+    /// of it around the cursor when it doesn't fit.
+    pub fn redraw(&mut self) -> Vec<u8> {
+        self.scroll_to_cursor();
+        self.scrolled = display_width(&self.text) > self.bounds.x as usize;
+        self.shown_column = self.cursor_pos().x;
+        commands![self.shown(), cursor::move_left(self.rest_width())]
+    }
+
+    /// Hands out the changes to draw. Those of a line that is or was scrolled are made for
+    /// a line showing all of it, so the part shown is drawn again instead.
     pub fn take_response(&mut self) -> Response {
+        let fits = display_width(&self.text) <= self.bounds.x as usize;
+        if !self.response.bytes.is_empty() && (self.scrolled || !fits) {
+            let start = cursor::move_left(self.shown_column);
+            self.response.bytes = commands![start, cursor::kill_line(), self.redraw()];
+        } else if fits {
+            self.shown_column = self.cursor_pos.x;
+        }
         mem::take(&mut self.response)
     }
 
@@ -291,7 +671,316 @@ impl TextField {
         &self.text
     }
 
+    /// Position of the cursor, counted from the first character shown.
     pub fn cursor_pos(&self) -> Pos {
-        self.cursor_pos
+        let hidden = display_width(&self.text[..self.scroll]) as u32;
+        Pos::new(self.cursor_pos.x.saturating_sub(hidden), self.cursor_pos.y)
+    }
+
+    /// Byte index of the cursor in [`TextField::text`].
+    pub fn cursor_index(&self) -> usize {
+        self.cursor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field() -> TextField {
+        TextField::new(Pos::new(80, 24))
+    }
+
+    /// Left and right arrows
+    const LEFT: &str = "\x1b[D";
+    const RIGHT: &str = "\x1b[C";
+
+    #[test]
+    fn multibyte_characters() {
+        let mut field = field();
+        let _ = field.handle_input("héllo");
+        assert_eq!(field.cursor_pos().x, 5);
+        let _ = field.handle_input(&LEFT.repeat(3));
+        assert_eq!(field.cursor_index(), "hé".len());
+        let _ = field.handle_input("\x7f");
+        assert_eq!(field.text(), "hllo");
+        let _ = field.handle_input("e");
+        assert_eq!(field.text(), "hello");
+        field.erase_rest();
+        assert_eq!(field.text(), "he");
+    }
+
+    #[test]
+    fn wide_characters_move_two_columns() {
+        let mut field = field();
+        let _ = field.handle_input("日本語");
+        assert_eq!(field.cursor_pos().x, 6);
+        let response = field.handle_input(LEFT);
+        assert_eq!(response.bytes, b"\x1b[2D");
+        assert_eq!(field.cursor_pos().x, 4);
+        let _ = field.handle_input("\x7f");
+        assert_eq!(field.text(), "日語");
+        assert_eq!(field.cursor_pos().x, 2);
+        let response = field.handle_input(&RIGHT.repeat(3));
+        assert_eq!(response.bytes, b"\x1b[2C");
+        assert_eq!(field.cursor_pos().x, 4);
+    }
+
+    #[test]
+    fn emoji_with_modifier_is_one_character() {
+        let emoji = "\u{1F44D}\u{1F3FD}";
+        let mut field = field();
+        let _ = field.handle_input(&format!("a{}b", emoji));
+        assert_eq!(field.cursor_pos().x, 4);
+        let _ = field.handle_input(&LEFT.repeat(2));
+        assert_eq!(field.cursor_index(), 1);
+        let _ = field.handle_input("\x1b[3~");
+        assert_eq!(field.text(), "ab");
+        let _ = field.handle_input(emoji);
+        let _ = field.handle_input("\x7f");
+        assert_eq!(field.text(), "ab");
+    }
+
+    #[test]
+    fn text_wider_than_the_bounds_scrolls() {
+        let mut field = TextField::new(Pos::new(5, 1));
+        let response = field.handle_input("日本");
+        assert_eq!(response.bytes, "日本".as_bytes());
+        // Whole characters are left out on the left to keep the cursor in view
+        let response = field.handle_input("語");
+        assert_eq!(field.text(), "日本語");
+        assert_eq!(response.bytes, "\x1b[4D\x1b[K本語".as_bytes());
+        assert_eq!(field.cursor_pos().x, 4);
+        assert_eq!(field.rest_width(), 0);
+        let response = field.handle_input("\x01");
+        assert_eq!(response.bytes, "\x1b[4D\x1b[K日本\x1b[4D".as_bytes());
+        assert_eq!(field.cursor_pos().x, 0);
+        assert_eq!(field.rest_width(), 4);
+        // Back to drawing only the changes once it fits again
+        let _ = field.handle_input("\x05\x7f");
+        assert!(!field.is_scrolled());
+        let response = field.handle_input("\x7f");
+        assert_eq!(response.bytes, b"\x1b[2D\x1b[K");
+
+        // Typing goes on with no room given
+        field.set_bounds(Pos::new(0, 0));
+        let _ = field.handle_input("\x7fabcdef");
+        assert_eq!(field.text(), "abcdef");
+        assert_eq!(field.cursor_pos().x, 4);
+        assert_eq!(field.redraw(), b"cdef");
+    }
+
+    #[test]
+    fn paste_is_inserted_literally() {
+        let mut field = field();
+        let response = field.handle_input("a\x1b[200~b\x03\tc\x1b[201~d");
+        assert_eq!(field.text(), "ab cd");
+        assert_eq!(response.commands, Commands::None);
+        assert_eq!(field.cursor_pos().x, 5);
+    }
+
+    #[test]
+    fn paste_in_the_middle_is_drawn_once() {
+        let mut field = field();
+        let _ = field.handle_input("ad\x1b[D");
+        let response = field.handle_input("\x1b[200~bc\x1b[201~");
+        assert_eq!(field.text(), "abcd");
+        assert_eq!(response.bytes, b"bcd\x1b[1D");
+    }
+
+    #[test]
+    fn terminal_shifts_the_rest_of_the_line() {
+        let mut field = TextField::new(Pos::new(1000, 24));
+        field.set_edit_in_place(true);
+        let _ = field.handle_input(&"x".repeat(500));
+        let _ = field.handle_input("\x1b[H");
+        let response = field.handle_input("a");
+        assert_eq!(response.bytes, b"\x1b[1@a");
+        let response = field.handle_input("日");
+        assert_eq!(response.bytes, "\x1b[2@日".as_bytes());
+        let response = field.handle_input("\x7f");
+        assert_eq!(response.bytes, b"\x1b[2D\x1b[2P");
+        let response = field.handle_input("\x1b[200~bc\x1b[201~");
+        assert_eq!(response.bytes, b"\x1b[2@bc");
+        assert_eq!(field.text(), format!("abc{}", "x".repeat(500)));
+        // At the end there is nothing to shift
+        let _ = field.handle_input("\x1b[F");
+        let response = field.handle_input("y\x7f");
+        assert_eq!(response.bytes, b"y\x1b[1D\x1b[K");
+    }
+
+    #[test]
+    fn paste_split_across_inputs() {
+        let mut field = field();
+        let _ = field.handle_input("\x1b[200~ab");
+        let _ = field.handle_input("\x03c\x1b[20");
+        let response = field.handle_input("1~\x03");
+        assert_eq!(field.text(), "abc");
+        assert_eq!(response.commands, Commands::Cancel);
+    }
+
+    #[test]
+    fn pasted_lines_are_queued() {
+        let mut field = field();
+        let response = field.handle_input("\x1b[200~echo 1\r\necho 2\necho 3\x1b[201~x");
+        assert_eq!(field.text(), "echo 1");
+        assert_eq!(response.commands, Commands::Newline);
+        assert_eq!(response.unprocessed, "\x1b[200~echo 2\necho 3\x1b[201~x");
+
+        // The paste may not be over yet
+        let mut field = self::field();
+        let response = field.handle_input("\x1b[200~echo 1\necho");
+        assert_eq!(response.unprocessed, "\x1b[200~echo");
+    }
+
+    #[test]
+    fn pasted_lines_wait_for_confirmation() {
+        let mut field = field();
+        field.set_confirm_paste(true);
+        let response = field.handle_input("\x1b[200~echo 1\r\necho\t2\n");
+        assert_eq!(field.text(), "echo 1");
+        assert_eq!(response.commands, Commands::None);
+        assert!(response.pasted_lines.is_empty());
+        // Once the paste is over, typing goes on in the first line
+        let response = field.handle_input("\necho 3\x1b[201~!");
+        assert_eq!(field.text(), "echo 1!");
+        assert_eq!(response.commands, Commands::None);
+        assert_eq!(response.unprocessed, "");
+        assert_eq!(response.pasted_lines, ["echo 2", "echo 3"]);
+
+        let response = field.handle_input("\x1b[200~ls\n\x1b[201~\r");
+        assert_eq!(response.commands, Commands::Newline);
+        assert!(response.pasted_lines.is_empty());
+    }
+
+    #[test]
+    fn redraw_keeps_cursor() {
+        let mut field = field();
+        let _ = field.handle_input("héllo\x1b[D\x1b[D");
+        let response = field.handle_input("\x0c");
+        assert_eq!(response.commands, Commands::ClearScreen);
+        assert_eq!(field.redraw(), "héllo\x1b[2D".as_bytes());
+    }
+
+    #[test]
+    fn ctrl_x_ctrl_c_copies_the_line() {
+        let mut field = field();
+        let _ = field.handle_input("ls\x1b[D");
+        let response = field.handle_input("\x18\x03x");
+        assert_eq!(response.commands, Commands::special(SpecialKey::CopyLine));
+        assert_eq!(response.bytes, b"");
+        assert_eq!(response.unprocessed, "x");
+        assert_eq!((field.text(), field.cursor_index()), ("ls", 1));
+        // Even when the terminal sends the keys apart
+        let _ = field.handle_input("\x18");
+        let response = field.handle_input("\x03");
+        assert_eq!(response.commands, Commands::special(SpecialKey::CopyLine));
+        // Anything else after Ctrl-X is a key of its own
+        let _ = field.handle_input("\x18a");
+        assert_eq!(field.text(), "las");
+        let response = field.handle_input("\x03");
+        assert_eq!(response.commands, Commands::Cancel);
+    }
+
+    #[test]
+    fn arrows_are_keys_while_a_menu_is_open() {
+        let mut field = field();
+        let _ = field.handle_input("ls");
+        field.set_menu_open(true);
+        let response = field.handle_input("\x1b[Dx");
+        assert_eq!(response.commands, Commands::special(SpecialKey::Left));
+        assert_eq!(response.unprocessed, "x");
+        let response = field.handle_input("\x1b[C");
+        assert_eq!(response.commands, Commands::special(SpecialKey::Right));
+        assert_eq!(field.cursor_index(), 2);
+        // Moving by words still edits the line
+        let _ = field.handle_input("\x1b[1;5D");
+        assert_eq!(field.cursor_index(), 0);
+
+        field.set_menu_open(false);
+        let response = field.handle_input("\x1b[C");
+        assert_eq!(response.commands, Commands::None);
+        assert_eq!(field.cursor_index(), 1);
+    }
+
+    #[test]
+    fn home_and_end() {
+        let mut field = field();
+        let _ = field.handle_input("abc");
+        for (key, index) in [("\x1b[H", 0), ("\x1bOF", 3), ("\x1b[1~", 0), ("\x1b[4~", 3)] {
+            let _ = field.handle_input(key);
+            assert_eq!(field.cursor_index(), index, "{key:?}");
+        }
+    }
+
+    #[test]
+    fn word_movement() {
+        let mut field = field();
+        let _ = field.handle_input("echo  hello world");
+        let _ = field.handle_input("\x1b[1;5D");
+        assert_eq!(field.cursor_index(), 12);
+        let _ = field.handle_input("\x1b[1;3D\x1b[1;5D");
+        assert_eq!(field.cursor_index(), 0);
+        let _ = field.handle_input("\x1b[1;5C\x1b[1;5C");
+        assert_eq!(field.cursor_index(), 11);
+        // Shift doesn't move by words
+        let _ = field.handle_input("\x1b[1;2C");
+        assert_eq!(field.cursor_index(), 12);
+    }
+
+    #[test]
+    fn unknown_sequences_are_consumed() {
+        let mut field = field();
+        let _ = field.handle_input("a\x1b[15~b\x1b[5~c\x1b[1;2Pd\x1bxe");
+        assert_eq!(field.text(), "abcde");
+        // Delete at the end of the line does nothing
+        let _ = field.handle_input("\x1b[3~");
+        assert_eq!(field.text(), "abcde");
+    }
+
+    #[test]
+    fn ctrl_d_ends_only_empty_lines() {
+        let mut field = field();
+        let response = field.handle_input("\x04");
+        assert!(response.commands.is_eof());
+
+        let response = field.handle_input("abc\x1b[D\x1b[D\x04");
+        assert_eq!(response.commands, Commands::None);
+        assert_eq!(field.text(), "ac");
+        // Like Delete, nothing happens at the end of the line
+        let response = field.handle_input("\x05\x04");
+        assert_eq!(response.commands, Commands::None);
+        assert_eq!(field.text(), "ac");
+    }
+
+    #[test]
+    fn ctrl_c_moves_to_the_end() {
+        let mut field = field();
+        let _ = field.handle_input("abc\x1b[D\x1b[D");
+        let response = field.handle_input("\x03");
+        assert!(response.commands.is_cancel());
+        assert_eq!(response.bytes, b"\x1b[2C");
+        assert_eq!(field.cursor_index(), 3);
+    }
+
+    #[test]
+    fn escape_split_across_inputs() {
+        let mut field = field();
+        let _ = field.handle_input("ab\x1b[1;");
+        assert_eq!(field.text(), "ab");
+        let _ = field.handle_input("5Dc");
+        assert_eq!(field.text(), "cab");
+    }
+
+    #[test]
+    fn keys_after_a_command_are_queued() {
+        let mut field = field();
+        let response = field.handle_input("ls\rpwd\r");
+        assert_eq!(field.text(), "ls");
+        assert_eq!(response.commands, Commands::Newline);
+        assert_eq!(response.unprocessed, "pwd\r");
+        let response = field.handle_input("\t\x1b[A");
+        assert_eq!(response.commands, Commands::special(SpecialKey::Tab));
+        assert_eq!(response.unprocessed, "\x1b[A");
     }
 }
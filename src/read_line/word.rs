@@ -0,0 +1,209 @@
+//! Finds the word being typed at the cursor, and what it is, to complete it.
+
+/// What the word at the cursor is, which decides how it is completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Position {
+    /// A command name, like at the start of the line or after `|` or `;`.
+    #[default]
+    Command,
+    /// An argument of a command.
+    Argument,
+    /// The file after `>` or `<`.
+    Redirection,
+    /// A variable, like `$HOME`.
+    Variable,
+}
+
+/// The word right before the cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Word<'a> {
+    pub text: &'a str,
+    /// Byte index where the word starts in the line.
+    pub start: usize,
+    pub position: Position,
+    /// The command the word is an argument of, like `cd` in `cd sr`.
+    pub command: Option<&'a str>,
+}
+
+fn is_operator(c: char) -> bool {
+    matches!(c, '|' | '>' | '<' | ';' | '&')
+}
+
+/// Splits `line` into words and single character operators, with their byte index.
+///
+/// Quoted and escaped characters never split words, even if the quote isn't closed.
+fn tokens(line: &str) -> Vec<(usize, &str)> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (_, '\\') if quote != Some('\'') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, c) if c.is_whitespace() || is_operator(c) => {
+                if let Some(start) = start.take() {
+                    tokens.push((start, &line[start..i]));
+                }
+                if is_operator(c) {
+                    tokens.push((i, &line[i..i + 1]));
+                }
+                continue;
+            }
+            (None, _) => (),
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        tokens.push((start, &line[start..]));
+    }
+    tokens
+}
+
+/// Position of a word coming after `tokens`.
+fn position_after(tokens: &[(usize, &str)]) -> Position {
+    let Some((&(last_start, last), before)) = tokens.split_last() else {
+        return Position::Command;
+    };
+    match last {
+        // `>|` is a redirection, not a pipe
+        "|" if before.last() == Some(&(last_start.wrapping_sub(1), ">")) => Position::Redirection,
+        "|" | ";" | "&" => Position::Command,
+        ">" | "<" => Position::Redirection,
+        // Keywords are followed by a command only where they are commands themselves
+        "if" | "then" | "else" | "do" if position_after(before) == Position::Command => {
+            Position::Command
+        }
+        _ => Position::Argument,
+    }
+}
+
+/// First word of the command `tokens` end in, past the keywords before it.
+fn command_of<'a>(tokens: &[(usize, &'a str)]) -> Option<&'a str> {
+    let start = tokens
+        .iter()
+        .enumerate()
+        .rposition(|(i, &(start, token))| match token {
+            "|" => i == 0 || tokens[i - 1] != (start.wrapping_sub(1), ">"),
+            ";" | "&" => true,
+            _ => false,
+        })
+        .map_or(0, |i| i + 1);
+    tokens[start..]
+        .iter()
+        .map(|&(_, token)| token)
+        .find(|token| !matches!(*token, "if" | "then" | "else" | "do"))
+}
+
+/// Finds the word that ends at byte index `cursor` of `line`, empty if the cursor comes
+/// right after a space or an operator.
+pub fn word_at(line: &str, cursor: usize) -> Word<'_> {
+    let mut tokens = tokens(&line[..cursor]);
+    let (start, text) = match tokens.last() {
+        Some(&(start, text)) if start + text.len() == cursor && !text.starts_with(is_operator) => {
+            tokens.pop();
+            (start, text)
+        }
+        _ => (cursor, ""),
+    };
+    let position = match text.starts_with('$') {
+        true => Position::Variable,
+        false => position_after(&tokens),
+    };
+    let command = match position {
+        Position::Argument => command_of(&tokens),
+        _ => None,
+    };
+    Word {
+        text,
+        start,
+        position,
+        command,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_end_at_operators() {
+        use Position::*;
+        // The cursor is at the `_`, which is removed from the line
+        let cases = [
+            ("_", "", Command),
+            ("ls_", "ls", Command),
+            ("ls _", "", Argument),
+            ("cat file1|les_", "les", Command),
+            ("cat file1 | les_", "les", Command),
+            ("echo hi>ou_", "ou", Redirection),
+            ("echo hi >_", "", Redirection),
+            ("echo hi >| ou_", "ou", Redirection),
+            ("cat <in_ | wc", "in", Redirection),
+            ("a;b_", "b", Command),
+            ("sleep 1&c_", "c", Command),
+            ("if true; then ec_", "ec", Command),
+            ("echo then _", "", Argument),
+            ("echo $HO_", "$HO", Variable),
+            ("ls fi_le", "fi", Argument),
+        ];
+        for (line, text, position) in cases {
+            let cursor = line.find('_').unwrap();
+            let line = line.replace('_', "");
+            let word = word_at(&line, cursor);
+            assert_eq!(
+                (word.text, word.position),
+                (text, position),
+                "in {:?}",
+                line
+            );
+            assert_eq!(word.start + word.text.len(), cursor);
+        }
+    }
+
+    #[test]
+    fn arguments_know_their_command() {
+        let cases = [
+            ("cd sr_", Some("cd")),
+            ("ls; cd _", Some("cd")),
+            ("echo hi >| out; unset PA_", Some("unset")),
+            ("if true; then source fi_", Some("source")),
+            ("git commit -m_", Some("git")),
+            ("cd_", None),
+            ("cd > ou_", None),
+        ];
+        for (line, command) in cases {
+            let cursor = line.find('_').unwrap();
+            let line = line.replace('_', "");
+            assert_eq!(word_at(&line, cursor).command, command, "in {:?}", line);
+        }
+    }
+
+    #[test]
+    fn quotes_keep_operators() {
+        use Position::*;
+        let cases = [
+            ("cat 'a|b_", "'a|b", Argument),
+            ("cat \"a b\"|gr_", "gr", Command),
+            ("cat a\\ b\\|c_", "a\\ b\\|c", Argument),
+            ("echo 'it''s_", "'it''s", Argument),
+        ];
+        for (line, text, position) in cases {
+            let cursor = line.find('_').unwrap();
+            let line = line.replace('_', "");
+            let word = word_at(&line, cursor);
+            assert_eq!(
+                (word.text, word.position),
+                (text, position),
+                "in {:?}",
+                line
+            );
+        }
+    }
+}
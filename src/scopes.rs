@@ -0,0 +1,118 @@
+//! Shell variables, kept in nested scopes so `local` ones go away with their script.
+
+use std::collections::HashMap;
+
+/// The variable scopes, the global one first and the innermost last.
+///
+/// Lookups go from the innermost scope out. Assigning changes the variable in the scope
+/// it already has, or else makes a global one, so only `local` variables stay behind.
+#[derive(Debug)]
+pub struct Scopes {
+    scopes: Vec<HashMap<String, String>>,
+}
+
+impl Default for Scopes {
+    fn default() -> Self {
+        Self {
+            scopes: vec![HashMap::new()],
+        }
+    }
+}
+
+impl Scopes {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .map(String::as_str)
+    }
+
+    fn scope_of(&mut self, name: &str) -> Option<&mut HashMap<String, String>> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find(|scope| scope.contains_key(name))
+    }
+
+    /// Assigns to the variable where it is defined, returning its previous value.
+    pub fn set(&mut self, name: String, value: String) -> Option<String> {
+        match self.scope_of(&name) {
+            Some(scope) => scope.insert(name, value),
+            None => self.scopes[0].insert(name, value),
+        }
+    }
+
+    /// Defines the variable in the innermost scope, shadowing any outer one.
+    pub fn set_local(&mut self, name: String, value: String) {
+        self.scopes.last_mut().unwrap().insert(name, value);
+    }
+
+    /// Removes the innermost variable with that name, uncovering outer ones.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.scope_of(name)?.remove(name)
+    }
+
+    /// Whether there is no scope but the global one.
+    pub fn is_global(&self) -> bool {
+        self.scopes.len() == 1
+    }
+
+    pub fn push(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Drops the innermost scope with its variables. The global scope always stays.
+    pub fn pop(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    /// Every variable visible from the innermost scope.
+    pub fn visible(&self) -> HashMap<String, String> {
+        self.scopes
+            .iter()
+            .flatten()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        let mut names = self
+            .scopes
+            .iter()
+            .flat_map(HashMap::keys)
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locals_shadow_until_popped() {
+        let mut scopes = Scopes::default();
+        scopes.set("A".into(), "global".into());
+        scopes.push();
+        scopes.set_local("A".into(), "local".into());
+        // Plain assignments change the local one, or else make a global
+        assert_eq!(
+            scopes.set("A".into(), "changed".into()).as_deref(),
+            Some("local")
+        );
+        scopes.set("B".into(), "new".into());
+        assert_eq!(scopes.get("A"), Some("changed"));
+        assert_eq!(scopes.names().collect::<Vec<_>>(), ["A", "B"]);
+        scopes.pop();
+        assert_eq!(scopes.get("A"), Some("global"));
+        assert_eq!(scopes.get("B"), Some("new"));
+        scopes.pop();
+        assert_eq!(scopes.remove("A").as_deref(), Some("global"));
+        assert_eq!(scopes.get("A"), None);
+    }
+}
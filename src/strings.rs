@@ -1,11 +1,283 @@
 use std::borrow::Cow;
 
+use color_eyre::eyre::eyre;
+
+use crate::YshResult;
+
+/// Cuts `text` at the first `#` that starts a word outside of quotes.
+pub fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut word_start = true;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"'), '\\') | (None, '\\') => escaped = true,
+            (Some(_), _) => (),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') if word_start => return &text[..i],
+            _ => (),
+        }
+        word_start = quote.is_none() && !escaped && c.is_whitespace();
+    }
+    text
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Returns the index of the `}` closing a `${`, in the text right after it.
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
 impl crate::Shell {
-    /// Unconditionally replaces all sequences of `$VAR` with a value for `VAR`.
-    pub fn expand_vars<'a>(&self, text: &'a str) -> Cow<'a, str> {
-        let regex = crate::static_regex!(r#"\$(\w+)"#);
-        regex.replace_all(text, move |captures: &regex::Captures| {
-            self.get_var_or_env(&captures[1]).unwrap_or_default()
-        })
+    /// Replaces all sequences of `$VAR` and `${...}` with a value for `VAR`, except in
+    /// single quotes or after a `\`. Quotes are left for the parser to remove.
+    ///
+    /// Unset variables expand to nothing, unless `${VAR:?message}` is used to make that
+    /// an error.
+    pub fn expand_vars<'a>(&mut self, text: &'a str) -> YshResult<Cow<'a, str>> {
+        if !text.contains('$') {
+            return Ok(Cow::Borrowed(text));
+        }
+        let mut expanded = String::with_capacity(text.len());
+        let mut quote = None;
+        let mut escaped = false;
+        let mut i = 0;
+        while let Some(c) = text[i..].chars().next() {
+            match (quote, c) {
+                _ if escaped => escaped = false,
+                (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+                (Some('\''), _) => (),
+                (_, '\\') => escaped = true,
+                (None, '\'' | '"') => quote = Some(c),
+                (_, '$') => {
+                    let (value, len) = self.expand_dollar(&text[i + 1..])?;
+                    expanded.push_str(&value);
+                    i += 1 + len;
+                    continue;
+                }
+                _ => (),
+            }
+            expanded.push(c);
+            i += c.len_utf8();
+        }
+        Ok(Cow::Owned(expanded))
+    }
+
+    /// Expands what comes after a `$` at the start of `text`, returning the value and how
+    /// much of `text` it took.
+    fn expand_dollar(&mut self, text: &str) -> YshResult<(String, usize)> {
+        if let Some(braced) = text.strip_prefix('{') {
+            let end =
+                closing_brace(braced).ok_or_else(|| eyre!("missing '}}' after '${{{}'", braced))?;
+            return Ok((self.expand_braced(&braced[..end])?, end + 2));
+        }
+        // `$$` is a name of its own, instead of a `$` before another variable
+        let len = match text.starts_with('$') {
+            true => 1,
+            false => text.find(|c| !is_name_char(c)).unwrap_or(text.len()),
+        };
+        match &text[..len] {
+            // Not a variable
+            "" => Ok(("$".to_string(), 0)),
+            name => Ok((self.get_var_or_env(name).unwrap_or_default(), len)),
+        }
+    }
+
+    /// Expands what is between the braces of `${...}`.
+    fn expand_braced(&mut self, expr: &str) -> YshResult<String> {
+        let bad_substitution = || eyre!("${{{}}}: bad substitution", expr);
+        if let Some(name) = expr.strip_prefix('#') {
+            if name.is_empty() || !name.chars().all(is_name_char) {
+                return Err(bad_substitution());
+            }
+            let value = self.get_var_or_env(name).unwrap_or_default();
+            return Ok(value.chars().count().to_string());
+        }
+        let len = expr.find(|c| !is_name_char(c)).unwrap_or(expr.len());
+        let (name, operation) = expr.split_at(len);
+        if name.is_empty() {
+            return Err(bad_substitution());
+        }
+        let value = self.get_var_or_env(name).filter(|value| !value.is_empty());
+        if operation.is_empty() {
+            return Ok(value.unwrap_or_default());
+        }
+        let (operator, word) = operation.split_at(operation.len().min(2));
+        match (operator, value) {
+            (":-" | ":=" | ":?", Some(value)) => Ok(value),
+            (":-", None) => Ok(self.expand_vars(word)?.into_owned()),
+            (":=", None) => {
+                let value = self.expand_vars(word)?.into_owned();
+                self.set_var(name.to_string(), value.clone());
+                Ok(value)
+            }
+            (":?", None) => match self.expand_vars(word)?.as_ref() {
+                "" => Err(eyre!("{}: parameter null or not set", name)),
+                message => Err(eyre!("{}: {}", name, message)),
+            },
+            _ => Err(bad_substitution()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn strip_whole_line_comment() {
+        assert_eq!(strip_comment("# a comment"), "");
+        assert_eq!(strip_comment("   # indented"), "   ");
+    }
+
+    #[test]
+    fn strip_inline_comment() {
+        assert_eq!(strip_comment("echo hi # there"), "echo hi ");
+    }
+
+    #[test]
+    fn keep_quoted_and_mid_word_hashes() {
+        assert_eq!(strip_comment("echo '#'"), "echo '#'");
+        assert_eq!(strip_comment("echo \"#not a comment\""), "echo \"#not a comment\"");
+        assert_eq!(strip_comment("echo a#b \\#c"), "echo a#b \\#c");
+    }
+
+    fn shell() -> crate::Shell {
+        let mut shell = crate::Shell::init(Default::default()).unwrap();
+        shell.set_var("A".into(), "apple".into());
+        shell.set_var("B".into(), "banana".into());
+        shell.set_var("EMPTY".into(), "".into());
+        shell
+    }
+
+    #[test]
+    fn expand_braced_names() {
+        let mut shell = shell();
+        assert_eq!(
+            shell.expand_vars("${A}s ${A}${B}").unwrap(),
+            "apples applebanana"
+        );
+        assert_eq!(
+            shell.expand_vars("$A$B $ ${#B}").unwrap(),
+            "applebanana $ 6"
+        );
+        assert_eq!(shell.expand_vars("${YASH_TEST_UNSET}.").unwrap(), ".");
+        assert!(shell.expand_vars("${A").is_err());
+        assert!(shell.expand_vars("${A%x}").is_err());
+        assert!(shell.expand_vars("${}").is_err());
+    }
+
+    #[test]
+    fn quoted_and_escaped_dollars_stay() {
+        let mut shell = shell();
+        assert_eq!(
+            shell.expand_vars("'$A' \"$A's\" \\$A $A").unwrap(),
+            "'$A' \"apple's\" \\$A apple"
+        );
+        assert_eq!(
+            shell.expand_vars("\"'$B'\" 'it''s $B'").unwrap(),
+            "\"'banana'\" 'it''s $B'"
+        );
+    }
+
+    #[test]
+    fn expand_pids() {
+        let mut shell = shell();
+        let pid = std::process::id();
+        assert_eq!(
+            shell.expand_vars("$$ ${A}$$$A").unwrap(),
+            format!("{} apple{}apple", pid, pid)
+        );
+        let ppid = nix::unistd::getppid();
+        assert_eq!(shell.expand_vars("$PPID").unwrap(), ppid.to_string());
+    }
+
+    #[test]
+    fn random_changes_every_expansion() {
+        let mut shell = shell();
+        let mut random = || {
+            shell
+                .expand_vars("$RANDOM")
+                .unwrap()
+                .parse::<u16>()
+                .unwrap()
+        };
+        let numbers = (0..8)
+            .map(|_| random())
+            .collect::<std::collections::HashSet<_>>();
+        assert!(numbers.len() > 1);
+    }
+
+    #[test]
+    fn seconds_count_from_start() {
+        let mut shell = shell();
+        assert_eq!(shell.expand_vars("$SECONDS").unwrap(), "0");
+        shell.dynamic_vars.start -= std::time::Duration::from_secs(75);
+        assert_eq!(shell.expand_vars("$SECONDS").unwrap(), "75");
+    }
+
+    #[test]
+    fn lineno_follows_sourced_lines() {
+        let mut shell = shell();
+        assert_eq!(shell.expand_vars("[$LINENO]").unwrap(), "[]");
+        let dir = TempDir::new("lineno");
+        let path = dir.join("script.ysh");
+        std::fs::write(&path, "FIRST=$LINENO\n\n# comment\nFOURTH=$LINENO\n").unwrap();
+        shell.source_file(&path).unwrap();
+        assert_eq!(shell.get_var("FIRST"), Some("1"));
+        assert_eq!(shell.get_var("FOURTH"), Some("4"));
+        assert_eq!(shell.expand_vars("[$LINENO]").unwrap(), "[]");
+    }
+
+    #[test]
+    fn expand_defaults() {
+        let mut shell = shell();
+        assert_eq!(shell.expand_vars("${A:-x}").unwrap(), "apple");
+        assert_eq!(shell.expand_vars("${EMPTY:-$B}").unwrap(), "banana");
+        assert_eq!(
+            shell.expand_vars("${YASH_TEST_UNSET:-${A}!}").unwrap(),
+            "apple!"
+        );
+        assert_eq!(shell.get_var("YASH_TEST_UNSET"), None);
+
+        assert_eq!(
+            shell.expand_vars("${YASH_TEST_ASSIGNED:=new}").unwrap(),
+            "new"
+        );
+        assert_eq!(shell.get_var("YASH_TEST_ASSIGNED"), Some("new"));
+        assert_eq!(
+            shell.expand_vars("${YASH_TEST_ASSIGNED:=other}").unwrap(),
+            "new"
+        );
+    }
+
+    #[test]
+    fn expand_required() {
+        let mut shell = shell();
+        assert_eq!(shell.expand_vars("${A:?unused}").unwrap(), "apple");
+        let error = shell
+            .expand_vars("${YASH_TEST_UNSET:?not set}")
+            .unwrap_err();
+        assert_eq!(error.to_string(), "YASH_TEST_UNSET: not set");
+        let error = shell.expand_vars("${EMPTY:?}").unwrap_err();
+        assert_eq!(error.to_string(), "EMPTY: parameter null or not set");
+        // The whole line fails
+        assert!(shell.execute_line("A=${YASH_TEST_UNSET:?}").is_err());
+        assert_eq!(shell.get_var("A"), Some("apple"));
     }
 }
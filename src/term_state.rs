@@ -2,6 +2,10 @@ use nix::sys::termios::{
     self, InputFlags, LocalFlags, OutputFlags, SpecialCharacterIndices, Termios,
 };
 
+/// Makes the terminal mark pasted text, see [`crate::read_line::text_field::TextField`].
+const BRACKETED_PASTE_ON: &[u8] = b"\x1b[?2004h";
+const BRACKETED_PASTE_OFF: &[u8] = b"\x1b[?2004l";
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct TermState {
     old: Option<Termios>,
@@ -44,9 +48,18 @@ impl TermState {
         Ok(())
     }
     pub fn put_new(&self) -> nix::Result<()> {
-        Self::put_termios(&self.new)
+        Self::put_termios(&self.new)?;
+        if self.new.is_some() {
+            crate::output::set_raw(true);
+            crate::write(BRACKETED_PASTE_ON)?;
+        }
+        Ok(())
     }
     pub fn put_old(&self) -> nix::Result<()> {
+        if self.old.is_some() {
+            crate::write(BRACKETED_PASTE_OFF)?;
+            crate::output::set_raw(false);
+        }
         Self::put_termios(&self.old)
     }
 
@@ -71,6 +84,7 @@ pub fn get_termstate() -> TermState {
 
 pub fn restore() {
     if let Some(termios) = OLD_TERMIOS.get() {
+        let _ = crate::write(BRACKETED_PASTE_OFF);
         // Hack undone :)
         let old_termios = (*termios).into();
         let _ = nix::sys::termios::tcsetattr(
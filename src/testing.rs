@@ -0,0 +1,85 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+};
+
+static WORKING_DIR: Mutex<()> = Mutex::new(());
+
+/// Keeps the other tests from changing the working directory of the process, which they
+/// all share, and goes back to where it was once dropped.
+pub struct WorkingDir {
+    original: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl WorkingDir {
+    pub fn lock() -> Self {
+        // A test failing while holding it leaves the directory to be restored all the same
+        let lock = WORKING_DIR.lock().unwrap_or_else(PoisonError::into_inner);
+        Self {
+            original: std::env::current_dir().unwrap(),
+            _lock: lock,
+        }
+    }
+}
+
+impl Drop for WorkingDir {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(&self.original);
+    }
+}
+
+/// An empty directory of its own for a test, removed with everything in it once dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// `name` only tells the directories apart when looking at them, each one is unique anyway.
+    pub fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!(
+            "yash-test-{}-{}-{}",
+            std::process::id(),
+            count,
+            name
+        ));
+        // Left over by a process that had the same id and was killed before cleaning up
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    /// Writes a file named `name` in the directory, returning its path.
+    pub fn file(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
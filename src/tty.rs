@@ -0,0 +1,186 @@
+//! File descriptors the line editor talks to.
+//!
+//! The shell uses the standard ones unless a program embedding it gives an [`Io`] with
+//! [`set_io`], and the line editor can be pointed at any terminal, like a pseudo terminal
+//! in tests.
+
+use std::{cell::RefCell, collections::VecDeque, os::unix::io::RawFd, rc::Rc, time::Duration};
+
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    pty::Winsize,
+};
+
+use crate::Vec2;
+
+thread_local! {
+    /// Input that was read while waiting for something else, like a reply from the terminal.
+    static PENDING_INPUT: RefCell<VecDeque<u8>> = Default::default();
+    /// What the standard input and output are replaced with, see [`set_io`].
+    static IO: RefCell<Option<Rc<dyn Io>>> = const { RefCell::new(None) };
+}
+
+/// Where the shell reads keys from and writes to instead of the terminal.
+///
+/// Errors are like those of the system calls, so `EINTR` and `EAGAIN` are retried.
+pub trait Io {
+    /// Reads what is available into `buf`, returning how much.
+    fn read(&self, buf: &mut [u8]) -> nix::Result<usize>;
+    /// Writes some of `bytes`, returning how much.
+    fn write(&self, bytes: &[u8]) -> nix::Result<usize>;
+    /// Waits up to `timeout` for input, returning whether there is some.
+    fn poll_input(&self, timeout: Duration) -> nix::Result<bool>;
+    /// Size in columns and rows.
+    fn size(&self) -> nix::Result<Vec2>;
+    /// Whether this is a terminal that understands escape sequences, like window titles.
+    fn is_terminal(&self) -> bool;
+}
+
+/// Makes the shell use `io` instead of the standard input and output, or these again
+/// with `None`.
+pub fn set_io(io: Option<Rc<dyn Io>>) {
+    IO.set(io);
+}
+
+/// Puts `bytes` back so the next calls to [`Tty::read`] return them first.
+pub fn unread(bytes: &[u8]) {
+    PENDING_INPUT.with(|pending| pending.borrow_mut().extend(bytes));
+}
+
+/// Fills `buf` with input given back with [`unread`], returning how much was taken.
+fn take_pending(buf: &mut [u8]) -> usize {
+    PENDING_INPUT.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let n = buf.len().min(pending.len());
+        for (byte, pending) in buf.iter_mut().zip(pending.drain(..n)) {
+            *byte = pending;
+        }
+        n
+    })
+}
+
+mod ioctl {
+    use super::*;
+    nix::ioctl_read_bad!(getwinsz, nix::libc::TIOCGWINSZ, Winsize);
+}
+
+/// A terminal, read from `input` and written to `output`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tty {
+    pub input: RawFd,
+    pub output: RawFd,
+}
+
+impl Default for Tty {
+    fn default() -> Self {
+        Self {
+            input: nix::libc::STDIN_FILENO,
+            output: nix::libc::STDOUT_FILENO,
+        }
+    }
+}
+
+impl Tty {
+    /// Runs `f` with what this terminal is: the [`Io`] given with [`set_io`] for the
+    /// standard one, or the file descriptors.
+    fn with_io<T>(&self, f: impl FnOnce(&dyn Io) -> T) -> T {
+        let io = match *self == Self::default() {
+            true => IO.with_borrow(Option::clone),
+            false => None,
+        };
+        match io {
+            Some(io) => f(&*io),
+            None => f(&Fds(*self)),
+        }
+    }
+
+    /// Writes raw bytes, like escape sequences, after any buffered shell output.
+    pub fn write(&self, bytes: &[u8]) -> nix::Result<()> {
+        crate::flush_output()?;
+        self.write_unbuffered(bytes)
+    }
+
+    pub fn write_unbuffered(&self, bytes: &[u8]) -> nix::Result<()> {
+        self.with_io(|io| {
+            let mut written = 0;
+            while written < bytes.len() {
+                match io.write(&bytes[written..]) {
+                    Ok(n) => written += n,
+                    // Interrupted by a signal, like a resize, before anything was written
+                    Err(nix::Error::EAGAIN | nix::Error::EINTR) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Reads what is available, handing out input given back with [`unread`] first.
+    pub fn read(&self, buf: &mut [u8]) -> nix::Result<usize> {
+        debug_assert!(!buf.is_empty());
+        // Whatever is waiting for an answer must be visible
+        crate::flush_output()?;
+        let pending = take_pending(buf);
+        if pending != 0 {
+            return Ok(pending);
+        }
+        self.with_io(|io| loop {
+            match io.read(buf) {
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(nix::errno::Errno::EAGAIN) => return Ok(0),
+                read => return read,
+            }
+        })
+    }
+
+    /// Waits up to `timeout` for input, returning whether there is some.
+    pub fn poll_input(&self, timeout: Duration) -> nix::Result<bool> {
+        self.with_io(|io| loop {
+            match io.poll_input(timeout) {
+                Err(nix::errno::Errno::EINTR) => continue,
+                ready => return ready,
+            }
+        })
+    }
+
+    /// Size of the terminal, in columns and rows.
+    pub fn size(&self) -> nix::Result<Vec2> {
+        self.with_io(|io| io.size())
+    }
+
+    /// Whether escape sequences written here reach a terminal.
+    pub fn is_terminal(&self) -> bool {
+        self.with_io(|io| io.is_terminal())
+    }
+}
+
+/// The file descriptors of a [`Tty`] themselves.
+struct Fds(Tty);
+
+impl Io for Fds {
+    fn read(&self, buf: &mut [u8]) -> nix::Result<usize> {
+        nix::unistd::read(self.0.input, buf)
+    }
+
+    fn write(&self, bytes: &[u8]) -> nix::Result<usize> {
+        nix::unistd::write(self.0.output, bytes)
+    }
+
+    fn poll_input(&self, timeout: Duration) -> nix::Result<bool> {
+        let mut fds = [PollFd::new(self.0.input, PollFlags::POLLIN)];
+        Ok(poll(&mut fds, timeout.as_millis() as i32)? > 0)
+    }
+
+    fn size(&self) -> nix::Result<Vec2> {
+        unsafe {
+            let mut winsz = std::mem::MaybeUninit::<Winsize>::uninit();
+            ioctl::getwinsz(self.0.output, winsz.as_mut_ptr())?;
+            let winsz = winsz.assume_init();
+            Ok(Vec2::new(winsz.ws_col as u32, winsz.ws_row as u32))
+        }
+    }
+
+    fn is_terminal(&self) -> bool {
+        nix::unistd::isatty(self.0.output).unwrap_or(false)
+    }
+}
@@ -0,0 +1,306 @@
+//! Resource limits of the shell process, which the programs it runs inherit.
+
+use color_eyre::eyre::eyre;
+use nix::{
+    errno::Errno,
+    libc::rlim_t,
+    sys::resource::{getrlimit, setrlimit, Resource, RLIM_INFINITY},
+};
+
+use crate::YshResult;
+
+/// A limit `ulimit` can print and set, and the units its values are given in.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Limit {
+    pub flag: char,
+    pub name: &'static str,
+    unit: Option<&'static str>,
+    /// How many of the kernel's units, bytes or seconds, a unit of `ulimit` is.
+    scale: rlim_t,
+    resource: Resource,
+}
+
+/// The limits by flag. The sizes are in 512-byte blocks for files, like POSIX has it, and in
+/// kbytes for memory.
+pub const LIMITS: [Limit; 6] = [
+    Limit {
+        flag: 'c',
+        name: "core file size",
+        unit: Some("blocks"),
+        scale: 512,
+        resource: Resource::RLIMIT_CORE,
+    },
+    Limit {
+        flag: 'f',
+        name: "file size",
+        unit: Some("blocks"),
+        scale: 512,
+        resource: Resource::RLIMIT_FSIZE,
+    },
+    Limit {
+        flag: 'n',
+        name: "open files",
+        unit: None,
+        scale: 1,
+        resource: Resource::RLIMIT_NOFILE,
+    },
+    Limit {
+        flag: 's',
+        name: "stack size",
+        unit: Some("kbytes"),
+        scale: 1024,
+        resource: Resource::RLIMIT_STACK,
+    },
+    Limit {
+        flag: 't',
+        name: "cpu time",
+        unit: Some("seconds"),
+        scale: 1,
+        resource: Resource::RLIMIT_CPU,
+    },
+    Limit {
+        flag: 'v',
+        name: "virtual memory",
+        unit: Some("kbytes"),
+        scale: 1024,
+        resource: Resource::RLIMIT_AS,
+    },
+];
+
+/// What a `ulimit` command line asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request<'a> {
+    /// Whether it is about the hard limits instead of the soft ones.
+    pub hard: bool,
+    /// The limit to print or set, or `None` for all of them with `-a`.
+    pub limit: Option<&'static Limit>,
+    pub value: Option<&'a str>,
+}
+
+pub fn find(flag: char) -> Option<&'static Limit> {
+    LIMITS.iter().find(|limit| limit.flag == flag)
+}
+
+/// Reads the flags and the value of a `ulimit` command line, the file size limit being
+/// the one without a flag.
+pub fn parse_args<'a>(args: &[&'a str]) -> YshResult<Request<'a>> {
+    let mut hard = false;
+    let mut all = false;
+    let mut limit = None;
+    let mut args = args.iter().peekable();
+    while let Some(flags) = args.next_if(|arg| arg.len() > 1 && arg.starts_with('-')) {
+        for flag in flags[1..].chars() {
+            match flag {
+                'H' => hard = true,
+                'S' => hard = false,
+                'a' => all = true,
+                _ => {
+                    let found = find(flag).ok_or_else(|| eyre!("-{}: unknown limit", flag))?;
+                    if limit.replace(found).is_some_and(|other| other != found) {
+                        return Err(eyre!("only one limit can be given"));
+                    }
+                }
+            }
+        }
+    }
+    let value = args.next().copied();
+    if args.next().is_some() {
+        return Err(eyre!("too many arguments"));
+    }
+    if all {
+        if limit.is_some() || value.is_some() {
+            return Err(eyre!("-a only prints the limits"));
+        }
+        return Ok(Request {
+            hard,
+            limit: None,
+            value,
+        });
+    }
+    Ok(Request {
+        hard,
+        limit: Some(limit.unwrap_or(&LIMITS[1])),
+        value,
+    })
+}
+
+impl Limit {
+    /// Reads a value in the units of `ulimit`, or `unlimited`, into the kernel's.
+    pub fn parse(&self, text: &str) -> YshResult<rlim_t> {
+        if text == "unlimited" {
+            return Ok(RLIM_INFINITY);
+        }
+        if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(eyre!("{}: invalid limit", text));
+        }
+        text.parse::<rlim_t>()
+            .ok()
+            .and_then(|value| value.checked_mul(self.scale))
+            .filter(|&value| value != RLIM_INFINITY)
+            .ok_or_else(|| eyre!("{}: limit too large", text))
+    }
+
+    /// Writes a value of the kernel's in the units of `ulimit`.
+    pub fn format(&self, value: rlim_t) -> String {
+        match value {
+            RLIM_INFINITY => "unlimited".to_string(),
+            _ => (value / self.scale).to_string(),
+        }
+    }
+
+    /// The line for the limit in `ulimit -a`, like `open files  (-n) 1024`.
+    pub fn row(&self, value: rlim_t) -> String {
+        let unit = match self.unit {
+            Some(unit) => format!("({}, -{})", unit, self.flag),
+            None => format!("(-{})", self.flag),
+        };
+        format!("{:<20}{:>16} {}", self.name, unit, self.format(value))
+    }
+
+    pub fn get(&self, hard: bool) -> YshResult<rlim_t> {
+        let (soft_limit, hard_limit) =
+            getrlimit(self.resource).map_err(|e| eyre!("can't get {}: {}", self.name, e.desc()))?;
+        Ok(if hard { hard_limit } else { soft_limit })
+    }
+
+    /// Changes the soft limit, or the hard one, lowering the soft limit along with it.
+    ///
+    /// Only privileged processes can raise hard limits, and the soft limit can't be above
+    /// the hard one.
+    pub fn set(&self, hard: bool, value: rlim_t) -> YshResult<()> {
+        let (soft_limit, hard_limit) =
+            getrlimit(self.resource).map_err(|e| eyre!("can't get {}: {}", self.name, e.desc()))?;
+        let result = match hard {
+            true => setrlimit(self.resource, soft_limit.min(value), value),
+            false => setrlimit(self.resource, value, hard_limit),
+        };
+        result.map_err(|e| {
+            let reason = match e {
+                Errno::EINVAL if !hard && value > hard_limit => {
+                    format!("above the hard limit of {}", self.format(hard_limit))
+                }
+                Errno::EPERM if hard => "only privileged users can raise it".to_string(),
+                _ => e.desc().to_string(),
+            };
+            eyre!(
+                "can't set {} to {}: {}",
+                self.name,
+                self.format(value),
+                reason
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_scaled_by_resource() {
+        let cases = [
+            ('c', "0", 0),
+            ('c', "1", 512),
+            ('f', "10", 5120),
+            ('n', "4096", 4096),
+            ('s', "8192", 8192 * 1024),
+            ('t', "60", 60),
+            ('v', "1", 1024),
+            ('n', "unlimited", RLIM_INFINITY),
+            ('v', "unlimited", RLIM_INFINITY),
+        ];
+        for (flag, text, value) in cases {
+            let limit = find(flag).unwrap();
+            assert_eq!(limit.parse(text).unwrap(), value, "-{} {}", flag, text);
+            assert_eq!(limit.format(value), text, "-{} {}", flag, text);
+        }
+        // Remainders of a unit are left out
+        assert_eq!(find('s').unwrap().format(1500), "1");
+    }
+
+    #[test]
+    fn invalid_values_are_errors() {
+        let cases = [
+            ('n', "", ": invalid limit"),
+            ('n', "-1", "-1: invalid limit"),
+            ('n', "1k", "1k: invalid limit"),
+            ('n', "Unlimited", "Unlimited: invalid limit"),
+            (
+                'n',
+                "18446744073709551616",
+                "18446744073709551616: limit too large",
+            ),
+            (
+                'v',
+                "18446744073709551615",
+                "18446744073709551615: limit too large",
+            ),
+        ];
+        for (flag, text, error) in cases {
+            let limit = find(flag).unwrap();
+            assert_eq!(limit.parse(text).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn rows_line_up() {
+        assert_eq!(
+            find('c').unwrap().row(0),
+            "core file size          (blocks, -c) 0"
+        );
+        assert_eq!(
+            find('n').unwrap().row(1024),
+            "open files                      (-n) 1024"
+        );
+        assert_eq!(
+            find('t').unwrap().row(RLIM_INFINITY),
+            "cpu time               (seconds, -t) unlimited"
+        );
+    }
+
+    #[test]
+    fn flags_pick_the_limit() {
+        let request = |hard, flag: Option<char>, value| Request {
+            hard,
+            limit: flag.map(|flag| find(flag).unwrap()),
+            value,
+        };
+        let cases = [
+            (&[][..], request(false, Some('f'), None)),
+            (&["-a"], request(false, None, None)),
+            (&["-Ha"], request(true, None, None)),
+            (&["-n"], request(false, Some('n'), None)),
+            (&["-n", "4096"], request(false, Some('n'), Some("4096"))),
+            (
+                &["-H", "-s", "unlimited"],
+                request(true, Some('s'), Some("unlimited")),
+            ),
+            (&["-HSc"], request(false, Some('c'), None)),
+            (&["-t", "-t", "5"], request(false, Some('t'), Some("5"))),
+            (&["100"], request(false, Some('f'), Some("100"))),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(parse_args(args).unwrap(), expected, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn bad_flags_are_errors() {
+        let cases = [
+            (&["-x"][..], "-x: unknown limit"),
+            (&["-n", "-s"], "only one limit can be given"),
+            (&["-ns"], "only one limit can be given"),
+            (&["-a", "-n"], "-a only prints the limits"),
+            (&["-a", "5"], "-a only prints the limits"),
+            (&["-n", "5", "6"], "too many arguments"),
+        ];
+        for (args, error) in cases {
+            assert_eq!(
+                parse_args(args).unwrap_err().to_string(),
+                error,
+                "{:?}",
+                args
+            );
+        }
+    }
+}
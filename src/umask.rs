@@ -0,0 +1,170 @@
+//! The file creation mask, in the octal and symbolic forms `umask` reads and prints.
+
+use color_eyre::eyre::eyre;
+use nix::sys::stat::{umask, Mode};
+
+use crate::YshResult;
+
+/// The permission bits a mask can hold.
+const ALL: u32 = 0o777;
+
+/// The classes the symbolic form names, with the bits they own within each permission.
+const CLASSES: [(char, u32); 3] = [('u', 0o700), ('g', 0o070), ('o', 0o007)];
+
+/// The permissions the symbolic form names, in every class at once.
+const PERMISSIONS: [(char, u32); 3] = [('r', 0o444), ('w', 0o222), ('x', 0o111)];
+
+/// The mask of the shell process.
+///
+/// The only way to read it is setting a new one, so the old one is put right back.
+pub fn current() -> u32 {
+    let mask = umask(Mode::empty());
+    umask(mask);
+    mask.bits() as u32 & ALL
+}
+
+pub fn set(mask: u32) {
+    umask(Mode::from_bits_truncate((mask & ALL) as _));
+}
+
+/// Writes the permissions `mask` leaves to new files, like `u=rwx,g=rx,o=rx` for `022`.
+pub fn format_symbolic(mask: u32) -> String {
+    let allowed = !mask & ALL;
+    CLASSES
+        .iter()
+        .map(|&(class, class_bits)| {
+            let permissions = PERMISSIONS
+                .iter()
+                .filter(|&&(_, bits)| allowed & bits & class_bits != 0)
+                .map(|&(permission, _)| permission)
+                .collect::<String>();
+            format!("{}={}", class, permissions)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Reads the symbolic form, comma-separated clauses like `u=rwx`, `go-w` or `+x`, into the
+/// mask it makes of `mask`.
+///
+/// Each clause changes what the ones before left: `+` allows the permissions, `-` denies
+/// them and `=` allows only them. Without classes, a clause is for all of them, like `a`.
+pub fn parse_symbolic(spec: &str, mask: u32) -> YshResult<u32> {
+    let mut allowed = !mask & ALL;
+    for clause in spec.split(',') {
+        let mut chars = clause.chars().peekable();
+        let mut classes = 0;
+        while let Some(&c) = chars.peek() {
+            classes |= match c {
+                'a' => ALL,
+                _ => match CLASSES.iter().find(|&&(class, _)| class == c) {
+                    Some(&(_, bits)) => bits,
+                    None => break,
+                },
+            };
+            chars.next();
+        }
+        if classes == 0 {
+            classes = ALL;
+        }
+        if chars.peek().is_none() {
+            return Err(eyre!("{}: missing '+', '-' or '='", clause));
+        }
+        while let Some(op) = chars.next() {
+            if !matches!(op, '+' | '-' | '=') {
+                return Err(eyre!("{}: invalid character '{}'", clause, op));
+            }
+            let mut permissions = 0;
+            while let Some(&c) = chars.peek() {
+                match PERMISSIONS.iter().find(|&&(permission, _)| permission == c) {
+                    Some(&(_, bits)) => permissions |= bits,
+                    None => break,
+                }
+                chars.next();
+            }
+            let bits = permissions & classes;
+            allowed = match op {
+                '+' => allowed | bits,
+                '-' => allowed & !bits,
+                _ => allowed & !classes | bits,
+            };
+        }
+    }
+    Ok(!allowed & ALL)
+}
+
+/// Reads a mask in octal, like `022`, or in the symbolic form relative to `mask`.
+pub fn parse(spec: &str, mask: u32) -> YshResult<u32> {
+    if !spec.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_symbolic(spec, mask);
+    }
+    match u32::from_str_radix(spec, 8) {
+        Ok(mask) if mask <= ALL => Ok(mask),
+        _ => Err(eyre!("{}: invalid octal mask", spec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_mask_is_written_and_read_back() {
+        for mask in 0..=ALL {
+            let symbolic = format_symbolic(mask);
+            assert_eq!(parse_symbolic(&symbolic, 0).unwrap(), mask, "{}", symbolic);
+            assert_eq!(
+                parse_symbolic(&symbolic, ALL).unwrap(),
+                mask,
+                "{}",
+                symbolic
+            );
+        }
+    }
+
+    #[test]
+    fn masks_are_written_as_what_they_allow() {
+        assert_eq!(format_symbolic(0o022), "u=rwx,g=rx,o=rx");
+        assert_eq!(format_symbolic(0o077), "u=rwx,g=,o=");
+        assert_eq!(format_symbolic(0o000), "u=rwx,g=rwx,o=rwx");
+        assert_eq!(format_symbolic(0o777), "u=,g=,o=");
+        assert_eq!(format_symbolic(0o351), "u=r,g=w,o=rw");
+    }
+
+    #[test]
+    fn clauses_change_the_mask_in_order() {
+        assert_eq!(parse_symbolic("u=rwx,g=rx,o=rx", 0o777).unwrap(), 0o022);
+        assert_eq!(parse_symbolic("go-w", 0o000).unwrap(), 0o022);
+        assert_eq!(parse_symbolic("o+r", 0o077).unwrap(), 0o073);
+        assert_eq!(parse_symbolic("a=r", 0o000).unwrap(), 0o333);
+        assert_eq!(parse_symbolic("=rx", 0o000).unwrap(), 0o222);
+        assert_eq!(parse_symbolic("+w", 0o777).unwrap(), 0o555);
+        assert_eq!(parse_symbolic("ug=", 0o000).unwrap(), 0o770);
+        // Several operations in one clause, and later clauses override earlier ones
+        assert_eq!(parse_symbolic("u=rwx-x+w", 0o777).unwrap(), 0o177);
+        assert_eq!(parse_symbolic("g=r,g+w", 0o000).unwrap(), 0o010);
+        assert_eq!(parse_symbolic("o=,a+r", 0o000).unwrap(), 0o003);
+    }
+
+    #[test]
+    fn octal_masks_are_read_unless_symbolic() {
+        assert_eq!(parse("022", 0o777).unwrap(), 0o022);
+        assert_eq!(parse("7", 0o000).unwrap(), 0o007);
+        assert_eq!(parse("0777", 0o000).unwrap(), 0o777);
+        assert_eq!(parse("g-w", 0o000).unwrap(), 0o020);
+    }
+
+    #[test]
+    fn invalid_masks_are_errors() {
+        let error = |spec| parse(spec, 0o022).unwrap_err().to_string();
+        assert_eq!(error("089"), "089: invalid octal mask");
+        assert_eq!(error("1000"), "1000: invalid octal mask");
+        assert_eq!(error("0x1"), "0x1: invalid octal mask");
+        assert_eq!(error("u"), "u: missing '+', '-' or '='");
+        assert_eq!(error(""), ": missing '+', '-' or '='");
+        assert_eq!(error("u=rx,"), ": missing '+', '-' or '='");
+        assert_eq!(error("u=rz"), "u=rz: invalid character 'z'");
+        assert_eq!(error("q=r"), "q=r: invalid character 'q'");
+        assert_eq!(error("u+r g-w"), "u+r g-w: invalid character ' '");
+    }
+}
@@ -1,4 +1,12 @@
-use std::{borrow::Cow, ffi::OsStr, io::BufRead, os::unix::prelude::OsStrExt, path::Path};
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    os::unix::prelude::OsStrExt,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 #[macro_export]
 macro_rules! binformat {
@@ -29,13 +37,31 @@ where
     hasher.finish()
 }
 
-pub fn char_count(s: &str) -> usize {
-    s.chars().count()
+/// Returns true if `c` is drawn together with the character before it.
+fn extends_cluster(c: char) -> bool {
+    // Emoji skin tone modifiers are wide on their own
+    matches!(c, '\u{1F3FB}'..='\u{1F3FF}') || c.width() == Some(0)
 }
 
-pub fn char_at(s: &str, index: usize) -> Option<usize> {
-    let (i, _) = s.char_indices().nth(index)?;
-    Some(i)
+/// Byte indices where each character, as seen by the user, starts in `s`.
+///
+/// Combining marks, emoji modifiers and characters joined with a zero width joiner
+/// belong to the character before them.
+pub fn cluster_starts(s: &str) -> Vec<usize> {
+    let mut starts = vec![];
+    let mut joined = false;
+    for (i, c) in s.char_indices() {
+        if starts.is_empty() || !(joined || extends_cluster(c)) {
+            starts.push(i);
+        }
+        joined = c == '\u{200D}';
+    }
+    starts
+}
+
+/// Number of terminal columns `s` takes.
+pub fn display_width(s: &str) -> usize {
+    s.width()
 }
 
 pub fn path_parent(path: &Path) -> Option<&Path> {
@@ -58,19 +84,54 @@ pub fn path_filename(path: &Path) -> Option<&OsStr> {
     }
 }
 
-pub fn read_file(p: impl AsRef<std::path::Path>) -> std::io::Result<Vec<String>> {
-    let file = match std::fs::File::open(p) {
-        Ok(f) => f,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
-        Err(e) => Err(e)?,
-    };
-    let st = std::io::BufReader::new(file);
-    Ok(st
-        .lines()
-        .filter_map(|s| s.ok())
-        .filter(|s| !s.is_empty())
-        .map(|s| String::from(s))
-        .collect())
+/// Shows `path` with the home directory as `~`.
+pub fn tilde_path(path: &Path) -> String {
+    let home = crate::builtins::get_home();
+    tilde_path_in(path, home.as_deref().map(Path::new))
+}
+
+/// Shows `path` with `home` as `~`, only when `path` is inside it.
+pub fn tilde_path_in(path: &Path, home: Option<&Path>) -> String {
+    match home.and_then(|home| path.strip_prefix(home).ok()) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".into(),
+        Some(rest) => format!("~/{}", rest.display()),
+        None => path.display().to_string(),
+    }
+}
+
+/// Resolves `.` and `..` in `path` lexically, without following symlinks.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Quotes `word` so that shell word splitting turns it back into a single, identical word.
+pub fn quote_word(word: &str) -> Cow<'_, str> {
+    let is_plain = |c: char| c.is_alphanumeric() || "-_./,:+@%=~".contains(c);
+    if !word.is_empty() && word.chars().all(is_plain) {
+        Cow::Borrowed(word)
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+    }
+}
+
+/// Formats `duration` for people, like `1h2m3s`, `1m23s` or `4.5s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}.{}s", s, duration.subsec_millis() / 100),
+        (0, m, s) => format!("{}m{}s", m, s),
+        (h, m, s) => format!("{}h{}m{}s", h, m, s),
+    }
 }
 
 /// Allows to push slices or vecs of bytes into a buffer and join them later.
@@ -141,3 +202,54 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_words_are_left_alone() {
+        let words = ["ls", "-la", "~/src", "a=b", "me@host:/tmp", "日本", "100%"];
+        for word in words {
+            assert!(matches!(quote_word(word), Cow::Borrowed(_)), "{}", word);
+        }
+        assert_eq!(quote_word("it's"), r"'it'\''s'");
+        assert_eq!(quote_word(""), "''");
+    }
+
+    #[test]
+    fn quoted_words_split_back_the_same() {
+        let words = [
+            "",
+            " ",
+            "a b",
+            "  two  spaces  ",
+            "tab\there",
+            "new\nline",
+            "it's",
+            "''",
+            "'\\''",
+            "\"double\"",
+            "back\\slash",
+            "$HOME",
+            "${X:-y}",
+            "$(rm -rf /)",
+            "`date`",
+            "a;b|c&d",
+            "<in >out",
+            "*.rs",
+            "#comment",
+            "!history",
+            "ünïcödé ✓",
+            "é'\"\\$ \n",
+        ];
+        for word in words {
+            let quoted = quote_word(word);
+            let split = shell_word_split::split(&quoted).unwrap();
+            assert_eq!(split, [word], "{:?} quoted as {}", word, quoted);
+        }
+        // Several words stay apart
+        let line = words.map(|word| quote_word(word)).join(" ");
+        assert_eq!(shell_word_split::split(&line).unwrap(), words);
+    }
+}
@@ -1,10 +1,43 @@
-use crate::{bytes_buf, read_line::cursor, sdbg, utils::BytesBuf};
+use std::borrow::Cow;
+
+use bstr::ByteSlice;
+use unicode_width::UnicodeWidthChar;
+
+use crate::{read_line::cursor, utils::BytesBuf};
 
 pub type Pos = crate::Vec2;
 pub type Size = Pos;
 
-fn paint_selected(text: &[u8]) -> Vec<u8> {
-    [b"\x1b[7m", text, b"\x1B[0m"].concat()
+/// Draws `text` in `style`, and in reverse video on top of it when `selected`.
+fn paint(text: &[u8], style: &str, selected: bool) -> Vec<u8> {
+    let style = match (style, selected) {
+        ("", false) => return text.to_vec(),
+        ("", true) => "7".into(),
+        (style, false) => style.to_owned(),
+        (style, true) => format!("{};7", style),
+    };
+    [format!("\x1b[{}m", style).as_bytes(), text, b"\x1B[0m"].concat()
+}
+
+/// An entry of [`grid`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GridItem {
+    pub text: String,
+    /// SGR parameters it is drawn with, like `34` for blue, or empty for none.
+    pub style: &'static str,
+    /// Indices of the characters drawn underlined, like those a completion matched.
+    pub marks: Vec<usize>,
+}
+
+impl GridItem {
+    /// An item drawn in the terminal's colors, with what isn't UTF-8 as U+FFFD.
+    pub fn new(text: impl AsRef<[u8]>) -> Self {
+        Self {
+            text: text.as_ref().to_str_lossy().into_owned(),
+            style: "",
+            marks: Vec::new(),
+        }
+    }
 }
 
 pub struct GridStyle {
@@ -13,69 +46,478 @@ pub struct GridStyle {
 
 impl Default for GridStyle {
     fn default() -> Self {
-        Self {
-            horizontal_gap: 2
-        }
+        Self { horizontal_gap: 2 }
     }
 }
 
 #[derive(Default)]
 pub struct GridResponse {
     pub elements_shown: u8,
-    pub response: Vec<u8>
+    /// Rows the items were laid out in, top to bottom and then left to right.
+    pub rows: u8,
+    pub response: Vec<u8>,
 }
 
+/// Length of the escape sequence `text` starts with, if any.
+fn escape_len(text: &str) -> Option<usize> {
+    let params = text.strip_prefix("\x1b[")?;
+    let end = params.find(|c| matches!(c, '\x40'..='\x7e'))?;
+    Some("\x1b[".len() + end + 1)
+}
+
+/// Cuts `item` to at most `width` columns, keeping its escape sequences.
+///
+/// Returns the text and how many columns it takes.
+fn fit(item: &str, width: usize) -> (Cow<'_, str>, usize) {
+    let mut used = 0;
+    let mut end = 0;
+    let mut escaped = false;
+    while let Some(c) = item[end..].chars().next() {
+        if let Some(len) = escape_len(&item[end..]) {
+            end += len;
+            escaped = true;
+            continue;
+        }
+        let c_width = c.width().unwrap_or(0);
+        if used + c_width > width {
+            let text = &item[..end];
+            // Colors started inside the item must not leak out of it
+            let text = match escaped {
+                true => Cow::Owned(format!("{}\x1b[0m", text)),
+                false => Cow::Borrowed(text),
+            };
+            return (text, used);
+        }
+        used += c_width;
+        end += c.len_utf8();
+    }
+    (Cow::Borrowed(item), used)
+}
+
+/// Cuts `text` to at most `columns` from the left, with a `…` where it was cut, so the
+/// end of a path is what's left.
+fn fit_left(text: &str, columns: usize) -> Cow<'_, str> {
+    if width(text) <= columns {
+        return Cow::Borrowed(text);
+    }
+    if columns == 0 {
+        return Cow::Borrowed("");
+    }
+    // The `…` takes one
+    let mut used = 1;
+    let mut start = text.len();
+    for (i, c) in text.char_indices().rev() {
+        used += c.width().unwrap_or(0);
+        if used > columns {
+            break;
+        }
+        start = i;
+    }
+    Cow::Owned(format!("…{}", &text[start..]))
+}
+
+/// Lays out `left` and then `right` on a line of `columns`, cutting `right` from the left
+/// if they don't fit.
+pub fn status_line(left: &str, right: &str, columns: usize) -> String {
+    const GAP: &str = "  ";
+    match columns.saturating_sub(width(left) + GAP.len()) {
+        0 => fit(left, columns).0.into_owned(),
+        room => format!("{}{}{}", left, GAP, fit_left(right, room)),
+    }
+}
+
+/// Underlines the characters of `text` at `marks`, counted without its escape sequences.
+fn underline<'a>(text: &'a str, marks: &[usize]) -> Cow<'a, str> {
+    if marks.is_empty() {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len() + marks.len() * 9);
+    let (mut end, mut index, mut underlined) = (0, 0, false);
+    while let Some(c) = text[end..].chars().next() {
+        if let Some(len) = escape_len(&text[end..]) {
+            out.push_str(&text[end..end + len]);
+            end += len;
+            continue;
+        }
+        let marked = marks.contains(&index);
+        if marked != underlined {
+            out.push_str(if marked { "\x1b[4m" } else { "\x1b[24m" });
+            underlined = marked;
+        }
+        out.push(c);
+        end += c.len_utf8();
+        index += 1;
+    }
+    if underlined {
+        out.push_str("\x1b[24m");
+    }
+    Cow::Owned(out)
+}
+
+/// Makes `text` safe to draw: control characters are shown like `^J`, escape as `\e`,
+/// and what isn't UTF-8 as U+FFFD.
+pub fn escape_controls(text: &[u8]) -> Cow<'_, str> {
+    let text = text.to_str_lossy();
+    if !text.chars().any(char::is_control) {
+        return text;
+    }
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '\x1b' => escaped.push_str("\\e"),
+            '\0'..='\x1f' => {
+                escaped.push('^');
+                escaped.push((c as u8 + b'@') as char);
+            }
+            '\x7f' => escaped.push_str("^?"),
+            c if c.is_control() => escaped.push(char::REPLACEMENT_CHARACTER),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Number of columns `item` takes, leaving escape sequences out.
+fn width(item: &str) -> usize {
+    fit(item, usize::MAX).1
+}
 
-pub fn grid<T: AsRef<[u8]> + std::fmt::Debug>(
+/// Widths of the columns when items of `widths` are laid out top to bottom in `rows` rows.
+fn column_widths(widths: &[usize], rows: usize) -> Vec<usize> {
+    widths
+        .chunks(rows)
+        .map(|column| column.iter().copied().max().unwrap_or(0))
+        .collect()
+}
+
+/// Fewest rows, up to `max_rows`, that fit all the items in `term_width` columns.
+fn row_count(widths: &[usize], max_rows: usize, term_width: usize, gap: usize) -> usize {
+    (1..max_rows)
+        .find(|&rows| {
+            let columns = column_widths(widths, rows);
+            columns.iter().sum::<usize>() + gap * (columns.len() - 1) <= term_width
+        })
+        .unwrap_or(max_rows)
+}
+
+/// Draws `items` in columns below the line at `pos`, highlighting `selected`, and
+/// `note` dimmed on the line after them.
+///
+/// The styles and marks of the items take no room. The grid takes at most half of the terminal. If there aren't enough lines below the
+/// cursor, the terminal is scrolled first. Columns that don't fit are left out.
+pub fn grid(
     pos: Pos,
     term_size: Size,
-    items: &[T],
+    items: &[GridItem],
     selected: u8,
+    note: Option<&str>,
     style: GridStyle,
 ) -> GridResponse {
-    let mut buf = bytes_buf![cursor::kill_to_term_end(), b"\r\n"];
-    // TODO: use sorta square root based algorithm for row count
-    let rows = 4;
-    let mut item_index = 0u8;
-    let mut remaining_width = term_size.x as u8;
-    for col in items.chunks(rows as usize) {
+    let items = &items[..items.len().min(u8::MAX as usize)];
+    let widths: Vec<_> = items.iter().map(|item| width(&item.text)).collect();
+    let term_width = term_size.x as usize;
+    let gap = style.horizontal_gap as usize;
+    let max_rows = (term_size.y as usize / 2).clamp(1, items.len().max(1));
+    let rows = match items.len() {
+        0 => 0,
+        _ => row_count(&widths, max_rows, term_width, gap),
+    };
+
+    let mut buf = BytesBuf::new();
+    let lines = rows + note.is_some() as usize;
+    let below = term_size.y.saturating_sub(pos.y) as usize;
+    let scroll = lines.saturating_sub(below);
+    let cursor = Pos::new(pos.x, pos.y.saturating_sub(scroll as u32).max(1));
+    if scroll != 0 {
+        // Going down from the cursor scrolls once the bottom is reached
+        buf.push(vec![b'\n'; lines]);
+        buf.push(cursor::set_positionv(cursor));
+    }
+    buf.push(cursor::save_cursor());
+    if cursor.y < term_size.y {
+        buf.push(cursor::set_positionv(Pos::new(1, cursor.y + 1)));
+        buf.push(cursor::kill_to_term_end());
+    }
+
+    let mut shown = 0u8;
+    let mut x = 0;
+    let columns = items
+        .chunks(rows.max(1))
+        .zip(column_widths(&widths, rows.max(1)));
+    for (column, column_width) in columns {
+        let remaining_width = term_width.saturating_sub(x);
         if remaining_width == 0 {
-            break
+            break;
         }
-        let mut col_buf = BytesBuf::new();
+        for (row, item) in column.iter().enumerate() {
+            let (text, _) = fit(&item.text, remaining_width);
+            let text = underline(&text, &item.marks);
+            buf.push(cursor::set_positionv(Pos::new(
+                x as u32 + 1,
+                cursor.y + 1 + row as u32,
+            )));
+            buf.push(paint(text.as_bytes(), item.style, shown == selected));
+            shown += 1;
+        }
+        x += column_width + gap;
+    }
+    if let Some(note) = note {
+        let (text, _) = fit(note, term_width);
+        buf.push(cursor::set_positionv(Pos::new(
+            1,
+            cursor.y + 1 + rows as u32,
+        )));
+        buf.push(format!("\x1b[2m{}\x1b[22m", text).into_bytes());
+    }
+    buf.push(cursor::restore_cursor());
+    GridResponse {
+        elements_shown: shown,
+        rows: rows as u8,
+        response: buf.join(b""),
+    }
+}
 
-        let mut col_width = 0;
-        for item in col.iter() {
-            let item = item.as_ref();
+/// Lays out `rows` as lines of two columns, the first one as wide as its widest entry.
+pub fn table<L: AsRef<str>, R: AsRef<str>>(rows: &[(L, R)], style: GridStyle) -> String {
+    let left_width = rows
+        .iter()
+        .map(|(left, _)| width(left.as_ref()))
+        .max()
+        .unwrap_or(0);
+    let mut out = String::new();
+    for (left, right) in rows {
+        let (left, right) = (left.as_ref(), right.as_ref());
+        let padding = left_width - width(left) + style.horizontal_gap as usize;
+        out.push_str(left);
+        out.push_str(&" ".repeat(padding));
+        out.push_str(right);
+        out.push('\n');
+    }
+    out
+}
 
-            let item = &item[..item.len().min(remaining_width as usize)];
+#[cfg(test)]
+mod tests {
+    use bstr::BString;
 
-            if item_index == selected {
-                col_buf.push(paint_selected(item));
-            } else {
-                col_buf.push(item);
-            }
+    use super::*;
 
-            // Move cursor to start of next line
-            let item_len = item.len() as u32;
-            col_buf.push(cursor::move_left(item_len));
-            col_buf.push(b"\n");
-            col_width = col_width.max(item_len as u8);
-            item_index += 1;
-        }
-        buf.push(col_buf.join(b""));
+    fn items(texts: &[&str]) -> Vec<GridItem> {
+        texts.iter().map(GridItem::new).collect()
+    }
 
-        // Move cursor to the start of the next column
-        let displacement = col_width + style.horizontal_gap;
-        buf.push(cursor::move_up(col.len() as u32));
-        buf.push(cursor::move_right(displacement as _));
+    fn draw(pos: (u32, u32), size: (u32, u32), texts: &[&str]) -> GridResponse {
+        let (pos, size) = (Pos::new(pos.0, pos.1), Size::new(size.0, size.1));
+        grid(
+            pos,
+            size,
+            &items(texts),
+            u8::MAX,
+            None,
+            GridStyle::default(),
+        )
+    }
 
-        remaining_width = remaining_width.saturating_sub(col_width);
+    #[test]
+    fn columns_use_display_width() {
+        let response = draw(
+            (3, 1),
+            (80, 24),
+            &["é.txt", "日本", "\x1b[31mred\x1b[0m", "x"],
+        );
+        assert_eq!(response.elements_shown, 4);
+        assert_eq!(
+            response.response.to_str().unwrap(),
+            "\x1b7\x1b[2;1H\x1b[J\x1b[2;1Hé.txt\x1b[2;8H日本\x1b[2;14H\x1b[31mred\x1b[0m\x1b[2;19Hx\x1b8"
+        );
     }
-    // Move cursor to where it was, hopefully 
-    buf.push(b"\r");
-    buf.push(cursor::move_up(1));
-    buf.push(cursor::move_right(pos.x - 1));
-    GridResponse { elements_shown: item_index, response: buf.join(b"") }
-}
 
+    #[test]
+    fn rows_grow_until_items_fit() {
+        let response = draw((3, 1), (10, 24), &["aaaa", "bbbb", "cccc", "dddd", "eeee"]);
+        assert_eq!((response.elements_shown, response.rows), (5, 3));
+        assert_eq!(
+            response.response.to_str().unwrap(),
+            "\x1b7\x1b[2;1H\x1b[J\x1b[2;1Haaaa\x1b[3;1Hbbbb\x1b[4;1Hcccc\x1b[2;7Hdddd\x1b[3;7Heeee\x1b8"
+        );
+        // But never past half of the terminal, leaving out what doesn't fit
+        let response = draw((3, 1), (10, 2), &["aaaa", "bbbb", "cccccccc"]);
+        assert_eq!(response.elements_shown, 2);
+        assert_eq!(
+            response.response.to_str().unwrap(),
+            "\x1b7\x1b[2;1H\x1b[J\x1b[2;1Haaaa\x1b[2;7Hbbbb\x1b8"
+        );
+    }
+
+    #[test]
+    fn long_items_are_cut() {
+        let response = draw((1, 1), (6, 24), &["\x1b[1mabcdefgh\x1b[0m", "日本語"]);
+        assert_eq!(
+            response.response.to_str().unwrap(),
+            "\x1b7\x1b[2;1H\x1b[J\x1b[2;1H\x1b[1mabcdef\x1b[0m\x1b[3;1H日本語\x1b8"
+        );
+        assert_eq!(fit("日本語", 5), (Cow::Borrowed("日本"), 4));
+    }
+
+    #[test]
+    fn scrolls_near_the_bottom() {
+        let response = draw((3, 23), (10, 24), &["aaaa", "bbbb", "cccc", "dddd", "eeee"]);
+        let response = response.response.to_str().unwrap();
+        assert!(response.starts_with("\n\n\n\x1b[21;3H\x1b7\x1b[22;1H\x1b[J\x1b[22;1Haaaa"));
+        assert!(response.contains("\x1b[24;1Hcccc"));
+        assert!(response.ends_with("\x1b[23;7Heeee\x1b8"));
+
+        // Nothing to scroll when there is room
+        let response = draw((3, 21), (10, 24), &["aaaa", "bbbb", "cccc", "dddd", "eeee"]);
+        assert!(response.response.starts_with(b"\x1b7\x1b[22;1H\x1b[J"));
+    }
+
+    #[test]
+    fn note_goes_below_the_items() {
+        let (pos, size) = (Pos::new(3, 23), Size::new(10, 24));
+        let response = grid(
+            pos,
+            size,
+            &items(&["aa", "bb"]),
+            0,
+            Some("more"),
+            GridStyle::default(),
+        );
+        // The note needs a line too, so the terminal scrolls
+        assert_eq!(
+            response.response.to_str().unwrap(),
+            "\n\n\x1b[22;3H\x1b7\x1b[23;1H\x1b[J\x1b[23;1H\x1b[7maa\x1b[0m\x1b[23;5Hbb\x1b[24;1H\x1b[2mmore\x1b[22m\x1b8"
+        );
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        let items = [
+            BString::from("new\nline"),
+            BString::from("tab\there"),
+            BString::from("\x1b[2Jred\x7f"),
+            BString::from(&b"bad\xff\xc2\x85"[..]),
+        ];
+        let shown: Vec<_> = items.iter().map(|item| escape_controls(item)).collect();
+        assert_eq!(
+            shown,
+            [
+                "new^Jline",
+                "tab^Ihere",
+                "\\e[2Jred^?",
+                "bad\u{fffd}\u{fffd}"
+            ]
+        );
+
+        let response = grid(
+            Pos::new(1, 1),
+            Size::new(80, 24),
+            &shown
+                .iter()
+                .map(|item| GridItem::new(&**item))
+                .collect::<Vec<_>>(),
+            0,
+            None,
+            GridStyle::default(),
+        );
+        let response = response.response.to_str().unwrap();
+        // Only the grid's own sequences are left, at the widths of the escaped names
+        let cursor_moves = regex::Regex::new("\x1b(\\[[0-9;]*[HJm]|[78])").unwrap();
+        let drawn = cursor_moves.replace_all(response, "|");
+        assert!(!drawn.contains(char::is_control), "{:?}", response);
+        assert_eq!(
+            drawn,
+            "|||||new^Jline||tab^Ihere|\\e[2Jred^?|bad\u{fffd}\u{fffd}|"
+        );
+    }
+
+    #[test]
+    fn styles_wrap_their_items_only() {
+        let items = [
+            GridItem {
+                text: "dir/".into(),
+                style: "34",
+                ..Default::default()
+            },
+            GridItem::new("plain"),
+            GridItem {
+                text: "run".into(),
+                style: "32",
+                ..Default::default()
+            },
+        ];
+        let response = grid(
+            Pos::new(1, 1),
+            Size::new(80, 24),
+            &items,
+            2,
+            None,
+            GridStyle::default(),
+        );
+        // Laid out as if unstyled, with the selection's reverse video over the color
+        assert_eq!(
+            response.response.to_str().unwrap(),
+            "\x1b7\x1b[2;1H\x1b[J\x1b[2;1H\x1b[34mdir/\x1b[0m\x1b[2;7Hplain\x1b[2;14H\x1b[32;7mrun\x1b[0m\x1b8"
+        );
+    }
+
+    #[test]
+    fn marks_underline_their_characters() {
+        let items = [
+            GridItem {
+                marks: vec![0, 4],
+                ..GridItem::new("Downloads")
+            },
+            GridItem {
+                text: "dl-tools".into(),
+                style: "34",
+                marks: vec![0, 1],
+            },
+        ];
+        let response = grid(
+            Pos::new(1, 1),
+            Size::new(80, 24),
+            &items,
+            1,
+            None,
+            GridStyle::default(),
+        );
+        // Underlining covers runs, leaving the color and the selection on
+        assert_eq!(
+            response.response.to_str().unwrap(),
+            "\x1b7\x1b[2;1H\x1b[J\x1b[2;1H\x1b[4mD\x1b[24mown\x1b[4ml\x1b[24moads\x1b[2;12H\x1b[34;7m\x1b[4mdl\x1b[24m-tools\x1b[0m\x1b8"
+        );
+        // Counted without escape sequences, and not past the end
+        assert_eq!(
+            underline("\x1b[1mab\x1b[0mc", &[1, 5]),
+            "\x1b[1ma\x1b[4mb\x1b[0m\x1b[24mc"
+        );
+    }
+
+    #[test]
+    fn status_line_cuts_the_path_from_the_left() {
+        assert_eq!(status_line("3/87", "~/projects/", 80), "3/87  ~/projects/");
+        assert_eq!(status_line("3/87", "~/projects/", 17), "3/87  ~/projects/");
+        assert_eq!(status_line("3/87", "~/projects/", 12), "3/87  …ects/");
+        // Wide characters aren't split
+        assert_eq!(status_line("1/2", "/日本/", 9), "1/2  …本/");
+        assert_eq!(status_line("1/2", "/日本/", 8), "1/2  …/");
+        // The count comes first
+        assert_eq!(status_line("10/200", "/tmp/", 8), "10/200");
+        assert_eq!(status_line("10/200", "/tmp/", 4), "10/2");
+    }
+
+    #[test]
+    fn table_aligns_second_column() {
+        let rows = [
+            ("cd", "Changes directory"),
+            ("日本", "Wide"),
+            ("[", "Tests"),
+        ];
+        assert_eq!(
+            table(&rows, GridStyle::default()),
+            "cd    Changes directory\n日本  Wide\n[     Tests\n"
+        );
+        assert_eq!(table::<&str, &str>(&[], GridStyle::default()), "");
+    }
+}
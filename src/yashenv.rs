@@ -0,0 +1,300 @@
+//! Per-directory environments: the `.yashenv` file of a directory is sourced when `cd`
+//! enters it, and what it set is undone when leaving it.
+//!
+//! Files are only sourced once allowed with `yashenv allow`, and allowing one again is
+//! needed whenever it changes.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    hash::Hash,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::eyre;
+
+use crate::{shell_println, utils, utils::quote_word, Shell, YshResult};
+
+pub const FILE_NAME: &str = ".yashenv";
+
+/// What sourcing an environment file changed, to undo it.
+#[derive(Debug, Clone)]
+struct Applied {
+    file: PathBuf,
+    hash: u64,
+    /// Previous values of the shell variables it changed
+    vars: Vec<(String, Option<String>)>,
+    /// Previous values of the environment variables it changed
+    env: Vec<(OsString, Option<OsString>)>,
+}
+
+/// The environment files in effect, outermost first.
+#[derive(Debug, Clone, Default)]
+pub struct EnvFiles {
+    applied: Vec<Applied>,
+    /// Where the allowed files are remembered, one `HASH PATH` per line
+    allow_file: Option<PathBuf>,
+    /// Set while files are sourced, so a `cd` in one of them doesn't source them again
+    updating: bool,
+}
+
+/// Hash of the contents of `file`, so it must be allowed again once it changes.
+fn content_hash(file: &Path) -> Option<u64> {
+    Some(utils::hash(&std::fs::read(file).ok()?))
+}
+
+/// The environment files of `dir` and its parents, outermost first.
+fn env_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<_> = dir
+        .ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .filter(|file| file.is_file())
+        .collect();
+    files.reverse();
+    files
+}
+
+/// Names whose value is different in `after`, with their value in `before`.
+fn changed<K, V>(before: &HashMap<K, V>, after: &HashMap<K, V>) -> Vec<(K, Option<V>)>
+where
+    K: Hash + Eq + Clone,
+    V: PartialEq + Clone,
+{
+    let added = after.keys().filter(|name| !before.contains_key(*name));
+    before
+        .keys()
+        .chain(added)
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| (name.clone(), before.get(name).cloned()))
+        .collect()
+}
+
+impl EnvFiles {
+    pub fn new(allow_file: Option<PathBuf>) -> Self {
+        Self {
+            allow_file,
+            ..Default::default()
+        }
+    }
+
+    fn allowed(&self) -> Vec<(u64, PathBuf)> {
+        let Some(contents) = self
+            .allow_file
+            .as_ref()
+            .and_then(|file| std::fs::read_to_string(file).ok())
+        else {
+            return vec![];
+        };
+        contents
+            .lines()
+            .filter_map(|line| {
+                let (hash, file) = line.split_once(' ')?;
+                Some((u64::from_str_radix(hash, 16).ok()?, file.into()))
+            })
+            .collect()
+    }
+
+    /// Hash of `file` if it is allowed as it is now.
+    fn allowed_hash(&self, file: &Path) -> Option<u64> {
+        let hash = content_hash(file)?;
+        self.allowed()
+            .contains(&(hash, file.to_path_buf()))
+            .then_some(hash)
+    }
+
+    /// Allows `file` as it is now to be sourced, or not anymore.
+    pub fn set_allowed(&self, file: &Path, allow: bool) -> YshResult<()> {
+        let Some(allow_file) = &self.allow_file else {
+            return Err(eyre!("no config directory to remember it in"));
+        };
+        let mut allowed = self.allowed();
+        allowed.retain(|(_, allowed)| allowed != file);
+        if allow {
+            let hash =
+                content_hash(file).ok_or_else(|| eyre!("can't read '{}'", file.display()))?;
+            allowed.push((hash, file.to_path_buf()));
+        }
+        let mut contents = String::new();
+        for (hash, file) in allowed {
+            contents.push_str(&format!("{:016x} {}\n", hash, file.display()));
+        }
+        if let Some(parent) = allow_file.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(allow_file, contents)?;
+        Ok(())
+    }
+}
+
+impl Shell {
+    /// Sources the allowed environment files of the working directory and its parents
+    /// that aren't in effect yet, undoing first those of the directories that were left.
+    ///
+    /// Files that aren't allowed are only pointed out, when their directory is entered
+    /// from outside `previous`.
+    pub fn update_env_files(&mut self, previous: Option<&Path>) -> YshResult<()> {
+        if self.env_files.updating {
+            return Ok(());
+        }
+        let mut wanted = vec![];
+        for file in env_files(&self.cwd) {
+            match self.env_files.allowed_hash(&file) {
+                Some(hash) => wanted.push((file, hash)),
+                None if !previous.is_some_and(|dir| dir.starts_with(file.parent().unwrap())) => {
+                    shell_println!(
+                        "yashenv: {} isn't allowed, source it with 'yashenv allow {}'",
+                        file.display(),
+                        quote_word(&file.to_string_lossy())
+                    );
+                }
+                None => (),
+            }
+        }
+        // Files in effect stay as long as the ones before them do
+        let kept = self
+            .env_files
+            .applied
+            .iter()
+            .zip(&wanted)
+            .take_while(|(applied, (file, hash))| applied.file == *file && applied.hash == *hash)
+            .count();
+        while self.env_files.applied.len() > kept {
+            let applied = self.env_files.applied.pop().unwrap();
+            self.undo_env_file(applied);
+        }
+        self.env_files.updating = true;
+        let result = wanted
+            .into_iter()
+            .skip(kept)
+            .try_for_each(|(file, hash)| self.apply_env_file(file, hash));
+        self.env_files.updating = false;
+        result
+    }
+
+    /// Sources `file`, remembering what it changed before telling what went wrong.
+    fn apply_env_file(&mut self, file: PathBuf, hash: u64) -> YshResult<()> {
+        let vars = self.vars.visible();
+        let env: HashMap<_, _> = std::env::vars_os().collect();
+        let result = self.source_file(&file);
+        self.env_files.applied.push(Applied {
+            file,
+            hash,
+            vars: changed(&vars, &self.vars.visible()),
+            env: changed(&env, &std::env::vars_os().collect()),
+        });
+        if let Err(e) = result {
+            shell_println!("yashenv: {}", e);
+        }
+        Ok(())
+    }
+
+    fn undo_env_file(&mut self, applied: Applied) {
+        for (name, value) in applied.vars {
+            match value {
+                Some(value) => self.vars.set(name, value),
+                None => self.vars.remove(&name),
+            };
+        }
+        for (name, value) in applied.env {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+
+    /// The environment file `path` names, or the innermost one of the working directory.
+    pub fn env_file(&self, path: Option<&str>) -> Option<PathBuf> {
+        let Some(path) = path else {
+            return env_files(&self.cwd).pop();
+        };
+        let path = utils::normalize_path(&self.cwd.join(path));
+        match path.is_dir() {
+            true => Some(path.join(FILE_NAME)),
+            false => Some(path),
+        }
+    }
+
+    /// Describes the environment files of the working directory and its parents.
+    pub fn env_files_status(&self) -> Vec<(PathBuf, &'static str)> {
+        env_files(&self.cwd)
+            .into_iter()
+            .map(|file| {
+                let applied = self.env_files.applied.iter().any(|a| a.file == file);
+                let status = match (applied, self.env_files.allowed_hash(&file)) {
+                    (true, _) => "applied",
+                    (false, Some(_)) => "allowed",
+                    (false, None) => "not allowed",
+                };
+                (file, status)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn changes_are_undone_when_leaving() {
+        let base = TempDir::new("yashenv");
+        let child = base.join("child");
+        std::fs::create_dir_all(&child).unwrap();
+        std::fs::write(
+            base.join(FILE_NAME),
+            "export YASH_TEST_YASHENV=outer\nA=outer\n",
+        )
+        .unwrap();
+        std::fs::write(child.join(FILE_NAME), "A=child\nB=2\n").unwrap();
+        let _cwd = crate::testing::WorkingDir::lock();
+        let mut shell = Shell::init(Default::default()).unwrap();
+        shell.env_files = EnvFiles::new(Some(base.join("allowed")));
+
+        // Nothing is sourced before it is allowed
+        let redirect = crate::output::Redirect::to_buffer();
+        shell.change_directory(&child).unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert_eq!(printed.lines().count(), 2, "{}", printed);
+        assert_eq!(shell.get_var("A"), None);
+
+        shell.execute_line("yashenv allow ..").unwrap();
+        assert_eq!(shell.get_var("A"), Some("outer"));
+        shell.execute_line("yashenv allow").unwrap();
+        assert_eq!(shell.get_var("A"), Some("child"));
+        assert_eq!(std::env::var("YASH_TEST_YASHENV").as_deref(), Ok("outer"));
+
+        shell.change_directory("..").unwrap();
+        assert_eq!(
+            (shell.get_var("A"), shell.get_var("B")),
+            (Some("outer"), None)
+        );
+        shell.change_directory("child").unwrap();
+        assert_eq!(shell.get_var("B"), Some("2"));
+
+        // A changed file must be allowed again
+        std::fs::write(child.join(FILE_NAME), "B=3\n").unwrap();
+        let redirect = crate::output::Redirect::to_buffer();
+        shell.change_directory("..").unwrap();
+        shell.change_directory("child").unwrap();
+        let printed = String::from_utf8(redirect.finish()).unwrap();
+        assert!(
+            printed.contains("child/.yashenv isn't allowed"),
+            "{}",
+            printed
+        );
+        assert_eq!(shell.get_var("B"), None);
+        assert_eq!(
+            shell.env_files_status(),
+            [
+                (base.join(FILE_NAME), "applied"),
+                (child.join(FILE_NAME), "not allowed")
+            ]
+        );
+
+        shell.change_directory(std::env::temp_dir()).unwrap();
+        assert_eq!(shell.get_var("A"), None);
+        assert!(std::env::var("YASH_TEST_YASHENV").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Drives the shell as a library, the way an embedding program would, without a terminal.
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use yash::{Io, Shell, Vec2};
+
+/// Stands in for the terminal, keeping what is written to it.
+struct Screen {
+    written: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Io for Screen {
+    fn read(&self, _buf: &mut [u8]) -> nix::Result<usize> {
+        Ok(0)
+    }
+    fn write(&self, bytes: &[u8]) -> nix::Result<usize> {
+        self.written.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+    fn poll_input(&self, _timeout: Duration) -> nix::Result<bool> {
+        Ok(false)
+    }
+    fn size(&self) -> nix::Result<Vec2> {
+        Ok(Vec2::new(80, 24))
+    }
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+#[test]
+fn output_of_command_lines_is_captured() {
+    let mut shell = Shell::init(Default::default()).unwrap();
+    let captured = shell
+        .execute_line_capture("NAME=world; echo hello $NAME")
+        .unwrap();
+    assert_eq!(captured.stdout, b"hello world\n");
+    assert_eq!(captured.status, 0);
+
+    // Programs print into the capture too, and their status is kept
+    let captured = shell
+        .execute_line_capture("sh -c 'echo from sh; exit 3'")
+        .unwrap();
+    assert_eq!(captured.stdout, b"from sh\n");
+    assert_eq!(captured.status, 3);
+    assert_eq!(shell.get_var("NAME"), Some("world"));
+}
+
+#[test]
+fn output_goes_to_the_given_io() {
+    let written = Rc::new(RefCell::new(vec![]));
+    let mut shell = Shell::init(Default::default()).unwrap();
+    shell.set_io(Screen {
+        written: written.clone(),
+    });
+    // Builtins print through the shell, unlike programs which have the real stdout
+    shell.execute_line("alias x=y; alias").unwrap();
+    yash::output::flush().unwrap();
+    assert_eq!(written.borrow().as_slice(), b"alias x=y\n");
+}
+
+#[test]
+fn errors_are_returned() {
+    let mut shell = Shell::init(Default::default()).unwrap();
+    let error = shell.execute_line("popd").unwrap_err();
+    assert_eq!(error.to_string(), "popd: directory stack empty");
+}
@@ -0,0 +1,56 @@
+//! Pipelines of several programs, each reading what the one before it wrote.
+
+use std::time::{Duration, Instant};
+
+use yash::Shell;
+
+mod common;
+use common::TempDir;
+
+fn run(line: &str) -> (String, i32) {
+    let mut shell = Shell::init(Default::default()).unwrap();
+    let captured = shell.execute_line_capture(line).unwrap();
+    (String::from_utf8(captured.stdout).unwrap(), captured.status)
+}
+
+#[test]
+fn three_stages_run_in_order() {
+    assert_eq!(run("printf 'b\\na\\n' | sort | head -1"), ("a\n".into(), 0));
+    assert_eq!(run("printf 'b\\na\\n' | head -1 | sort"), ("b\n".into(), 0));
+    // Builtins feed the first program
+    assert_eq!(
+        run("echo c b a | tr ' ' '\\n' | sort"),
+        ("a\nb\nc\n".into(), 0)
+    );
+}
+
+#[test]
+fn four_stages_run_in_order() {
+    let line = "printf '3\\n1\\n2\\n' | sort | tr 1 x | head -2";
+    assert_eq!(run(line), ("x\n2\n".into(), 0));
+    let line = "printf 'a\\nb\\na\\n' | sort | uniq -c | wc -l";
+    assert_eq!(run(line).0.trim(), "2");
+}
+
+#[test]
+fn every_stage_is_waited_for() {
+    let start = Instant::now();
+    let (printed, status) = run("sleep 0.3 | true | sh -c 'exit 4' | cat");
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert_eq!((printed.as_str(), status), ("", 0));
+    assert_eq!(run("true | cat | sh -c 'exit 4'").1, 4);
+}
+
+#[test]
+fn last_stage_writes_where_it_is_redirected() {
+    let dir = TempDir::new("pipelines");
+    let out = dir.join("out");
+    let input = dir.join("in");
+    std::fs::write(&input, "from file\n").unwrap();
+    let line = format!("printf x | cat | cat > {}", out.display());
+    assert_eq!(run(&line), ("".into(), 0));
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "x");
+    // A file read with `<` comes instead of the pipe
+    let line = format!("echo ignored | cat < {} | cat", input.display());
+    assert_eq!(run(&line), ("from file\n".into(), 0));
+}
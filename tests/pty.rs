@@ -0,0 +1,169 @@
+//! Runs the built shell interactively on a pseudo terminal, answering it like a terminal.
+
+use std::{
+    os::unix::io::{FromRawFd, RawFd},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    pty::{openpty, Winsize},
+};
+
+mod common;
+use common::TempDir;
+
+/// Gives up on the shell after this long, so a bug can't hang the tests.
+const DEADLINE: Duration = Duration::from_secs(10);
+
+/// The side of the pty the user is on, keeping track of the cursor column to answer
+/// the shell's position queries.
+struct Terminal {
+    master: RawFd,
+    drawn: Vec<u8>,
+    /// Counted from 1, like in position reports
+    column: usize,
+    saved_column: usize,
+}
+
+impl Terminal {
+    /// Follows the cursor through `bytes`, answering the position queries in them.
+    fn draw(&mut self, bytes: &[u8]) {
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\r' => self.column = 1,
+                0x08 => self.column = self.column.saturating_sub(1).max(1),
+                b'\x1b' => {
+                    i += self.escape(&bytes[i..]);
+                    continue;
+                }
+                // Not the continuation bytes of UTF-8
+                b' '..=0x7e | 0xc0..=0xff => self.column += 1,
+                _ => (),
+            }
+            i += 1;
+        }
+        self.drawn.extend_from_slice(bytes);
+    }
+
+    /// Handles the escape sequence at the start of `bytes`, returning its length.
+    fn escape(&mut self, bytes: &[u8]) -> usize {
+        match bytes.get(1) {
+            Some(b'7') => self.saved_column = self.column,
+            Some(b'8') => self.column = self.saved_column,
+            Some(b'[') => {
+                let Some(end) = bytes[2..].iter().position(|b| (0x40..=0x7e).contains(b)) else {
+                    return bytes.len();
+                };
+                let params = std::str::from_utf8(&bytes[2..2 + end]).unwrap_or_default();
+                let count = params.parse().unwrap_or(1);
+                match bytes[2 + end] {
+                    b'C' => self.column += count,
+                    b'D' => self.column = self.column.saturating_sub(count).max(1),
+                    b'H' => self.column = 1,
+                    b'n' if params == "6" => {
+                        let report = format!("\x1b[1;{}R", self.column);
+                        nix::unistd::write(self.master, report.as_bytes()).unwrap();
+                    }
+                    _ => (),
+                }
+                return end + 3;
+            }
+            // Titles and other strings end at BEL
+            Some(b']') => {
+                let end = bytes
+                    .iter()
+                    .position(|&b| b == 0x07)
+                    .unwrap_or(bytes.len() - 1);
+                return end + 1;
+            }
+            _ => (),
+        }
+        2.min(bytes.len())
+    }
+
+    /// Reads what the shell draws until `done` says it is enough.
+    fn wait_for(&mut self, done: impl Fn(&[u8]) -> bool) {
+        let deadline = Instant::now() + DEADLINE;
+        while !done(&self.drawn) {
+            assert!(
+                Instant::now() < deadline,
+                "gave up waiting, drawn: {:?}",
+                String::from_utf8_lossy(&self.drawn)
+            );
+            let mut fds = [PollFd::new(self.master, PollFlags::POLLIN)];
+            if poll(&mut fds, 10).unwrap() > 0 {
+                let mut chunk = [0u8; 1024];
+                match nix::unistd::read(self.master, &mut chunk) {
+                    Ok(n) => self.draw(&chunk[..n]),
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    fn type_keys(&self, keys: &[u8]) {
+        nix::unistd::write(self.master, keys).unwrap();
+    }
+}
+
+fn count(haystack: &[u8], needle: &[u8]) -> usize {
+    haystack
+        .windows(needle.len())
+        .filter(|w| *w == needle)
+        .count()
+}
+
+#[test]
+fn unfinished_output_is_marked_before_the_prompt() {
+    let home = TempDir::new("pty");
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let pty = openpty(Some(&winsize), None).unwrap();
+    let slave = || unsafe { Stdio::from_raw_fd(nix::unistd::dup(pty.slave).unwrap()) };
+    let mut child = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .env("HOME", &home)
+        .env("TERM", "xterm")
+        .env("PS1", "$ ")
+        .env("YASH_SET_TITLE", "0")
+        .env_remove("XDG_CONFIG_HOME")
+        .stdin(slave())
+        .stdout(slave())
+        .stderr(slave())
+        .spawn()
+        .unwrap();
+    nix::unistd::close(pty.slave).unwrap();
+    let mut terminal = Terminal {
+        master: pty.master,
+        drawn: Vec::new(),
+        column: 1,
+        saved_column: 1,
+    };
+    let mark = b"\x1b[7m%\x1b[27m\r\n";
+    let prompts = |drawn: &[u8]| count(drawn, b"\x1b[6n");
+    terminal.wait_for(|drawn| prompts(drawn) == 1);
+
+    terminal.type_keys(b"printf abc\r");
+    terminal.wait_for(|drawn| prompts(drawn) == 2);
+    // A whole line before doesn't get one
+    terminal.type_keys(b"echo done\r");
+    terminal.wait_for(|drawn| prompts(drawn) == 3);
+    terminal.type_keys(b"exit\r");
+    let status = child.wait().unwrap();
+    terminal.wait_for(|_| true);
+    nix::unistd::close(pty.master).unwrap();
+
+    let drawn = String::from_utf8_lossy(&terminal.drawn).into_owned();
+    assert!(status.success(), "{:?}", drawn);
+    assert_eq!(count(&terminal.drawn, mark), 1, "{:?}", drawn);
+    // Between what printf left and the next prompt
+    let marked = drawn.find("\x1b[7m%").unwrap();
+    assert!(drawn.rfind("abc").unwrap() < marked, "{:?}", drawn);
+    assert!(marked < drawn.find("done").unwrap(), "{:?}", drawn);
+}
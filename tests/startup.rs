@@ -0,0 +1,316 @@
+//! Runs the built shell with a crafted home directory, to see which startup files it sources.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+mod common;
+use common::TempDir;
+
+/// A home directory whose startup files each print their name.
+fn home(name: &str) -> TempDir {
+    let home = TempDir::new(name);
+    let config = home.join(".config/yash");
+    std::fs::create_dir_all(&config).unwrap();
+    for file in ["profile.ysh", "yashrc", "logout.ysh"] {
+        std::fs::write(config.join(file), format!("echo {}\n", file)).unwrap();
+    }
+    home
+}
+
+/// Runs yash with `args` and returns what it printed.
+fn yash(home: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args(args)
+        .env("HOME", home)
+        .env_remove("XDG_CONFIG_HOME")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn login_command_sources_profile_but_not_rc() {
+    let home = home("login");
+    assert_eq!(
+        yash(&home, &["-l", "-c", "echo hi"]),
+        "profile.ysh\nhi\nlogout.ysh\n"
+    );
+    assert_eq!(yash(&home, &["-c", "echo hi"]), "hi\n");
+    // Like bash's --noprofile, the logout file still runs
+    assert_eq!(
+        yash(&home, &["--login", "--norc", "-c", "echo hi"]),
+        "hi\nlogout.ysh\n"
+    );
+}
+
+#[test]
+fn exit_status_of_command_is_kept() {
+    let home = home("status");
+    let status = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args(["-c", "sh -c 'exit 4'"])
+        .env("HOME", &home)
+        .stdin(Stdio::null())
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(4));
+    let output = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args(["-c", "set -e; echo before; sh -c 'exit 5'; echo after"])
+        .env("HOME", &home)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.stdout, b"before\n");
+    assert_eq!(output.status.code(), Some(5));
+}
+
+#[test]
+fn deleted_start_directory_falls_back_to_home() {
+    let home = home("deleted");
+    let gone = home.join("gone");
+    std::fs::create_dir_all(&gone).unwrap();
+    // The shell starts in a directory removed right before
+    let script = format!(
+        "cd {} && rmdir {} && exec {} -c 'echo $PWD'",
+        gone.display(),
+        gone.display(),
+        env!("CARGO_BIN_EXE_yash")
+    );
+    let output = Command::new("sh")
+        .args(["-c", &script])
+        .env("HOME", &home)
+        .env_remove("PWD")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let printed = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        printed.starts_with("yash: can't use the current directory"),
+        "{}",
+        printed
+    );
+    assert!(
+        printed.ends_with(&format!("\n{}\n", home.display())),
+        "{}",
+        printed
+    );
+}
+
+#[test]
+fn closed_stdout_ends_the_shell_quietly() {
+    let home = home("pipe");
+    let (reader, writer) = std::io::pipe().unwrap();
+    drop(reader);
+    // More than fits in the output buffer, so it is written while running
+    let word = "x".repeat(16 * 1024);
+    let output = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args(["-c", &format!("echo {}; echo {}", word, word)])
+        .env("HOME", &home)
+        .stdin(Stdio::null())
+        .stdout(writer)
+        .stderr(Stdio::piped())
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert_eq!(output.status.code(), Some(141), "{}", stderr);
+}
+
+#[test]
+fn exec_replaces_the_shell() {
+    let home = home("exec");
+    let child = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args([
+            "-c",
+            "GREETING=hi exec sh -c 'echo $$; printenv GREETING; exit 7'; echo not replaced",
+        ])
+        .env("HOME", &home)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    let output = child.wait_with_output().unwrap();
+    // The program runs in the shell's own process, and its status is the shell's
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}\nhi\n", pid)
+    );
+    assert_eq!(output.status.code(), Some(7));
+
+    // A program that can't run leaves the shell going
+    let printed = yash(&home, &["-c", "exec yash-missing-program; echo still here"]);
+    assert!(
+        printed.contains("yash-missing-program: command not found"),
+        "{}",
+        printed
+    );
+    assert!(printed.ends_with("still here\n"), "{}", printed);
+}
+
+#[test]
+fn scripts_without_hashbang_run_with_yash() {
+    use std::os::unix::fs::PermissionsExt;
+    let home = home("script");
+    let script = home.join("script");
+    std::fs::write(&script, "echo one $1\necho two; sh -c 'exit 3'\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args(["-c", &format!("{} arg | cat", script.display())])
+        .env("HOME", &home)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "one arg\ntwo\n");
+
+    // The script's status is the command's
+    let output = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args(["-c", &script.display().to_string()])
+        .env("HOME", &home)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+
+    // The stages before it run once
+    let log = home.join("log");
+    let stage = format!("sh -c 'echo ran >> {}'", log.display());
+    let line = format!("{} | {} arg", stage, script.display());
+    yash(&home, &["-c", &line]);
+    assert_eq!(std::fs::read_to_string(&log).unwrap(), "ran\n");
+
+    // One running itself gives up instead of looping forever
+    std::fs::write(&script, "$0\n").unwrap();
+    let printed = yash(&home, &["-c", &script.display().to_string()]);
+    assert!(
+        printed.contains("scripts nested deeper than"),
+        "{}",
+        printed
+    );
+}
+
+#[test]
+fn umask_applies_to_redirections() {
+    use std::os::unix::fs::PermissionsExt;
+    // Run by another process, since the mask is shared by the threads of the tests
+    let home = home("umask");
+    let path = home.join("secret");
+    let line = format!(
+        "umask 077; umask; umask -S; echo secret > {}; umask -S g+rx; umask 8",
+        path.display()
+    );
+    let printed = yash(&home, &["-c", &line]);
+    let expected = "0077\nu=rwx,g=,o=\nu=rwx,g=rx,o=\numask: 8: invalid octal mask\n";
+    assert_eq!(printed, expected);
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn nested_shells_count_their_level() {
+    let home = home("shlvl");
+    let exe = env!("CARGO_BIN_EXE_yash");
+    let script = format!("echo $SHLVL; {} -c 'echo $SHLVL $SHELL'", exe);
+    let output = Command::new(exe)
+        .args(["-c", &script])
+        .env("HOME", &home)
+        .env_remove("SHLVL")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    let exe = Path::new(exe).canonicalize().unwrap();
+    let expected = format!("1\n2 {}\n", exe.display());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+#[test]
+fn limits_are_inherited_by_programs() {
+    let home = home("ulimit");
+    let printed = yash(&home, &["-c", "ulimit -c 0; sh -c 'ulimit -c'; ulimit -c"]);
+    assert_eq!(printed.lines().collect::<Vec<_>>(), ["0", "0"]);
+    let output = Command::new(env!("CARGO_BIN_EXE_yash"))
+        .args(["-c", "ulimit -n 4096; ulimit -H -n"])
+        .env("HOME", &home)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    // Above the hard limit unless that is high enough
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match String::from_utf8(output.stdout).unwrap().trim() {
+        "unlimited" => (),
+        hard if hard.parse::<u64>().unwrap() >= 4096 => (),
+        hard => assert!(
+            stderr.contains(&format!(
+                "ulimit: can't set open files to 4096: above the hard limit of {}",
+                hard
+            )),
+            "{}",
+            stderr
+        ),
+    }
+}
+
+#[test]
+fn reload_keeps_aliases_not_from_the_rc_files() {
+    let home = home("reload");
+    let yashrc = home.join(".config/yash/yashrc");
+    std::fs::write(
+        &yashrc,
+        "echo yashrc\nalias ll='ls -l'\nalias dirs='echo shadowed'\ncd /nonexistent-yash\n",
+    )
+    .unwrap();
+    let script = format!(
+        "Y=typed; alias mine=pwd; reload; alias; echo $Y; \
+        echo 'echo edited' > {}; reload; alias; help dirs; \
+        reload -a; alias; echo \"[$Y]\"",
+        yashrc.display()
+    );
+    let printed = yash(&home, &["-c", &script]);
+    let lines = printed.lines().collect::<Vec<_>>();
+    // Errors are told by line, like on startup
+    assert!(lines[1].starts_with(&format!("{}:4: ", yashrc.display())));
+    assert_eq!(
+        lines[2..],
+        [
+            "alias 'dirs=echo shadowed'",
+            "alias 'll=ls -l'",
+            "alias mine=pwd",
+            "typed",
+            // The edited file no longer has the aliases, which uncovers the builtin
+            "edited",
+            "alias mine=pwd",
+            "dirs: Lists the directory stack",
+            "",
+            "usage: dirs",
+            "",
+            "Lists the current directory first, at 0, followed by the saved ones.",
+            "edited",
+            "[]",
+        ]
+    );
+}
+
+#[test]
+fn plugins_in_the_config_folder_are_commands() {
+    use std::os::unix::fs::PermissionsExt;
+    let home = home("plugins");
+    let plugins = home.join(".config/yash/plugins");
+    std::fs::create_dir_all(&plugins).unwrap();
+    let plugin = plugins.join("yash-go");
+    std::fs::write(
+        &plugin,
+        "#!/bin/sh\necho \"going to $1\"\necho \"::cd $1\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let printed = yash(&home, &["-c", "go /; pwd"]);
+    let plain = yash(&home, &["--norc", "-c", "go /"]);
+    assert_eq!(printed.lines().collect::<Vec<_>>(), ["going to /", "/"]);
+    // Like the rc files, they are left out with --norc
+    assert_eq!(
+        plain.lines().collect::<Vec<_>>(),
+        ["yash: go: command not found"]
+    );
+}